@@ -0,0 +1,461 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures::{SinkExt, StreamExt};
+use p2p::pairing::PAIR_TIMEOUT;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+use tracing::{debug, warn};
+
+use crate::node::{AppCmd, AppQuery, CoreController, CoreEvent, CoreResponse};
+
+// json-rpc error codes
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const CORE_ERROR: i32 = -32000;
+
+// how many events a slow client can fall behind before it misses some
+const EVENT_BACKLOG: usize = 64;
+
+/// how many pairing requests are held while no client is connected, unless changed with
+/// [ApiServer::set_waiting_cap]
+pub const DEFAULT_WAITING_CAP: usize = 16;
+
+// pairing requests which came in while no client was connected, with when they came
+type Waiting = Arc<Mutex<VecDeque<(Instant, CoreEvent)>>>;
+
+/// Serves the node to uis in other processes over a localhost websocket.
+///
+/// Every text message is a json-rpc 2.0 request. The method `query` takes an [AppQuery] and
+/// `command` takes an [AppCmd] as params, both answered with a [CoreResponse]. Every
+/// [CoreEvent] is pushed to all clients as an `event` notification. Requests without an id are
+/// notifications too, they are carried out but never answered.
+///
+/// Clients prove they were given the token with a `token` query parameter in the url or a
+/// bearer `Authorization` header, others are refused during the handshake.
+///
+/// Pairing requests which come in while no client is connected are held until one connects, as
+/// long as they can still be answered.
+pub struct ApiServer {
+    listener: TcpListener,
+    token: String,
+    waiting_cap: usize,
+}
+
+impl ApiServer {
+    /// listen on a loopback address, the api is never reachable from the network
+    pub async fn bind(addr: SocketAddr, token: String) -> io::Result<Self> {
+        if !addr.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the api only listens on loopback addresses",
+            ));
+        }
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the api needs a token",
+            ));
+        }
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            token,
+            waiting_cap: DEFAULT_WAITING_CAP,
+        })
+    }
+
+    /// hold at most `cap` pairing requests while no client is connected, the oldest are dropped
+    pub fn set_waiting_cap(&mut self, cap: usize) {
+        self.waiting_cap = cap;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// serve clients until the node stops sending events
+    pub async fn run(self, controller: CoreController, mut events: mpsc::Receiver<CoreEvent>) {
+        let (broadcast, _) = broadcast::channel(EVENT_BACKLOG);
+        let waiting = Waiting::default();
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let Ok((stream, addr)) = accepted else {
+                        continue;
+                    };
+                    debug!("Api client connecting from {:?}", addr);
+                    let controller = controller.clone();
+                    let token = self.token.clone();
+                    let events = broadcast.clone();
+                    let waiting = waiting.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = client(stream, &token, controller, events, waiting).await {
+                            debug!("Api client {:?} disconnected: {:?}", addr, e);
+                        }
+                    });
+                }
+                event = events.recv() => match event {
+                    Some(event) => {
+                        let mut waiting = waiting.lock().unwrap();
+                        if broadcast.receiver_count() == 0
+                            && self.waiting_cap > 0
+                            && matches!(event, CoreEvent::PairRequest(..))
+                        {
+                            if waiting.len() == self.waiting_cap {
+                                waiting.pop_front();
+                            }
+                            waiting.push_back((Instant::now(), event));
+                        } else {
+                            // other events are only pushed to connected clients
+                            _ = broadcast.send(event);
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+        debug!("Shutting down the api server");
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    // requests without an id are notifications, which are never answered
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<CoreResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct RpcNotification<'a> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: &'a CoreEvent,
+}
+
+// an id which is there, even when it is null
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+async fn client(
+    stream: TcpStream,
+    token: &str,
+    controller: CoreController,
+    broadcast: broadcast::Sender<CoreEvent>,
+    waiting: Waiting,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    // the error type is set by tungstenite
+    #[allow(clippy::result_large_err)]
+    let authorize = |request: &Request, response: Response| {
+        if authorized(request, token) {
+            Ok(response)
+        } else {
+            let mut refused = ErrorResponse::new(Some(String::from("invalid token")));
+            *refused.status_mut() = StatusCode::UNAUTHORIZED;
+            Err(refused)
+        }
+    };
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, authorize).await?;
+
+    // subscribe and take the held requests together so no event is missed or sent twice
+    let (mut events, held) = {
+        let mut waiting = waiting.lock().unwrap();
+        let held: Vec<CoreEvent> = waiting
+            .drain(..)
+            .filter(|(at, _)| at.elapsed() < PAIR_TIMEOUT)
+            .map(|(_, event)| event)
+            .collect();
+        (broadcast.subscribe(), held)
+    };
+    for event in held {
+        ws.send(Message::Text(notification(&event))).await?;
+    }
+
+    loop {
+        tokio::select! {
+            message = ws.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e),
+                };
+                let Some(response) = handle(&controller, &text).await else {
+                    continue;
+                };
+                let json = serde_json::to_string(&response).expect("responses serialize");
+                ws.send(Message::Text(json)).await?;
+            }
+            event = events.recv() => match event {
+                Ok(event) => ws.send(Message::Text(notification(&event))).await?,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("An api client fell behind and missed {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+// answer a request, None for notifications
+async fn handle(controller: &CoreController, text: &str) -> Option<RpcResponse> {
+    let request: RpcRequest = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => return Some(failure(Value::Null, PARSE_ERROR, e.to_string())),
+    };
+    let core_error = |e: crate::err::CoreError| (CORE_ERROR, e.to_string());
+    let response = match request.method.as_str() {
+        "query" => match serde_json::from_value::<AppQuery>(request.params) {
+            Ok(query) => controller.query(query).await.map_err(core_error),
+            Err(e) => Err((INVALID_PARAMS, e.to_string())),
+        },
+        "command" => match serde_json::from_value::<AppCmd>(request.params) {
+            Ok(cmd) => controller.command(cmd).await.map_err(core_error),
+            Err(e) => Err((INVALID_PARAMS, e.to_string())),
+        },
+        method => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    };
+    let id = request.id?;
+    Some(match response {
+        Ok(response) => RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: Some(response),
+            error: None,
+        },
+        Err((code, message)) => failure(id, code, message),
+    })
+}
+
+fn notification(event: &CoreEvent) -> String {
+    let notification = RpcNotification {
+        jsonrpc: "2.0",
+        method: "event",
+        params: event,
+    };
+    serde_json::to_string(&notification).expect("events serialize")
+}
+
+fn failure(id: Value, code: i32, message: String) -> RpcResponse {
+    RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(RpcError { code, message }),
+    }
+}
+
+/// whether the handshake carries the token, compared in constant time
+fn authorized(request: &Request, token: &str) -> bool {
+    let from_query = request.uri().query().and_then(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.into_owned())
+    });
+    let from_header = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(String::from);
+    let Some(given) = from_query.or(from_header) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use p2p::peer::{DeviceType, PeerId, PeerMetadata};
+    use serde_json::{json, Value};
+    use tokio::{
+        sync::mpsc,
+        time::{sleep, timeout},
+    };
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    use super::ApiServer;
+    use crate::node::{CoreController, CoreEvent, CoreResponse};
+
+    async fn next_json<S>(ws: &mut S) -> Value
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let message = timeout(Duration::from_secs(1), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_query_and_receive_events() {
+        let (controller, mut queries, _commands) = CoreController::detached();
+        // answer queries like a node running two tasks
+        tokio::spawn(async move {
+            while let Some(query) = queries.recv().await {
+                query.respond(Ok(CoreResponse::Tasks(2)));
+            }
+        });
+        let (events, events_rx) = mpsc::channel(8);
+        let server = ApiServer::bind("127.0.0.1:0".parse().unwrap(), String::from("secret"))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run(controller, events_rx));
+
+        assert!(connect_async(format!("ws://{}/?token=wrong", addr))
+            .await
+            .is_err());
+
+        let (mut ws, _) = connect_async(format!("ws://{}/?token=secret", addr))
+            .await
+            .unwrap();
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "query",
+            "params": { "type": "GetTaskCount" },
+        });
+        ws.send(Message::Text(request.to_string())).await.unwrap();
+        assert_eq!(
+            json!({ "jsonrpc": "2.0", "id": 7, "result": { "type": "Tasks", "data": 2 } }),
+            next_json(&mut ws).await
+        );
+
+        ws.send(Message::Text(String::from("{ not json")))
+            .await
+            .unwrap();
+        assert_eq!(-32700, next_json(&mut ws).await["error"]["code"]);
+
+        // notifications are handled but never answered, even when they fail
+        for notification in [
+            json!({ "jsonrpc": "2.0", "method": "query", "params": { "type": "GetTaskCount" } }),
+            json!({ "jsonrpc": "2.0", "method": "unknown" }),
+        ] {
+            ws.send(Message::Text(notification.to_string()))
+                .await
+                .unwrap();
+        }
+        let request = json!({ "jsonrpc": "2.0", "id": 8, "method": "unknown" });
+        ws.send(Message::Text(request.to_string())).await.unwrap();
+        assert_eq!(8, next_json(&mut ws).await["id"]);
+
+        events.send(CoreEvent::Ephemeral).await.unwrap();
+        assert_eq!(
+            json!({ "jsonrpc": "2.0", "method": "event", "params": { "type": "Ephemeral" } }),
+            next_json(&mut ws).await
+        );
+    }
+
+    #[tokio::test]
+    async fn pair_requests_wait_for_a_client() {
+        let (controller, _queries, _commands) = CoreController::detached();
+        let (events, events_rx) = mpsc::channel(8);
+        let mut server = ApiServer::bind("127.0.0.1:0".parse().unwrap(), String::from("secret"))
+            .await
+            .unwrap();
+        server.set_waiting_cap(1);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run(controller, events_rx));
+
+        // nobody is connected, only the latest request is kept and other events are dropped
+        let request = |name: &str| {
+            CoreEvent::PairRequest(
+                PeerMetadata {
+                    name: name.to_string(),
+                    typ: DeviceType::AppleiPhone,
+                    id: PeerId::from_string(String::from(
+                        "QWERTYUIOPQWERTYUIOPQWERTYUIOPQWERTYUIOP",
+                    ))
+                    .unwrap(),
+                    addrs: Vec::new(),
+                },
+                String::from("1234"),
+            )
+        };
+        events.send(request("first")).await.unwrap();
+        events.send(request("second")).await.unwrap();
+        events.send(CoreEvent::Ephemeral).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        let (mut ws, _) = connect_async(format!("ws://{}/?token=secret", addr))
+            .await
+            .unwrap();
+        let held = next_json(&mut ws).await;
+        assert_eq!("PairRequest", held["params"]["type"]);
+        assert_eq!("second", held["params"]["data"][0]["name"]);
+
+        events.send(CoreEvent::Ephemeral).await.unwrap();
+        assert_eq!("Ephemeral", next_json(&mut ws).await["params"]["type"]);
+    }
+
+    #[tokio::test]
+    async fn tokens_are_percent_decoded() {
+        let (controller, _queries, _commands) = CoreController::detached();
+        let (_events, events_rx) = mpsc::channel(8);
+        let server = ApiServer::bind("127.0.0.1:0".parse().unwrap(), String::from("a+b/c&d"))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run(controller, events_rx));
+
+        assert!(connect_async(format!("ws://{}/?token=a%2Bb%2Fc%26d", addr))
+            .await
+            .is_ok());
+        assert!(connect_async(format!("ws://{}/?token=a+b/c", addr))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn only_loopback_is_served() {
+        let bound = ApiServer::bind("0.0.0.0:0".parse().unwrap(), String::from("secret")).await;
+        assert!(bound.is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path;
 
@@ -6,12 +6,18 @@ use p2p::peer;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
+use tracing::warn;
 
 use crate::err::ConfError;
 use crate::plat;
 use crate::secret;
+use crate::visibility::VisibilitySchedule;
 
 pub static NODE_CONFIG_NAME: &str = "settings.json";
+pub static NODE_CONFIG_BACKUP_NAME: &str = "settings.json.bak";
+pub static NODE_CONFIG_CORRUPT_NAME: &str = "settings.json.corrupt";
+pub static NODE_CONFIG_PROBE_NAME: &str = ".probe";
+pub static NODE_SHUTDOWN_NAME: &str = "shutdown.json";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NodeConfig {
@@ -19,6 +25,169 @@ pub struct NodeConfig {
     #[serde(skip)]
     pub id: peer::PeerId,
     pub known_peers: HashSet<peer::PeerMetadata>,
+    /// when the node can be discovered, always if unset
+    #[serde(default)]
+    pub visibility: Option<VisibilitySchedule>,
+    /// whether core events are journaled to disk
+    #[serde(default)]
+    pub journal: bool,
+    /// set when the config directory can't be written, nothing is persisted this run
+    #[serde(skip)]
+    pub ephemeral: bool,
+    /// why the node stopped last run, unset if it exited without recording a reason
+    #[serde(skip)]
+    pub last_shutdown: Option<ShutdownReason>,
+    /// what is left out of the metadata the node broadcasts
+    #[serde(default)]
+    pub privacy: Privacy,
+    /// the local names the user gave paired peers
+    #[serde(default)]
+    pub nicknames: HashMap<peer::PeerId, String>,
+    /// the peers which are ignored on discovery and turned away when they connect
+    #[serde(default)]
+    pub blocked: HashSet<peer::PeerId>,
+    /// how many days a paired peer can go unseen before it has to pair again, never if unset
+    #[serde(default)]
+    pub pairing_expiry: Option<u32>,
+    /// whether the router is asked over NAT-PMP or UPnP to forward a port to the node, so devices
+    /// on other subnets behind it can connect
+    #[serde(default)]
+    pub port_mapping: bool,
+    /// whether paired peers off the lan are reached through the rendezvous server
+    #[serde(default)]
+    pub enable_relay: bool,
+    /// the rendezvous server as a host and port, used while the relay is enabled
+    #[serde(default)]
+    pub relay_server: Option<String>,
+    /// the pins made for other devices to pair with, the latest last
+    #[serde(default)]
+    pub invitations: Vec<Invitation>,
+}
+
+impl NodeConfig {
+    /// the name to broadcast, a pseudonym made from the id when the name is hidden
+    pub fn advertised_name(&self) -> String {
+        if self.privacy.hide_name {
+            format!("Flydrop {}", self.id.inner()[..4].to_uppercase())
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// the device type to broadcast, unknown when it is hidden
+    pub fn advertised_device(&self, device: peer::DeviceType) -> peer::DeviceType {
+        if self.privacy.hide_device {
+            peer::DeviceType::Unknown
+        } else {
+            device
+        }
+    }
+
+    /// every paired peer with the nickname the user gave it
+    pub fn known(&self) -> Vec<KnownPeer> {
+        let mut known: Vec<KnownPeer> = self
+            .known_peers
+            .iter()
+            .map(|metadata| KnownPeer {
+                metadata: metadata.clone(),
+                nickname: self.nicknames.get(&metadata.id).cloned(),
+            })
+            .collect();
+        known.sort_by(|a, b| a.name().cmp(b.name()));
+        known
+    }
+
+    /// record a new pin invitation valid for `lifetime` ms, it replaces any earlier one.
+    /// Returns the number it got.
+    pub fn invite(&mut self, now: u64, lifetime: u64) -> u64 {
+        let id = self.invitations.last().map_or(1, |last| last.id + 1);
+        if self.invitations.len() >= MAX_INVITATIONS {
+            self.invitations.remove(0);
+        }
+        self.invitations.push(Invitation {
+            id,
+            created: now,
+            expires: now + lifetime,
+            used_by: None,
+            revoked: false,
+        });
+        id
+    }
+
+    /// every recorded invitation, only the latest can still be valid and only if `pin_shown`
+    pub fn invitations(&self, now: u64, pin_shown: bool) -> Vec<InvitationState> {
+        let latest = self.invitations.last().map(|last| last.id);
+        self.invitations
+            .iter()
+            .map(|invitation| InvitationState {
+                valid: pin_shown && Some(invitation.id) == latest && invitation.is_open(now),
+                invitation: invitation.clone(),
+            })
+            .collect()
+    }
+
+    /// give a paired peer a nickname, an empty one clears it.
+    /// Returns false if the peer is not paired.
+    pub fn rename_peer(&mut self, id: &peer::PeerId, nickname: &str) -> bool {
+        if !self.known_peers.iter().any(|p| &p.id == id) {
+            return false;
+        }
+        let nickname = nickname.trim();
+        if nickname.is_empty() {
+            self.nicknames.remove(id);
+        } else {
+            self.nicknames.insert(id.clone(), nickname.to_string());
+        }
+        true
+    }
+}
+
+/// A paired peer as the user knows it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub metadata: peer::PeerMetadata,
+    /// the local name shown instead of the name the peer advertises
+    pub nickname: Option<String>,
+}
+
+impl KnownPeer {
+    /// the name to show for the peer
+    pub fn name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.metadata.name)
+    }
+}
+
+/// how many invitations are kept, the oldest is forgotten first
+pub const MAX_INVITATIONS: usize = 100;
+
+/// A pin made for another device to pair with, kept so the user can audit which still work
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invitation {
+    /// numbered from 1 in the order the invitations were made
+    pub id: u64,
+    /// when it was made, in ms since the unix epoch
+    pub created: u64,
+    /// when it stops working, in ms since the unix epoch
+    pub expires: u64,
+    /// the peer which used the pin up, whether or not the pairing went through
+    pub used_by: Option<peer::PeerId>,
+    /// whether the user revoked it
+    pub revoked: bool,
+}
+
+impl Invitation {
+    /// whether nothing stopped the pin working yet, apart from a newer one replacing it
+    pub fn is_open(&self, now: u64) -> bool {
+        self.used_by.is_none() && !self.revoked && now < self.expires
+    }
+}
+
+/// An invitation and whether the pin can still be used to pair
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InvitationState {
+    #[serde(flatten)]
+    pub invitation: Invitation,
+    pub valid: bool,
 }
 
 impl Default for NodeConfig {
@@ -27,44 +196,213 @@ impl Default for NodeConfig {
             name: plat::host_name(),
             known_peers: HashSet::new(),
             id: peer::PeerId::default(),
+            visibility: None,
+            journal: false,
+            ephemeral: false,
+            last_shutdown: None,
+            privacy: Privacy::default(),
+            nicknames: HashMap::new(),
+            pairing_expiry: None,
+            port_mapping: false,
+            enable_relay: false,
+            relay_server: None,
+            blocked: HashSet::new(),
+            invitations: Vec::new(),
         }
     }
 }
 
+/// What the node leaves out of the metadata it broadcasts, applied the next time it starts
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Privacy {
+    /// broadcast a pseudonym instead of the host name
+    #[serde(default)]
+    pub hide_name: bool,
+    /// broadcast the device type as unknown
+    #[serde(default)]
+    pub hide_device: bool,
+}
+
+/// A stored artifact checked at startup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageIssue {
+    /// the node config file
+    Config,
+    /// the pairing secret of a known peer
+    Secret(peer::PeerId),
+}
+
+/// The result of checking the integrity of the config directory at startup
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageReport {
+    /// artifacts which were damaged and recovered from a backup
+    pub repaired: Vec<StorageIssue>,
+    /// artifacts which were damaged and could not be recovered
+    pub corrupt: Vec<StorageIssue>,
+}
+
+/// Why the node stopped running
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ShutdownReason {
+    /// the ui asked the node to stop
+    User,
+    /// the platform is shutting down or terminating the app
+    Signal,
+    /// the node could not keep running
+    Fatal(String),
+}
+
+impl ShutdownReason {
+    /// whether the node stopped in a way that leaves stored state intact
+    pub fn is_clean(&self) -> bool {
+        !matches!(self, ShutdownReason::Fatal(_))
+    }
+}
+
 pub struct NodeConfigStore(String);
 
+/// Replace the file at `path` so it holds either the old or the new data after a crash, never a
+/// mix of both. The data is written and synced to a temporary file next to it, then renamed over.
+pub(crate) fn write_atomic(path: &path::Path, data: &[u8]) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    // make the rename itself durable, not every platform can open a directory
+    if let Some(dir) = path.parent() {
+        _ = fs::File::open(dir).and_then(|dir| dir.sync_all());
+    }
+    Ok(())
+}
+
 impl NodeConfigStore {
     pub fn set(&self, conf: &NodeConfig) -> Result<(), ConfError> {
         // only write to disk if config path is set
         if !self.0.is_empty() {
-            let mut builder = path::PathBuf::from(self.0.clone());
-            builder.push(NODE_CONFIG_NAME);
-            let path = builder.as_path();
-            let mut file = fs::File::create(path)?;
+            let path = self.path(NODE_CONFIG_NAME);
+            // rotate the last good config into the backup before replacing it, atomically so a
+            // crash can't leave a torn backup behind
+            if let Ok(last) = fs::read(&path) {
+                if serde_json::from_slice::<NodeConfig>(&last).is_ok() {
+                    write_atomic(&self.path(NODE_CONFIG_BACKUP_NAME), &last)?;
+                }
+            }
             let json = serde_json::to_string(conf)?;
-            file.write_all(json.as_bytes())?;
+            write_atomic(&path, json.as_bytes())?;
         }
         Ok(())
     }
 
     pub fn get(&self) -> Result<NodeConfig, ConfError> {
+        self.get_with(peer::Identity::new)
+    }
+
+    /// get the config, creating the identity with `identity` if there is none stored yet
+    pub fn get_with(
+        &self,
+        identity: impl FnOnce() -> peer::Identity,
+    ) -> Result<NodeConfig, ConfError> {
         let mut conf = self
             .from_disk()
             .or_else(|_| -> Result<NodeConfig, ConfError> { Ok(NodeConfig::default()) })?;
-        let (cert, _) = secret::get_identity()?.into_rustls();
+        let (cert, _) = secret::get_identity(identity)?.into_rustls();
         conf.id = peer::PeerId::from_cert(&cert);
         Ok(conf)
     }
 
+    /// validate the stored config, restoring it from the backup when it is damaged
+    pub fn check(&self) -> StorageReport {
+        let mut report = StorageReport::default();
+        let path = self.path(NODE_CONFIG_NAME);
+        if self.0.is_empty() || !path.exists() || Self::read(&path).is_ok() {
+            return report;
+        }
+
+        warn!("The node config is damaged, attempting to restore the backup");
+        // keep the damaged file around so it can be inspected
+        _ = fs::rename(&path, self.path(NODE_CONFIG_CORRUPT_NAME));
+        let backup = self.path(NODE_CONFIG_BACKUP_NAME);
+        let restored = match fs::read(&backup) {
+            Ok(backup) if serde_json::from_slice::<NodeConfig>(&backup).is_ok() => {
+                write_atomic(&path, &backup).is_ok()
+            }
+            _ => false,
+        };
+        if restored {
+            report.repaired.push(StorageIssue::Config);
+        } else {
+            report.corrupt.push(StorageIssue::Config);
+        }
+        report
+    }
+
+    /// whether the config directory can be written to, it is created if missing
+    pub fn is_writable(&self) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        let probe = self.path(NODE_CONFIG_PROBE_NAME);
+        let writable = fs::create_dir_all(&self.0)
+            .and_then(|_| fs::write(&probe, b""))
+            .is_ok();
+        _ = fs::remove_file(probe);
+        writable
+    }
+
+    /// record why the node stopped so the next startup knows how it exited
+    pub fn set_shutdown(&self, reason: &ShutdownReason) -> Result<(), ConfError> {
+        if !self.0.is_empty() {
+            let json = serde_json::to_string(reason)?;
+            write_atomic(&self.path(NODE_SHUTDOWN_NAME), json.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// take the reason the node stopped last run, none if it crashed or was killed before
+    /// recording one. The record is removed so a crash during this run is detected next time.
+    pub fn take_shutdown(&self) -> Option<ShutdownReason> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let path = self.path(NODE_SHUTDOWN_NAME);
+        let reason = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok());
+        _ = fs::remove_file(path);
+        reason
+    }
+
+    /// where the event journal is kept, none if nothing is persisted
+    pub(crate) fn journal_path(&self) -> Option<path::PathBuf> {
+        (!self.0.is_empty()).then(|| self.path(crate::journal::EVENT_JOURNAL_NAME))
+    }
+
+    /// where the last seen peers are kept, none if nothing is persisted
+    pub(crate) fn seen_path(&self) -> Option<path::PathBuf> {
+        (!self.0.is_empty()).then(|| self.path(crate::seen::SEEN_PEERS_NAME))
+    }
+
     fn from_disk(&self) -> Result<NodeConfig, ConfError> {
-        let mut builder = path::PathBuf::from(self.0.clone());
-        builder.push(NODE_CONFIG_NAME);
-        let path = builder.as_path();
+        Self::read(&self.path(NODE_CONFIG_NAME))
+    }
+
+    fn read(path: &path::Path) -> Result<NodeConfig, ConfError> {
         let file = fs::File::open(path)?;
         let reader = io::BufReader::new(file);
         let config = serde_json::from_reader(reader)?;
         Ok(config)
     }
+
+    fn path(&self, name: &str) -> path::PathBuf {
+        let mut builder = path::PathBuf::from(self.0.clone());
+        builder.push(name);
+        builder
+    }
 }
 
 impl From<String> for NodeConfigStore {
@@ -78,7 +416,10 @@ mod tests {
 
     use p2p::peer::PeerId;
 
-    use crate::conf::{NodeConfigStore, NODE_CONFIG_NAME};
+    use crate::conf::{
+        NodeConfig, NodeConfigStore, Privacy, ShutdownReason, StorageIssue, MAX_INVITATIONS,
+        NODE_CONFIG_BACKUP_NAME, NODE_CONFIG_CORRUPT_NAME, NODE_CONFIG_NAME, NODE_CONFIG_PROBE_NAME,
+    };
     use crate::err::ConfError;
     use crate::secret::mock_store;
 
@@ -98,4 +439,177 @@ mod tests {
         _ = std::fs::remove_file(path);
         Ok(())
     }
+
+    #[test]
+    pub fn check_restores_backup() -> Result<(), ConfError> {
+        let dir = std::env::temp_dir().join("flydrop-check-restores-backup");
+        std::fs::create_dir_all(&dir)?;
+        let store = NodeConfigStore(dir.to_string_lossy().to_string());
+        let mut conf = NodeConfig {
+            name: String::from("good name"),
+            ..Default::default()
+        };
+        store.set(&conf)?;
+        conf.name = String::from("newer name");
+        store.set(&conf)?;
+
+        // nothing to report while the config is healthy
+        let report = store.check();
+        assert!(report.repaired.is_empty() && report.corrupt.is_empty());
+
+        std::fs::write(dir.join(NODE_CONFIG_NAME), b"{ garbage")?;
+        let report = store.check();
+        assert_eq!(vec![StorageIssue::Config], report.repaired);
+        assert!(report.corrupt.is_empty());
+        assert_eq!("good name", store.from_disk()?.name);
+        assert!(dir.join(NODE_CONFIG_CORRUPT_NAME).exists());
+
+        // without a usable backup the config is reported as corrupt
+        std::fs::write(dir.join(NODE_CONFIG_NAME), b"{ garbage")?;
+        std::fs::write(dir.join(NODE_CONFIG_BACKUP_NAME), b"{ garbage")?;
+        let report = store.check();
+        assert_eq!(vec![StorageIssue::Config], report.corrupt);
+
+        // cleanup
+        _ = std::fs::remove_dir_all(dir);
+        Ok(())
+    }
+
+    #[test]
+    pub fn unwritable_dir_is_detected() -> Result<(), ConfError> {
+        let dir = std::env::temp_dir().join("flydrop-unwritable-dir-is-detected");
+        std::fs::create_dir_all(&dir)?;
+        assert!(NodeConfigStore(dir.to_string_lossy().to_string()).is_writable());
+        assert!(!dir.join(NODE_CONFIG_PROBE_NAME).exists());
+
+        // a directory can't be created below a file
+        let file = dir.join("file");
+        std::fs::write(&file, b"")?;
+        let store = NodeConfigStore(file.join("conf").to_string_lossy().to_string());
+        assert!(!store.is_writable());
+
+        // cleanup
+        _ = std::fs::remove_dir_all(dir);
+        Ok(())
+    }
+
+    #[test]
+    pub fn shutdown_reason_is_taken_once() -> Result<(), ConfError> {
+        let dir = std::env::temp_dir().join("flydrop-shutdown-reason-is-taken-once");
+        std::fs::create_dir_all(&dir)?;
+        let store = NodeConfigStore(dir.to_string_lossy().to_string());
+        assert_eq!(None, store.take_shutdown());
+
+        let reason = ShutdownReason::Fatal(String::from("the p2p event loop stopped"));
+        store.set_shutdown(&reason)?;
+        assert_eq!(Some(reason), store.take_shutdown());
+        // a run which never records a reason exited uncleanly
+        assert_eq!(None, store.take_shutdown());
+
+        // cleanup
+        _ = std::fs::remove_dir_all(dir);
+        Ok(())
+    }
+
+    #[test]
+    pub fn privacy_hides_name_and_device() {
+        let mut conf = NodeConfig {
+            name: String::from("bryan's laptop"),
+            id: PeerId::from_string(String::from("0123456789abcdef0123456789abcdef01234567"))
+                .unwrap(),
+            ..Default::default()
+        };
+        let device = p2p::peer::DeviceType::LinuxDevice;
+        assert_eq!("bryan's laptop", conf.advertised_name());
+        assert_eq!(device, conf.advertised_device(device));
+
+        conf.privacy = Privacy {
+            hide_name: true,
+            hide_device: true,
+        };
+        assert_eq!("Flydrop 0123", conf.advertised_name());
+        assert_eq!(p2p::peer::DeviceType::Unknown, conf.advertised_device(device));
+    }
+
+    #[test]
+    pub fn only_the_latest_open_invitation_is_valid() {
+        let mut conf = NodeConfig::default();
+        assert_eq!(1, conf.invite(1000, 60_000));
+        assert_eq!(2, conf.invite(2000, 60_000));
+        let valid = |conf: &NodeConfig, now, shown| {
+            conf.invitations(now, shown)
+                .iter()
+                .map(|state| state.valid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![false, true], valid(&conf, 3000, true));
+        // the pin is gone, expired or was used up
+        assert_eq!(vec![false, false], valid(&conf, 3000, false));
+        assert_eq!(vec![false, false], valid(&conf, 62_000, true));
+        conf.invitations[1].used_by = Some(PeerId::from_string("a".repeat(40)).unwrap());
+        assert_eq!(vec![false, false], valid(&conf, 3000, true));
+
+        // the numbering goes on after the oldest are forgotten
+        for _ in 0..MAX_INVITATIONS {
+            conf.invite(4000, 60_000);
+        }
+        assert_eq!(MAX_INVITATIONS, conf.invitations.len());
+        assert_eq!(MAX_INVITATIONS as u64 + 2, conf.invitations.last().unwrap().id);
+    }
+
+    #[test]
+    pub fn interrupted_write_keeps_config() -> Result<(), ConfError> {
+        let dir = std::env::temp_dir().join("flydrop-interrupted-write-keeps-config");
+        std::fs::create_dir_all(&dir)?;
+        let store = NodeConfigStore(dir.to_string_lossy().to_string());
+        let conf = NodeConfig {
+            name: String::from("good name"),
+            ..Default::default()
+        };
+        store.set(&conf)?;
+
+        // a crash while writing leaves only the temporary file half written
+        let tmp = dir.join(format!("{}.tmp", NODE_CONFIG_NAME));
+        std::fs::write(&tmp, b"{ \"name\": \"half")?;
+        let report = store.check();
+        assert!(report.repaired.is_empty() && report.corrupt.is_empty());
+        assert_eq!("good name", store.from_disk()?.name);
+
+        // the next write replaces it
+        store.set(&conf)?;
+        assert!(!tmp.exists());
+        assert_eq!("good name", store.from_disk()?.name);
+
+        // cleanup
+        _ = std::fs::remove_dir_all(dir);
+        Ok(())
+    }
+
+    #[test]
+    pub fn nicknames_only_for_known_peers() {
+        let phone = p2p::peer::PeerMetadata {
+            name: String::from("iPhone"),
+            typ: p2p::peer::DeviceType::AppleiPhone,
+            id: PeerId::from_string(String::from("0123456789abcdef0123456789abcdef01234567"))
+                .unwrap(),
+            addrs: Vec::new(),
+        };
+        let stranger =
+            PeerId::from_string(String::from("QWERTYUIOPQWERTYUIOPQWERTYUIOPQWERTYUIOP")).unwrap();
+        let mut conf = NodeConfig::default();
+        conf.known_peers.insert(phone.clone());
+
+        assert!(!conf.rename_peer(&stranger, "Someone"));
+        assert!(conf.nicknames.is_empty());
+
+        assert!(conf.rename_peer(&phone.id, " Work phone "));
+        let known = conf.known();
+        assert_eq!(1, known.len());
+        assert_eq!("Work phone", known[0].name());
+        assert_eq!(phone, known[0].metadata);
+
+        // an empty nickname goes back to the advertised name
+        assert!(conf.rename_peer(&phone.id, ""));
+        assert_eq!("iPhone", conf.known()[0].name());
+    }
 }
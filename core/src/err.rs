@@ -16,6 +16,33 @@ pub enum CoreError {
 
     #[error("An error occured initializing p2p")]
     P2p(#[from] p2p::err::InitError),
+
+    #[error("A pairing could not be started")]
+    Pairing(#[from] p2p::err::HandshakeError),
+
+    #[error("The pairing qr code could not be made")]
+    QrCode(#[from] p2p::err::PairingError),
+
+    #[error("The peer is not paired")]
+    NotPaired,
+
+    #[error("The name is empty or longer than 255 bytes")]
+    InvalidName,
+
+    #[error("There is no pairing invitation with this number")]
+    NoInvitation,
+
+    #[error("The command has effects a failed batch can't take back")]
+    NotBatchable,
+
+    #[error("Command {index} of the batch failed, none of it was applied")]
+    Batch {
+        index: usize,
+        source: Box<CoreError>,
+    },
+
+    #[error("The node is not running")]
+    Stopped,
 }
 
 #[derive(Debug, Error)]
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A part of the node the ui can show a status for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Subsystem {
+    /// finding peers and being found
+    Discovery,
+    /// accepting connections from peers
+    Listener,
+    /// the config directory and everything stored in it
+    Storage,
+    /// the clock the pairing codes are derived from
+    Clock,
+    /// the local network the node is reachable on
+    Network,
+}
+
+/// How well a subsystem works, ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum HealthState {
+    Ok,
+    /// it works with limitations, see the reason
+    Degraded,
+    /// it does not work, see the reason
+    Failed,
+}
+
+/// The state of a single subsystem
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubsystemHealth {
+    pub subsystem: Subsystem,
+    pub state: HealthState,
+    /// why the subsystem is not ok, meant to be shown to the user
+    pub reason: Option<String>,
+}
+
+/// The health of every subsystem, each one updated by the code watching it
+pub(crate) struct Health(BTreeMap<Subsystem, SubsystemHealth>);
+
+impl Health {
+    /// every subsystem starts out ok
+    pub(crate) fn new() -> Self {
+        Self(
+            [
+                Subsystem::Discovery,
+                Subsystem::Listener,
+                Subsystem::Storage,
+                Subsystem::Clock,
+                Subsystem::Network,
+            ]
+            .into_iter()
+            .map(|subsystem| {
+                let health = SubsystemHealth {
+                    subsystem,
+                    state: HealthState::Ok,
+                    reason: None,
+                };
+                (subsystem, health)
+            })
+            .collect(),
+        )
+    }
+
+    /// update a subsystem, returns its new health if it changed
+    pub(crate) fn set(
+        &mut self,
+        subsystem: Subsystem,
+        state: HealthState,
+        reason: Option<String>,
+    ) -> Option<SubsystemHealth> {
+        let health = SubsystemHealth {
+            subsystem,
+            state,
+            reason,
+        };
+        if self.0.get(&subsystem) == Some(&health) {
+            return None;
+        }
+        self.0.insert(subsystem, health.clone());
+        Some(health)
+    }
+
+    pub(crate) fn list(&self) -> Vec<SubsystemHealth> {
+        self.0.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{Health, HealthState, Subsystem};
+
+    #[test]
+    fn only_changes_are_returned() {
+        let mut health = Health::new();
+        assert_eq!(5, health.list().len());
+        assert_eq!(None, health.set(Subsystem::Clock, HealthState::Ok, None));
+
+        let reason = Some(String::from("the clock is 120s off"));
+        let changed = health.set(Subsystem::Clock, HealthState::Degraded, reason.clone());
+        assert_eq!(HealthState::Degraded, changed.unwrap().state);
+        assert_eq!(
+            None,
+            health.set(Subsystem::Clock, HealthState::Degraded, reason)
+        );
+        // a new reason is a change
+        let reason = Some(String::from("the clock is 300s off"));
+        assert!(health
+            .set(Subsystem::Clock, HealthState::Degraded, reason)
+            .is_some());
+    }
+}
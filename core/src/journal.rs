@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::node::CoreEvent;
+
+pub static EVENT_JOURNAL_NAME: &str = "events.jsonl";
+
+// the number of events kept, the file is compacted once it holds twice as many
+const JOURNAL_CAPACITY: usize = 1000;
+
+/// A core event as recorded in the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    /// milliseconds since the unix epoch
+    pub time: u64,
+    pub event: CoreEvent,
+}
+
+/// Keeps the most recent core events in memory and optionally appends them to disk so they
+/// survive a restart
+pub(crate) struct EventJournal {
+    entries: VecDeque<JournalEntry>,
+    sequence: u64,
+    path: Option<PathBuf>,
+    // lines in the file, which can grow past the capacity until it is compacted
+    lines: usize,
+}
+
+impl EventJournal {
+    /// open the journal at `path`, or keep events in memory only without one
+    pub(crate) fn open(path: Option<PathBuf>) -> Self {
+        let mut journal = Self {
+            entries: VecDeque::new(),
+            sequence: 0,
+            path,
+            lines: 0,
+        };
+        if let Some(file) = journal.path.as_ref().and_then(|p| fs::File::open(p).ok()) {
+            // lines cut short by a crash are skipped
+            for line in io::BufReader::new(file).lines().map_while(Result::ok) {
+                journal.lines += 1;
+                if let Ok(entry) = serde_json::from_str::<JournalEntry>(&line) {
+                    journal.sequence = journal.sequence.max(entry.sequence);
+                    journal.push(entry);
+                }
+            }
+        }
+        journal
+    }
+
+    pub(crate) fn record(&mut self, event: CoreEvent) {
+        self.sequence += 1;
+        let entry = JournalEntry {
+            sequence: self.sequence,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            event,
+        };
+        if let Err(e) = self.append(&entry) {
+            warn!("Failed to journal core event: {:?}", e);
+        }
+        self.push(entry);
+    }
+
+    /// the events recorded after `sequence`, only of the kinds in `filter` unless it is empty
+    pub(crate) fn after(&self, sequence: u64, filter: &[String]) -> Vec<JournalEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.sequence > sequence)
+            .filter(|e| filter.is_empty() || filter.iter().any(|k| k == e.event.kind()))
+            .cloned()
+            .collect()
+    }
+
+    fn push(&mut self, entry: JournalEntry) {
+        if self.entries.len() == JOURNAL_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> Result<(), io::Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.lines >= JOURNAL_CAPACITY * 2 {
+            // rewrite the file with only the entries kept in memory
+            let mut data = String::new();
+            for e in &self.entries {
+                data.push_str(&serde_json::to_string(e)?);
+                data.push('\n');
+            }
+            crate::conf::write_atomic(path, data.as_bytes())?;
+            self.lines = self.entries.len();
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        self.lines += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::{EventJournal, JOURNAL_CAPACITY};
+    use crate::node::CoreEvent;
+
+    #[test]
+    fn journal_survives_restart() {
+        let dir = std::env::temp_dir().join("flydrop-journal-survives-restart");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(crate::journal::EVENT_JOURNAL_NAME);
+        _ = std::fs::remove_file(&path);
+
+        let mut journal = EventJournal::open(Some(path.clone()));
+        journal.record(CoreEvent::DiscoveryRecovered(1));
+        journal.record(CoreEvent::VisibilityChanged(false));
+
+        let journal = EventJournal::open(Some(path.clone()));
+        assert_eq!(2, journal.after(0, &[]).len());
+        let recovered = journal.after(0, &[String::from("DiscoveryRecovered")]);
+        assert_eq!(1, recovered.len());
+        assert_eq!(1, recovered[0].sequence);
+        assert_eq!(2, journal.after(1, &[])[0].sequence);
+
+        // cleanup
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn journal_is_bounded() {
+        let mut journal = EventJournal::open(None);
+        for n in 0..JOURNAL_CAPACITY as u64 + 10 {
+            journal.record(CoreEvent::DiscoveryRecovered(n));
+        }
+        let entries = journal.after(0, &[]);
+        assert_eq!(JOURNAL_CAPACITY, entries.len());
+        assert_eq!(11, entries[0].sequence);
+    }
+}
@@ -1,13 +1,53 @@
 use std::{
     collections::HashSet,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener},
+    time::Duration,
 };
 
 use futures::StreamExt;
-use if_watch::{tokio::IfWatcher, IfEvent};
+use if_watch::{tokio::IfWatcher, IfEvent, IpNet};
+use serde::{Deserialize, Serialize};
+
+// networks wider than this prefix hold thousands of hosts, like a campus or public hotspot
+const LARGE_SUBNET_PREFIX: u8 = 20;
+
+// this many unknown peers answering discovery suggests a shared network
+const CROWDED_STRANGERS: usize = 10;
+
+// how long the watcher may stay quiet before the initial interfaces are all listed
+const SETTLE_WAIT: Duration = Duration::from_millis(100);
+
+/// Signs that the current network may not be trusted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkRisk {
+    /// a local interface is on a very large subnet
+    pub large_subnet: bool,
+    /// many unknown peers answered discovery
+    pub crowded: bool,
+}
+
+impl NetworkRisk {
+    pub fn is_risky(&self) -> bool {
+        self.large_subnet || self.crowded
+    }
+
+    /// assess a network with the interfaces `nets` on which `strangers` unknown peers answered
+    fn assess<'a>(nets: impl IntoIterator<Item = &'a IpNet>, strangers: usize) -> Self {
+        let large_subnet = nets.into_iter().any(|net| match net {
+            IpNet::V4(net) => {
+                net.addr() != Ipv4Addr::LOCALHOST && net.prefix_len() < LARGE_SUBNET_PREFIX
+            }
+            IpNet::V6(_) => false,
+        });
+        NetworkRisk {
+            large_subnet,
+            crowded: strangers >= CROWDED_STRANGERS,
+        }
+    }
+}
 
 pub struct LanManager {
-    pub(crate) lan: HashSet<Ipv4Addr>,
+    pub(crate) lan: HashSet<IpAddr>,
     watch: IfWatcher,
 }
 
@@ -16,18 +56,52 @@ impl LanManager {
         let watch = IfWatcher::new()?;
         let mut lan = HashSet::new();
         for net in watch.iter() {
-            if let IpAddr::V4(ip) = net.addr() {
-                if ip != Ipv4Addr::LOCALHOST {
-                    lan.insert(ip);
-                }
+            let ip = net.addr();
+            if is_reachable(&ip) {
+                lan.insert(ip);
             }
         }
         Ok(Self { watch, lan })
     }
 
+    /// wait for the interfaces the watcher lists once it is polled, linux only reports them
+    /// then rather than when the watcher is created
+    pub async fn settle(&mut self) {
+        while let Ok(Ok(event)) = tokio::time::timeout(SETTLE_WAIT, self.next()).await {
+            self.apply(&event);
+        }
+    }
+
+    /// apply an interface change, returns whether the ips peers can reach changed
+    pub fn apply(&mut self, event: &IfEvent) -> bool {
+        match event {
+            IfEvent::Up(net) => is_reachable(&net.addr()) && self.lan.insert(net.addr()),
+            IfEvent::Down(net) => self.lan.remove(&net.addr()),
+        }
+    }
+
     pub async fn next(&mut self) -> Result<IfEvent, std::io::Error> {
         self.watch.select_next_some().await
     }
+
+    /// assess the current network given how many unknown peers answered discovery
+    pub fn risk(&self, strangers: usize) -> NetworkRisk {
+        NetworkRisk::assess(self.watch.iter(), strangers)
+    }
+}
+
+/// whether the host has an ipv6 stack to listen on, even if no address is up yet
+pub(crate) fn ipv6_supported() -> bool {
+    TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok()
+}
+
+/// whether peers on the lan can connect to `ip`. IPv6 link-local addresses are skipped since
+/// the scope id they need is not advertised.
+fn is_reachable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => *ip != Ipv4Addr::LOCALHOST,
+        IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_unicast_link_local(),
+    }
 }
 
 // pub fn lan_ips() -> Result<Vec<Ipv4Addr>, std::io::Error> {
@@ -42,3 +116,44 @@ impl LanManager {
 //     }
 //     return Ok(output);
 // }
+
+#[cfg(test)]
+mod tests {
+    use if_watch::IpNet;
+
+    use crate::lan::{NetworkRisk, CROWDED_STRANGERS};
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn home_network_is_not_risky() {
+        let home = nets(&["127.0.0.1/8", "192.168.1.23/24", "fd00::23/64"]);
+        let risk = NetworkRisk::assess(&home, CROWDED_STRANGERS - 1);
+        assert_eq!(NetworkRisk::default(), risk);
+        assert!(!risk.is_risky());
+    }
+
+    #[test]
+    fn large_subnet_is_risky() {
+        let campus = nets(&["127.0.0.1/8", "10.20.30.40/16"]);
+        let risk = NetworkRisk::assess(&campus, 0);
+        assert!(risk.large_subnet);
+        assert!(!risk.crowded);
+        assert!(risk.is_risky());
+
+        // the subnet just small enough and ipv6 prefixes don't count
+        let office = nets(&["10.20.30.40/20", "2001:db8::1/48"]);
+        assert!(!NetworkRisk::assess(&office, 0).large_subnet);
+    }
+
+    #[test]
+    fn many_strangers_are_risky() {
+        let home = nets(&["192.168.1.23/24"]);
+        let risk = NetworkRisk::assess(&home, CROWDED_STRANGERS);
+        assert!(risk.crowded);
+        assert!(!risk.large_subnet);
+        assert!(risk.is_risky());
+    }
+}
@@ -1,6 +1,16 @@
+#[cfg(feature = "api")]
+pub mod api;
 pub mod conf;
 pub mod err;
-mod lan;
+pub mod health;
+pub mod journal;
+pub mod lan;
 pub mod node;
 pub mod plat;
+pub mod policy;
 mod secret;
+pub mod seen;
+pub mod visibility;
+
+#[cfg(any(test, feature = "test-util"))]
+pub use secret::mock_store;
@@ -1,23 +1,89 @@
 
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::Duration;
 
-use crate::{conf, err, lan::LanManager, plat, secret};
+use chrono::Local;
+
+use crate::{
+    conf, err,
+    health::{Health, HealthState, Subsystem, SubsystemHealth},
+    journal::{EventJournal, JournalEntry},
+    lan::{self, LanManager, NetworkRisk},
+    plat::{
+        self, Permission, PermissionState, PermissionStatus, Permissions, PowerEvent, PowerMonitor,
+    },
+    policy::{self, Decision, Policy},
+    secret,
+    seen::{self, SeenPeer, SeenPeers},
+    visibility::VisibilitySchedule,
+};
 
 use p2p::{
     discovery,
-    event::P2pEvent,
+    event::{Observation, P2pEvent},
+    guard::InboundStats,
     manager::{P2pConfig, P2pManager},
+    pairing::{QrMatrix, PAIR_TIMEOUT},
+    path::LatencyHistory,
+    peer::{ConnectionInfo, Identity, PeerDelta, PeerId, PeerMetadata},
+    portmap,
+    rng::Rng,
+    trace::FrameRecord,
 };
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use tokio::time::sleep;
-use tracing::debug;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{interval, sleep, Interval};
+use tracing::{debug, warn};
+
+// how often the visibility schedule and network risk are checked
+const HOUSEKEEPING_TICK: Duration = Duration::from_secs(30);
+
+// how often presence is requested while discovery is boosted
+const BOOST_INTERVAL: Duration = Duration::from_millis(250);
+
+// the longest discovery stays boosted, in case the ui never ends it
+pub const BOOST_LIMIT: Duration = Duration::from_secs(120);
+
+// the longest name in bytes, it is sent in every presence datagram
+pub const MAX_NAME_LEN: usize = 255;
+
+// what the user is told to do when another device shares the node's identity
+const IDENTITY_CONFLICT_REMEDY: &str = "Another device is using this device's identity, most \
+    likely because it was cloned from this one. On one of them, delete the \"flydrop\" Identity \
+    entry from the system keychain and restart Flydrop, then pair it with your devices again.";
 
 pub struct Node {
     conf: conf::NodeConfig,
     store: conf::NodeConfigStore,
     p2p: std::sync::Arc<P2pManager>,
     lan: LanManager,
+    power: PowerMonitor,
+    permissions: Permissions,
+
+    // whether the system is asleep or the visibility schedule hides the node, discovery is paused for either
+    asleep: bool,
+    hidden: bool,
+    risk: NetworkRisk,
+    housekeeping: Interval,
+
+    // how well each part of the node works, as shown to the ui
+    health: Health,
+
+    // decides on requests from peers
+    policy: std::sync::Arc<dyn Policy>,
+
+    // set once something asks the node to stop
+    shutdown: Option<conf::ShutdownReason>,
+
+    // set while a batch of commands runs, with the peers it unpaired
+    batch: Option<Vec<PeerId>>,
+
+    // every task the node spawned, aborted when it stops
+    tasks: JoinSet<()>,
+
+    // the running discovery boost, replaced by the next one
+    boost: Option<AbortHandle>,
 
     // a channel for the ui to send queries w/ returnable values
     query: (
@@ -40,109 +106,868 @@ pub struct Node {
     // a channel sender for core to send events to the ui
     events: mpsc::Sender<CoreEvent>,
 
+    // the recent events sent to the ui
+    journal: EventJournal,
+
+    // the peers discovered this run or recently before it
+    seen: SeenPeers,
+
     // a channel receiver for core to receive p2p events
     p2p_events: mpsc::UnboundedReceiver<P2pEvent>,
 }
 
 impl Node {
     pub async fn init(dir: String) -> Result<(Self, mpsc::Receiver<CoreEvent>), err::CoreError> {
-        // build node config from disk or create
+        Self::init_with(dir, Identity::new, None).await
+    }
+
+    /// init creating the identity with `identity` when none is stored and drawing pairing secrets,
+    /// pins and nonces from `rng` if given. Simulations pass [Identity::from_seed] and a
+    /// [p2p::rng::SeededRng] to get the same peer id and secrets on every run.
+    pub async fn init_with(
+        dir: String,
+        identity: impl FnOnce() -> Identity,
+        rng: Option<std::sync::Arc<dyn Rng>>,
+    ) -> Result<(Self, mpsc::Receiver<CoreEvent>), err::CoreError> {
+        // build node config from disk or create, repairing it if needed
         let store: conf::NodeConfigStore = dir.into();
-        let conf = store.get()?;
+        // live usbs and sandboxes may not allow writing the config, run without persisting then
+        let ephemeral = !store.is_writable();
+        // stored state is only checked when the last run did not stop cleanly
+        let last_shutdown = store.take_shutdown();
+        let clean = last_shutdown
+            .as_ref()
+            .is_some_and(conf::ShutdownReason::is_clean);
+        let mut report = if ephemeral || clean {
+            conf::StorageReport::default()
+        } else {
+            warn!("The last run did not stop cleanly: {:?}", last_shutdown);
+            store.check()
+        };
+        let identity = secret::get_identity(identity)?;
+        let mut conf = store.get_with({
+            let identity = identity.clone();
+            move || identity
+        })?;
+        conf.ephemeral = ephemeral;
+        conf.last_shutdown = last_shutdown;
+        let store = if ephemeral {
+            warn!("The config directory is not writable, nothing will be saved");
+            conf::NodeConfigStore::from(String::new())
+        } else {
+            store
+        };
+        report.corrupt.extend(
+            secret::missing(&conf.known_peers)
+                .into_iter()
+                .map(conf::StorageIssue::Secret),
+        );
 
         // build lan
-        let lan = LanManager::new()?;
+        let mut lan = LanManager::new()?;
+        lan.settle().await;
 
         // build p2p
         let p2p_conf = P2pConfig {
             id: conf.id.clone(),
-            device: plat::device_type(),
-            name: conf.name.clone(),
+            device: conf.advertised_device(plat::device_type()),
+            name: conf.advertised_name(),
             multicast: SocketAddr::V4(SocketAddrV4::new(discovery::DISCOVERY_MULTICAST, 50692)), // TODO 0 port??
-            p2p_addr: SocketAddr::V4(SocketAddrV4::new(
-                *lan.lan
-                    .iter()
-                    .next()
-                    .ok_or(err::CoreError::NoNetworkAccess)?,
+            // joined once the lan has an ipv6 address, which may only come up later
+            multicast_v6: Some(SocketAddr::V6(SocketAddrV6::new(
+                discovery::DISCOVERY_MULTICAST_V6,
+                50692,
                 0,
-            )),
+                0,
+            ))),
+            // listen on both ip versions when the host has ipv6, so addresses coming up later
+            // are reachable too
+            p2p_addr: if lan::ipv6_supported() {
+                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0))
+            } else {
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            },
+            lan: lan.lan.iter().copied().collect(),
+            identity: Some(identity),
+            limits: Default::default(),
         };
+        if p2p_conf.lan.is_empty() {
+            return Err(err::CoreError::NoNetworkAccess);
+        }
         let (p2p, p2p_events) = P2pManager::new(p2p_conf).await?;
+        if let Some(rng) = rng {
+            p2p.set_rng(rng);
+        }
 
         // append known peers
         for p in secret::to_known(&conf.known_peers) {
             p2p.add_known_peer(p);
         }
+        for id in &conf.blocked {
+            p2p.block_peer(id);
+        }
 
         let (events, events_rx) = mpsc::channel(64);
+        let journal = EventJournal::open(store.journal_path().filter(|_| conf.journal));
+        let seen = SeenPeers::open(store.seen_path());
 
-        let node = Self {
+        let mut node = Self {
             conf,
             store,
             p2p,
             lan,
+            power: PowerMonitor::new(),
+            permissions: Permissions::detect(),
+            asleep: false,
+            hidden: false,
+            risk: NetworkRisk::default(),
+            housekeeping: interval(HOUSEKEEPING_TICK),
+            health: Health::new(),
+            policy: std::sync::Arc::new(policy::DefaultPolicy),
+            tasks: JoinSet::new(),
+            boost: None,
+            shutdown: None,
+            batch: None,
             query: mpsc::unbounded_channel(),
             cmd: mpsc::unbounded_channel(),
             internal: mpsc::unbounded_channel(),
             events,
+            journal,
+            seen,
             p2p_events,
         };
 
+        if node.conf.ephemeral {
+            node.try_emit(CoreEvent::Ephemeral);
+            node.health.set(
+                Subsystem::Storage,
+                HealthState::Degraded,
+                Some(String::from(
+                    "The config directory is not writable, nothing is saved",
+                )),
+            );
+        } else if !report.corrupt.is_empty() {
+            node.health.set(
+                Subsystem::Storage,
+                HealthState::Degraded,
+                Some(String::from(
+                    "Some stored state was damaged and could not be recovered",
+                )),
+            );
+        }
+        node.check_health().await;
+
+        // report what the integrity check found
+        if !report.repaired.is_empty() {
+            node.try_emit(CoreEvent::StorageRepaired(report.clone()));
+        }
+        if !report.corrupt.is_empty() {
+            node.try_emit(CoreEvent::StorageCorrupt(report));
+        }
+
         Ok((node, events_rx))
     }
 
-    // called by
-    pub async fn start(&mut self) {
+    /// a handle to send queries and commands to the node once it is started
+    pub fn controller(&self) -> CoreController {
+        CoreController {
+            query_tx: self.query.0.clone(),
+            command_tx: self.cmd.0.clone(),
+        }
+    }
+
+    /// decide on requests from peers with `policy` instead of the [policy::DefaultPolicy]
+    pub fn set_policy(&mut self, policy: impl Policy + 'static) {
+        self.policy = std::sync::Arc::new(policy);
+        self.p2p
+            .set_connection_filter(Some(policy::connection_filter(self.policy.clone())));
+    }
+
+    /// run the node until it is asked to stop or can't continue, returning why it stopped
+    pub async fn start(&mut self) -> conf::ShutdownReason {
         // TODO: start p2p event loop here?
-        loop {
+        self.expire_pairings().await;
+        self.update_port_mapping().await;
+        if let Err(e) = self.update_relay().await {
+            warn!("Unable to find the rendezvous server: {:?}", e);
+        }
+        let reason = loop {
             tokio::select! {
                 Some(q) = self.query.1.recv() => {
                     let res = self.handle_query(q.data).await;
                     q.tx_return.send(res).unwrap_or(());
                 }
-                Some(c) = self.cmd.1.recv() => {
-                    let res = self.handle_command(c.data).await;
-                    c.tx_return.send(res).unwrap_or(());
-                }
+                Some(c) = self.cmd.1.recv() => match c.data {
+                    AppCmd::RefreshDiscovery => self.refresh_discovery(Some(c.tx_return)),
+                    cmd => {
+                        let res = self.handle_command(cmd).await;
+                        c.tx_return.send(res).unwrap_or(());
+                    }
+                },
                 Some(e) = self.internal.1.recv() => self.handle_event(e).await,
                 Ok(n) = self.lan.next() => {
                     debug!("LAN event: {:?}", n);
+                    if self.lan.apply(&n) {
+                        self.handle_network_change().await;
+                    }
+                    self.check_network_risk().await;
+                    self.check_health().await;
+                }
+                power = self.power.next() => {
+                    self.handle_power(power);
+                    self.check_health().await;
+                }
+                Some(done) = self.tasks.join_next(), if !self.tasks.is_empty() => {
+                    if let Err(e) = done {
+                        warn!("A node task failed: {:?}", e);
+                    }
+                }
+                _ = self.housekeeping.tick() => {
+                    self.check_visibility().await;
+                    self.check_network_risk().await;
+                    self.expire_pairings().await;
+                    self.check_health().await;
+                    // peers which stop answering are lost, keep asking so present ones stay
+                    self.p2p.request_presence().await;
                 }
-                // Ok(p2p) = self.p2p_events.recv() => {
-                //     match p2p {
-                //         P2pEvent::PeerDiscovered(metadata)
-                //     }
-                // }
+                p2p = self.p2p_events.recv() => match p2p {
+                    Some(p2p) => self.handle_p2p(p2p).await,
+                    None => {
+                        self.shutdown = Some(conf::ShutdownReason::Fatal(String::from(
+                            "the p2p event loop stopped",
+                        )));
+                    }
+                },
             }
-        }
+            if let Some(reason) = self.shutdown.take() {
+                break reason;
+            }
+        };
+
+        // nothing the node started outlives it
+        self.tasks.shutdown().await;
+
+        // the router should not keep forwarding to a node which is gone, nor p2p keep running
+        self.p2p.shutdown().await;
 
         // get state from p2p and persist
+        self.save_seen();
+        if let Err(e) = self.store.set_shutdown(&reason) {
+            warn!("Unable to record the shutdown reason: {:?}", e);
+        }
+        self.emit(CoreEvent::Shutdown {
+            reason: reason.clone(),
+        })
+        .await;
+        reason
     }
 
     // handle queries
-    async fn handle_query(&self, _query: AppQuery) -> Result<CoreResponse, err::CoreError> {
-        todo!()
+    async fn handle_query(&self, query: AppQuery) -> Result<CoreResponse, err::CoreError> {
+        match query {
+            AppQuery::GetConf => Ok(CoreResponse::Conf(Box::new(self.conf.clone()))),
+            AppQuery::GetKnownPeers => Ok(CoreResponse::KnownPeers(self.conf.known())),
+            AppQuery::GetSeenPeers => Ok(CoreResponse::SeenPeers(self.seen.list())),
+            AppQuery::GetPeersSince(sequence) => {
+                Ok(CoreResponse::Peers(self.p2p.discovered_since(sequence)))
+            }
+            AppQuery::GetEvents { after, filter } => {
+                Ok(CoreResponse::Events(self.journal.after(after, &filter)))
+            }
+            AppQuery::GetConnectionTrace(id) => {
+                Ok(CoreResponse::Trace(self.p2p.connection_trace(&id)))
+            }
+            AppQuery::GetPermissions => Ok(CoreResponse::Permissions(self.permissions.list())),
+            AppQuery::GetHealth => Ok(CoreResponse::Health(self.health.list())),
+            AppQuery::GetInboundStats => Ok(CoreResponse::Inbound(self.p2p.inbound_stats())),
+            AppQuery::GetTaskCount => Ok(CoreResponse::Tasks(self.tasks.len())),
+            AppQuery::GetLatency(id) => Ok(CoreResponse::Latency(self.p2p.latency(&id))),
+            AppQuery::GetConnectedPeers => Ok(CoreResponse::Connections(self.p2p.connections())),
+            AppQuery::GetPeerState(id) => Ok(CoreResponse::Connection(self.p2p.connection(&id))),
+            AppQuery::GetInvitations => Ok(CoreResponse::Invitations(
+                self.conf
+                    .invitations(seen::now(), self.p2p.pin_invitation().is_some()),
+            )),
+            AppQuery::GetPinQr => match self.p2p.pin_invitation() {
+                Some(invitation) => Ok(CoreResponse::Qr(Some(invitation.to_qr_matrix()?))),
+                None => Ok(CoreResponse::Qr(None)),
+            },
+        }
     }
 
     // handle commands
     async fn handle_command(&mut self, cmd: AppCmd) -> Result<CoreResponse, err::CoreError> {
         match cmd {
-            AppCmd::Discover(span) => {
-                let p2p = self.p2p.clone();
-                tokio::spawn(async move {
-                    for _ in 0..span {
-                        sleep(Duration::from_secs(1)).await;
-                        p2p.request_presence().await;
-                    }
-                });
+            AppCmd::Discover(span) => self.discover(span),
+            AppCmd::BoostDiscovery(span) => self.boost_discovery(span),
+            AppCmd::RefreshDiscovery => self.refresh_discovery(None),
+            AppCmd::SetName(name) => {
+                let name = name.trim();
+                if name.is_empty() || name.len() > MAX_NAME_LEN {
+                    return Err(err::CoreError::InvalidName);
+                }
+                self.conf.name = name.to_string();
+                self.save_conf()?;
+                self.p2p.set_name(self.conf.advertised_name()).await;
+            }
+            AppCmd::SetConnectionTrace(enabled) => self.p2p.set_connection_trace(enabled),
+            AppCmd::SetObserver(enabled) => self.p2p.set_observer(enabled),
+            AppCmd::SetPreferredPath(id, addr) => self.p2p.set_preferred_path(&id, addr),
+            AppCmd::Shutdown => self.shutdown = Some(conf::ShutdownReason::User),
+            AppCmd::Pair(id) => self.pair(id, None),
+            AppCmd::StartPinPairing => {
+                let pin = self.p2p.start_pin_pairing()?;
+                self.conf.invite(seen::now(), PAIR_TIMEOUT.as_millis() as u64);
+                self.save_conf()?;
+                return Ok(CoreResponse::Pin(pin));
+            }
+            AppCmd::RevokeInvitation(id) => self.revoke_invitation(id)?,
+            AppCmd::PairWithPin(id, pin) => self.pair(id, Some(pin)),
+            AppCmd::Ack(id, accept) => {
+                if !self.p2p.answer_pairing(&id, accept) {
+                    warn!("The pairing request from {} is no longer waiting", id);
+                }
             }
-            AppCmd::SetName(_new) => {
-                todo!()
+            AppCmd::Unpair(id) => self.unpair(&id)?,
+            AppCmd::BlockPeer(id) => {
+                self.conf.blocked.insert(id.clone());
+                self.save_conf()?;
+                self.p2p.block_peer(&id);
+            }
+            AppCmd::UnblockPeer(id) => {
+                self.conf.blocked.remove(&id);
+                self.save_conf()?;
+                self.p2p.unblock_peer(&id);
+            }
+            AppCmd::RenamePeer(id, nickname) => {
+                if !self.conf.rename_peer(&id, &nickname) {
+                    return Err(err::CoreError::NotPaired);
+                }
+                self.save_conf()?;
+            }
+            AppCmd::Batch(cmds) => return self.batch(cmds).await,
+            AppCmd::RequestPermission(permission) => {
+                let status = self.permissions.request(permission);
+                // the local network prompt shows up when discovery first reaches the network
+                if permission == Permission::LocalNetwork {
+                    self.p2p.request_presence().await;
+                }
+                self.check_health().await;
+                return Ok(CoreResponse::Permission(status));
+            }
+            AppCmd::SetPermission(permission, status) => {
+                self.permissions.set(permission, status);
+                self.check_health().await;
+            }
+            AppCmd::SetPairingExpiry(days) => {
+                self.conf.pairing_expiry = days;
+                self.save_conf()?;
+                self.expire_pairings().await;
+            }
+            AppCmd::SetPortMapping(enabled) => {
+                self.conf.port_mapping = enabled;
+                self.save_conf()?;
+                self.update_port_mapping().await;
+            }
+            AppCmd::SetRelay { enabled, server } => {
+                self.conf.enable_relay = enabled;
+                self.conf.relay_server = server;
+                self.save_conf()?;
+                self.update_relay().await?;
+            }
+            AppCmd::SetVisibility(schedule) => {
+                self.conf.visibility = schedule;
+                self.save_conf()?;
+                self.check_visibility().await;
+                self.check_health().await;
             }
         }
         Ok(CoreResponse::Ok)
     }
 
+    // handle events coming from p2p
+    async fn handle_p2p(&mut self, event: P2pEvent) {
+        match event {
+            P2pEvent::DiscoveryRecovered { count, .. } => {
+                self.emit(CoreEvent::DiscoveryRecovered(count)).await;
+            }
+            P2pEvent::PeerDiscovered(metadata) => {
+                self.seen.seen(metadata);
+                self.save_seen();
+            }
+            P2pEvent::PeerLost(id) => {
+                self.seen.lost(&id);
+                self.save_seen();
+                self.emit(CoreEvent::PeerLost(id)).await;
+            }
+            P2pEvent::PairRequest { metadata, code } => {
+                match self.policy.pairing_request(&metadata) {
+                    Decision::Ask => self.emit(CoreEvent::PairRequest(metadata, code)).await,
+                    decision => {
+                        debug!("Pairing request from {} decided by policy", metadata.id);
+                        self.p2p.answer_pairing(&metadata.id, decision == Decision::Allow);
+                    }
+                }
+            }
+            P2pEvent::PairCode { id, code } => self.emit(CoreEvent::PairCode(id, code)).await,
+            P2pEvent::PinUsed(id) => {
+                // the pin shown is always the latest invitation
+                if let Some(invitation) = self.conf.invitations.last_mut() {
+                    invitation.used_by.get_or_insert(id);
+                }
+                if let Err(e) = self.save_conf() {
+                    warn!("Unable to save the used invitation: {:?}", e);
+                }
+            }
+            P2pEvent::Paired { metadata, secret } => {
+                if let Err(e) = self.remember_peer(&metadata, &secret) {
+                    warn!("Unable to save the paired peer {}: {:?}", metadata.id, e);
+                }
+                self.emit(CoreEvent::Paired(metadata)).await;
+            }
+            P2pEvent::KeyRotated {
+                id,
+                secret,
+                previous,
+            } => {
+                // the peer already uses the new secret, without it the pairing is lost on restart
+                if let Err(e) = secret::rotate_totp(&id, &secret, previous.as_deref()) {
+                    warn!("Unable to save the new secret of {}: {:?}", id, e);
+                    let reason = String::from(
+                        "The keychain refused a paired device's new secret, pair it again after \
+                        the next restart",
+                    );
+                    self.set_health(Subsystem::Storage, HealthState::Failed, Some(reason))
+                        .await;
+                }
+            }
+            P2pEvent::ClockSkew { id, skew } => {
+                let reason = format!("The clock is {}s off from a paired peer's", skew.abs());
+                self.set_health(Subsystem::Clock, HealthState::Degraded, Some(reason))
+                    .await;
+                self.emit(CoreEvent::ClockSkew { id, skew }).await;
+            }
+            // a paired peer authenticated, so the clocks agree again
+            P2pEvent::PeerConnected(_) => {
+                self.set_health(Subsystem::Clock, HealthState::Ok, None)
+                    .await;
+            }
+            P2pEvent::PortMapped { external } => self.emit(CoreEvent::PortMapped(external)).await,
+            P2pEvent::IdentityConflict { addrs } => {
+                self.emit(CoreEvent::IdentityConflict {
+                    addrs,
+                    remedy: String::from(IDENTITY_CONFLICT_REMEDY),
+                })
+                .await;
+            }
+            P2pEvent::Observed {
+                from, observation, ..
+            } => self.try_emit(CoreEvent::Observed { from, observation }),
+            e => debug!("P2p event: {:?}", e),
+        }
+    }
+
+    // forget a paired peer, its secret and any live connection to it
+    fn unpair(&mut self, id: &PeerId) -> Result<(), err::CoreError> {
+        self.conf.known_peers.retain(|p| &p.id != id);
+        self.conf.nicknames.remove(id);
+        self.save_conf()?;
+        // the secret can't be put back, a batch forgets it only once all of its commands ran
+        match &mut self.batch {
+            Some(unpaired) => unpaired.push(id.clone()),
+            None => self.forget_pairing(id)?,
+        }
+        Ok(())
+    }
+
+    // stop the pin of an invitation working, if it still does
+    fn revoke_invitation(&mut self, id: u64) -> Result<(), err::CoreError> {
+        let latest = self.conf.invitations.last().map(|last| last.id);
+        let Some(invitation) = self.conf.invitations.iter_mut().find(|i| i.id == id) else {
+            return Err(err::CoreError::NoInvitation);
+        };
+        if invitation.is_open(seen::now()) && Some(id) == latest {
+            self.p2p.revoke_pin();
+        }
+        invitation.revoked = true;
+        self.save_conf()?;
+        Ok(())
+    }
+
+    // forget everything about a peer which was unpaired
+    fn forget_pairing(&mut self, id: &PeerId) -> Result<(), err::CoreError> {
+        secret::remove_totp(id)?;
+        self.p2p.remove_known_peer(id);
+        self.seen.forget(id);
+        self.save_seen();
+        Ok(())
+    }
+
+    // save the config, unless a batch is running which saves it once all of its commands ran
+    fn save_conf(&self) -> Result<(), err::ConfError> {
+        if self.batch.is_some() {
+            return Ok(());
+        }
+        self.store.set(&self.conf)
+    }
+
+    // run commands in order, either all of them apply or none does
+    async fn batch(&mut self, cmds: Vec<AppCmd>) -> Result<CoreResponse, err::CoreError> {
+        if let Some(index) = cmds.iter().position(|cmd| !cmd.can_batch()) {
+            return Err(err::CoreError::Batch {
+                index,
+                source: Box::new(err::CoreError::NotBatchable),
+            });
+        }
+        // a nested batch is part of the outer one
+        let snapshot = match self.batch {
+            Some(_) => None,
+            None => {
+                self.batch = Some(Vec::new());
+                Some(self.conf.clone())
+            }
+        };
+        let mut responses = Vec::with_capacity(cmds.len());
+        let mut failed = None;
+        for (index, cmd) in cmds.into_iter().enumerate() {
+            match Box::pin(self.handle_command(cmd)).await {
+                Ok(response) => responses.push(response),
+                Err(e) => {
+                    failed = Some(err::CoreError::Batch {
+                        index,
+                        source: Box::new(e),
+                    });
+                    break;
+                }
+            }
+        }
+        let Some(snapshot) = snapshot else {
+            return failed.map_or(Ok(CoreResponse::Batch(responses)), Err);
+        };
+
+        let unpaired = self.batch.take().unwrap_or_default();
+        if let Some(e) = failed.or_else(|| self.store.set(&self.conf).err().map(Into::into)) {
+            let applied = std::mem::replace(&mut self.conf, snapshot);
+            self.restore(&applied).await;
+            return Err(e);
+        }
+        // the batch applied, a secret left in the keychain is only untidy
+        for id in unpaired {
+            if let Err(e) = self.forget_pairing(&id) {
+                warn!("Unable to forget the pairing with {}: {:?}", id, e);
+            }
+        }
+        Ok(CoreResponse::Batch(responses))
+    }
+
+    // bring p2p back in line with the config after a failed batch changed it to `applied`
+    async fn restore(&mut self, applied: &conf::NodeConfig) {
+        if applied.advertised_name() != self.conf.advertised_name() {
+            self.p2p.set_name(self.conf.advertised_name()).await;
+        }
+        for id in applied.blocked.difference(&self.conf.blocked) {
+            self.p2p.unblock_peer(id);
+        }
+        for id in self.conf.blocked.difference(&applied.blocked) {
+            self.p2p.block_peer(id);
+        }
+        if applied.port_mapping != self.conf.port_mapping {
+            self.update_port_mapping().await;
+        }
+        if (&applied.relay_server, applied.enable_relay)
+            != (&self.conf.relay_server, self.conf.enable_relay)
+        {
+            if let Err(e) = self.update_relay().await {
+                warn!("Unable to find the rendezvous server: {:?}", e);
+            }
+        }
+        if applied.visibility != self.conf.visibility {
+            self.check_visibility().await;
+            self.check_health().await;
+        }
+    }
+
+    // unpair the peers which were not seen for longer than the pairing expiry
+    async fn expire_pairings(&mut self) {
+        let Some(days) = self.conf.pairing_expiry else {
+            return;
+        };
+        let cutoff = seen::now().saturating_sub(u64::from(days) * 24 * 60 * 60 * 1000);
+        let known: Vec<PeerMetadata> = self.conf.known_peers.iter().cloned().collect();
+        let mut remembered = false;
+        for peer in known {
+            // peers paired before they were ever seen start counting now
+            remembered |= self.seen.remember(&peer);
+            if self
+                .seen
+                .last_seen(&peer.id)
+                .is_none_or(|seen| seen >= cutoff)
+            {
+                continue;
+            }
+            debug!("The pairing with {} expired", peer.id);
+            if let Err(e) = self.unpair(&peer.id) {
+                warn!("Unable to unpair {}: {:?}", peer.id, e);
+                continue;
+            }
+            self.emit(CoreEvent::PairingExpired(peer.id)).await;
+        }
+        if remembered {
+            self.save_seen();
+        }
+    }
+
+    // work out the health of the subsystems which follow from the node's state
+    async fn check_health(&mut self) {
+        let discovery = if self.permissions.is_denied(Permission::LocalNetwork)
+            || self.permissions.is_denied(Permission::NearbyDevices)
+        {
+            (
+                HealthState::Failed,
+                Some("Flydrop is not allowed to look for devices on the network"),
+            )
+        } else if self.asleep {
+            (
+                HealthState::Degraded,
+                Some("Discovery is paused while the system sleeps"),
+            )
+        } else if self.hidden {
+            (
+                HealthState::Degraded,
+                Some("The visibility schedule hides this device"),
+            )
+        } else {
+            (HealthState::Ok, None)
+        };
+        let listener = if self.permissions.is_denied(Permission::Firewall) {
+            (
+                HealthState::Degraded,
+                Some("The firewall may block devices connecting to this one"),
+            )
+        } else {
+            (HealthState::Ok, None)
+        };
+        let network = if self.lan.lan.is_empty() {
+            (
+                HealthState::Failed,
+                Some("This device is not connected to a local network"),
+            )
+        } else if self.risk.is_risky() {
+            (
+                HealthState::Degraded,
+                Some("The network looks public or crowded"),
+            )
+        } else {
+            (HealthState::Ok, None)
+        };
+        for (subsystem, (state, reason)) in [
+            (Subsystem::Discovery, discovery),
+            (Subsystem::Listener, listener),
+            (Subsystem::Network, network),
+        ] {
+            self.set_health(subsystem, state, reason.map(String::from))
+                .await;
+        }
+    }
+
+    // update the health of a subsystem and tell the ui if it changed
+    async fn set_health(
+        &mut self,
+        subsystem: Subsystem,
+        state: HealthState,
+        reason: Option<String>,
+    ) {
+        if let Some(health) = self.health.set(subsystem, state, reason) {
+            self.emit(CoreEvent::HealthChanged(health)).await;
+        }
+    }
+
+    // persist the seen peers, they are only a cache so failing is not fatal
+    fn save_seen(&mut self) {
+        if let Err(e) = self.seen.save() {
+            warn!("Unable to save the seen peers: {:?}", e);
+        }
+    }
+
+    // send an event to the ui
+    async fn emit(&mut self, event: CoreEvent) {
+        self.journal.record(event.clone());
+        if self.events.send(event).await.is_err() {
+            debug!("The ui stopped receiving core events");
+        }
+    }
+
+    // send an event to the ui without waiting, it is dropped if the ui is behind
+    fn try_emit(&mut self, event: CoreEvent) {
+        self.journal.record(event.clone());
+        _ = self.events.try_send(event);
+    }
+
+    // handle power state changes of the system
+    fn handle_power(&mut self, event: PowerEvent) {
+        debug!("Power event: {:?}", event);
+        match event {
+            PowerEvent::Suspend => {
+                self.asleep = true;
+                self.p2p.pause_discovery();
+            }
+            PowerEvent::Resume => {
+                self.asleep = false;
+                self.resume_discovery();
+            }
+            PowerEvent::Shutdown => self.shutdown = Some(conf::ShutdownReason::Signal),
+        }
+    }
+
+    // flip discovery when the visibility schedule opens or closes and tell the ui
+    async fn check_visibility(&mut self) {
+        let visible = self
+            .conf
+            .visibility
+            .as_ref()
+            .is_none_or(|s| s.is_visible(Local::now().naive_local()));
+        if visible != self.hidden {
+            return;
+        }
+        self.hidden = !visible;
+        if self.hidden {
+            self.p2p.pause_discovery();
+        } else {
+            self.resume_discovery();
+        }
+        self.emit(CoreEvent::VisibilityChanged(visible)).await;
+    }
+
+    // tell the ui when the network starts or stops looking untrusted
+    async fn check_network_risk(&mut self) {
+        let risk = self.lan.risk(self.p2p.stranger_count());
+        if risk != self.risk {
+            self.risk = risk;
+            self.emit(CoreEvent::NetworkRiskChanged(risk)).await;
+        }
+    }
+
+    // resume discovery unless something else still wants it paused
+    fn resume_discovery(&mut self) {
+        if self.asleep || self.hidden {
+            return;
+        }
+        // stale peers are dropped on resume, announce a fresh burst to find them again
+        self.p2p.resume_discovery();
+        self.discover(3);
+    }
+
+    /// a notifier for platform code to report native suspend & resume events
+    pub fn power_notifier(&self) -> mpsc::UnboundedSender<PowerEvent> {
+        self.power.notifier()
+    }
+
+    // pair with an unpaired peer in the background, the user has a while to answer on the other end
+    fn pair(&mut self, id: PeerId, pin: Option<String>) {
+        let p2p = self.p2p.clone();
+        self.tasks.spawn(async move {
+            let paired = match pin {
+                Some(pin) => p2p.pair_with_pin(&id, &pin).await,
+                None => p2p.pair_with(&id).await,
+            };
+            if let Err(e) = paired {
+                warn!("Unable to pair with {}: {:?}", id, e);
+            }
+        });
+    }
+
+    // persist a newly paired peer and its secret
+    fn remember_peer(&mut self, metadata: &PeerMetadata, secret: &str) -> Result<(), err::CoreError> {
+        secret::set_totp(&metadata.id, secret)?;
+        self.conf.known_peers.replace(metadata.clone());
+        self.store.set(&self.conf)?;
+        // the pairing expiry counts from now until the peer is discovered
+        if self.seen.remember(metadata) {
+            self.save_seen();
+        }
+        Ok(())
+    }
+
+    // advertise the new ips and look for peers on them
+    async fn handle_network_change(&mut self) {
+        let ips: Vec<IpAddr> = self.lan.lan.iter().copied().collect();
+        self.p2p.set_lan(&ips).await;
+        // the gateway changes along with the network
+        self.update_port_mapping().await;
+        // register the new addresses with the rendezvous server
+        if let Err(e) = self.update_relay().await {
+            warn!("Unable to find the rendezvous server: {:?}", e);
+        }
+        self.p2p.request_presence().await;
+        self.emit(CoreEvent::NetworkChanged(ips)).await;
+    }
+
+    // map a port on the gateway of the lan when enabled, or remove the mapping
+    async fn update_port_mapping(&mut self) {
+        let ips: Vec<IpAddr> = self.lan.lan.iter().copied().collect();
+        match portmap::guess_gateway(&ips).filter(|_| self.conf.port_mapping) {
+            Some(gateway) => self.p2p.start_port_mapping(gateway).await,
+            None => self.p2p.stop_port_mapping().await,
+        }
+    }
+
+    // register with the rendezvous server while the relay is enabled
+    async fn update_relay(&mut self) -> Result<(), err::CoreError> {
+        self.p2p.stop_relay();
+        let Some(server) = self.conf.relay_server.as_ref().filter(|_| self.conf.enable_relay) else {
+            return Ok(());
+        };
+        let Some(server) = tokio::net::lookup_host(server.as_str()).await?.next() else {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+        };
+        self.p2p.start_relay(server);
+        Ok(())
+    }
+
+    // refresh discovery in the background so the node keeps running while peers answer, `reply`
+    // is answered once they had the time to
+    fn refresh_discovery(
+        &mut self,
+        reply: Option<tokio::sync::oneshot::Sender<Result<CoreResponse, err::CoreError>>>,
+    ) {
+        let p2p = self.p2p.clone();
+        self.tasks.spawn(async move {
+            p2p.refresh_discovery().await;
+            if let Some(reply) = reply {
+                _ = reply.send(Ok(CoreResponse::Ok));
+            }
+        });
+    }
+
+    // request presence once a second for `span` seconds
+    fn discover(&mut self, span: u8) {
+        let p2p = self.p2p.clone();
+        self.tasks.spawn(async move {
+            for _ in 0..span {
+                sleep(Duration::from_secs(1)).await;
+                p2p.request_presence().await;
+            }
+        });
+    }
+
+    // request presence every BOOST_INTERVAL for `span`, ending any boost already running
+    fn boost_discovery(&mut self, span: Duration) {
+        if let Some(boost) = self.boost.take() {
+            boost.abort();
+        }
+        if span.is_zero() {
+            return;
+        }
+        let p2p = self.p2p.clone();
+        let end = tokio::time::Instant::now() + span.min(BOOST_LIMIT);
+        self.boost = Some(self.tasks.spawn(async move {
+            let mut tick = interval(BOOST_INTERVAL);
+            while tick.tick().await < end {
+                p2p.request_presence().await;
+            }
+        }));
+    }
+
     // handle events
     async fn handle_event(&mut self, _event: InternalEvent) {
         todo!()
@@ -152,26 +977,267 @@ impl Node {
 // pub enum NodeError {}
 
 // events to be subscribed to by the application ui
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum CoreEvent {
     Discovered(),
+
+    /// a discovered peer stopped answering discovery and is no longer available
+    PeerLost(PeerId),
+
+    /// damaged stored state was recovered from backups at startup
+    StorageRepaired(conf::StorageReport),
+
+    /// damaged stored state could not be recovered at startup
+    StorageCorrupt(conf::StorageReport),
+
+    /// discovery silently stopped working and was restored, carries the total number of recoveries
+    DiscoveryRecovered(u64),
+
+    /// the visibility schedule made the node discoverable (true) or hidden (false)
+    VisibilityChanged(bool),
+
+    /// the local ips changed, carries the ips the node is now reachable on
+    NetworkChanged(Vec<IpAddr>),
+
+    /// the network looks more or less trusted than before, the ui may suggest hiding the node
+    NetworkRiskChanged(NetworkRisk),
+
+    /// the config directory is not writable, settings and events are not saved this run
+    Ephemeral,
+
+    /// an unpaired peer asked to pair, answer with [AppCmd::Ack] once the user compares the code
+    PairRequest(PeerMetadata, String),
+
+    /// the code to show while the peer asked with [AppCmd::Pair] decides
+    PairCode(PeerId, String),
+
+    /// a peer was paired and is now known
+    Paired(PeerMetadata),
+
+    /// a paired peer was not seen for longer than the pairing expiry and was unpaired
+    PairingExpired(PeerId),
+
+    /// a paired peer could not connect because its clock is `skew` seconds ahead of this one,
+    /// or behind when negative. The ui should suggest setting the clock automatically.
+    ClockSkew { id: PeerId, skew: i64 },
+
+    /// another device announced this node's id, peers can't tell the two apart until one of
+    /// them gets a new identity. `remedy` tells the user how to fix it.
+    IdentityConflict {
+        addrs: Vec<SocketAddr>,
+        remedy: String,
+    },
+
+    /// a discovery frame seen while observing, `from` is unknown for malformed frames
+    Observed {
+        from: Option<SocketAddr>,
+        observation: Observation,
+    },
+
+    /// the router forwards a port to the node and peers on other subnets can connect on this
+    /// address, or it stopped when None
+    PortMapped(Option<SocketAddr>),
+
+    /// a subsystem got better or worse, [AppQuery::GetHealth] returns every subsystem
+    HealthChanged(SubsystemHealth),
+
+    /// the node stopped, this is the last event it sends
+    Shutdown { reason: conf::ShutdownReason },
+}
+
+impl CoreEvent {
+    /// the name of the event, as used to filter the journal
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CoreEvent::Discovered() => "Discovered",
+            CoreEvent::PeerLost(_) => "PeerLost",
+            CoreEvent::StorageRepaired(_) => "StorageRepaired",
+            CoreEvent::StorageCorrupt(_) => "StorageCorrupt",
+            CoreEvent::DiscoveryRecovered(_) => "DiscoveryRecovered",
+            CoreEvent::VisibilityChanged(_) => "VisibilityChanged",
+            CoreEvent::NetworkChanged(_) => "NetworkChanged",
+            CoreEvent::NetworkRiskChanged(_) => "NetworkRiskChanged",
+            CoreEvent::Ephemeral => "Ephemeral",
+            CoreEvent::PairRequest(..) => "PairRequest",
+            CoreEvent::PairCode(..) => "PairCode",
+            CoreEvent::Paired(_) => "Paired",
+            CoreEvent::PairingExpired(_) => "PairingExpired",
+            CoreEvent::ClockSkew { .. } => "ClockSkew",
+            CoreEvent::IdentityConflict { .. } => "IdentityConflict",
+            CoreEvent::Observed { .. } => "Observed",
+            CoreEvent::PortMapped(_) => "PortMapped",
+            CoreEvent::HealthChanged(_) => "HealthChanged",
+            CoreEvent::Shutdown { .. } => "Shutdown",
+        }
+    }
 }
 
 // commands and queries sent from the application layer to core
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum AppCmd {
+    /// rename this device, the name is advertised right away unless it is hidden. It can't be
+    /// empty.
     SetName(String),
     Discover(u8),
+    /// ask for presence several times a second for a while, such as when the share screen is
+    /// open, then go back to the usual cadence. A new boost replaces the running one and a zero
+    /// duration ends it, it never lasts longer than [BOOST_LIMIT].
+    BoostDiscovery(Duration),
+    /// drop peers which stopped answering and ask for presence now, answered after a fixed
+    /// [REFRESH_WAIT](p2p::manager::REFRESH_WAIT) however many peers replied in it, or at once
+    /// while discovery is paused
+    RefreshDiscovery,
+    SetVisibility(Option<VisibilitySchedule>),
+    SetConnectionTrace(bool),
+    /// report everything seen on discovery as [CoreEvent::Observed] and stop announcing the node
+    SetObserver(bool),
+    /// always connect to a peer over this address first, or choose automatically with None
+    SetPreferredPath(PeerId, Option<SocketAddr>),
+    /// stop the node, [Node::start] returns once the command is answered
+    Shutdown,
+    /// ask an unpaired peer which answered discovery to pair
+    Pair(PeerId),
+    /// a pin for the user to type on another device, which then pairs without being accepted
+    StartPinPairing,
+    /// stop the pin of an invitation from [AppQuery::GetInvitations] working, a used or expired
+    /// one is only marked as revoked
+    RevokeInvitation(u64),
+    /// pair with an unpaired peer using the pin shown on it after [AppCmd::StartPinPairing]
+    PairWithPin(PeerId, String),
+    /// accept (true) or decline a [CoreEvent::PairRequest] from a peer
+    Ack(PeerId, bool),
+    /// forget a paired peer and close any live connection to it
+    Unpair(PeerId),
+    /// ask the platform for a permission where core can, answers with its status afterwards
+    RequestPermission(Permission),
+    /// report the status of a permission only the host app can check, such as
+    /// [Permission::NearbyDevices] on android
+    SetPermission(Permission, PermissionStatus),
+    /// unpair peers which were not seen for this many days, or keep them paired with None
+    SetPairingExpiry(Option<u32>),
+    /// ask the router to forward a port to the node so devices on other subnets behind it can
+    /// connect, see [conf::NodeConfig::port_mapping]
+    SetPortMapping(bool),
+    /// reach paired peers off the lan through the rendezvous server at `server`, a host and
+    /// port. Connections it relays are still secured end to end.
+    SetRelay {
+        enabled: bool,
+        server: Option<String>,
+    },
+    /// ignore a peer on discovery, close its connection and turn it away until it is unblocked.
+    /// A paired peer stays paired.
+    BlockPeer(PeerId),
+    UnblockPeer(PeerId),
+    /// give a paired peer a local nickname, an empty one goes back to the name it advertises
+    RenamePeer(PeerId, String),
+    /// run commands in order and answer with all their responses at once. The batch stops at the
+    /// first command that fails and fails with its index. Only commands which change the config
+    /// can be batched, see [AppCmd::can_batch], and either all of them apply or none does. A
+    /// paired peer's secret is forgotten only once the batch applied.
+    Batch(Vec<AppCmd>),
+}
+
+impl AppCmd {
+    /// whether the command only changes what a failed [AppCmd::Batch] can put back: SetName,
+    /// Unpair, BlockPeer, UnblockPeer, RenamePeer, SetPortMapping, SetRelay, SetVisibility and
+    /// nested batches of them
+    pub fn can_batch(&self) -> bool {
+        match self {
+            AppCmd::SetName(_)
+            | AppCmd::Unpair(_)
+            | AppCmd::BlockPeer(_)
+            | AppCmd::UnblockPeer(_)
+            | AppCmd::RenamePeer(..)
+            | AppCmd::SetPortMapping(_)
+            | AppCmd::SetRelay { .. }
+            | AppCmd::SetVisibility(_) => true,
+            AppCmd::Batch(cmds) => cmds.iter().all(AppCmd::can_batch),
+            // these reach the network, the platform or the ui in ways which can't be taken back
+            AppCmd::Discover(_)
+            | AppCmd::BoostDiscovery(_)
+            | AppCmd::RefreshDiscovery
+            | AppCmd::SetConnectionTrace(_)
+            | AppCmd::SetObserver(_)
+            | AppCmd::SetPreferredPath(..)
+            | AppCmd::Shutdown
+            | AppCmd::Pair(_)
+            | AppCmd::StartPinPairing
+            | AppCmd::RevokeInvitation(_)
+            | AppCmd::PairWithPin(..)
+            | AppCmd::Ack(..)
+            | AppCmd::RequestPermission(_)
+            | AppCmd::SetPermission(..)
+            | AppCmd::SetPairingExpiry(_) => false,
+        }
+    }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum AppQuery {
     GetConf,
+    /// every paired peer with its nickname
+    GetKnownPeers,
+    /// the peers discovered this run or a previous one, the most recently seen first. Peers
+    /// from a previous run are offline until discovery finds them again.
+    GetSeenPeers,
+    /// the discovered peers which changed since a sequence returned by an earlier call
+    GetPeersSince(u64),
+    /// the recent events after a sequence, only of the kinds in filter unless it is empty
+    GetEvents {
+        after: u64,
+        filter: Vec<String>,
+    },
+    /// the frames of the latest connection with a peer, if connections are traced
+    GetConnectionTrace(PeerId),
+    /// the round trips to a peer measured while connected
+    GetLatency(PeerId),
+    /// how many tasks the node spawned are still running
+    GetTaskCount,
+    /// the inbound connection attempts turned away and the ips banned for failing to
+    /// authenticate
+    GetInboundStats,
+    /// the permissions the node needs on this platform and whether they are granted
+    GetPermissions,
+    /// how well discovery, the listener, storage, the clock and the network work, with the
+    /// reason for any which are not ok
+    GetHealth,
+    /// the live connections with every connected peer
+    GetConnectedPeers,
+    /// the live connection with a peer, if it is connected
+    GetPeerState(PeerId),
+    /// every pin made by [AppCmd::StartPinPairing] with who used it and whether it still works
+    GetInvitations,
+    /// the pin of [AppCmd::StartPinPairing] as a qr code of its [p2p::pairing::PinInvitation]
+    /// for small displays to draw, until the pin is used or expires
+    GetPinQr,
 }
 
-// #[derive(Serialize, Deserialize, Debug)]
-// #[serde(tag = "key", content = "data")]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data")]
 // #[ts(export)]
 pub enum CoreResponse {
     Ok,
-    Conf(conf::NodeConfig), // ClientGetState(ClientState),
+    Peers(PeerDelta),
+    Events(Vec<JournalEntry>),
+    Trace(Option<Vec<FrameRecord>>),
+    Latency(Option<LatencyHistory>),
+    Tasks(usize),
+    Inbound(InboundStats),
+    Permissions(Vec<PermissionState>),
+    Permission(PermissionStatus),
+    Health(Vec<SubsystemHealth>),
+    Batch(Vec<CoreResponse>),
+    Connections(Vec<ConnectionInfo>),
+    Connection(Option<ConnectionInfo>),
+    Pin(String),
+    Qr(Option<QrMatrix>),
+    Invitations(Vec<conf::InvitationState>),
+    KnownPeers(Vec<conf::KnownPeer>),
+    SeenPeers(Vec<SeenPeer>),
+    Conf(Box<conf::NodeConfig>), // ClientGetState(ClientState),
                             // Sum(i32),
 }
 
@@ -184,13 +1250,41 @@ pub struct ReturnableMessage<D, R = Result<CoreResponse, err::CoreError>> {
     tx_return: tokio::sync::oneshot::Sender<R>,
 }
 
+#[cfg(test)]
+impl<D, R> ReturnableMessage<D, R> {
+    pub(crate) fn respond(self, response: R) {
+        _ = self.tx_return.send(response);
+    }
+}
+
 // core controller is passed to the client to communicate with the core which runs in a dedicated thread
+#[derive(Clone)]
 pub struct CoreController {
     query_tx: mpsc::UnboundedSender<ReturnableMessage<AppQuery>>,
     command_tx: mpsc::UnboundedSender<ReturnableMessage<AppCmd>>,
 }
 
 impl CoreController {
+    /// a controller with no node behind it, the test answers what is sent instead
+    #[cfg(test)]
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn detached() -> (
+        Self,
+        mpsc::UnboundedReceiver<ReturnableMessage<AppQuery>>,
+        mpsc::UnboundedReceiver<ReturnableMessage<AppCmd>>,
+    ) {
+        let (query_tx, queries) = mpsc::unbounded_channel();
+        let (command_tx, commands) = mpsc::unbounded_channel();
+        (
+            Self {
+                query_tx,
+                command_tx,
+            },
+            queries,
+            commands,
+        )
+    }
+
     pub async fn query(&self, query: AppQuery) -> Result<CoreResponse, err::CoreError> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let payload = ReturnableMessage {
@@ -199,7 +1293,8 @@ impl CoreController {
         };
 
         self.query_tx.send(payload).unwrap_or(());
-        rx.await.unwrap()
+        // the node dropped the message or stopped before answering
+        rx.await.unwrap_or(Err(err::CoreError::Stopped))
     }
 
     pub async fn command(&self, cmd: AppCmd) -> Result<CoreResponse, err::CoreError> {
@@ -210,6 +1305,163 @@ impl CoreController {
         };
 
         self.command_tx.send(payload).unwrap_or(());
-        rx.await.unwrap()
+        // the node dropped the message or stopped before answering
+        rx.await.unwrap_or(Err(err::CoreError::Stopped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use p2p::manager::REFRESH_WAIT;
+    use p2p::pairing::{PinInvitation, QrMatrix};
+    use p2p::peer::{Identity, PeerId};
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    use super::{AppCmd, AppQuery, CoreEvent, CoreResponse, Node, MAX_NAME_LEN};
+    use crate::err::CoreError;
+    use crate::plat::PowerEvent;
+    use crate::secret::mock_store;
+
+    // a node with a fresh config in the temp dir `name` and secrets kept in memory
+    async fn node(name: &str) -> (Node, mpsc::Receiver<CoreEvent>) {
+        mock_store();
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Node::init_with(dir.display().to_string(), Identity::new, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn names_fit_a_presence() {
+        let (mut node, _events) = node("flydrop-long-name").await;
+
+        let name = "a".repeat(MAX_NAME_LEN + 1);
+        assert!(matches!(
+            node.handle_command(AppCmd::SetName(name)).await,
+            Err(CoreError::InvalidName)
+        ));
+        let name = "a".repeat(MAX_NAME_LEN);
+        node.handle_command(AppCmd::SetName(name.clone())).await.unwrap();
+        assert_eq!(name, node.p2p.get_metadata().name);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_changes_nothing() {
+        let (mut node, _events) = node("flydrop-failed-batch").await;
+        let name = node.conf.name.clone();
+
+        let batch = AppCmd::Batch(vec![
+            AppCmd::SetName(String::from("renamed")),
+            AppCmd::RenamePeer(
+                PeerId::from_string("a".repeat(40)).unwrap(),
+                String::from("stranger"),
+            ),
+        ]);
+        assert!(matches!(
+            node.handle_command(batch).await,
+            Err(CoreError::Batch { index: 1, .. })
+        ));
+        assert_eq!(name, node.conf.name);
+        assert_eq!(name, node.store.get().unwrap().name);
+        assert_eq!(node.conf.advertised_name(), node.p2p.get_metadata().name);
+
+        // nothing runs when a command can't be taken back
+        let batch = AppCmd::Batch(vec![AppCmd::SetName(String::from("renamed")), AppCmd::Shutdown]);
+        let Err(CoreError::Batch { index: 1, source }) = node.handle_command(batch).await else {
+            panic!("the batch was not refused");
+        };
+        assert!(matches!(*source, CoreError::NotBatchable));
+        assert_eq!(name, node.conf.name);
+        assert!(node.shutdown.is_none());
+    }
+
+    #[tokio::test]
+    async fn node_answers_while_refreshing() {
+        let (mut node, _events) = node("flydrop-refreshing").await;
+        let controller = node.controller();
+
+        let app = async {
+            let started = Instant::now();
+            // the command is sent on the first poll
+            let refresh = controller.command(AppCmd::RefreshDiscovery);
+            tokio::pin!(refresh);
+            assert!(futures::poll!(&mut refresh).is_pending());
+            controller.query(AppQuery::GetTaskCount).await.unwrap();
+            assert!(started.elapsed() < REFRESH_WAIT);
+            assert!(matches!(refresh.await, Ok(CoreResponse::Ok)));
+            assert!(started.elapsed() >= REFRESH_WAIT);
+            controller.command(AppCmd::Shutdown).await.unwrap();
+        };
+        tokio::join!(node.start(), app);
+    }
+
+    #[tokio::test]
+    async fn pin_qr_follows_the_pin() {
+        let (mut node, _events) = node("flydrop-pin-qr").await;
+        assert!(matches!(
+            node.handle_query(AppQuery::GetPinQr).await,
+            Ok(CoreResponse::Qr(None))
+        ));
+
+        let Ok(CoreResponse::Pin(pin)) = node.handle_command(AppCmd::StartPinPairing).await else {
+            panic!("no pin was made");
+        };
+        // front ends read the matrix from json
+        let response = node.handle_query(AppQuery::GetPinQr).await.unwrap();
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!("Qr", json["type"]);
+        let matrix: QrMatrix = serde_json::from_value(json["data"].clone()).unwrap();
+        let invitation = PinInvitation {
+            id: node.p2p.get_metadata().id,
+            pin,
+        };
+        assert_eq!(invitation.to_qr_matrix().unwrap(), matrix);
+    }
+
+    #[tokio::test]
+    async fn invitations_are_listed_and_revoked() {
+        let (mut node, _events) = node("flydrop-invitations").await;
+
+        node.handle_command(AppCmd::StartPinPairing).await.unwrap();
+        node.handle_command(AppCmd::StartPinPairing).await.unwrap();
+        let valid = |response| match response {
+            Ok(CoreResponse::Invitations(states)) => states
+                .iter()
+                .map(|state| (state.invitation.id, state.valid))
+                .collect::<Vec<_>>(),
+            _ => panic!("no invitations were listed"),
+        };
+        let listed = valid(node.handle_query(AppQuery::GetInvitations).await);
+        assert_eq!(vec![(1, false), (2, true)], listed);
+
+        node.handle_command(AppCmd::RevokeInvitation(2)).await.unwrap();
+        assert!(node.p2p.pin_invitation().is_none());
+        let listed = valid(node.handle_query(AppQuery::GetInvitations).await);
+        assert_eq!(vec![(1, false), (2, false)], listed);
+        assert!(node.conf.invitations[1].revoked);
+        assert!(matches!(
+            node.handle_command(AppCmd::RevokeInvitation(3)).await,
+            Err(CoreError::NoInvitation)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sleep_pauses_discovery_until_wake() {
+        let (mut node, _events) = node("flydrop-power").await;
+
+        node.handle_power(PowerEvent::Suspend);
+        assert!(node.p2p.is_paused());
+        node.handle_power(PowerEvent::Resume);
+        assert!(!node.p2p.is_paused());
+
+        // waking up while the visibility schedule hides the node keeps it paused
+        node.handle_power(PowerEvent::Suspend);
+        node.hidden = true;
+        node.handle_power(PowerEvent::Resume);
+        assert!(node.p2p.is_paused());
     }
 }
@@ -1,10 +1,20 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
 use p2p::peer;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    time::{interval, Interval, MissedTickBehavior},
+};
 
 pub(crate) fn device_type() -> peer::DeviceType {
     #[cfg(target_os = "windows")]
     return win::device_type();
     #[cfg(target_os = "ios")]
     return ios::device_type();
+    #[cfg(target_os = "linux")]
+    return linux::device_type();
 }
 
 pub(crate) fn host_name() -> String {
@@ -13,13 +23,236 @@ pub(crate) fn host_name() -> String {
         .unwrap_or_else(|_| String::from("my-flydrop"))
 }
 
+/// Something the platform has to allow before peers can find or reach the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    /// inbound connections to the listener through the windows defender firewall
+    Firewall,
+    /// reaching devices on the local network, apple platforms prompt the first time the node
+    /// sends discovery
+    LocalNetwork,
+    /// finding nearby devices on android, only the host app can ask for it
+    NearbyDevices,
+}
+
+/// Whether the platform allows what a [Permission] covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PermissionStatus {
+    Granted,
+    Denied,
+    /// the user was not asked yet
+    NotDetermined,
+    /// the platform offers no way to check, the host app may know and report it
+    Unknown,
+}
+
+/// A permission and its status, as reported to the ui
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionState {
+    pub permission: Permission,
+    pub status: PermissionStatus,
+}
+
+/// The permissions the node needs on this platform. Core detects and requests what it can,
+/// host apps report the rest through [crate::node::AppCmd::SetPermission].
+pub struct Permissions(HashMap<Permission, PermissionStatus>);
+
+impl Permissions {
+    /// check every permission this platform needs
+    pub fn detect() -> Self {
+        Self(needed_permissions().into_iter().collect())
+    }
+
+    /// record a status reported by the host app
+    pub fn set(&mut self, permission: Permission, status: PermissionStatus) {
+        self.0.insert(permission, status);
+    }
+
+    /// ask the platform for a permission where core can, returning its status afterwards
+    pub fn request(&mut self, permission: Permission) -> PermissionStatus {
+        if let Some(status) = request_permission(permission) {
+            self.0.insert(permission, status);
+        }
+        self.0
+            .get(&permission)
+            .copied()
+            .unwrap_or(PermissionStatus::Unknown)
+    }
+
+    /// whether the user refused the permission
+    pub fn is_denied(&self, permission: Permission) -> bool {
+        self.0.get(&permission) == Some(&PermissionStatus::Denied)
+    }
+
+    pub fn list(&self) -> Vec<PermissionState> {
+        let mut states: Vec<PermissionState> = self
+            .0
+            .iter()
+            .map(|(&permission, &status)| PermissionState { permission, status })
+            .collect();
+        states.sort_by_key(|s| s.permission);
+        states
+    }
+}
+
+fn needed_permissions() -> Vec<(Permission, PermissionStatus)> {
+    #[cfg(target_os = "windows")]
+    return vec![(Permission::Firewall, win::firewall())];
+    #[cfg(target_os = "ios")]
+    return vec![(Permission::LocalNetwork, PermissionStatus::Unknown)];
+    #[cfg(target_os = "linux")]
+    return Vec::new();
+}
+
+// request a permission, none if core can't ask for it on this platform
+fn request_permission(permission: Permission) -> Option<PermissionStatus> {
+    #[cfg(target_os = "windows")]
+    if permission == Permission::Firewall {
+        return Some(win::allow_firewall());
+    }
+    _ = permission;
+    None
+}
+
+/// how often the power monitor checks the wall clock
+const POWER_TICK: Duration = Duration::from_secs(5);
+
+/// how far the wall clock has to jump past a tick to count as the system waking up
+const POWER_WAKE_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// A change in the power state of the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    /// the system is about to sleep
+    Suspend,
+
+    /// the system woke up from sleep
+    Resume,
+
+    /// the system is shutting down or terminating the app, the node stops
+    Shutdown,
+}
+
+/// Watches for the system going to sleep and waking back up.
+/// Waking up is detected on every platform by the wall clock jumping forward between ticks,
+/// platforms with native power notifications can report them through [PowerMonitor::notifier].
+pub struct PowerMonitor {
+    tx: mpsc::UnboundedSender<PowerEvent>,
+    rx: mpsc::UnboundedReceiver<PowerEvent>,
+    tick: Interval,
+    last: SystemTime,
+}
+
+impl PowerMonitor {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut tick = interval(POWER_TICK);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            tx,
+            rx,
+            tick,
+            last: SystemTime::now(),
+        }
+    }
+
+    /// a sender for platform code to report native power notifications
+    pub fn notifier(&self) -> mpsc::UnboundedSender<PowerEvent> {
+        self.tx.clone()
+    }
+
+    /// wait for the next power event
+    pub async fn next(&mut self) -> PowerEvent {
+        loop {
+            tokio::select! {
+                Some(event) = self.rx.recv() => return event,
+                _ = self.tick.tick() => {
+                    let now = SystemTime::now();
+                    let elapsed = now.duration_since(self.last).unwrap_or_default();
+                    self.last = now;
+                    if elapsed > POWER_TICK + POWER_WAKE_THRESHOLD {
+                        return PowerEvent::Resume;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for PowerMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(target_os = "windows")]
 mod win {
+    use std::process::Command;
+
     use p2p::peer;
 
+    use super::PermissionStatus;
+
+    // the name of the inbound firewall rule for the app
+    const FIREWALL_RULE: &str = "Flydrop";
+
     pub fn device_type() -> peer::DeviceType {
         peer::DeviceType::WindowsLaptop
     }
+
+    /// whether an inbound firewall rule lets the running executable listen
+    pub fn firewall() -> PermissionStatus {
+        let Ok(exe) = std::env::current_exe() else {
+            return PermissionStatus::Unknown;
+        };
+        let rule = format!("name={}", FIREWALL_RULE);
+        let output = Command::new("netsh")
+            .args(["advfirewall", "firewall", "show", "rule", rule.as_str(), "verbose"])
+            .output();
+        match output {
+            // netsh fails when there is no rule by that name
+            Ok(output) if !output.status.success() => PermissionStatus::NotDetermined,
+            Ok(output) => {
+                let rules = String::from_utf8_lossy(&output.stdout).to_lowercase();
+                let exe = exe.to_string_lossy().to_lowercase();
+                if !rules.contains(&exe) {
+                    PermissionStatus::NotDetermined
+                } else if rules.contains("block") {
+                    PermissionStatus::Denied
+                } else {
+                    PermissionStatus::Granted
+                }
+            }
+            Err(_) => PermissionStatus::Unknown,
+        }
+    }
+
+    /// add an inbound rule for the running executable, which only works with admin rights
+    pub fn allow_firewall() -> PermissionStatus {
+        let Ok(exe) = std::env::current_exe() else {
+            return PermissionStatus::Unknown;
+        };
+        let rule = format!("name={}", FIREWALL_RULE);
+        let program = format!("program={}", exe.to_string_lossy());
+        let added = Command::new("netsh")
+            .args([
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                rule.as_str(),
+                "dir=in",
+                "action=allow",
+                program.as_str(),
+                "enable=yes",
+                "profile=private,domain",
+            ])
+            .status();
+        match added {
+            Ok(status) if status.success() => PermissionStatus::Granted,
+            _ => firewall(),
+        }
+    }
 }
 
 #[cfg(target_os = "ios")]
@@ -30,3 +263,12 @@ mod ios {
         peer::DeviceType::AppleiPhone
     }
 }
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use p2p::peer;
+
+    pub fn device_type() -> peer::DeviceType {
+        peer::DeviceType::LinuxDevice
+    }
+}
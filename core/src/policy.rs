@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use p2p::peer::PeerMetadata;
+
+/// What to do with a request from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// leave it to the user through the ui
+    Ask,
+}
+
+/// Decides on requests from peers, embedders register their own with [crate::node::Node::set_policy]
+pub trait Policy: Send + Sync {
+    /// an unpaired peer asked to pair
+    fn pairing_request(&self, _peer: &PeerMetadata) -> Decision {
+        Decision::Ask
+    }
+
+    /// a paired peer passed authentication and wants to connect
+    fn inbound_connection(&self, _peer: &PeerMetadata) -> bool {
+        true
+    }
+}
+
+/// The policy used unless another is registered: the user answers pairing requests and every
+/// paired peer may connect
+#[derive(Debug, Default)]
+pub struct DefaultPolicy;
+
+impl Policy for DefaultPolicy {}
+
+/// the filter p2p asks before accepting a connection
+pub(crate) fn connection_filter(policy: Arc<dyn Policy>) -> p2p::manager::ConnectionFilter {
+    Arc::new(move |peer| policy.inbound_connection(peer))
+}
@@ -6,14 +6,15 @@ use p2p::peer::{self, Identity};
 pub static SERVICE_NAME: &str = "flydrop";
 pub static IDENTITY: &str = "Identity";
 pub static TOTP_AUTH: &str = "_Totp";
+pub static TOTP_PREVIOUS: &str = "_TotpPrevious";
 
-/// Get or create a new identity
-pub(crate) fn get_identity() -> Result<peer::Identity, ConfError> {
+/// Get or create a new identity with `create`
+pub(crate) fn get_identity(create: impl FnOnce() -> Identity) -> Result<peer::Identity, ConfError> {
     let e = keyring::Entry::new(SERVICE_NAME, IDENTITY)?;
     match e.get_password() {
         Ok(data) => Ok(serde_json::from_str(&data)?),
         Err(keyring::error::Error::NoEntry) => {
-            let id = Identity::new();
+            let id = create();
             let data = serde_json::to_string(&id)?;
             e.set_password(&data)?;
             Ok(id)
@@ -28,19 +29,73 @@ pub(crate) fn get_totp(peer: &peer::PeerId) -> Result<String, ConfError> {
     Ok(e.get_password()?)
 }
 
+pub(crate) fn set_totp(peer: &peer::PeerId, secret: &str) -> Result<(), ConfError> {
+    let key = peer.inner().clone() + TOTP_AUTH;
+    let e = keyring::Entry::new(SERVICE_NAME, &key)?;
+    Ok(e.set_password(secret)?)
+}
+
+/// forget the pairing secrets of a peer, it is fine if there were none
+pub(crate) fn remove_totp(peer: &peer::PeerId) -> Result<(), ConfError> {
+    remove(&(peer.inner().clone() + TOTP_AUTH))?;
+    remove(&(peer.inner().clone() + TOTP_PREVIOUS))
+}
+
+/// store the secrets of a peer after a rotation, `previous` is the one it may still use
+pub(crate) fn rotate_totp(
+    peer: &peer::PeerId,
+    secret: &str,
+    previous: Option<&str>,
+) -> Result<(), ConfError> {
+    let key = peer.inner().clone() + TOTP_PREVIOUS;
+    match previous {
+        Some(previous) => keyring::Entry::new(SERVICE_NAME, &key)?.set_password(previous)?,
+        None => remove(&key)?,
+    }
+    set_totp(peer, secret)
+}
+
+fn get_previous_totp(peer: &peer::PeerId) -> Result<String, ConfError> {
+    let key = peer.inner().clone() + TOTP_PREVIOUS;
+    let e = keyring::Entry::new(SERVICE_NAME, &key)?;
+    Ok(e.get_password()?)
+}
+
+fn remove(key: &str) -> Result<(), ConfError> {
+    let e = keyring::Entry::new(SERVICE_NAME, key)?;
+    match e.delete_password() {
+        Ok(()) | Err(keyring::error::Error::NoEntry) => Ok(()),
+        Err(x) => Err(ConfError::Secret(x)),
+    }
+}
+
 pub(crate) fn to_known(peers: &HashSet<peer::PeerMetadata>) -> Vec<peer::PeerCandidate> {
     let mut map = Vec::new();
     for peer in peers {
         if let Ok(pwd) = get_totp(&peer.id) {
             if let Ok(auth) = p2p::pairing::PairingAuthenticator::new(pwd.into_bytes()) {
-                map.push(peer::PeerCandidate::new(peer, auth));
+                let mut candidate = peer::PeerCandidate::new(peer, auth);
+                candidate.previous = get_previous_totp(&peer.id)
+                    .ok()
+                    .and_then(|pwd| p2p::pairing::PairingAuthenticator::new(pwd.into_bytes()).ok());
+                map.push(candidate);
             }
         }
     }
     map
 }
 
+/// known peers whose pairing secret can no longer be found
+pub(crate) fn missing(peers: &HashSet<peer::PeerMetadata>) -> Vec<peer::PeerId> {
+    peers
+        .iter()
+        .filter(|p| get_totp(&p.id).is_err())
+        .map(|p| p.id.clone())
+        .collect()
+}
+
 /// used for testing, to mock the underlying secret store
+#[cfg(any(test, feature = "test-util"))]
 pub fn mock_store() {
     use keyring::{mock::default_credential_builder, set_default_credential_builder};
     set_default_credential_builder(default_credential_builder());
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use p2p::peer::{PeerId, PeerMetadata};
+use serde::{Deserialize, Serialize};
+
+pub static SEEN_PEERS_NAME: &str = "peers.json";
+
+/// A peer discovered this run or a previous one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeenPeer {
+    pub metadata: PeerMetadata,
+    /// milliseconds since the unix epoch
+    pub last_seen: u64,
+    /// whether discovery found the peer this run, peers loaded from disk are only previously
+    /// seen until they answer again
+    #[serde(skip_deserializing)]
+    pub online: bool,
+}
+
+/// The last known snapshot of discovered peers, saved so the ui can show recently seen devices
+/// right after a restart while discovery confirms which are still around
+pub(crate) struct SeenPeers {
+    peers: HashMap<PeerId, SeenPeer>,
+    path: Option<PathBuf>,
+}
+
+impl SeenPeers {
+    /// load the snapshot at `path`, or keep peers in memory only without one
+    pub(crate) fn open(path: Option<PathBuf>) -> Self {
+        let peers = path
+            .as_ref()
+            .and_then(|p| fs::read(p).ok())
+            .and_then(|data| serde_json::from_slice::<Vec<SeenPeer>>(&data).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| (p.metadata.id.clone(), p))
+            .collect();
+        Self { peers, path }
+    }
+
+    /// a peer answered discovery
+    pub(crate) fn seen(&mut self, metadata: PeerMetadata) {
+        self.peers.insert(
+            metadata.id.clone(),
+            SeenPeer {
+                metadata,
+                last_seen: now(),
+                online: true,
+            },
+        );
+    }
+
+    /// a peer stopped answering discovery, it was last seen just now
+    pub(crate) fn lost(&mut self, id: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(id) {
+            peer.last_seen = now();
+            peer.online = false;
+        }
+    }
+
+    /// keep a peer which was not seen yet as seen now so it has a last seen time, returns
+    /// whether it was added
+    pub(crate) fn remember(&mut self, metadata: &PeerMetadata) -> bool {
+        if self.peers.contains_key(&metadata.id) {
+            return false;
+        }
+        self.peers.insert(
+            metadata.id.clone(),
+            SeenPeer {
+                metadata: metadata.clone(),
+                last_seen: now(),
+                online: false,
+            },
+        );
+        true
+    }
+
+    /// when a peer was last seen, now if it is online
+    pub(crate) fn last_seen(&self, id: &PeerId) -> Option<u64> {
+        self.peers
+            .get(id)
+            .map(|p| if p.online { now() } else { p.last_seen })
+    }
+
+    pub(crate) fn forget(&mut self, id: &PeerId) {
+        self.peers.remove(id);
+    }
+
+    /// every seen peer, the most recently seen first
+    pub(crate) fn list(&self) -> Vec<SeenPeer> {
+        let mut peers: Vec<SeenPeer> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|p| std::cmp::Reverse(p.last_seen));
+        peers
+    }
+
+    /// mark the peers still online as seen now and write the snapshot
+    pub(crate) fn save(&mut self) -> Result<(), io::Error> {
+        let now = now();
+        for peer in self.peers.values_mut().filter(|p| p.online) {
+            peer.last_seen = now;
+        }
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string(&self.list())?;
+        crate::conf::write_atomic(path, json.as_bytes())
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p::peer::{DeviceType, PeerId, PeerMetadata};
+
+    use crate::seen::{SeenPeers, SEEN_PEERS_NAME};
+
+    #[test]
+    fn seen_peers_survive_restart_offline() {
+        let dir = std::env::temp_dir().join("flydrop-seen-peers-survive-restart");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SEEN_PEERS_NAME);
+        _ = std::fs::remove_file(&path);
+
+        let phone = PeerMetadata {
+            name: String::from("iPhone"),
+            typ: DeviceType::AppleiPhone,
+            id: PeerId::from_string(String::from("0123456789abcdef0123456789abcdef01234567"))
+                .unwrap(),
+            addrs: vec!["127.0.0.1:5001".parse().unwrap()],
+        };
+        let mut seen = SeenPeers::open(Some(path.clone()));
+        seen.seen(phone.clone());
+        assert!(seen.list()[0].online);
+        seen.save().unwrap();
+
+        let mut seen = SeenPeers::open(Some(path.clone()));
+        let peers = seen.list();
+        assert_eq!(1, peers.len());
+        assert_eq!(phone, peers[0].metadata);
+        assert!(!peers[0].online);
+
+        seen.forget(&phone.id);
+        seen.save().unwrap();
+        assert!(SeenPeers::open(Some(path)).list().is_empty());
+
+        // cleanup
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn remembered_peers_keep_when_they_were_seen() {
+        let phone = PeerMetadata {
+            name: String::from("iPhone"),
+            typ: DeviceType::AppleiPhone,
+            id: PeerId::from_string(String::from("0123456789abcdef0123456789abcdef01234567"))
+                .unwrap(),
+            addrs: Vec::new(),
+        };
+        let mut seen = SeenPeers::open(None);
+        assert_eq!(None, seen.last_seen(&phone.id));
+
+        assert!(seen.remember(&phone));
+        let first = seen.last_seen(&phone.id).unwrap();
+        assert!(!seen.list()[0].online);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(!seen.remember(&phone));
+        assert_eq!(Some(first), seen.last_seen(&phone.id));
+
+        // an online peer is seen right now
+        seen.seen(phone.clone());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(seen.last_seen(&phone.id).unwrap() > first);
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// A weekly window during which the node can be discovered, outside of it discovery is paused
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisibilitySchedule {
+    /// the days of the week the window opens on
+    pub days: Vec<Weekday>,
+    /// the local time the node becomes discoverable
+    pub start: NaiveTime,
+    /// the local time the node stops being discoverable, before `start` for windows past midnight
+    pub end: NaiveTime,
+}
+
+impl VisibilitySchedule {
+    /// whether the node is discoverable at the local time `at`
+    pub fn is_visible(&self, at: NaiveDateTime) -> bool {
+        let day = at.weekday();
+        let time = at.time();
+        if self.start <= self.end {
+            self.days.contains(&day) && self.start <= time && time < self.end
+        } else {
+            // the window wraps past midnight into the following day
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+    use crate::visibility::VisibilitySchedule;
+
+    fn at(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+        // 2023-05-01 was a monday
+        NaiveDate::from_ymd_opt(2023, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, min, 0)
+            .unwrap()
+    }
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn office_hours_on_weekdays() {
+        let schedule = VisibilitySchedule {
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            start: time(9),
+            end: time(18),
+        };
+        assert!(schedule.is_visible(at(1, 9, 0)));
+        assert!(schedule.is_visible(at(5, 17, 59)));
+        assert!(!schedule.is_visible(at(1, 8, 59)));
+        assert!(!schedule.is_visible(at(1, 18, 0)));
+        assert!(!schedule.is_visible(at(6, 12, 0)));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let schedule = VisibilitySchedule {
+            days: vec![Weekday::Fri],
+            start: time(22),
+            end: time(2),
+        };
+        assert!(schedule.is_visible(at(5, 23, 0)));
+        assert!(schedule.is_visible(at(6, 1, 59)));
+        assert!(!schedule.is_visible(at(5, 1, 0)));
+        assert!(!schedule.is_visible(at(6, 2, 0)));
+        assert!(!schedule.is_visible(at(6, 23, 0)));
+    }
+}
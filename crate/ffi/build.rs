@@ -0,0 +1,10 @@
+// generate the C header from the exported functions, so it never drifts from their signatures
+fn main() {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{dir}/cbindgen.toml")).unwrap();
+    cbindgen::generate_with_config(&dir, config)
+        .expect("the header is generated")
+        .write_to_file(format!("{dir}/include/flydrop.h"));
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
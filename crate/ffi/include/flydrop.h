@@ -0,0 +1,71 @@
+#ifndef FLYDROP_H
+#define FLYDROP_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ A node running on its own thread and tokio runtime, see [flydrop_start]
+ */
+typedef struct FlydropNode FlydropNode;
+
+/*
+ Called with every core event as a json string, on a thread owned by the node. The string is
+ only valid during the call, which must not call back into the node.
+ */
+typedef void (*FlydropEventCallback)(const char *event, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Start a node storing its config in `dir`, core events are passed to `on_event` along with
+ `user_data`. Returns null if the node could not start.
+
+ # Safety
+ `dir` must be a valid nul terminated string, the node must be stopped with [flydrop_stop].
+ */
+struct FlydropNode *flydrop_start(const char *dir, FlydropEventCallback on_event, void *user_data);
+
+/*
+ Send a json [AppQuery] to the node and wait for the answer. The returned string must be
+ freed with [flydrop_string_free].
+
+ # Safety
+ `node` must come from [flydrop_start] and `request` must be a valid nul terminated string.
+ */
+char *flydrop_query(const struct FlydropNode *node, const char *request);
+
+/*
+ Send a json [AppCmd] to the node and wait for the answer. The returned string must be freed
+ with [flydrop_string_free].
+
+ # Safety
+ `node` must come from [flydrop_start] and `request` must be a valid nul terminated string.
+ */
+char *flydrop_command(const struct FlydropNode *node, const char *request);
+
+/*
+ Stop the node and wait for it to shut down, `node` can't be used afterwards.
+
+ # Safety
+ `node` must come from [flydrop_start] and not be stopped already.
+ */
+void flydrop_stop(struct FlydropNode *node);
+
+/*
+ Free a string returned by the node.
+
+ # Safety
+ `s` must come from [flydrop_query] or [flydrop_command] and not be freed already.
+ */
+void flydrop_string_free(char *s);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* FLYDROP_H */
@@ -0,0 +1,270 @@
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{mpsc as sync_mpsc, Arc},
+    thread::{self, JoinHandle},
+};
+
+use flydrop_core::node::{AppCmd, AppQuery, CoreController, CoreResponse, Node};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::runtime::Runtime;
+use tracing::warn;
+
+/// Called with every core event as a json string, on a thread owned by the node. The string is
+/// only valid during the call, which must not call back into the node.
+pub type EventCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+/// A node running on its own thread and tokio runtime, see [flydrop_start]
+pub struct FlydropNode {
+    runtime: Arc<Runtime>,
+    controller: CoreController,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The answer to a query or command, serialized as `{"result": ...}` or `{"error": "..."}`
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply {
+    Result(CoreResponse),
+    Error(String),
+}
+
+// the host promises the user data can be used from the node's threads
+struct Subscriber {
+    callback: EventCallback,
+    user_data: *mut c_void,
+}
+
+unsafe impl Send for Subscriber {}
+
+/// Start a node storing its config in `dir`, core events are passed to `on_event` along with
+/// `user_data`. Returns null if the node could not start.
+///
+/// # Safety
+/// `dir` must be a valid nul terminated string, the node must be stopped with [flydrop_stop].
+#[no_mangle]
+pub unsafe extern "C" fn flydrop_start(
+    dir: *const c_char,
+    on_event: EventCallback,
+    user_data: *mut c_void,
+) -> *mut FlydropNode {
+    // unwinding into the host is undefined, a panic is reported like any other failure
+    panic::catch_unwind(AssertUnwindSafe(|| start(dir, on_event, user_data))).unwrap_or_else(|_| {
+        warn!("The node panicked while starting");
+        ptr::null_mut()
+    })
+}
+
+unsafe fn start(
+    dir: *const c_char,
+    on_event: EventCallback,
+    user_data: *mut c_void,
+) -> *mut FlydropNode {
+    let Some(dir) = string(dir) else {
+        return ptr::null_mut();
+    };
+    let runtime = match Runtime::new() {
+        Ok(runtime) => Arc::new(runtime),
+        Err(e) => {
+            warn!("Unable to start the runtime: {:?}", e);
+            return ptr::null_mut();
+        }
+    };
+    let subscriber = Subscriber {
+        callback: on_event,
+        user_data,
+    };
+
+    // the node is not Send, it lives on a dedicated thread from init until it stops
+    let (started_tx, started) = sync_mpsc::sync_channel(1);
+    let rt = runtime.clone();
+    let thread = thread::spawn(move || {
+        rt.block_on(async move {
+            let (mut node, mut events) = match Node::init(dir).await {
+                Ok(node) => node,
+                Err(e) => {
+                    _ = started_tx.send(Err(e));
+                    return;
+                }
+            };
+            _ = started_tx.send(Ok(node.controller()));
+            tokio::spawn(async move {
+                let subscriber = subscriber;
+                while let Some(event) = events.recv().await {
+                    let json = serde_json::to_string(&event).expect("events serialize");
+                    if let Ok(json) = CString::new(json) {
+                        (subscriber.callback)(json.as_ptr(), subscriber.user_data);
+                    }
+                }
+            });
+            node.start().await;
+        })
+    });
+
+    match started.recv() {
+        Ok(Ok(controller)) => Box::into_raw(Box::new(FlydropNode {
+            runtime,
+            controller,
+            thread: Some(thread),
+        })),
+        Ok(Err(e)) => {
+            warn!("Unable to start the node: {:?}", e);
+            _ = thread.join();
+            ptr::null_mut()
+        }
+        Err(_) => {
+            _ = thread.join();
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Send a json [AppQuery] to the node and wait for the answer. The returned string must be
+/// freed with [flydrop_string_free].
+///
+/// # Safety
+/// `node` must come from [flydrop_start] and `request` must be a valid nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn flydrop_query(
+    node: *const FlydropNode,
+    request: *const c_char,
+) -> *mut c_char {
+    call(node, request, |controller, query: AppQuery| async move {
+        controller.query(query).await
+    })
+}
+
+/// Send a json [AppCmd] to the node and wait for the answer. The returned string must be freed
+/// with [flydrop_string_free].
+///
+/// # Safety
+/// `node` must come from [flydrop_start] and `request` must be a valid nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn flydrop_command(
+    node: *const FlydropNode,
+    request: *const c_char,
+) -> *mut c_char {
+    call(node, request, |controller, cmd: AppCmd| async move {
+        controller.command(cmd).await
+    })
+}
+
+/// Stop the node and wait for it to shut down, `node` can't be used afterwards.
+///
+/// # Safety
+/// `node` must come from [flydrop_start] and not be stopped already.
+#[no_mangle]
+pub unsafe extern "C" fn flydrop_stop(node: *mut FlydropNode) {
+    if node.is_null() {
+        return;
+    }
+    let mut node = Box::from_raw(node);
+    let stopped = panic::catch_unwind(AssertUnwindSafe(|| {
+        let controller = node.controller.clone();
+        if let Err(e) = node
+            .runtime
+            .block_on(async move { controller.command(AppCmd::Shutdown).await })
+        {
+            warn!("Unable to stop the node: {:?}", e);
+        }
+        if let Some(thread) = node.thread.take() {
+            _ = thread.join();
+        }
+    }));
+    if stopped.is_err() {
+        warn!("The node panicked while stopping");
+    }
+}
+
+/// Free a string returned by the node.
+///
+/// # Safety
+/// `s` must come from [flydrop_query] or [flydrop_command] and not be freed already.
+#[no_mangle]
+pub unsafe extern "C" fn flydrop_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+unsafe fn string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok().map(String::from)
+}
+
+unsafe fn call<R, F, Fut>(node: *const FlydropNode, request: *const c_char, send: F) -> *mut c_char
+where
+    R: DeserializeOwned,
+    F: FnOnce(CoreController, R) -> Fut,
+    Fut: std::future::Future<Output = Result<CoreResponse, flydrop_core::err::CoreError>>,
+{
+    let reply = panic::catch_unwind(AssertUnwindSafe(|| {
+        match (node.as_ref(), string(request)) {
+            (None, _) => Reply::Error(String::from("the node is not running")),
+            (_, None) => Reply::Error(String::from("the request is not a valid string")),
+            (Some(node), Some(request)) => match serde_json::from_str(&request) {
+                Err(e) => Reply::Error(e.to_string()),
+                Ok(request) => match node
+                    .runtime
+                    .block_on(send(node.controller.clone(), request))
+                {
+                    Ok(response) => Reply::Result(response),
+                    Err(e) => Reply::Error(e.to_string()),
+                },
+            },
+        }
+    }))
+    .unwrap_or_else(|_| Reply::Error(String::from("the node panicked")));
+    let json = serde_json::to_string(&reply).expect("replies serialize");
+    CString::new(json).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_char, c_void, CStr, CString},
+        ptr,
+    };
+
+    use super::{flydrop_query, flydrop_start, flydrop_stop, flydrop_string_free};
+
+    extern "C" fn ignore(_event: *const c_char, _user_data: *mut c_void) {}
+
+    #[test]
+    fn calls_without_a_node_fail() {
+        let request = CString::new(r#"{ "type": "GetConf" }"#).unwrap();
+        unsafe {
+            let reply = flydrop_query(ptr::null(), request.as_ptr());
+            assert_eq!(
+                r#"{"error":"the node is not running"}"#,
+                CStr::from_ptr(reply).to_str().unwrap()
+            );
+            flydrop_string_free(reply);
+            flydrop_stop(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn node_starts_answers_and_stops() {
+        flydrop_core::mock_store();
+        let dir = std::env::temp_dir().join("flydrop-ffi-node");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = CString::new(dir.display().to_string()).unwrap();
+        let request = CString::new(r#"{ "type": "GetTaskCount" }"#).unwrap();
+        unsafe {
+            let node = flydrop_start(dir.as_ptr(), ignore, ptr::null_mut());
+            assert!(!node.is_null());
+            let reply = flydrop_query(node, request.as_ptr());
+            assert_eq!(
+                r#"{"result":{"type":"Tasks","data":0}}"#,
+                CStr::from_ptr(reply).to_str().unwrap()
+            );
+            flydrop_string_free(reply);
+            flydrop_stop(node);
+        }
+    }
+}
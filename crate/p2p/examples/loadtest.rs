@@ -0,0 +1,93 @@
+//! Load test a node against hundreds of synthetic peers on the local machine.
+//!
+//! cargo run -p p2p --example loadtest --features loadtest -- [peer count]
+
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use p2p::{
+    discovery::DISCOVERY_MULTICAST,
+    event::P2pEvent,
+    manager::{P2pConfig, P2pManager},
+    peer::{DeviceType, Identity, PeerId},
+    synthetic::SyntheticPeers,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    let count: usize = std::env::args()
+        .nth(1)
+        .map(|c| c.parse())
+        .transpose()?
+        .unwrap_or(200);
+    let multicast = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50693));
+    let secret = b"LoadTestingSecretLoadTestingSecret".to_vec();
+
+    let identity = Identity::from_seed([0x10; 32]);
+    let config = P2pConfig {
+        id: PeerId::from_cert(&identity.clone().into_rustls().0),
+        device: DeviceType::LinuxDevice,
+        name: String::from("Load tester"),
+        multicast,
+        multicast_v6: None,
+        p2p_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+        lan: Vec::new(),
+        identity: Some(identity),
+        limits: Default::default(),
+    };
+    let (manager, mut events) = P2pManager::new(config).await?;
+    let swarm = SyntheticPeers::spawn(count, &manager.get_metadata(), multicast, secret).await?;
+    for candidate in swarm.candidates() {
+        manager.add_known_peer(candidate);
+    }
+
+    // discovery
+    let start = Instant::now();
+    manager.request_presence().await;
+    let mut discovered = Vec::with_capacity(count);
+    while discovered.len() < count {
+        match timeout(Duration::from_secs(10), events.recv()).await {
+            Ok(Some(P2pEvent::PeerDiscovered(metadata))) => discovered.push(metadata.id),
+            Ok(Some(_)) => {}
+            Ok(None) | Err(_) => break,
+        }
+    }
+    println!(
+        "discovered {}/{} peers in {:?}",
+        discovered.len(),
+        count,
+        start.elapsed()
+    );
+
+    // handshakes & a small round trip on every connection
+    let start = Instant::now();
+    let mut connected = 0;
+    for id in &discovered {
+        let Ok(mut peer) = manager.connect_to_peer(id).await else {
+            continue;
+        };
+        let mut buffer = [0u8; 4];
+        peer.conn.write_all(b"PING").await?;
+        peer.conn.read_exact(&mut buffer).await?;
+        connected += 1;
+    }
+    println!(
+        "connected to {}/{} peers in {:?}",
+        connected,
+        discovered.len(),
+        start.elapsed()
+    );
+    swarm.shutdown().await;
+    Ok(())
+}
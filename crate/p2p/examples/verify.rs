@@ -0,0 +1,49 @@
+//! Check every datagram sent to the discovery multicast group against the protocol, or print the
+//! conformance vectors other implementations can test against.
+//!
+//! cargo run -p p2p --example verify -- [port | --vectors]
+
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use p2p::{
+    conformance::{
+        connection_vectors, control_vectors, discovery_vectors, rendezvous_vectors,
+        verify_discovery,
+    },
+    discovery::{multicast, DISCOVERY_MULTICAST},
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let arg = std::env::args().nth(1);
+    if arg.as_deref() == Some("--vectors") {
+        for vector in discovery_vectors()
+            .into_iter()
+            .chain(connection_vectors())
+            .chain(control_vectors())
+            .chain(rendezvous_vectors())
+        {
+            let frame: String = vector.frame.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{} {}", vector.name, frame);
+        }
+        return Ok(());
+    }
+
+    let port: u16 = arg.map(|p| p.parse()).transpose()?.unwrap_or(50692);
+    let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+    let group = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, port));
+    let (socket, _) = multicast(&local, &group)?;
+    println!("listening on {}", group);
+
+    let mut buf = [0u8; 65535];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        match verify_discovery(&buf[..len]) {
+            Ok(message) => println!("{} ok {}", from, message),
+            Err(reason) => println!("{} broken: {}", from, reason),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use bytes::BytesMut;
+use hex_literal::hex;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    err::ParseError,
+    event::DiscoveryEvent,
+    proto::{ConnectionCodec, Control, ControlCodec, DiscoveryCodec, RendezvousCodec},
+};
+
+/// A canonical frame of the wire protocol described in doc/Protocol.md, every implementation
+/// must encode the message to exactly these bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    /// the message the frame carries
+    pub name: &'static str,
+    pub frame: Vec<u8>,
+}
+
+impl Vector {
+    fn new(name: &'static str, frame: &[u8]) -> Self {
+        Self {
+            name,
+            frame: frame.to_vec(),
+        }
+    }
+}
+
+// the metadata carried by the vectors, "test phone" on 127.0.0.1:5001 with the id 0123...789
+const METADATA: [u8; 70] = hex!(
+    "0006"
+    "000a 746573742070686f6e65"
+    "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+    "000e 3132372e302e302e313a35303031"
+);
+
+// the id 0123...789
+const ID: [u8; 40] =
+    hex!("30313233343536373839303132333435363738393031323334353637383930313233343536373839");
+// a 32 byte tag or proof counting up from 0
+const TAG: [u8; 32] = hex!("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+
+/// the frames sent to the discovery multicast group
+pub fn discovery_vectors() -> Vec<Vector> {
+    vec![
+        Vector::new("PresenceRequest", &hex!("4040 0006 01 00")),
+        Vector::new(
+            "PresenceResponse",
+            &[&hex!("4040 006d 01 01")[..], &METADATA, &hex!("01"), &TAG].concat(),
+        ),
+    ]
+}
+
+/// the frames of the connection handshake
+pub fn connection_vectors() -> Vec<Vector> {
+    vec![
+        Vector::new(
+            "Request",
+            &[&hex!("4040 004e 02 00")[..], &ID, &TAG].concat(),
+        ),
+        Vector::new("Response", &[&hex!("4040 0026 02 01")[..], &TAG].concat()),
+        Vector::new("CompleteRequest", &hex!("4040 0007 02 02 01")),
+        Vector::new("CompleteResponse", &hex!("4040 0007 02 03 01")),
+        Vector::new("Failure", &hex!("4040 000a 02 04 000007d1")),
+        Vector::new(
+            "PairRequest",
+            &[
+                &hex!("4040 0074 02 05")[..],
+                &METADATA,
+                &hex!("0006 736563726574"),
+                &TAG,
+            ]
+            .concat(),
+        ),
+        Vector::new(
+            "PinPairRequest",
+            &[
+                &hex!("4040 0074 02 06")[..],
+                &METADATA,
+                &hex!("0006 736563726574"),
+                &TAG,
+            ]
+            .concat(),
+        ),
+        Vector::new("PairNonce", &[&hex!("4040 0026 02 07")[..], &TAG].concat()),
+        Vector::new("PinShare", &[&hex!("4040 0026 02 08")[..], &TAG].concat()),
+        Vector::new("PinConfirmation", &[&hex!("4040 0026 02 09")[..], &TAG].concat()),
+    ]
+}
+
+/// the frames of a connection once the handshake completed
+pub fn control_vectors() -> Vec<Vector> {
+    vec![
+        Vector::new("Data", &hex!("4040 000a 03 00 50494e47")),
+        Vector::new("Ping", &hex!("4040 0006 03 01")),
+        Vector::new("Pong", &hex!("4040 0006 03 02")),
+        Vector::new("Chunk", &hex!("4040 000a 03 03 00000004 50494e47")),
+    ]
+}
+
+/// the frames exchanged with a rendezvous server
+pub fn rendezvous_vectors() -> Vec<Vector> {
+    vec![
+        Vector::new(
+            "Register",
+            &[&hex!("4040 004c 06 00")[..], &METADATA].concat(),
+        ),
+        Vector::new("Lookup", &[&hex!("4040 002e 06 01")[..], &ID].concat()),
+        Vector::new("Found", &[&hex!("4040 004c 06 02")[..], &METADATA].concat()),
+        Vector::new("Missing", &hex!("4040 0006 06 03")),
+        Vector::new("Relay", &[&hex!("4040 002e 06 04")[..], &ID].concat()),
+        Vector::new("Incoming", &hex!("4040 000e 06 05 000000000000002a")),
+        Vector::new("Attach", &hex!("4040 000e 06 06 000000000000002a")),
+        Vector::new("Relayed", &hex!("4040 0006 06 07")),
+    ]
+}
+
+/// check a datagram received on the discovery multicast group, returns the message it carries
+/// or how it breaks the protocol
+pub fn verify_discovery(datagram: &[u8]) -> Result<&'static str, String> {
+    verify(DiscoveryCodec, datagram).map(|event| match event {
+        DiscoveryEvent::PresenceRequest => "PresenceRequest",
+        DiscoveryEvent::PresenceResponse(_) => "PresenceResponse",
+    })
+}
+
+/// check a single handshake frame, returns the message it carries or how it breaks the protocol
+pub fn verify_connection(frame: &[u8]) -> Result<&'static str, String> {
+    verify(ConnectionCodec, frame).map(|connection| connection.kind())
+}
+
+/// check a single frame sent after the handshake, returns the message it carries or how it
+/// breaks the protocol
+pub fn verify_control(frame: &[u8]) -> Result<&'static str, String> {
+    verify(ControlCodec, frame).map(|control| match control {
+        Control::Data(_) => "Data",
+        Control::Ping => "Ping",
+        Control::Pong => "Pong",
+        Control::Chunk(_) => "Chunk",
+    })
+}
+
+/// check a single frame exchanged with a rendezvous server, returns the message it carries or
+/// how it breaks the protocol
+pub fn verify_rendezvous(frame: &[u8]) -> Result<&'static str, String> {
+    verify(RendezvousCodec, frame).map(|rendezvous| rendezvous.kind())
+}
+
+/// decode exactly one frame and make sure encoding it again gives back the same bytes
+fn verify<C, T>(mut codec: C, frame: &[u8]) -> Result<T, String>
+where
+    C: Decoder<Item = T, Error = ParseError> + Encoder<T, Error = ParseError>,
+    T: Clone,
+{
+    let mut src = BytesMut::from(frame);
+    let item = match codec.decode(&mut src) {
+        Ok(Some(item)) => item,
+        Ok(None) => return Err(String::from("the frame is incomplete")),
+        Err(e) => return Err(format!("the frame can't be decoded: {}", e)),
+    };
+    if !src.is_empty() {
+        return Err(format!("extra bytes after the frame: {}", src.len()));
+    }
+    let mut encoded = BytesMut::new();
+    codec
+        .encode(item.clone(), &mut encoded)
+        .map_err(|e| format!("the message can't be encoded again: {}", e))?;
+    if encoded[..] != *frame {
+        return Err(String::from("the frame is not encoded canonically"));
+    }
+    Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        connection_vectors, control_vectors, discovery_vectors, rendezvous_vectors,
+        verify_connection, verify_control, verify_discovery, verify_rendezvous,
+    };
+
+    #[test]
+    fn every_vector_verifies() {
+        for vector in discovery_vectors() {
+            assert_eq!(Ok(vector.name), verify_discovery(&vector.frame));
+        }
+        for vector in connection_vectors() {
+            assert_eq!(Ok(vector.name), verify_connection(&vector.frame));
+        }
+        for vector in control_vectors() {
+            assert_eq!(Ok(vector.name), verify_control(&vector.frame));
+        }
+        for vector in rendezvous_vectors() {
+            assert_eq!(Ok(vector.name), verify_rendezvous(&vector.frame));
+        }
+    }
+
+    #[test]
+    fn broken_frames_are_explained() {
+        let mut frame = discovery_vectors()[0].frame.clone();
+        frame.push(0);
+        assert_eq!(
+            Err(String::from("extra bytes after the frame: 1")),
+            verify_discovery(&frame)
+        );
+        assert!(verify_discovery(&frame[..3]).is_err());
+        // a handshake frame is not a discovery frame
+        assert!(verify_discovery(&connection_vectors()[2].frame).is_err());
+    }
+}
@@ -1,13 +1,251 @@
-use futures::{SinkExt, StreamExt};
-use std::net::{Ipv4Addr, SocketAddr};
-use tokio::{net::UdpSocket, sync::mpsc};
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    task::AbortHandle,
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
 use tokio_util::udp::UdpFramed;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-use crate::{event::DiscoveryEvent, proto::DiscoveryCodec};
+use crate::{
+    event::DiscoveryEvent, pairing::PairingAuthenticator, peer::PeerMetadata, proto::DiscoveryCodec,
+};
 
 pub static DISCOVERY_MULTICAST: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 98);
 
+/// The link-local IPv6 group used for discovery, so peers on IPv6 only networks can be found
+pub static DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4298, 0x4298);
+
+/// The length of the tag a presence carries for each peer the announcing peer is paired with
+pub const PRESENCE_TAG_LEN: usize = 32;
+
+/// The most tags a presence carries, their 512 bytes leave room for the metadata in a datagram
+/// every link can carry without fragmenting
+pub const MAX_PRESENCE_TAGS: usize = 16;
+
+/// The metadata a peer announces, with a tag for each peer it is paired with made from their
+/// pairing secret and the current time step. Paired peers only trust a recent announcement
+/// carrying their tag, strangers can't check any and only get to pair with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub metadata: PeerMetadata,
+    pub tags: Vec<[u8; PRESENCE_TAG_LEN]>,
+}
+
+impl Presence {
+    /// sign `metadata` for the peers holding the first [MAX_PRESENCE_TAGS] of `auths`, it is
+    /// left unsigned while the clock is before the unix epoch
+    pub fn new<'a>(
+        metadata: PeerMetadata,
+        auths: impl IntoIterator<Item = &'a PairingAuthenticator>,
+    ) -> Self {
+        let tags = auths
+            .into_iter()
+            .take(MAX_PRESENCE_TAGS)
+            .filter_map(|auth| auth.sign_presence(&metadata))
+            .collect();
+        Self { metadata, tags }
+    }
+
+    /// whether the peer sharing `auth` announced this
+    pub fn is_signed_by(&self, auth: &PairingAuthenticator) -> bool {
+        self.tags
+            .iter()
+            .any(|tag| auth.verify_presence(&self.metadata, tag))
+    }
+}
+
+impl From<PeerMetadata> for Presence {
+    /// an announcement only strangers accept
+    fn from(metadata: PeerMetadata) -> Self {
+        Self {
+            metadata,
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// Identifies the discovery mechanism a peer was found through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiscoverySource {
+    /// UDP multicast on the local network
+    Multicast,
+
+    /// A discovery mechanism provided by the application
+    Custom(&'static str),
+}
+
+/// A mechanism for discovering peers. Many can be registered with the [crate::manager::P2pManager]
+/// at once, the peers they discover are merged together while remembering which source found them.
+pub trait Discovery: Send + Sync {
+    /// the source peers discovered by this mechanism are attributed to
+    fn source(&self) -> DiscoverySource;
+
+    /// announce the presence of the local peer
+    fn announce(&self, presence: Presence) -> BoxFuture<'_, ()>;
+
+    /// request the presence of any other peers
+    fn request(&self) -> BoxFuture<'_, ()>;
+
+    /// take the events received by this mechanism, this is only called once when registering
+    fn events(&mut self) -> Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>>;
+
+    /// take the notifications of this mechanism recovering from a silent failure, each carrying
+    /// the total number of recoveries. This is only called once when registering.
+    fn recoveries(&mut self) -> Option<mpsc::UnboundedReceiver<u64>> {
+        None
+    }
+
+    /// take the reasons frames received by this mechanism could not be read, so observers can
+    /// report them. This is only called once when registering.
+    fn malformed(&mut self) -> Option<mpsc::UnboundedReceiver<String>> {
+        None
+    }
+}
+
+/// Discovery using UDP multicast on the local network
+pub struct MulticastDiscovery {
+    sender: mpsc::Sender<DiscoveryEvent>,
+    events: Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>>,
+    recoveries: Option<mpsc::UnboundedReceiver<u64>>,
+    malformed: Option<mpsc::UnboundedReceiver<String>>,
+    task: AbortHandle,
+}
+
+impl MulticastDiscovery {
+    /// join the multicast group on the given address & start sending and receiving events
+    pub fn new(addr: &SocketAddr, multi_addr: &SocketAddr) -> Result<Self, std::io::Error> {
+        let (socket, multi_addr) = multicast(addr, multi_addr)?;
+        let (recoveries_tx, recoveries) = mpsc::unbounded_channel();
+        let (malformed_tx, malformed) = mpsc::unbounded_channel();
+        let (sender, events, task) =
+            start_monitored(socket, multi_addr, recoveries_tx, malformed_tx);
+        Ok(Self {
+            sender,
+            events: Some(events),
+            recoveries: Some(recoveries),
+            malformed: Some(malformed),
+            task,
+        })
+    }
+
+    async fn send(&self, event: DiscoveryEvent) {
+        if let Err(e) = self.sender.send(event).await {
+            error!("multicast discovery is unable to send: {}", e);
+        }
+    }
+}
+
+// the socket is closed and the group left right away, not once the loop notices
+impl Drop for MulticastDiscovery {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Discovery for MulticastDiscovery {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Multicast
+    }
+
+    fn announce(&self, presence: Presence) -> BoxFuture<'_, ()> {
+        self.send(DiscoveryEvent::PresenceResponse(presence))
+            .boxed()
+    }
+
+    fn request(&self) -> BoxFuture<'_, ()> {
+        self.send(DiscoveryEvent::PresenceRequest).boxed()
+    }
+
+    fn events(&mut self) -> Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>> {
+        self.events.take()
+    }
+
+    fn recoveries(&mut self) -> Option<mpsc::UnboundedReceiver<u64>> {
+        self.recoveries.take()
+    }
+
+    fn malformed(&mut self) -> Option<mpsc::UnboundedReceiver<String>> {
+        self.malformed.take()
+    }
+}
+
+/// The max number of discovery frames that can be sent back to back
+const DISCOVERY_BURST: u32 = 16;
+
+/// The time it takes to earn back a single discovery frame once the burst is spent
+const DISCOVERY_REFILL: Duration = Duration::from_millis(100);
+
+/// How often the discovery socket verifies it is still joined to the multicast group
+const MEMBERSHIP_CHECK: Duration = Duration::from_secs(30);
+
+/// A token bucket used to shape outgoing discovery traffic, no matter how fast events are queued
+pub(crate) struct TokenBucket {
+    capacity: u32,
+    tokens: u32,
+    refill: Duration,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(capacity: u32, refill: Duration) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill,
+            last: Instant::now(),
+        }
+    }
+
+    /// credit the bucket with the tokens earned since the last refill
+    fn refill(&mut self, now: Instant) {
+        let earned = now.saturating_duration_since(self.last).as_nanos() / self.refill.as_nanos();
+        if earned == 0 {
+            return;
+        }
+        let earned = u32::try_from(earned).unwrap_or(u32::MAX);
+        self.tokens = self.tokens.saturating_add(earned).min(self.capacity);
+        if self.tokens == self.capacity {
+            self.last = now;
+        } else {
+            self.last += self.refill * earned;
+        }
+    }
+
+    /// check if a frame can be sent right now
+    pub(crate) fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens > 0
+    }
+
+    /// check if no token was spent that has not been earned back
+    pub(crate) fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens == self.capacity
+    }
+
+    /// spend a token, returns false if the bucket is empty
+    pub(crate) fn take(&mut self, now: Instant) -> bool {
+        if !self.has_token(now) {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// the instant the next token will be available
+    pub(crate) fn next_token(&self) -> Instant {
+        self.last + self.refill
+    }
+}
+
 pub fn multicast(
     addr: &SocketAddr,
     multi_addr: &SocketAddr,
@@ -15,12 +253,42 @@ pub fn multicast(
     use socket2::{Domain, Protocol, Socket, Type};
 
     assert!(multi_addr.ip().is_multicast(), "Must be multcast address");
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
     socket.set_reuse_address(true)?;
-    socket.bind(&socket2::SockAddr::from(*addr))?;
-    socket.set_multicast_loop_v4(true)?;
-    if let (SocketAddr::V4(a), SocketAddr::V4(m)) = (addr, multi_addr) {
-        socket.join_multicast_v4(m.ip(), a.ip())?
+    match (addr, multi_addr) {
+        (SocketAddr::V4(a), SocketAddr::V4(m)) => {
+            // a socket bound to an interface address only receives the group's traffic on
+            // windows, elsewhere it binds the group itself
+            let bind = if a.ip().is_loopback() || cfg!(windows) {
+                *addr
+            } else {
+                SocketAddr::V4(SocketAddrV4::new(*m.ip(), a.port()))
+            };
+            socket.bind(&socket2::SockAddr::from(bind))?;
+            // linux delivers the group's traffic from every interface any socket joined it on
+            // to a socket bound to the group, keep it to the interface this one joined
+            #[cfg(target_os = "linux")]
+            socket.set_multicast_all_v4(false)?;
+            socket.set_multicast_loop_v4(true)?;
+            socket.set_multicast_if_v4(a.ip())?;
+            socket.join_multicast_v4(m.ip(), a.ip())?
+        }
+        (SocketAddr::V6(a), SocketAddr::V6(m)) => {
+            // keep ipv4 traffic on its own socket
+            socket.set_only_v6(true)?;
+            socket.bind(&socket2::SockAddr::from(*addr))?;
+            #[cfg(target_os = "linux")]
+            socket.set_multicast_all_v6(false)?;
+            socket.set_multicast_loop_v6(true)?;
+            socket.set_multicast_if_v6(a.scope_id())?;
+            socket.join_multicast_v6(m.ip(), a.scope_id())?
+        }
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the multicast group and address must be the same ip version",
+            ))
+        }
     }
     socket.set_nonblocking(true)?;
     Ok((UdpSocket::from_std(socket.into())?, *multi_addr))
@@ -32,19 +300,93 @@ pub fn start(
 ) -> (
     mpsc::Sender<DiscoveryEvent>,
     mpsc::Receiver<(DiscoveryEvent, SocketAddr)>,
+) {
+    let (recoveries, _) = mpsc::unbounded_channel();
+    let (malformed, _) = mpsc::unbounded_channel();
+    let (sender, events, _) = start_monitored(sock, addr, recoveries, malformed);
+    (sender, events)
+}
+
+/// check the socket is still a member of the multicast group, rejoining if the os dropped it.
+/// Returns true if the membership had to be restored.
+fn rejoin_multicast(socket: &UdpSocket, local_addr: &SocketAddr, addr: &SocketAddr) -> bool {
+    if !addr.ip().is_multicast() {
+        return false;
+    }
+    let joined = match (local_addr, addr) {
+        (SocketAddr::V4(local), SocketAddr::V4(group)) => {
+            // the socket may be bound to the group, the interface is the one it sends on
+            let interface = socket2::SockRef::from(socket)
+                .multicast_if_v4()
+                .unwrap_or(*local.ip());
+            socket.join_multicast_v4(*group.ip(), interface)
+        }
+        (SocketAddr::V6(local), SocketAddr::V6(group)) => {
+            socket.join_multicast_v6(group.ip(), local.scope_id())
+        }
+        _ => return false,
+    };
+    match joined {
+        Ok(()) => true,
+        Err(e) if already_member(&e) => false,
+        Err(e) => {
+            error!("Unable to verify multicast membership: {:?}", e);
+            false
+        }
+    }
+}
+
+/// whether joining a group was refused because the socket already is a member of it. Windows
+/// refuses with WSAEINVAL, other platforms with EADDRINUSE.
+fn already_member(error: &io::Error) -> bool {
+    const WSAEINVAL: i32 = 10022;
+    if cfg!(windows) {
+        error.raw_os_error() == Some(WSAEINVAL) || error.kind() == ErrorKind::InvalidInput
+    } else {
+        error.kind() == ErrorKind::AddrInUse
+    }
+}
+
+/// start discovery, reporting the total count of multicast memberships restored on `recoveries`
+/// and why frames could not be read on `malformed`. The task stops once the sender is dropped
+/// or it is aborted.
+pub(crate) fn start_monitored(
+    sock: UdpSocket,
+    addr: SocketAddr,
+    recoveries: mpsc::UnboundedSender<u64>,
+    malformed: mpsc::UnboundedSender<String>,
+) -> (
+    mpsc::Sender<DiscoveryEvent>,
+    mpsc::Receiver<(DiscoveryEvent, SocketAddr)>,
+    AbortHandle,
 ) {
     let (app_tx, mut app_rx) = mpsc::channel(1024);
     let (transport_tx, transport_rx) = mpsc::channel::<(DiscoveryEvent, SocketAddr)>(1024);
-    let discovery_socket = sock;
+    let discovery_socket = Arc::new(sock);
 
-    tokio::spawn(async move {
+    let task = tokio::spawn(async move {
         let local_addr = discovery_socket.local_addr().unwrap();
-        let (mut writer, mut reader) = UdpFramed::new(discovery_socket, DiscoveryCodec).split();
+        // a socket bound to the group sends from the interface it joined on
+        let own_addr = match socket2::SockRef::from(&*discovery_socket).multicast_if_v4() {
+            Ok(ip) if local_addr.is_ipv4() && !ip.is_unspecified() => {
+                SocketAddr::new(ip.into(), local_addr.port())
+            }
+            _ => local_addr,
+        };
+        let (mut writer, mut reader) =
+            UdpFramed::new(discovery_socket.clone(), DiscoveryCodec).split();
         let mut just_send_request = false;
+        let mut shaper = TokenBucket::new(DISCOVERY_BURST, DISCOVERY_REFILL);
+        let mut membership = interval(MEMBERSHIP_CHECK);
+        membership.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut rejoined = 0;
         loop {
+            let can_send = shaper.has_token(Instant::now());
             tokio::select! {
-                broadcast = app_rx.recv() => {
+                // only pull events off the channel when the shaper allows it
+                broadcast = app_rx.recv(), if can_send => {
                     if let Some(event) = broadcast {
+                        shaper.take(Instant::now());
                         match event {
                             DiscoveryEvent::PresenceRequest => {
                                 debug!("Sending PresenceRequest");
@@ -67,6 +409,16 @@ pub fn start(
                         break;
                     }
                 }
+                _ = sleep_until(shaper.next_token()), if !can_send => {
+                    debug!("Discovery rate limited, waiting for the next token");
+                }
+                _ = membership.tick() => {
+                    if rejoin_multicast(&discovery_socket, &local_addr, &addr) {
+                        rejoined += 1;
+                        warn!("Multicast membership was lost and has been restored ({} times)", rejoined);
+                        _ = recoveries.send(rejoined);
+                    }
+                }
                 network = reader.next() => {
                     if let Some(result) = network {
                         match result {
@@ -75,7 +427,7 @@ pub fn start(
                                 // this is hacky to avoid presence requests from self
                                 if just_send_request {
                                     if let (DiscoveryEvent::PresenceRequest, addr) = frame {
-                                        if local_addr == addr {
+                                        if own_addr == addr {
                                             just_send_request = false;
                                             continue;
                                         }
@@ -88,7 +440,8 @@ pub fn start(
                                 }
                             },
                             Err(error) => {
-                                error!("error reading from Discovery: {:?}", error)
+                                error!("error reading from Discovery: {:?}", error);
+                                _ = malformed.send(error.to_string());
                             }
                         }
                     }
@@ -97,5 +450,161 @@ pub fn start(
         }
     });
 
-    (app_tx, transport_rx)
+    (app_tx, transport_rx, task.abort_handle())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use std::io::{self, ErrorKind};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+    use tokio::time::{timeout, Instant};
+
+    use super::{
+        already_member, multicast, rejoin_multicast, start, Presence, TokenBucket,
+        DISCOVERY_MULTICAST, DISCOVERY_MULTICAST_V6,
+    };
+    use crate::{
+        event::DiscoveryEvent,
+        pairing::PairingAuthenticator,
+        peer::{DeviceType, PeerId, PeerMetadata},
+    };
+
+    #[test]
+    fn presence_is_trusted_by_paired_peers_only() {
+        let metadata = PeerMetadata {
+            name: "Tester's phone".into(),
+            typ: DeviceType::AppleiPhone,
+            id: PeerId::from_string("a".repeat(40)).unwrap(),
+            addrs: vec!["192.168.1.20:4000".parse().unwrap()],
+        };
+        let paired = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec()).unwrap();
+        let other = PairingAuthenticator::new(b"ASDFGHJKLZASDFGHJKLZ".to_vec()).unwrap();
+        let presence = Presence::new(metadata.clone(), [&other, &paired]);
+        assert!(presence.is_signed_by(&paired));
+        assert!(!Presence::from(metadata).is_signed_by(&paired));
+
+        // another address can't be announced with the same tags
+        let mut forged = presence.clone();
+        forged.metadata.addrs = vec!["192.168.1.66:4000".parse().unwrap()];
+        assert!(!forged.is_signed_by(&paired));
+        assert!(!Presence::new(forged.metadata, [&other]).is_signed_by(&paired));
+    }
+
+    #[test]
+    fn stale_presence_is_not_trusted() {
+        let metadata = PeerMetadata {
+            name: "Tester's phone".into(),
+            typ: DeviceType::AppleiPhone,
+            id: PeerId::from_string("a".repeat(40)).unwrap(),
+            addrs: vec!["192.168.1.20:4000".parse().unwrap()],
+        };
+        let paired = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec()).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let signed_at = |time| Presence {
+            metadata: metadata.clone(),
+            tags: vec![paired.sign_presence_at(&metadata, time)],
+        };
+        assert!(signed_at(now - 30).is_signed_by(&paired));
+        assert!(!signed_at(now - 600).is_signed_by(&paired));
+    }
+
+    #[test]
+    fn token_bucket_limits_bursts() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_millis(100));
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+        let next = bucket.next_token();
+        assert!(next > start);
+        assert!(bucket.take(next));
+    }
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_millis(100));
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.has_token(start + Duration::from_millis(99)));
+        assert!(bucket.take(start + Duration::from_millis(150)));
+        assert!(!bucket.take(start + Duration::from_millis(150)));
+
+        // the bucket never holds more than its capacity
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.take(later));
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+    }
+
+    #[tokio::test]
+    async fn rejoin_is_noop_while_joined() {
+        let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50694));
+        let group = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50694));
+        let (socket, group) = multicast(&local, &group).unwrap();
+        let local = socket.local_addr().unwrap();
+        assert!(!rejoin_multicast(&socket, &local, &group));
+
+        // a dropped membership is restored
+        socket
+            .leave_multicast_v4(DISCOVERY_MULTICAST, Ipv4Addr::LOCALHOST)
+            .unwrap();
+        assert!(rejoin_multicast(&socket, &local, &group));
+        assert!(!rejoin_multicast(&socket, &local, &group));
+    }
+
+    #[test]
+    fn duplicate_joins_are_recognised() {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .join_multicast_v4(&DISCOVERY_MULTICAST, &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        let error = socket
+            .join_multicast_v4(&DISCOVERY_MULTICAST, &Ipv4Addr::LOCALHOST)
+            .unwrap_err();
+        assert!(already_member(&error));
+        assert!(!already_member(&io::Error::from(ErrorKind::PermissionDenied)));
+    }
+
+    #[tokio::test]
+    async fn multicast_sends_on_the_joined_interface() {
+        let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50697));
+        let group = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50697));
+        let (socket, _) = multicast(&local, &group).unwrap();
+        let interface = socket2::SockRef::from(&socket).multicast_if_v4().unwrap();
+        assert_eq!(Ipv4Addr::LOCALHOST, interface);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn multicast_only_receives_its_own_memberships() {
+        let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50698));
+        let group = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50698));
+        let (socket, _) = multicast(&local, &group).unwrap();
+        assert!(!socket2::SockRef::from(&socket).multicast_all_v4().unwrap());
+    }
+
+    #[test]
+    fn multicast_requires_matching_ip_versions() {
+        let local = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 50695, 0, 0));
+        let group = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50695));
+        assert!(multicast(&local, &group).is_err());
+    }
+
+    #[tokio::test]
+    async fn discovery_over_ipv6() {
+        let local = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 50696, 0, 0));
+        let group = SocketAddr::V6(SocketAddrV6::new(DISCOVERY_MULTICAST_V6, 50696, 0, 0));
+        let (sender, _) = multicast(&local, &group).unwrap();
+        let (receiver, _) = multicast(&local, &group).unwrap();
+        let (sender, _sender_rx) = start(sender, group);
+        let (_receiver_tx, mut receiver) = start(receiver, group);
+
+        sender.send(DiscoveryEvent::PresenceRequest).await.unwrap();
+        let event = timeout(Duration::from_secs(1), receiver.recv()).await.unwrap();
+        assert!(matches!(event, Some((DiscoveryEvent::PresenceRequest, _))));
+    }
 }
@@ -10,6 +10,10 @@ pub enum InitError {
     /// An unspecified network error occured
     #[error("A network related error occured")]
     Net(#[from] std::io::Error),
+
+    /// The identity could not be used for TLS
+    #[error("The TLS configuration is invalid")]
+    Tls(#[from] rustls::Error),
 }
 
 /// An error that can occur during the handshake process
@@ -50,6 +54,18 @@ pub enum HandshakeError {
     /// The remote peer had no connectable addresses
     #[error("No connectable addresses")]
     Addr,
+
+    /// The TLS handshake failed or the remote certificate was not the expected peer's
+    #[error("The TLS handshake failed")]
+    Tls(std::io::Error),
+
+    /// The local or remote peer is connected to as many peers as it allows
+    #[error("Too many peers are connected")]
+    Full,
+
+    /// Pairing was attempted without TLS, the secret would be sent in the clear
+    #[error("Pairing requires a secured connection")]
+    Insecure,
 }
 
 impl From<ring::error::Unspecified> for HandshakeError {
@@ -58,6 +74,30 @@ impl From<ring::error::Unspecified> for HandshakeError {
     }
 }
 
+/// Errors while asking the gateway to forward a port
+#[derive(Debug, Error)]
+pub enum PortMapError {
+    /// The NAT-PMP gateway never answered, it may not support it
+    #[error("The gateway did not answer")]
+    Timeout,
+
+    /// The gateway answered with a result code other than success
+    #[error("The gateway refused with the result code {0}")]
+    Refused(u16),
+
+    /// The gateway answered with something other than a NAT-PMP response
+    #[error("The gateway sent a malformed response")]
+    Malformed,
+
+    /// An unspecified network error occured
+    #[error("A network related error occured")]
+    Net(#[from] std::io::Error),
+
+    /// No UPnP gateway was found or it failed the request
+    #[error("The UPnP gateway failed: {0}")]
+    Upnp(String),
+}
+
 /// Represents an error that can occur when creating a [PeerId] from a string.
 #[derive(Error, Debug)]
 pub enum IdError {
@@ -96,6 +136,24 @@ pub enum ParseError {
     /// The peer id is not valid
     #[error("The peer id {0} is not valid")]
     Id(#[from] IdError),
+
+    /// The frame is larger than the protocol allows
+    #[error("The frame of {0} bytes is too long")]
+    TooLong(usize),
+
+    /// The frame ended before all of its fields
+    #[error("The frame is shorter than its fields")]
+    Truncated,
+
+    /// A text field is not valid UTF-8
+    #[error("The text is not valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+impl From<bytes::TryGetError> for ParseError {
+    fn from(_: bytes::TryGetError) -> Self {
+        ParseError::Truncated
+    }
 }
 
 impl<T> From<num_enum::TryFromPrimitiveError<T>> for ParseError
@@ -119,6 +177,10 @@ pub enum PairingError {
     #[error("Error generating QR code: {0}")]
     QrCode(String),
 
+    /// The pairing url does not fit in a qr code
+    #[error("Pairing url is too long for a QR code")]
+    QrTooLong(#[from] qrcodegen::DataTooLong),
+
     /// An invalid secret was used
     #[error("Error parsing secret")]
     Secret(String),
@@ -1,4 +1,8 @@
-use crate::peer;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{discovery::DiscoverySource, peer};
 
 /// P2p Events that get sent to the application
 #[derive(Debug)]
@@ -11,28 +15,85 @@ pub enum P2pEvent {
 
     /// A peer disconnected
     PeerDisconnected(peer::PeerId),
+
+    /// A discovered peer stopped answering discovery and is no longer available
+    PeerLost(peer::PeerId),
+
+    /// A discovery mechanism recovered from silently failing, `count` is the total number of recoveries
+    DiscoveryRecovered { source: DiscoverySource, count: u64 },
+
+    /// An unpaired peer asked to pair, the user accepts only if the other device shows the same code
+    PairRequest { metadata: peer::PeerMetadata, code: String },
+
+    /// The code to show while a pairing request sent to a peer waits for an answer
+    PairCode { id: peer::PeerId, code: String },
+
+    /// The pin from [crate::manager::P2pManager::start_pin_pairing] was used up by a peer which
+    /// tried to pair with it, whether or not the pairing went through
+    PinUsed(peer::PeerId),
+
+    /// A peer was paired, the secret has to be stored to reconnect later
+    Paired { metadata: peer::PeerMetadata, secret: String },
+
+    /// The pairing secret of a paired peer changed, it has to be stored in place of the old one.
+    /// `previous` is the secret to also accept until the peer shows it has the new one.
+    KeyRotated {
+        id: peer::PeerId,
+        secret: String,
+        previous: Option<String>,
+    },
+
+    /// A paired peer failed to connect because its clock and this node's are further apart than
+    /// [crate::pairing::CLOCK_SKEW_TOLERANCE], `skew` is the seconds the peer is ahead
+    ClockSkew { id: peer::PeerId, skew: i64 },
+
+    /// Another node announced this node's id, usually because the config and identity were
+    /// copied to another device. `addrs` are where it announced itself from.
+    IdentityConflict { addrs: Vec<SocketAddr> },
+
+    /// The gateway started forwarding a port to the listener and `external` is advertised, or
+    /// stopped and it is None
+    PortMapped { external: Option<SocketAddr> },
+
+    /// Something was seen on discovery while observing, `from` is unknown for malformed frames
+    Observed {
+        source: DiscoverySource,
+        from: Option<SocketAddr>,
+        observation: Observation,
+    },
+}
+
+/// A discovery frame seen by an observing node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Observation {
+    /// A peer requested presence
+    PresenceRequest,
+
+    /// A peer announced itself, whether it is known or not
+    PresenceResponse(peer::PeerMetadata),
+
+    /// A frame could not be read
+    Malformed(String),
 }
 
 /// Events being sent and recieved to the discovery mechanism
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DiscoveryEvent {
     /// Request for any presence information
     PresenceRequest,
 
     /// Response to any presence request
-    PresenceResponse(peer::PeerMetadata),
+    PresenceResponse(crate::discovery::Presence),
 }
 
 impl crate::proto::Frame for DiscoveryEvent {
-    fn len(&self) -> u16 {
+    fn len(&self) -> usize {
         match self {
             DiscoveryEvent::PresenceRequest => 1,
-            DiscoveryEvent::PresenceResponse(meta) => {
-                1 + 2
-                    + 2
-                    + u16::try_from(meta.name.len()).unwrap()
-                    + 40
-                    + 2
-                    + u16::try_from(meta.addr.to_string().len()).unwrap()
+            DiscoveryEvent::PresenceResponse(presence) => {
+                let tags = presence.tags.len() * crate::discovery::PRESENCE_TAG_LEN;
+                1 + crate::proto::metadata_len(&presence.metadata) + 1 + tags
             }
         }
     }
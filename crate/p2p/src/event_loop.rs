@@ -1,43 +1,56 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
-    net::TcpListener,
     sync::mpsc::{Receiver, UnboundedReceiver},
+    time::interval,
 };
 use tracing::debug;
 
 use crate::{
+    discovery::DiscoverySource,
     event::{DiscoveryEvent, InternalEvent},
     manager::P2pManager,
+    transport::Listener,
 };
 
+/// how often discovered peers are checked for having been lost
+const PEER_EXPIRY_TICK: Duration = Duration::from_secs(1);
+
 pub(crate) async fn p2p_event_loop(
     manager: Arc<P2pManager>,
-    mut discovery: Receiver<(DiscoveryEvent, SocketAddr)>,
+    mut discovery: Receiver<(DiscoverySource, DiscoveryEvent, SocketAddr)>,
     mut internal_channel: UnboundedReceiver<InternalEvent>,
-    listener: TcpListener,
+    mut listener: Box<dyn Listener>,
 ) {
+    let mut expiry = interval(PEER_EXPIRY_TICK);
     loop {
         tokio::select! {
+            _ = expiry.tick() => {
+                manager.expire_peers();
+                manager.prune_inbound();
+            }
             discovery_event = discovery.recv() => {
                 let Some(event) = discovery_event else {
                     debug!("Discovery stopped sending main event loop messages");
                     break
                 };
+                manager.observe(event.0, &event.1, event.2);
                 match event {
-                    (DiscoveryEvent::PresenceResponse(peer), _) => {
-                        if manager.id == peer.id {
-                            // the node received its own presence response
+                    (source, DiscoveryEvent::PresenceResponse(presence), _) => {
+                        if manager.id == presence.metadata.id {
+                            // the node received its own presence response, or one from a node
+                            // sharing its identity
+                            manager.handle_own_presence(presence.metadata);
                             continue;
                         }
-                        debug!("Peer discovered at {:?}", peer.addr);
-                        manager.handle_peer_discovered(peer);
+                        debug!("Peer discovered at {:?} by {:?}", presence.metadata.addrs, source);
+                        manager.handle_peer_discovered(presence, source);
                         // if let Ok(id) = crate::PeerId::from_string(peer.id.clone()) {
                         //     manager.handle_peer_discovered(id, peer, addr);
                         // }
                     },
-                    (DiscoveryEvent::PresenceRequest, addr) => {
-                        debug!("Peer requested presence at {:?}", addr);
-                        manager.handle_presence_request().await;
+                    (source, DiscoveryEvent::PresenceRequest, addr) => {
+                        debug!("Peer requested presence at {:?} by {:?}", addr, source);
+                        manager.handle_presence_request(source).await;
                     }
                 }
             },
@@ -53,10 +66,23 @@ pub(crate) async fn p2p_event_loop(
                    continue;
                 };
                 debug!("Peer attempting to connect at {:?}", &addr);
-                let manager = manager.clone();
-                tokio::spawn(async move {
-                    if let Ok(peer) = crate::net::accept(&manager, stream).await {
-                        manager.handle_new_connection(peer);
+                // spammers and ips which keep failing to authenticate are dropped unanswered
+                if !manager.admit_inbound(&addr) {
+                    debug!("Dropping the connection from {:?}", &addr);
+                    continue;
+                }
+                // a slow handshake only holds up its own task, up to the handshake limit
+                let Some(slot) = manager.handshake_slot() else {
+                    debug!("Too many handshakes in progress, dropping {:?}", &addr);
+                    continue;
+                };
+                let handshake = manager.clone();
+                manager.spawn(async move {
+                    let _slot = slot;
+                    let accepted = crate::net::accept(&handshake, stream, false).await;
+                    handshake.inbound_handshake_done(&addr, &accepted);
+                    if let Ok(Some(peer)) = accepted {
+                        handshake.handle_new_connection(peer, addr);
                     }
                 });
             }
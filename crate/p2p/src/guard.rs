@@ -0,0 +1,189 @@
+use std::{collections::HashMap, fmt, hash::Hash, net::IpAddr, time::Duration};
+
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::discovery::TokenBucket;
+
+/// The connection attempts a single ip can make back to back
+const ATTEMPT_BURST: u32 = 10;
+
+/// The time it takes an ip to earn back a single connection attempt once the burst is spent
+const ATTEMPT_REFILL: Duration = Duration::from_secs(1);
+
+/// The failed authentications in a row after which an ip is banned
+pub const BAN_AFTER_FAILURES: u32 = 5;
+
+/// How long an ip which kept failing to authenticate is turned away
+pub const BAN_DURATION: Duration = Duration::from_secs(600);
+
+/// Counts of the inbound connection attempts turned away, for diagnostics
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InboundStats {
+    /// attempts dropped for coming too fast
+    pub rate_limited: u64,
+    /// attempts dropped because the ip is banned
+    pub banned_attempts: u64,
+    /// handshakes which failed to authenticate
+    pub auth_failures: u64,
+    /// the ips banned right now
+    pub banned: Vec<IpAddr>,
+}
+
+struct Source {
+    attempts: TokenBucket,
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Source {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Rate limits inbound connection attempts per ip and bans ips which keep failing to
+/// authenticate, before any handshake work is done for them. Relayed connections all come from
+/// the server's ip and are guarded by the peer id in their certificate instead.
+pub(crate) struct InboundGuard<K = IpAddr> {
+    sources: HashMap<K, Source>,
+    rate_limited: u64,
+    banned_attempts: u64,
+    auth_failures: u64,
+}
+
+impl<K> Default for InboundGuard<K> {
+    fn default() -> Self {
+        Self {
+            sources: HashMap::new(),
+            rate_limited: 0,
+            banned_attempts: 0,
+            auth_failures: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + fmt::Display> InboundGuard<K> {
+    /// whether `from` may start a handshake
+    pub(crate) fn admit(&mut self, from: K, now: Instant) -> bool {
+        let source = self.source(from);
+        if source.is_banned(now) {
+            self.banned_attempts += 1;
+            return false;
+        }
+        if !source.attempts.take(now) {
+            self.rate_limited += 1;
+            return false;
+        }
+        true
+    }
+
+    /// `from` failed to authenticate
+    pub(crate) fn auth_failed(&mut self, from: K, now: Instant) {
+        self.auth_failures += 1;
+        let source = self.source(from.clone());
+        source.failures += 1;
+        if source.failures >= BAN_AFTER_FAILURES {
+            warn!("banning {} after {} failed authentications", from, source.failures);
+            source.failures = 0;
+            source.banned_until = Some(now + BAN_DURATION);
+        }
+    }
+
+    /// `from` authenticated, its earlier failures are forgiven
+    pub(crate) fn auth_succeeded(&mut self, from: &K) {
+        if let Some(source) = self.sources.get_mut(from) {
+            source.failures = 0;
+        }
+    }
+
+    /// forget the sources which are not banned, failing or limited
+    pub(crate) fn prune(&mut self, now: Instant) {
+        self.sources.retain(|_, source| {
+            source.is_banned(now) || source.failures > 0 || !source.attempts.is_full(now)
+        });
+    }
+
+    fn source(&mut self, from: K) -> &mut Source {
+        self.sources.entry(from).or_insert_with(|| Source {
+            attempts: TokenBucket::new(ATTEMPT_BURST, ATTEMPT_REFILL),
+            failures: 0,
+            banned_until: None,
+        })
+    }
+}
+
+impl InboundGuard {
+    pub(crate) fn stats(&self, now: Instant) -> InboundStats {
+        let mut banned: Vec<IpAddr> = self
+            .sources
+            .iter()
+            .filter(|(_, source)| source.is_banned(now))
+            .map(|(ip, _)| *ip)
+            .collect();
+        banned.sort();
+        InboundStats {
+            rate_limited: self.rate_limited,
+            banned_attempts: self.banned_attempts,
+            auth_failures: self.auth_failures,
+            banned,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::time::Instant;
+
+    use super::{InboundGuard, ATTEMPT_BURST, ATTEMPT_REFILL, BAN_AFTER_FAILURES, BAN_DURATION};
+
+    #[test]
+    fn attempts_are_rate_limited_per_ip() {
+        let mut guard = InboundGuard::default();
+        let start = Instant::now();
+        let spammer = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 66));
+        let neighbour = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        for _ in 0..ATTEMPT_BURST {
+            assert!(guard.admit(spammer, start));
+        }
+        assert!(!guard.admit(spammer, start));
+        assert!(guard.admit(neighbour, start));
+        assert!(guard.admit(spammer, start + ATTEMPT_REFILL * 2));
+        assert_eq!(1, guard.stats(start).rate_limited);
+
+        // ips which settle down are forgotten
+        guard.prune(start + ATTEMPT_REFILL * (ATTEMPT_BURST + 2));
+        assert!(guard.sources.is_empty());
+    }
+
+    #[test]
+    fn failing_ips_are_banned_for_a_while() {
+        let mut guard = InboundGuard::default();
+        let start = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 66));
+
+        // a success in between forgives the earlier failures
+        for _ in 0..BAN_AFTER_FAILURES - 1 {
+            guard.auth_failed(ip, start);
+        }
+        guard.auth_succeeded(&ip);
+        guard.auth_failed(ip, start);
+        assert!(guard.admit(ip, start));
+
+        for _ in 0..BAN_AFTER_FAILURES - 1 {
+            guard.auth_failed(ip, start);
+        }
+        assert!(!guard.admit(ip, start));
+        let stats = guard.stats(start);
+        assert_eq!(vec![ip], stats.banned);
+        assert_eq!(1, stats.banned_attempts);
+        assert_eq!(u64::from(BAN_AFTER_FAILURES) * 2 - 1, stats.auth_failures);
+
+        let later = start + BAN_DURATION;
+        assert!(guard.admit(ip, later));
+        assert!(guard.stats(later).banned.is_empty());
+    }
+}
@@ -1,10 +1,21 @@
+pub mod conformance;
 pub mod discovery;
 pub mod err;
 pub mod event;
 mod event_loop;
+pub mod guard;
 mod hmac;
 pub mod manager;
 mod net;
 pub mod pairing;
+pub mod path;
 pub mod peer;
+pub mod portmap;
 mod proto;
+pub mod relay;
+pub mod rng;
+#[cfg(feature = "loadtest")]
+pub mod synthetic;
+mod tls;
+pub mod trace;
+pub mod transport;
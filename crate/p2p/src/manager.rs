@@ -1,32 +1,105 @@
 use std::{
-    collections::HashSet,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use dashmap::{DashMap, DashSet};
+use futures::StreamExt;
+use ring::rand::SystemRandom;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore},
+    task::{AbortHandle, JoinHandle},
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{
-    discovery, err,
+    discovery::{Discovery, DiscoverySource, MulticastDiscovery, Presence, MAX_PRESENCE_TAGS},
+    err,
     event::*,
     event_loop,
-    peer::{DeviceType, Peer, PeerCandidate, PeerId, PeerMetadata},
+    guard::{InboundGuard, InboundStats},
+    pairing::{PairingAuthenticator, PinInvitation},
+    path::LatencyHistory,
+    portmap::{self, MAPPING_LIFETIME},
+    proto::Rendezvous,
+    relay,
+    rng::Rng,
+    peer::{
+        ConnectionInfo, ConnectionType, DeviceType, Identity, Peer, PeerCandidate, PeerDelta,
+        PeerId, PeerLog, PeerMetadata,
+    },
+    tls::Tls,
+    trace::{FrameRecord, Tracer},
+    transport::{BoxedStream, TcpTransport, Transport},
 };
 
+/// how long a discovered peer stays available without answering discovery, unless changed
+/// with [P2pManager::set_peer_ttl]
+pub const DEFAULT_PEER_TTL: Duration = Duration::from_secs(90);
+
+/// how long a connected peer can stay silent before its connection is closed, unless changed
+/// with [P2pManager::set_keepalive_timeout]
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how long [P2pManager::refresh_discovery] waits for peers to answer
+pub const REFRESH_WAIT: Duration = Duration::from_millis(500);
+
+/// how long to wait before asking the gateway again after a port mapping failed
+pub const PORT_MAPPING_RETRY: Duration = Duration::from_secs(60);
+
+/// how many unknown peers answering discovery are kept to pair with, more are ignored until
+/// some stop answering
+pub const MAX_STRANGERS: usize = 256;
+
+/// Decides whether an authenticated known peer may connect
+pub type ConnectionFilter = Arc<dyn Fn(&PeerMetadata) -> bool + Send + Sync>;
+
 pub struct P2pManager {
     // store internal state
     /// PeerId is the unique identifier of the current peer.
     pub(crate) id: PeerId,
 
-    // /// identity is the TLS identity of the current peer.
-    // pub(crate) identity: (Certificate, PrivateKey),
-    /// The metadata of the current peer
-    pub(crate) metadata: PeerMetadata,
+    /// tls secures connections with the identity of the current peer
+    tls: Option<Tls>,
+
+    /// The metadata of the current peer, its addresses follow the lan
+    metadata: RwLock<PeerMetadata>,
+
+    /// the address the listener is bound to, advertised on every lan ip
+    listener_addr: SocketAddr,
+
+    /// the multicast group discovery joins on every lan interface
+    multicast: SocketAddr,
+
+    /// the IPv6 multicast group discovery joins while the lan has an IPv6 address
+    multicast_v6: Option<SocketAddr>,
+
+    /// the address the gateway forwards to the listener, advertised along with the lan ones
+    external_addr: RwLock<Option<SocketAddr>>,
+
+    /// the gateway asked to forward a port and the task keeping the mapping alive
+    port_mapper: Mutex<Option<(SocketAddr, tokio::task::AbortHandle)>>,
+
+    /// the UPnP gateway found when the NAT-PMP one did not answer, mappings go through it
+    upnp_gateway: Mutex<Option<portmap::UpnpGateway>>,
+
+    /// the rendezvous server paired peers off the lan are reached through and the task keeping
+    /// the registration with it
+    relay: Mutex<Option<(SocketAddr, tokio::task::AbortHandle)>>,
+
+    /// the tasks the manager spawned, stopped on [P2pManager::shutdown]. Finished ones are
+    /// dropped whenever another is spawned.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+
+    /// the multicast discovery on each lan interface, keyed by its ip. IPv6 discovery is keyed
+    /// by the unspecified address since it is not bound to an interface.
+    interfaces: DashMap<IpAddr, Arc<dyn Discovery>>,
 
     /// known_peers are peers who have been previously paired up with, only from these peers can the
     /// P2p Manager discover and connect with.
@@ -38,8 +111,91 @@ pub struct P2pManager {
     /// connected_peers
     connected_peers: DashSet<PeerId>,
 
-    /// channel to send Discovery events
-    discovery_channel: mpsc::Sender<DiscoveryEvent>,
+    /// path_latency is the latest round trip over each address of a peer, from dialing it or
+    /// pinging the connection using it
+    path_latency: Mutex<HashMap<SocketAddr, Duration>>,
+
+    /// connections are how the current peer is connected with each connected peer
+    connections: DashMap<PeerId, ConnectionInfo>,
+
+    /// latency is the history of round trips to each peer connected since startup
+    latency: DashMap<PeerId, LatencyHistory>,
+
+    /// preferred_paths are the addresses the user chose to always try first for a peer
+    preferred_paths: DashMap<PeerId, SocketAddr>,
+
+    /// connection_filter is asked before accepting a connection from a known peer
+    connection_filter: RwLock<Option<ConnectionFilter>>,
+
+    /// keepalive_timeout is how long a new connection can go without hearing from the peer
+    keepalive_timeout: Mutex<Duration>,
+
+    /// hangups close the live connection of each connected peer
+    hangups: DashMap<PeerId, Arc<Notify>>,
+
+    /// every registered discovery mechanism
+    discovery: RwLock<Vec<Arc<dyn Discovery>>>,
+
+    /// last_seen is when each discovered peer last answered discovery
+    last_seen: DashMap<PeerId, Instant>,
+
+    /// peer_ttl is how long a discovered peer that is not connected is kept without answering
+    peer_ttl: Mutex<Duration>,
+
+    /// peer_log records changes to discovered_peers for callers polling for differences
+    peer_log: Mutex<PeerLog>,
+
+    /// strangers are unknown peers which answered discovery within the peer ttl, at most
+    /// [MAX_STRANGERS] of them
+    strangers: DashMap<PeerId, PeerMetadata>,
+
+    /// limits bounds the connections handled at once
+    limits: ConnectionLimits,
+
+    /// handshakes holds a permit for every inbound handshake in progress
+    handshakes: Arc<Semaphore>,
+
+    /// inbound rate limits connection attempts and bans ips which keep failing to authenticate
+    inbound: Mutex<InboundGuard>,
+
+    /// relayed does the same for the ids connecting through the rendezvous server
+    relayed: Mutex<InboundGuard<PeerId>>,
+
+    /// blocked are the peers whose discovery responses and connections are ignored
+    blocked: DashSet<PeerId>,
+
+    /// conflicts are the addresses another node announced this node's id from
+    conflicts: DashSet<SocketAddr>,
+
+    /// pairings are the requests from unpaired peers waiting on the user to answer
+    pairings: DashMap<PeerId, oneshot::Sender<bool>>,
+
+    /// pin is shown to the user for another device to pair by typing it, with when it was made
+    pin: Mutex<Option<(String, Instant)>>,
+
+    /// rng makes pairing secrets, pins and nonces
+    rng: RwLock<Arc<dyn Rng>>,
+
+    /// whether the frames of new connections are traced for debugging
+    trace: AtomicBool,
+
+    /// observing reports everything seen on discovery while never announcing the current peer
+    observing: Arc<AtomicBool>,
+
+    /// the frame traces of the latest connection with each peer
+    traces: DashMap<PeerId, Arc<Tracer>>,
+
+    /// paused is set while the system is asleep, discovery is neither sent nor answered
+    paused: AtomicBool,
+
+    /// presence_round counts announcements, to pick the known peers the next one signs for
+    presence_round: AtomicUsize,
+
+    /// the transport used to connect with peers
+    transport: Arc<dyn Transport>,
+
+    /// channel to merge the events of every discovery mechanism into the event loop
+    discovery_channel: mpsc::Sender<(DiscoverySource, DiscoveryEvent, SocketAddr)>,
 
     /// internal_channel is a channel which is used to communicate with the main internal event loop.
     internal_channel: mpsc::UnboundedSender<InternalEvent>,
@@ -53,25 +209,74 @@ pub struct P2pConfig {
     pub device: DeviceType,
     pub name: String,
     pub multicast: SocketAddr,
+    /// the IPv6 multicast group to also discover peers on while the lan has an IPv6 address, see
+    /// [crate::discovery::DISCOVERY_MULTICAST_V6]
+    pub multicast_v6: Option<SocketAddr>,
+    /// binding the unspecified IPv6 address listens on both IPv4 and IPv6
     pub p2p_addr: SocketAddr,
+    /// the local ips the listener is advertised on, the listener's own address is used if empty
+    pub lan: Vec<IpAddr>,
+    /// the identity connections are secured with using TLS, `id` must be derived from it.
+    /// Without one connections are plaintext, which is only meant for testing.
+    pub identity: Option<Identity>,
+    /// how many connections the manager handles at once
+    pub limits: ConnectionLimits,
+}
+
+/// Bounds on the connections a manager handles at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// inbound handshakes run at the same time, connections beyond it are dropped
+    pub max_handshakes: usize,
+    /// peers connected at the same time, both inbound and outbound
+    pub max_peers: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_handshakes: 16,
+            max_peers: 64,
+        }
+    }
+}
+
+/// the addresses to advertise for a listener bound to `listener`
+fn advertised_addrs(lan: &[IpAddr], listener: SocketAddr) -> Vec<SocketAddr> {
+    if lan.is_empty() {
+        return vec![listener];
+    }
+    // a dual-stack listener is reachable on every ip, otherwise only on its own ip version
+    let dual_stack = listener.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+    lan.iter()
+        .filter(|ip| dual_stack || ip.is_ipv6() == listener.is_ipv6())
+        .map(|ip| SocketAddr::new(*ip, listener.port()))
+        .collect()
 }
 
 impl P2pManager {
     pub async fn new(
         config: P2pConfig,
     ) -> Result<(Arc<Self>, mpsc::UnboundedReceiver<P2pEvent>), err::InitError> {
-        let discover = {
+        Self::with_transport(config, TcpTransport).await
+    }
+
+    /// create a manager which connects to peers over a custom transport
+    pub async fn with_transport(
+        config: P2pConfig,
+        transport: impl Transport,
+    ) -> Result<(Arc<Self>, mpsc::UnboundedReceiver<P2pEvent>), err::InitError> {
+        let multicast = {
             // use LOCALHOST or UNSPECIFICED?
             let local = SocketAddr::V4(SocketAddrV4::new(
                 Ipv4Addr::LOCALHOST,
                 config.multicast.port(),
             ));
-            let (socket, multi_addr) = discovery::multicast(&local, &config.multicast)?;
-            discovery::start(socket, multi_addr)
+            MulticastDiscovery::new(&local, &config.multicast)?
         };
 
-        // setup tcp listener
-        let listener = TcpListener::bind(config.p2p_addr).await?;
+        // setup listener
+        let listener = transport.listen(config.p2p_addr).await?;
         debug!(
             "Peer {} listening on {}",
             config.id.clone(),
@@ -79,30 +284,73 @@ impl P2pManager {
         );
 
         // setup metadata
+        let listener_addr = listener.local_addr()?;
         let metadata = PeerMetadata {
             id: config.id.clone(),
             typ: config.device,
             name: config.name,
-            addr: listener.local_addr()?,
+            addrs: advertised_addrs(&config.lan, listener_addr),
         };
 
+        let discovery_channel = mpsc::channel(1024);
         let internal_channel = mpsc::unbounded_channel();
         let app_channel = mpsc::unbounded_channel();
 
+        let tls = config.identity.map(Tls::new).transpose()?;
+
         let this = Arc::new(Self {
             id: config.id,
-            metadata,
+            tls,
+            metadata: RwLock::new(metadata),
+            listener_addr,
+            multicast: config.multicast,
+            multicast_v6: config.multicast_v6,
+            external_addr: RwLock::new(None),
+            port_mapper: Mutex::new(None),
+            upnp_gateway: Mutex::new(None),
+            relay: Mutex::new(None),
+            tasks: Mutex::new(Vec::new()),
+            interfaces: DashMap::new(),
             known_peers: DashMap::new(),
             discovered_peers: DashMap::new(),
             connected_peers: DashSet::new(),
-            discovery_channel: discover.0,
+            path_latency: Mutex::new(HashMap::new()),
+            connections: DashMap::new(),
+            latency: DashMap::new(),
+            preferred_paths: DashMap::new(),
+            connection_filter: RwLock::new(None),
+            keepalive_timeout: Mutex::new(DEFAULT_KEEPALIVE_TIMEOUT),
+            hangups: DashMap::new(),
+            discovery: RwLock::new(Vec::new()),
+            last_seen: DashMap::new(),
+            peer_ttl: Mutex::new(DEFAULT_PEER_TTL),
+            peer_log: Mutex::new(PeerLog::default()),
+            strangers: DashMap::new(),
+            conflicts: DashSet::new(),
+            blocked: DashSet::new(),
+            inbound: Mutex::new(InboundGuard::default()),
+            relayed: Mutex::new(InboundGuard::default()),
+            limits: config.limits,
+            handshakes: Arc::new(Semaphore::new(config.limits.max_handshakes)),
+            pairings: DashMap::new(),
+            pin: Mutex::new(None),
+            rng: RwLock::new(Arc::new(SystemRandom::new())),
+            trace: AtomicBool::new(false),
+            observing: Arc::new(AtomicBool::new(false)),
+            traces: DashMap::new(),
+            paused: AtomicBool::new(false),
+            presence_round: AtomicUsize::new(0),
+            transport: Arc::new(transport),
+            discovery_channel: discovery_channel.0,
             internal_channel: internal_channel.0,
             app_channel: app_channel.0,
         });
+        this.add_discovery(multicast);
+        this.follow_interfaces(&config.lan);
 
-        tokio::spawn(event_loop::p2p_event_loop(
+        this.spawn(event_loop::p2p_event_loop(
             this.clone(),
-            discover.1,
+            discovery_channel.1,
             internal_channel.1,
             listener,
         ));
@@ -115,31 +363,549 @@ impl P2pManager {
         self.known_peers.insert(peer.id.clone(), peer);
     }
 
+    /// called by the application when unpairing, the peer is forgotten and its live connection is closed
+    pub fn remove_known_peer(&self, id: &PeerId) {
+        self.known_peers.remove(id);
+        if self.discovered_peers.remove(id).is_some() {
+            self.peer_log.lock().unwrap().removed(id.clone());
+        }
+        self.connected_peers.remove(id);
+        self.connections.remove(id);
+        if let Some((_, hangup)) = self.hangups.remove(id) {
+            hangup.notify_one();
+        }
+    }
+
+    /// called by the application to ignore a peer. It is dropped from the discovered peers, its
+    /// connection and pairing request are closed, and it is turned away until unblocked.
+    pub fn block_peer(&self, id: &PeerId) {
+        self.blocked.insert(id.clone());
+        if self.discovered_peers.remove(id).is_some() {
+            self.peer_log.lock().unwrap().removed(id.clone());
+        }
+        self.strangers.remove(id);
+        self.pairings.remove(id);
+        self.connected_peers.remove(id);
+        self.connections.remove(id);
+        if let Some((_, hangup)) = self.hangups.remove(id) {
+            hangup.notify_one();
+        }
+    }
+
+    /// called by the application to let a blocked peer be discovered and connect again
+    pub fn unblock_peer(&self, id: &PeerId) {
+        self.blocked.remove(id);
+    }
+
+    pub fn is_blocked(&self, id: &PeerId) -> bool {
+        self.blocked.contains(id)
+    }
+
+    /// called by the application to register another discovery mechanism.
+    /// Peers it discovers are merged with the peers found by every other mechanism.
+    pub fn add_discovery(&self, discovery: impl Discovery + 'static) {
+        self.register_discovery(discovery);
+    }
+
+    // start forwarding the events of `discovery`, they stop once it is removed and dropped
+    fn register_discovery(&self, mut discovery: impl Discovery + 'static) -> Arc<dyn Discovery> {
+        let source = discovery.source();
+        if let Some(mut events) = discovery.events() {
+            let merged = self.discovery_channel.clone();
+            self.spawn(async move {
+                while let Some((event, addr)) = events.recv().await {
+                    if merged.send((source, event, addr)).await.is_err() {
+                        break;
+                    }
+                }
+                debug!("Discovery source {:?} stopped sending events", source);
+            });
+        }
+        if let Some(mut recoveries) = discovery.recoveries() {
+            let app = self.app_channel.clone();
+            self.spawn(async move {
+                while let Some(count) = recoveries.recv().await {
+                    if app
+                        .send(P2pEvent::DiscoveryRecovered { source, count })
+                        .is_err()
+                    {
+                        error!("failed to send DiscoveryRecovered event to the application");
+                    }
+                }
+            });
+        }
+        if let Some(mut malformed) = discovery.malformed() {
+            let app = self.app_channel.clone();
+            let observing = self.observing.clone();
+            self.spawn(async move {
+                while let Some(reason) = malformed.recv().await {
+                    if !observing.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let event = P2pEvent::Observed {
+                        source,
+                        from: None,
+                        observation: Observation::Malformed(reason),
+                    };
+                    if app.send(event).is_err() {
+                        error!("failed to send Observed event to the application");
+                    }
+                }
+            });
+        }
+        let discovery: Arc<dyn Discovery> = Arc::new(discovery);
+        self.discovery.write().unwrap().push(discovery.clone());
+        discovery
+    }
+
+    // stop using `discovery`, a multicast one leaves the group once the last use of it ends
+    fn remove_discovery(&self, discovery: &Arc<dyn Discovery>) {
+        self.discovery
+            .write()
+            .unwrap()
+            .retain(|d| !Arc::ptr_eq(d, discovery));
+    }
+
+    /// snapshot every registered discovery mechanism so they can be used across an await
+    fn discovery_mechanisms(&self) -> Vec<Arc<dyn Discovery>> {
+        self.discovery.read().unwrap().clone()
+    }
+
     // called by the application to send a presenct request
     pub async fn request_presence(&self) {
-        if let Err(e) = self
-            .discovery_channel
-            .send(DiscoveryEvent::PresenceRequest)
-            .await
-        {
-            error!("application is unable to request presence: {}", e);
+        if self.is_paused() {
+            return;
+        }
+        for discovery in self.discovery_mechanisms() {
+            discovery.request().await;
         }
         // debug!("peer is emitting presence request");
     }
 
+    /// application calls this when the user asks to look for peers again. Peers which stopped
+    /// answering are dropped right away, then every discovery asks for presence and this returns
+    /// once the peers answering within [REFRESH_WAIT] have been handled.
+    pub async fn refresh_discovery(&self) {
+        if self.is_paused() {
+            return;
+        }
+        self.expire_peers();
+        self.request_presence().await;
+        tokio::time::sleep(REFRESH_WAIT).await;
+    }
+
+    /// called by the application when the system goes to sleep to stop all discovery
+    pub fn pause_discovery(&self) {
+        debug!("pausing discovery");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// called by the application when the system wakes up. Discovered peers that are not connected
+    /// are forgotten as their addresses may be stale, the application should request presence
+    /// afterwards to rediscover them with fresh metadata.
+    pub fn resume_discovery(&self) {
+        debug!("resuming discovery");
+        let mut log = self.peer_log.lock().unwrap();
+        self.discovered_peers.retain(|id, _| {
+            let keep = self.connected_peers.contains(id);
+            if !keep {
+                log.removed(id.clone());
+            }
+            keep
+        });
+        drop(log);
+        self.strangers.clear();
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// the number of unknown peers which answered discovery, many of them hints at a public network
+    pub fn stranger_count(&self) -> usize {
+        self.strangers.len()
+    }
+
+    /// observe discovery for debugging, everything seen is sent as [P2pEvent::Observed] and presence
+    /// requests are no longer answered so the current peer stays hidden
+    pub fn set_observer(&self, enabled: bool) {
+        self.observing.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn is_observer(&self) -> bool {
+        self.observing.load(Ordering::SeqCst)
+    }
+
+    /// change how long a discovered peer is kept without answering discovery before it is lost.
+    /// The application should request presence more often than this to keep peers around.
+    pub fn set_peer_ttl(&self, ttl: Duration) {
+        *self.peer_ttl.lock().unwrap() = ttl;
+    }
+
+    /// application calls this to decide which known peers may connect, every one may without a filter.
+    /// A refused peer is told the connection was denied.
+    pub fn set_connection_filter(&self, filter: Option<ConnectionFilter>) {
+        *self.connection_filter.write().unwrap() = filter;
+    }
+
+    /// whether a known peer which passed authentication may connect
+    pub(crate) fn allows_connection(&self, peer: &PeerMetadata) -> bool {
+        match self.connection_filter.read().unwrap().as_ref() {
+            Some(filter) => filter(peer),
+            None => true,
+        }
+    }
+
+    /// change how long a new connection can go without hearing from the peer before it is closed
+    /// and [P2pEvent::PeerDisconnected] is sent, idle connections are pinged well before then
+    pub fn set_keepalive_timeout(&self, timeout: Duration) {
+        *self.keepalive_timeout.lock().unwrap() = timeout;
+    }
+
+    pub(crate) fn keepalive_timeout(&self) -> Duration {
+        *self.keepalive_timeout.lock().unwrap()
+    }
+
+    /// make pairing secrets, pins and nonces with `rng`, simulations and tests pass a
+    /// [crate::rng::SeededRng] to reproduce a run
+    pub fn set_rng(&self, rng: Arc<dyn Rng>) {
+        *self.rng.write().unwrap() = rng;
+    }
+
+    pub(crate) fn rng(&self) -> Arc<dyn Rng> {
+        self.rng.read().unwrap().clone()
+    }
+
+    /// opt in to tracing the frames of new connections, disabling drops the recorded traces
+    pub fn set_connection_trace(&self, enabled: bool) {
+        self.trace.store(enabled, Ordering::SeqCst);
+        if !enabled {
+            self.traces.clear();
+        }
+    }
+
+    /// the traced frames of the latest connection with a peer
+    pub fn connection_trace(&self, id: &PeerId) -> Option<Vec<FrameRecord>> {
+        self.traces.get(id).map(|t| t.frames())
+    }
+
+    /// a tracer for a new connection when tracing is enabled
+    pub(crate) fn start_trace(&self) -> Option<Arc<Tracer>> {
+        self.trace
+            .load(Ordering::SeqCst)
+            .then(|| Arc::new(Tracer::new()))
+    }
+
+    /// keep the trace of a connection once the peer on the other end is known
+    pub(crate) fn keep_trace(&self, id: &PeerId, tracer: Option<&Arc<Tracer>>) {
+        if let Some(tracer) = tracer {
+            self.traces.insert(id.clone(), tracer.clone());
+        }
+    }
+
+    /// the TLS layer when connections are secured
+    pub(crate) fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
     // application calls this to get local metadata
-    pub fn get_metadata(&self) -> &PeerMetadata {
-        &self.metadata
+    pub fn get_metadata(&self) -> PeerMetadata {
+        self.metadata.read().unwrap().clone()
+    }
+
+    /// the local metadata as it is announced, signed for the known peers. With more of them than
+    /// a presence has tags for, each announcement signs for the next ones in turn.
+    pub fn presence(&self) -> Presence {
+        // a peer which may have missed the last rotation finds a tag for the previous secret
+        let mut known: Vec<_> = self
+            .known_peers
+            .iter()
+            .flat_map(|p| p.keys().map(|auth| (p.id.clone(), auth.clone())).collect::<Vec<_>>())
+            .collect();
+        known.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        if !known.is_empty() {
+            let round = self.presence_round.fetch_add(1, Ordering::Relaxed);
+            let shift = round % known.len() * MAX_PRESENCE_TAGS % known.len();
+            known.rotate_left(shift);
+        }
+        Presence::new(self.get_metadata(), known.iter().map(|(_, auth)| auth))
+    }
+
+    /// called by the application when the local ips change. The new addresses are advertised
+    /// right away and discovery follows the interfaces, joining the multicast group on new ones
+    /// and leaving it on the ones which went down.
+    pub async fn set_lan(&self, lan: &[IpAddr]) {
+        {
+            let mut metadata = self.metadata.write().unwrap();
+            metadata.addrs = advertised_addrs(lan, self.listener_addr);
+            metadata.addrs.extend(*self.external_addr.read().unwrap());
+        }
+        self.follow_interfaces(lan);
+        self.announce().await;
+    }
+
+    /// called by the application when the device is renamed, the new name is advertised right away
+    pub async fn set_name(&self, name: String) {
+        self.metadata.write().unwrap().name = name;
+        self.announce().await;
+    }
+
+    // advertise the local metadata through every discovery mechanism
+    async fn announce(&self) {
+        if self.is_paused() || self.is_observer() {
+            return;
+        }
+        let presence = self.presence();
+        for discovery in self.discovery_mechanisms() {
+            discovery.announce(presence.clone()).await;
+        }
+    }
+
+    /// ask `gateway` over NAT-PMP, or the lan's UPnP gateway when it doesn't answer, to forward a
+    /// port to the listener, so peers on other subnets behind the same router can connect. The
+    /// external address is advertised while the gateway keeps the mapping, which is renewed until
+    /// [P2pManager::stop_port_mapping].
+    pub async fn start_port_mapping(self: &Arc<Self>, gateway: SocketAddr) {
+        self.stop_port_mapping().await;
+        let this = self.clone();
+        let task = self.spawn(async move {
+            loop {
+                let wait = match this.map_port(gateway).await {
+                    Ok(mapping) => {
+                        this.set_external_addr(Some(mapping.external));
+                        // renew halfway through so the mapping never lapses
+                        (mapping.lifetime / 2).max(Duration::from_secs(1))
+                    }
+                    Err(e) => {
+                        warn!("Unable to map a port on the gateway {}: {:?}", gateway, e);
+                        this.set_external_addr(None);
+                        PORT_MAPPING_RETRY
+                    }
+                };
+                tokio::time::sleep(wait).await;
+            }
+        });
+        *self.port_mapper.lock().unwrap() = Some((gateway, task));
+    }
+
+    /// stop renewing the port mapping and remove it from the gateway
+    pub async fn stop_port_mapping(&self) {
+        let Some((gateway, task)) = self.port_mapper.lock().unwrap().take() else {
+            return;
+        };
+        task.abort();
+        self.set_external_addr(None);
+        // the task may have stopped with a request in flight, so the gateway can hold a mapping
+        // that was never recorded
+        let port = self.listener_addr.port();
+        let upnp = self.upnp_gateway.lock().unwrap().take();
+        let unmapped = match upnp {
+            Some(upnp) => portmap::unmap_tcp_upnp(&upnp, port).await,
+            None => portmap::unmap_tcp(gateway, port).await,
+        };
+        if let Err(e) = unmapped {
+            debug!("Unable to remove the port mapping on {}: {:?}", gateway, e);
+        }
+    }
+
+    // map the listener's port over NAT-PMP, once the gateway doesn't answer search for a UPnP
+    // one on the same lan and keep using it
+    async fn map_port(
+        &self,
+        gateway: SocketAddr,
+    ) -> Result<portmap::PortMapping, err::PortMapError> {
+        let port = self.listener_addr.port();
+        let upnp = self.upnp_gateway.lock().unwrap().clone();
+        if upnp.is_none() {
+            let error = match portmap::map_tcp(gateway, port, MAPPING_LIFETIME).await {
+                Ok(mapping) => return Ok(mapping),
+                Err(error) => error,
+            };
+            debug!("No NAT-PMP on {} ({:?}), searching for UPnP", gateway, error);
+        }
+        let lan: Vec<IpAddr> =
+            self.metadata.read().unwrap().addrs.iter().map(SocketAddr::ip).collect();
+        let Some(local) = portmap::local_ip(&lan, gateway) else {
+            return Err(err::PortMapError::Upnp(format!("no address on the lan of {}", gateway)));
+        };
+        let upnp = match upnp {
+            Some(upnp) => upnp,
+            None => {
+                let upnp = portmap::search_upnp(local, portmap::SSDP_MULTICAST).await?;
+                *self.upnp_gateway.lock().unwrap() = Some(upnp.clone());
+                upnp
+            }
+        };
+        portmap::map_tcp_upnp(&upnp, SocketAddr::new(local, port), MAPPING_LIFETIME).await
+    }
+
+    /// the address the gateway forwards to the listener, if a port is mapped
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        *self.external_addr.read().unwrap()
+    }
+
+    // advertise `external` in place of the previous external address, returns whether it changed
+    fn set_external_addr(&self, external: Option<SocketAddr>) -> bool {
+        let previous = std::mem::replace(&mut *self.external_addr.write().unwrap(), external);
+        if previous == external {
+            return false;
+        }
+        {
+            let mut metadata = self.metadata.write().unwrap();
+            metadata.addrs.retain(|addr| Some(*addr) != previous);
+            metadata.addrs.extend(external);
+        }
+        if self.app_channel.send(P2pEvent::PortMapped { external }).is_err() {
+            error!("failed to send PortMapped event to the application");
+        }
+        true
+    }
+
+    /// join the multicast group on every lan interface not joined yet, so peers on any of them
+    /// are found, and leave it on the interfaces no longer in `lan`. IPv6 discovery runs while
+    /// the lan has an IPv6 address. Interfaces which can't join are skipped.
+    fn follow_interfaces(&self, lan: &[IpAddr]) {
+        let v6 = self
+            .multicast_v6
+            .filter(|_| lan.iter().any(IpAddr::is_ipv6))
+            .map(|group| (SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), group.port()), group));
+        let wanted: Vec<(SocketAddr, SocketAddr)> = lan
+            .iter()
+            .filter(|ip| ip.is_ipv4() && !ip.is_loopback())
+            .map(|ip| (SocketAddr::new(*ip, self.multicast.port()), self.multicast))
+            .chain(v6)
+            .collect();
+
+        self.interfaces.retain(|ip, discovery| {
+            let up = wanted.iter().any(|(local, _)| local.ip() == *ip);
+            if !up {
+                debug!("Leaving the multicast group on {}", ip);
+                self.remove_discovery(discovery);
+            }
+            up
+        });
+        for (local, group) in wanted {
+            if self.interfaces.contains_key(&local.ip()) {
+                continue;
+            }
+            match MulticastDiscovery::new(&local, &group) {
+                Ok(multicast) => {
+                    let discovery = self.register_discovery(multicast);
+                    self.interfaces.insert(local.ip(), discovery);
+                }
+                Err(e) => warn!("Multicast discovery on {} is unavailable: {:?}", local.ip(), e),
+            }
+        }
+    }
+
+    /// the ips multicast discovery joined the group on, the unspecified IPv6 address stands for
+    /// IPv6 discovery
+    pub fn multicast_interfaces(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = self.interfaces.iter().map(|entry| *entry.key()).collect();
+        ips.sort();
+        ips
+    }
+
+    /// the changes to the discovered peers after `sequence`, start from 0 to get every peer
+    pub fn discovered_since(&self, sequence: u64) -> PeerDelta {
+        self.peer_log.lock().unwrap().since(sequence)
     }
 
     pub fn is_discovered(&self, id: &PeerId) -> bool {
         self.discovered_peers.contains_key(id)
     }
 
+    /// the discovery mechanisms a peer has been discovered through
+    pub fn discovered_by(&self, id: &PeerId) -> HashSet<DiscoverySource> {
+        self.discovered_peers
+            .get(id)
+            .map(|p| p.sources.clone())
+            .unwrap_or_default()
+    }
+
     pub fn is_connected(&self, id: &PeerId) -> bool {
         self.connected_peers.contains(id)
     }
 
+    /// the live connections with every connected peer
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.iter().map(|c| c.value().clone()).collect()
+    }
+
+    /// the live connection with a peer, None when it is not connected
+    pub fn connection(&self, id: &PeerId) -> Option<ConnectionInfo> {
+        self.connections.get(id).map(|c| c.value().clone())
+    }
+
+    /// how many inbound connection attempts were turned away and the ips banned right now
+    pub fn inbound_stats(&self) -> InboundStats {
+        let now = tokio::time::Instant::now();
+        self.inbound.lock().unwrap().stats(now)
+    }
+
+    /// event loop calls this before handshaking with an inbound connection
+    pub(crate) fn admit_inbound(&self, addr: &SocketAddr) -> bool {
+        let now = tokio::time::Instant::now();
+        self.inbound.lock().unwrap().admit(addr.ip(), now)
+    }
+
+    /// event loop calls this for a slot to handshake with an inbound connection in, none while
+    /// [ConnectionLimits::max_handshakes] are in progress
+    pub(crate) fn handshake_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.handshakes.clone().try_acquire_owned().ok()
+    }
+
+    /// whether another peer can connect without going over [ConnectionLimits::max_peers]
+    pub(crate) fn has_room_for_peer(&self) -> bool {
+        self.connected_peers.len() < self.limits.max_peers
+    }
+
+    /// event loop calls this periodically to forget ips which stopped connecting
+    pub(crate) fn prune_inbound(&self) {
+        let now = tokio::time::Instant::now();
+        self.inbound.lock().unwrap().prune(now);
+        self.relayed.lock().unwrap().prune(now);
+    }
+
+    /// event loop calls this once an inbound handshake is done
+    pub(crate) fn inbound_handshake_done(
+        &self,
+        addr: &SocketAddr,
+        result: &Result<Option<Peer>, err::HandshakeError>,
+    ) {
+        let mut inbound = self.inbound.lock().unwrap();
+        match result {
+            Ok(_) => inbound.auth_succeeded(&addr.ip()),
+            Err(err::HandshakeError::Auth) => {
+                inbound.auth_failed(addr.ip(), tokio::time::Instant::now())
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// called before answering a peer connecting through the rendezvous server
+    pub(crate) fn admit_relayed(&self, id: &PeerId) -> bool {
+        let now = tokio::time::Instant::now();
+        self.relayed.lock().unwrap().admit(id.clone(), now)
+    }
+
+    /// called once a relayed handshake is done
+    pub(crate) fn relayed_handshake_done(
+        &self,
+        id: &PeerId,
+        result: &Result<Option<Peer>, err::HandshakeError>,
+    ) {
+        let mut relayed = self.relayed.lock().unwrap();
+        match result {
+            Ok(_) => relayed.auth_succeeded(id),
+            Err(err::HandshakeError::Auth) => {
+                relayed.auth_failed(id.clone(), tokio::time::Instant::now())
+            }
+            Err(_) => {}
+        }
+    }
+
     /// application calls this to connect to a peer
     pub async fn connect_to_peer(
         self: &Arc<Self>,
@@ -148,33 +914,410 @@ impl P2pManager {
         if self.connected_peers.contains(id) {
             return Err(err::HandshakeError::Dup);
         }
-        let Some(candidate) = self.discovered_peers.get(id) else {
-            return Err(err::HandshakeError::NotFound)
+        if !self.has_room_for_peer() {
+            return Err(err::HandshakeError::Full);
+        }
+        let Some(candidate) = self.discovered_peers.get(id).map(|c| c.clone()) else {
+            // paired peers off the lan are reached through the rendezvous server
+            let known = self.known_peers.get(id).map(|c| c.clone());
+            return match (self.relay_server(), known) {
+                (Some(server), Some(candidate)) => {
+                    self.connect_over_relay(server, &candidate).await
+                }
+                _ => Err(err::HandshakeError::NotFound),
+            };
         };
 
         // let peer = candidate.clone();
 
-        for addr in &candidate.addrs {
-            match TcpStream::connect(addr).await {
+        for addr in self.order_paths(id, &candidate.addrs) {
+            match self.dial(addr).await {
                 Err(e) => {
                     error!("Attempt to connect to address {:?} failed {:?}", addr, e);
                 }
                 Ok(conn) => {
                     debug!("Attempting to connect to {:?}", addr);
                     let peer = crate::net::connect(self, conn, &candidate).await?;
-                    self.connected_peers.insert(id.clone());
+                    self.client_connected(id, addr);
+                    return Ok(peer);
+                }
+            }
+        }
+        Err(err::HandshakeError::Addr)
+    }
+
+    /// register with the rendezvous server at `server`, so paired peers which can't be
+    /// discovered are reached through it. [Self::connect_to_peer] tries the addresses the server
+    /// saw them at and falls back to having it relay the connection.
+    pub fn start_relay(self: &Arc<Self>, server: SocketAddr) {
+        self.stop_relay();
+        let this = self.clone();
+        let task = self.spawn(async move {
+            loop {
+                if let Err(e) = this.serve_relay(server).await {
+                    warn!("Lost the rendezvous server {}: {:?}", server, e);
+                }
+                tokio::time::sleep(relay::REGISTER_RETRY).await;
+            }
+        });
+        *self.relay.lock().unwrap() = Some((server, task));
+    }
+
+    /// stop being reachable through the rendezvous server, relayed connections stay open
+    pub fn stop_relay(&self) {
+        if let Some((_, task)) = self.relay.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    /// the rendezvous server the manager is registered with, if any
+    pub fn relay_server(&self) -> Option<SocketAddr> {
+        self.relay.lock().unwrap().as_ref().map(|(server, _)| *server)
+    }
+
+    /// stop everything the manager runs: the event loop, discovery, the connections to peers,
+    /// the port mapping and the relay. This returns once every task has ended, the manager
+    /// can't be used afterwards.
+    pub async fn shutdown(&self) {
+        debug!("shutting down p2p");
+        self.stop_port_mapping().await;
+        self.stop_relay();
+        self.interfaces.clear();
+        self.discovery.write().unwrap().clear();
+        // a task may spawn another before it is aborted, those are stopped in the next round
+        loop {
+            let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                break;
+            }
+            for task in &tasks {
+                task.abort();
+            }
+            for task in tasks {
+                _ = task.await;
+            }
+        }
+    }
+
+    /// how many tasks the manager is running
+    pub fn task_count(&self) -> usize {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.len()
+    }
+
+    // stay registered with the rendezvous server and handshake on every session it relays
+    async fn serve_relay(self: &Arc<Self>, server: SocketAddr) -> Result<(), err::HandshakeError> {
+        let metadata = self.get_metadata();
+        let mut registration = relay::register(self.transport.as_ref(), server, metadata).await?;
+        debug!("Registered with the rendezvous server {}", server);
+        while let Some(frame) = registration.next().await {
+            let Rendezvous::Incoming(session) = frame? else {
+                return Err(err::HandshakeError::Msg);
+            };
+            let Some(slot) = self.handshake_slot() else {
+                debug!("Too many handshakes in progress, dropping relayed session {}", session);
+                continue;
+            };
+            let this = self.clone();
+            self.spawn(async move {
+                let _slot = slot;
+                let stream = match relay::attach(this.transport.as_ref(), server, session).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Unable to join relayed session {}: {:?}", session, e);
+                        return;
+                    }
+                };
+                if let Ok(Some(peer)) = crate::net::accept(&this, stream, true).await {
+                    this.handle_new_connection(peer, server);
+                }
+            });
+        }
+        Err(err::HandshakeError::Disconnect)
+    }
+
+    // try the addresses the rendezvous server saw a paired peer at, then have it relay
+    async fn connect_over_relay(
+        self: &Arc<Self>,
+        server: SocketAddr,
+        candidate: &PeerCandidate,
+    ) -> Result<Peer, err::HandshakeError> {
+        let transport = self.transport.as_ref();
+        let Some(found) = relay::lookup(transport, server, &candidate.id).await? else {
+            return Err(err::HandshakeError::NotFound);
+        };
+        for addr in self.order_paths(&candidate.id, &found.addrs) {
+            let Ok(Ok(conn)) = tokio::time::timeout(relay::DIRECT_TIMEOUT, self.dial(addr)).await
+            else {
+                continue;
+            };
+            debug!("Connecting to {} directly at {:?}", candidate.id, addr);
+            match crate::net::connect(self, conn, candidate).await {
+                Ok(peer) => {
+                    self.client_connected(&candidate.id, addr);
                     return Ok(peer);
                 }
+                Err(e) => debug!("Unable to connect to {} at {:?}: {:?}", candidate.id, addr, e),
+            }
+        }
+        debug!("Relaying the connection to {} through {}", candidate.id, server);
+        let conn = relay::relay(transport, server, &candidate.id).await?;
+        let peer = crate::net::connect(self, conn, candidate).await?;
+        self.client_connected(&candidate.id, server);
+        Ok(peer)
+    }
+
+    // record a connection the current peer dialed
+    fn client_connected(&self, id: &PeerId, addr: SocketAddr) {
+        self.connected_peers.insert(id.clone());
+        self.connections.insert(
+            id.clone(),
+            ConnectionInfo {
+                id: id.clone(),
+                conn_type: ConnectionType::Client,
+                addr,
+            },
+        );
+    }
+
+    /// application calls this to pair with an unpaired peer which answered discovery.
+    /// [P2pEvent::PairCode] carries the code to show while the remote user answers.
+    pub async fn pair_with(
+        self: &Arc<Self>,
+        id: &PeerId,
+    ) -> Result<PeerCandidate, err::HandshakeError> {
+        self.pair(id, None).await
+    }
+
+    /// application calls this to pair with an unpaired peer using the pin its user read from
+    /// the remote device, see [Self::start_pin_pairing]
+    pub async fn pair_with_pin(
+        self: &Arc<Self>,
+        id: &PeerId,
+        pin: &str,
+    ) -> Result<PeerCandidate, err::HandshakeError> {
+        self.pair(id, Some(pin)).await
+    }
+
+    async fn pair(
+        self: &Arc<Self>,
+        id: &PeerId,
+        pin: Option<&str>,
+    ) -> Result<PeerCandidate, err::HandshakeError> {
+        if self.known_peers.contains_key(id) {
+            return Err(err::HandshakeError::Dup);
+        }
+        let Some(stranger) = self.strangers.get(id).map(|p| p.value().clone()) else {
+            return Err(err::HandshakeError::NotFound)
+        };
+
+        for addr in self.order_paths(id, &stranger.addrs) {
+            match self.dial(addr).await {
+                Err(e) => {
+                    error!("Attempt to pair at address {:?} failed {:?}", addr, e);
+                }
+                Ok(conn) => {
+                    debug!("Attempting to pair at {:?}", addr);
+                    return crate::net::pair(self, conn, &stranger, pin).await;
+                }
             }
         }
         Err(err::HandshakeError::Addr)
     }
 
+    /// application calls this to always try `addr` first when connecting to a peer, or to go
+    /// back to the automatic choice with None
+    pub fn set_preferred_path(&self, id: &PeerId, addr: Option<SocketAddr>) {
+        match addr {
+            Some(addr) => self.preferred_paths.insert(id.clone(), addr),
+            None => self.preferred_paths.remove(id).map(|(_, addr)| addr),
+        };
+    }
+
+    /// the addresses of a discovered peer in the order they are tried when connecting
+    pub fn paths(&self, id: &PeerId) -> Vec<SocketAddr> {
+        match self.discovered_peers.get(id) {
+            Some(candidate) => self.order_paths(id, &candidate.addrs),
+            None => Vec::new(),
+        }
+    }
+
+    fn order_paths<'a>(
+        &self,
+        id: &PeerId,
+        addrs: impl IntoIterator<Item = &'a SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let preferred = self.preferred_paths.get(id).map(|addr| *addr);
+        crate::path::order(addrs, preferred, &self.path_latency.lock().unwrap())
+    }
+
+    /// the round trips to a peer measured while connected, for showing how its link behaves
+    pub fn latency(&self, id: &PeerId) -> Option<LatencyHistory> {
+        self.latency.get(id).map(|history| history.clone())
+    }
+
+    /// called by a connection handler when the peer answers a ping, the round trip also ranks the
+    /// address in use if the current peer dialed it
+    pub(crate) fn record_latency(&self, id: &PeerId, rtt: Duration) {
+        self.latency.entry(id.clone()).or_default().record(rtt);
+        if let Some(conn) = self.connections.get(id) {
+            if conn.conn_type == ConnectionType::Client {
+                self.path_latency.lock().unwrap().insert(conn.addr, rtt);
+            }
+        }
+    }
+
+    /// dial an address, measuring how long it takes so faster paths are tried first next time
+    async fn dial(&self, addr: SocketAddr) -> Result<BoxedStream, std::io::Error> {
+        let started = Instant::now();
+        let dialed = self.transport.dial(addr).await;
+        let mut latency = self.path_latency.lock().unwrap();
+        match dialed {
+            Ok(_) => latency.insert(addr, started.elapsed()),
+            Err(_) => latency.remove(&addr),
+        };
+        dialed
+    }
+
+    /// application calls this to accept or decline a [P2pEvent::PairRequest],
+    /// returns false if the request is no longer waiting for an answer
+    pub fn answer_pairing(&self, id: &PeerId, accept: bool) -> bool {
+        match self.pairings.remove(id) {
+            Some((_, answer)) => answer.send(accept).is_ok(),
+            None => false,
+        }
+    }
+
+    /// application calls this for a pin to show the user, another device pairs by typing it.
+    /// The pin replaces any earlier one and works for a single attempt within a minute.
+    pub fn start_pin_pairing(&self) -> Result<String, err::HandshakeError> {
+        let pin = crate::pairing::new_pin(&*self.rng())?;
+        *self.pin.lock().unwrap() = Some((pin.clone(), Instant::now()));
+        Ok(pin)
+    }
+
+    /// the pin from [Self::start_pin_pairing] as an invitation for another device to scan,
+    /// while it has not been used or expired
+    pub fn pin_invitation(&self) -> Option<PinInvitation> {
+        let pin = self.pin.lock().unwrap();
+        let (pin, made) = pin.as_ref()?;
+        (made.elapsed() < crate::pairing::PAIR_TIMEOUT).then(|| PinInvitation {
+            id: self.id.clone(),
+            pin: pin.clone(),
+        })
+    }
+
+    /// application calls this to stop the pin from [Self::start_pin_pairing] working before it
+    /// expires, returns whether a pin was still valid
+    pub fn revoke_pin(&self) -> bool {
+        let pin = self.pin.lock().unwrap().take();
+        pin.is_some_and(|(_, made)| made.elapsed() < crate::pairing::PAIR_TIMEOUT)
+    }
+
     // [START] Crate methods the event loop can call
 
-    /// called by a connected peer's connection handler when closing
-    pub(crate) fn peer_disconnected(self: &Arc<Self>, id: &PeerId) {
+    /// called when a peer pairs with a pin, the pin is gone afterwards whether it matches or not
+    pub(crate) fn take_pin(&self, by: &PeerId) -> Option<String> {
+        let (pin, made) = self.pin.lock().unwrap().take()?;
+        if made.elapsed() >= crate::pairing::PAIR_TIMEOUT {
+            return None;
+        }
+        if self.app_channel.send(P2pEvent::PinUsed(by.clone())).is_err() {
+            error!("failed to send PinUsed event to the application");
+        }
+        Some(pin)
+    }
+
+    /// called when an unpaired peer asks to pair, the user answers through [Self::answer_pairing]
+    pub(crate) fn pairing_requested(
+        &self,
+        metadata: PeerMetadata,
+        code: String,
+    ) -> oneshot::Receiver<bool> {
+        let (answer, answered) = oneshot::channel();
+        self.pairings.insert(metadata.id.clone(), answer);
+        if self
+            .app_channel
+            .send(P2pEvent::PairRequest { metadata, code })
+            .is_err()
+        {
+            error!("failed to send PairRequest event to the application");
+        }
+        answered
+    }
+
+    /// called when a pairing request was not answered in time
+    pub(crate) fn pairing_expired(&self, id: &PeerId) {
+        self.pairings.remove(id);
+    }
+
+    /// called when sending a pairing request with the code the remote user should see
+    pub(crate) fn pairing_code(&self, id: &PeerId, code: String) {
+        let event = P2pEvent::PairCode {
+            id: id.clone(),
+            code,
+        };
+        if self.app_channel.send(event).is_err() {
+            error!("failed to send PairCode event to the application");
+        }
+    }
+
+    /// called once either side of a pairing request completes, the peer becomes known
+    pub(crate) fn paired(&self, candidate: PeerCandidate, secret: Vec<u8>) {
+        let metadata = candidate.metadata.clone();
+        self.strangers.remove(&candidate.id);
+        self.known_peers.insert(candidate.id.clone(), candidate);
+        // the secret is made printable by whoever sent the request
+        let secret = String::from_utf8(secret).unwrap_or_default();
+        if self
+            .app_channel
+            .send(P2pEvent::Paired { metadata, secret })
+            .is_err()
+        {
+            error!("failed to send Paired event to the application");
+        }
+    }
+
+    /// called once a handshake changed the secrets of a paired peer, `previous` is the one to
+    /// also accept until the peer uses `auth`
+    pub(crate) fn rekey(
+        &self,
+        id: &PeerId,
+        auth: PairingAuthenticator,
+        previous: Option<PairingAuthenticator>,
+    ) {
+        let Some(mut known) = self.known_peers.get_mut(id) else {
+            return;
+        };
+        known.auth = auth.clone();
+        known.previous = previous.clone();
+        drop(known);
+        if let Some(mut discovered) = self.discovered_peers.get_mut(id) {
+            discovered.auth = auth.clone();
+            discovered.previous = previous.clone();
+        }
+        let event = P2pEvent::KeyRotated {
+            id: id.clone(),
+            secret: auth.secret(),
+            previous: previous.map(|previous| previous.secret()),
+        };
+        if self.app_channel.send(event).is_err() {
+            error!("failed to send KeyRotated event to the application");
+        }
+    }
+
+    /// called by a connected peer's connection handler when closing, with the hangup it was
+    /// given. A connection which was already replaced by a newer one leaves the peer connected.
+    pub(crate) fn peer_disconnected(self: &Arc<Self>, id: &PeerId, hangup: &Arc<Notify>) {
+        if self.hangups.remove_if(id, |_, current| Arc::ptr_eq(current, hangup)).is_none()
+            && self.hangups.contains_key(id)
+        {
+            debug!("A replaced connection to {} closed", id);
+            return;
+        }
         self.connected_peers.remove(id);
+        self.connections.remove(id);
         if self
             .app_channel
             .send(P2pEvent::PeerDisconnected(id.clone()))
@@ -184,6 +1327,13 @@ impl P2pManager {
         }
     }
 
+    /// called when a peer connects, the connection handler closes once the returned hangup is notified
+    pub(crate) fn hangup(&self, id: &PeerId) -> Arc<Notify> {
+        let hangup = Arc::new(Notify::new());
+        self.hangups.insert(id.clone(), hangup.clone());
+        hangup
+    }
+
     /// called by host handshake to attempt to get the PeerCandidate
     pub(crate) fn get_peer_candidate(&self, id: &PeerId) -> Option<PeerCandidate> {
         self.discovered_peers
@@ -201,18 +1351,47 @@ impl P2pManager {
     // }
 
     /// event loop calls this to inform manager a peer was discovered
-    pub(crate) fn handle_peer_discovered(&self, peer: PeerMetadata) {
+    pub(crate) fn handle_peer_discovered(&self, presence: Presence, source: DiscoverySource) {
+        if self.is_paused() || self.is_blocked(&presence.metadata.id) {
+            return;
+        }
+        let known = self.known_peers.get(&presence.metadata.id).map(|known| known.clone());
+        if let Some(known) = known {
+            // anyone on the lan can announce a known peer's id with their own address
+            if !known.keys().any(|auth| presence.is_signed_by(auth)) {
+                warn!(
+                    "ignoring a presence of {} it did not sign",
+                    presence.metadata.id
+                );
+                return;
+            }
+        }
+        let peer = presence.metadata;
         let id = peer.id.clone();
-        if !self.connected_peers.contains(&id) && !self.discovered_peers.contains_key(&id) {
+        self.last_seen.insert(id.clone(), Instant::now());
+        if let Some(mut discovered) = self.discovered_peers.get_mut(&id) {
+            // merge what another mechanism found about an already discovered peer
+            discovered.addrs.extend(peer.addrs.iter().copied());
+            discovered.sources.insert(source);
+            let mut metadata = peer;
+            metadata.addrs = discovered.addrs.iter().copied().collect();
+            self.peer_log.lock().unwrap().updated(metadata);
+            return;
+        }
+        if !self.connected_peers.contains(&id) {
             if let Some(known) = self.known_peers.remove(&id) {
                 let mut candidate = PeerCandidate {
                     id: id.clone(),
                     metadata: peer.clone(),
                     addrs: HashSet::new(),
+                    sources: HashSet::new(),
                     auth: known.1.auth,
+                    previous: known.1.previous,
                 };
-                candidate.addrs.insert(peer.addr);
+                candidate.addrs.extend(peer.addrs.iter().copied());
+                candidate.sources.insert(source);
                 self.discovered_peers.insert(id.clone(), candidate.clone());
+                self.peer_log.lock().unwrap().updated(peer);
                 self.known_peers.insert(id, candidate.clone());
                 debug!("discovered peer is recorded");
                 if self
@@ -222,26 +1401,153 @@ impl P2pManager {
                 {
                     error!("failed to send PeerDiscovered event to the application");
                 };
+            } else if self.strangers.len() < MAX_STRANGERS || self.strangers.contains_key(&id) {
+                self.strangers.insert(id, peer);
+            } else {
+                debug!("ignoring the stranger {}, too many answered discovery", id);
+                self.last_seen.remove(&id);
             }
         }
     }
 
-    /// event loop calls this to inform manager a peer requested our precesence
-    pub(crate) async fn handle_presence_request(&self) {
-        if let Err(e) = self
-            .discovery_channel
-            .send(DiscoveryEvent::PresenceResponse(self.metadata.clone()))
-            .await
+    /// the handshake calls this when a paired peer's clock is too far off to connect
+    pub(crate) fn report_clock_skew(&self, id: &PeerId, skew: i64) {
+        let event = P2pEvent::ClockSkew {
+            id: id.clone(),
+            skew,
+        };
+        if self.app_channel.send(event).is_err() {
+            error!("failed to send ClockSkew event to the application");
+        }
+    }
+
+    /// event loop calls this with presence responses carrying this node's id. Its own responses
+    /// come back over multicast loopback with its addresses, from anywhere else another node
+    /// uses the same identity, which is reported once per address.
+    pub(crate) fn handle_own_presence(&self, peer: PeerMetadata) {
+        let own = self.get_metadata();
+        if peer.addrs.is_empty() || peer.addrs.iter().any(|a| own.addrs.contains(a)) {
+            return;
+        }
+        let addrs: Vec<SocketAddr> = peer
+            .addrs
+            .into_iter()
+            .filter(|a| self.conflicts.insert(*a))
+            .collect();
+        if addrs.is_empty() {
+            return;
+        }
+        warn!("another node announced this node's id from {:?}", addrs);
+        if self
+            .app_channel
+            .send(P2pEvent::IdentityConflict { addrs })
+            .is_err()
         {
-            error!("event loop is unable to emit presence: {}", e);
+            error!("failed to send IdentityConflict event to the application");
+        }
+    }
+
+    /// event loop calls this periodically to forget discovered peers which stopped answering
+    /// discovery, connected peers are kept
+    pub(crate) fn expire_peers(&self) {
+        if self.is_paused() {
+            return;
+        }
+        let ttl = *self.peer_ttl.lock().unwrap();
+        let mut lost = Vec::new();
+        let mut log = self.peer_log.lock().unwrap();
+        self.discovered_peers.retain(|id, _| {
+            let alive = self.connected_peers.contains(id)
+                || self
+                    .last_seen
+                    .get(id)
+                    .is_some_and(|seen| seen.elapsed() < ttl);
+            if !alive {
+                log.removed(id.clone());
+                lost.push(id.clone());
+            }
+            alive
+        });
+        drop(log);
+
+        // forged presences with fresh ids would otherwise pile up
+        self.strangers.retain(|id, _| {
+            let alive = self
+                .last_seen
+                .get(id)
+                .is_some_and(|seen| seen.elapsed() < ttl);
+            if !alive {
+                self.last_seen.remove(id);
+            }
+            alive
+        });
+
+        for id in lost {
+            debug!("discovered peer {} is lost", id);
+            self.last_seen.remove(&id);
+            if self.app_channel.send(P2pEvent::PeerLost(id)).is_err() {
+                error!("failed to send PeerLost event to the application");
+            }
+        }
+    }
+
+    /// event loop calls this with every discovery frame, they are reported while observing
+    pub(crate) fn observe(&self, source: DiscoverySource, event: &DiscoveryEvent, from: SocketAddr) {
+        if !self.is_observer() {
+            return;
+        }
+        let observation = match event {
+            DiscoveryEvent::PresenceRequest => Observation::PresenceRequest,
+            DiscoveryEvent::PresenceResponse(presence) => {
+                Observation::PresenceResponse(presence.metadata.clone())
+            }
+        };
+        let event = P2pEvent::Observed {
+            source,
+            from: Some(from),
+            observation,
+        };
+        if self.app_channel.send(event).is_err() {
+            error!("failed to send Observed event to the application");
+        }
+    }
+
+    /// event loop calls this to inform manager a peer requested our precesence
+    pub(crate) async fn handle_presence_request(&self, source: DiscoverySource) {
+        if self.is_paused() || self.is_observer() {
+            return;
+        }
+        // answer through the same mechanism the request came from
+        for discovery in self.discovery_mechanisms() {
+            if discovery.source() == source {
+                discovery.announce(self.presence()).await;
+            }
         }
         debug!("peer is emitting presence");
     }
 
+    /// run `task` until it ends or the manager shuts down
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) -> AbortHandle {
+        let task = tokio::spawn(task);
+        let abort = task.abort_handle();
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+        abort
+    }
+
     /// event loop calls this to inform manager a peer is now connected
-    pub(crate) fn handle_new_connection(&self, peer: Peer) {
+    pub(crate) fn handle_new_connection(&self, peer: Peer, addr: SocketAddr) {
         let id = peer.id.clone();
-        self.connected_peers.insert(id);
+        self.connected_peers.insert(id.clone());
+        self.connections.insert(
+            id.clone(),
+            ConnectionInfo {
+                id,
+                conn_type: ConnectionType::Server,
+                addr,
+            },
+        );
         if self
             .app_channel
             .send(P2pEvent::PeerConnected(peer))
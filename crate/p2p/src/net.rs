@@ -1,38 +1,63 @@
 use std::{sync::Arc, time::Duration};
 
 use futures::{SinkExt, StreamExt};
-use tokio::{net::TcpStream, time::timeout};
+use ring::constant_time::verify_slices_are_equal;
+use tokio::time::timeout;
 use tokio_util::codec::Framed;
 use tracing::{debug, error};
 
 use crate::{
     err, hmac,
     manager::P2pManager,
-    peer::{Peer, PeerCandidate},
-    proto::{Connection, ConnectionCodec},
+    pairing::{
+        self, PairingAuthenticator, PinExchange, CLOCK_SKEW_SEARCH, CLOCK_SKEW_TOLERANCE,
+        PAIR_TIMEOUT,
+    },
+    peer::{Peer, PeerCandidate, PeerId, PeerMetadata},
+    proto::{Connection, ConnectionCodec, Features},
+    trace::TracedCodec,
+    transport::BoxedStream,
 };
 
 const TIMEOUT_ERR: u32 = 2001;
 const NOT_FOUND_ERR: u32 = 2002;
-const AUTH_ERR: u32 = 2003;
+pub(crate) const AUTH_ERR: u32 = 2003;
+pub(crate) const PAIR_DENIED_ERR: u32 = 2004;
+const CONNECTION_DENIED_ERR: u32 = 2005;
+const TOO_MANY_PEERS_ERR: u32 = 2006;
+const BLOCKED_ERR: u32 = 2007;
 
 /// handshake as the client to attempt to connect as a connected peer
 pub(crate) async fn connect(
     manager: &Arc<P2pManager>,
-    conn: TcpStream,
+    conn: BoxedStream,
     peer: &PeerCandidate,
 ) -> Result<Peer, err::HandshakeError> {
-    // get auth code
+    // get auth codes, the previous secret signs too in case the peer missed the last rotation
     let code = peer.auth.generate().unwrap();
-    let key = code.as_bytes();
-    let tag = hmac::sign(key, manager.id.as_bytes());
+    let previous_code = peer.previous.as_ref().map(|previous| previous.generate().unwrap());
+    let tag = hmac::sign(code.as_bytes(), manager.id.as_bytes());
+    let previous_tag = previous_code
+        .as_ref()
+        .map(|code| hmac::sign(code.as_bytes(), manager.id.as_bytes()).as_ref().to_vec());
+
+    // the server must present the pinned certificate of the peer
+    let conn = match manager.tls() {
+        Some(tls) => timeout(Duration::from_secs(1), tls.connect(conn, &peer.id))
+            .await
+            .map_err(|_| err::HandshakeError::Timeout)??,
+        None => conn,
+    };
 
     // send a connect request
-    let mut frame = Framed::new(conn, ConnectionCodec);
+    let tracer = manager.start_trace();
+    manager.keep_trace(&peer.id, tracer.as_ref());
+    let mut frame = Framed::new(conn, TracedCodec::new(ConnectionCodec, tracer));
     frame
         .send(Connection::Request {
             id: manager.id.clone(),
             tag: tag.as_ref().to_vec(),
+            previous: previous_tag,
         })
         .await?;
 
@@ -51,15 +76,20 @@ pub(crate) async fn connect(
             match res? {
                 Connection::Response(tag) => {
                     debug!("validating peer's totp code");
-                    if let Err(e) = hmac::verify(key, peer.id.as_bytes(), &tag) {
-                        error!("Error verifying totp hmac: {:?}", e);
+                    // the peer answers with the secret it has
+                    let signed = |code: &String| {
+                        hmac::verify(code.as_bytes(), peer.id.as_bytes(), &tag).is_ok()
+                    };
+                    let current = signed(&code);
+                    if !current && !previous_code.as_ref().is_some_and(signed) {
+                        error!("Error verifying totp hmac");
                         _ = frame
                             .send(crate::proto::Connection::Failure(AUTH_ERR))
                             .await;
                         return Err(err::HandshakeError::Auth);
                     }
                     // send a complete request & wait for a complete response
-                    frame.send(Connection::CompleteRequest).await?;
+                    frame.send(Connection::CompleteRequest(Features::ALL)).await?;
                     let Ok(complete) = timeout(Duration::from_secs(1), frame.next()).await else {
                         error!("peer timed out waiting for ConnectionCompleteResponse");
                         _ = frame.send(crate::proto::Connection::Failure(TIMEOUT_ERR)).await;
@@ -67,12 +97,20 @@ pub(crate) async fn connect(
                     };
                     match complete {
                         Some(res) => match res? {
-                            Connection::CompleteResponse => {
+                            Connection::CompleteResponse(features) => {
+                                let features = Features::ALL.common(features);
+                                // the new secret is only ever sent over TLS
+                                if features.contains(Features::ROTATE) && manager.tls().is_some() {
+                                    rotate(manager, &mut frame, &peer.id).await?;
+                                } else if current && peer.previous.is_some() {
+                                    manager.rekey(&peer.id, peer.auth.clone(), None);
+                                }
                                 let connected = Peer::new(
                                     manager,
                                     crate::peer::ConnectionType::Client,
                                     frame.into_inner(),
                                     peer.metadata.clone(),
+                                    features,
                                 )
                                 .unwrap();
                                 debug!("Peer is connected!");
@@ -102,12 +140,294 @@ pub(crate) async fn connect(
     }
 }
 
-/// handshake as the host to accept an incoming tcp connection as a connected peer
+/// hand a connected peer a fresh secret, both use it from the next connection on. Until the
+/// peer acknowledges it the secret the connection was made with stays in use.
+async fn rotate(
+    manager: &Arc<P2pManager>,
+    frame: &mut Framed<BoxedStream, TracedCodec<ConnectionCodec>>,
+    id: &PeerId,
+) -> Result<(), err::HandshakeError> {
+    let secret = pairing::new_secret(&*manager.rng())?;
+    let auth = PairingAuthenticator::new(secret.clone()).map_err(|_| err::HandshakeError::Auth)?;
+    frame.send(Connection::Rotate(secret)).await?;
+    let Connection::RotateAck = receive(frame, "RotateAck").await? else {
+        return Err(err::HandshakeError::Msg);
+    };
+    manager.rekey(id, auth, None);
+    Ok(())
+}
+
+/// ask an unpaired peer to pair, the connection closes once the remote user answers.
+/// With the pin shown by the remote peer the request is answered without asking its user.
+pub(crate) async fn pair(
+    manager: &Arc<P2pManager>,
+    conn: BoxedStream,
+    peer: &PeerMetadata,
+    pin: Option<&str>,
+) -> Result<PeerCandidate, err::HandshakeError> {
+    // the secret must never be sent in the clear
+    let Some(tls) = manager.tls() else {
+        return Err(err::HandshakeError::Insecure);
+    };
+    let conn = timeout(Duration::from_secs(1), tls.connect(conn, &peer.id))
+        .await
+        .map_err(|_| err::HandshakeError::Timeout)??;
+
+    let rng = manager.rng();
+    let secret = pairing::new_secret(&*rng)?;
+    let auth = PairingAuthenticator::new(secret.clone()).map_err(|_| err::HandshakeError::Auth)?;
+    let nonce = pairing::new_nonce(&*rng)?;
+    let exchange = match pin {
+        Some(pin) => Some(PinExchange::client(pin, &secret, &manager.id, &peer.id, &*rng)?),
+        None => None,
+    };
+    let request = match &exchange {
+        Some(exchange) => Connection::PinPairRequest {
+            metadata: manager.get_metadata(),
+            secret: secret.clone(),
+            share: exchange.share(),
+        },
+        None => Connection::PairRequest {
+            metadata: manager.get_metadata(),
+            secret: secret.clone(),
+            commitment: pairing::commitment(&nonce),
+        },
+    };
+
+    let tracer = manager.start_trace();
+    manager.keep_trace(&peer.id, tracer.as_ref());
+    let mut frame = Framed::new(conn, TracedCodec::new(ConnectionCodec, tracer));
+    frame.send(request).await?;
+
+    match exchange {
+        // the nonce is revealed only once the server's can no longer change
+        None => {
+            let Connection::PairNonce(server_nonce) = receive(&mut frame, "PairNonce").await? else {
+                return Err(err::HandshakeError::Msg);
+            };
+            frame.send(Connection::PairNonce(nonce.clone())).await?;
+            let code =
+                pairing::confirmation_code(&secret, &manager.id, &peer.id, &nonce, &server_nonce);
+            manager.pairing_code(&peer.id, code);
+        }
+        Some(exchange) => {
+            let Connection::PinShare(share) = receive(&mut frame, "PinShare").await? else {
+                return Err(err::HandshakeError::Msg);
+            };
+            let Some(confirmations) = exchange.finish(&share) else {
+                _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                error!("peer sent a PinShare which is not valid");
+                return Err(err::HandshakeError::Auth);
+            };
+            frame
+                .send(Connection::PinConfirmation(confirmations.ours))
+                .await?;
+            let Connection::PinConfirmation(confirmation) =
+                receive(&mut frame, "PinConfirmation").await?
+            else {
+                return Err(err::HandshakeError::Msg);
+            };
+            if verify_slices_are_equal(&confirmations.theirs, &confirmation).is_err() {
+                _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                error!("peer does not know the pin it showed");
+                return Err(err::HandshakeError::Auth);
+            }
+        }
+    }
+
+    // the remote user has a while to compare the codes
+    let Ok(response) = timeout(PAIR_TIMEOUT + Duration::from_secs(1), frame.next()).await else {
+        error!("peer timed out answering the pairing request");
+        return Err(err::HandshakeError::Timeout);
+    };
+    match response {
+        None => {
+            error!("peer closed the connection");
+            Err(err::HandshakeError::Disconnect)
+        }
+        Some(res) => match res? {
+            Connection::CompleteResponse(_) => {
+                let mut candidate = PeerCandidate::new(peer, auth);
+                candidate.addrs.extend(peer.addrs.iter().copied());
+                manager.paired(candidate.clone(), secret);
+                debug!("Peer is paired!");
+                Ok(candidate)
+            }
+            Connection::Failure(code) => {
+                error!("received error {} instead of accepting the pairing request", code);
+                Err(err::HandshakeError::Failure(code))
+            }
+            _ => {
+                error!("peer recieved the wrong message instead of accepting the pairing request");
+                Err(err::HandshakeError::Msg)
+            }
+        },
+    }
+}
+
+/// wait for the `expected` message while pairing, a failure the other side sends is returned as the error
+async fn receive(
+    frame: &mut Framed<BoxedStream, TracedCodec<ConnectionCodec>>,
+    expected: &str,
+) -> Result<Connection, err::HandshakeError> {
+    let Ok(response) = timeout(Duration::from_secs(1), frame.next()).await else {
+        error!("peer timed out sending its {}", expected);
+        _ = frame.send(Connection::Failure(TIMEOUT_ERR)).await;
+        return Err(err::HandshakeError::Timeout);
+    };
+    match response {
+        None => {
+            error!("peer closed the connection");
+            Err(err::HandshakeError::Disconnect)
+        }
+        Some(res) => match res? {
+            Connection::Failure(code) => {
+                error!("received error {} instead of {}", code, expected);
+                Err(err::HandshakeError::Failure(code))
+            }
+            res if res.kind() == expected => Ok(res),
+            _ => {
+                error!("peer recieved the wrong message instead of {}", expected);
+                Err(err::HandshakeError::Msg)
+            }
+        },
+    }
+}
+
+/// how the client of a pairing request shows its user wants to pair
+enum PairingProof {
+    /// the users compare a code made from both sides' nonces, the client committed to its own
+    Code { commitment: Vec<u8> },
+    /// the user typed the pin the local peer shows, proven by a [PinExchange]
+    Pin { share: Vec<u8> },
+}
+
+/// answer a pairing request once the local user accepts or declines it,
+/// or right away when it carries a proof of the pin the local peer shows
+async fn accept_pairing(
+    manager: &Arc<P2pManager>,
+    frame: &mut Framed<BoxedStream, TracedCodec<ConnectionCodec>>,
+    cert_id: Option<PeerId>,
+    metadata: PeerMetadata,
+    secret: Vec<u8>,
+    proof: PairingProof,
+) -> Result<(), err::HandshakeError> {
+    // only peers which can be discovered take requests, and only over TLS from the peer itself
+    if manager.is_paused() {
+        _ = frame.send(Connection::Failure(PAIR_DENIED_ERR)).await;
+        return Err(err::HandshakeError::NotFound);
+    }
+    if manager.is_blocked(&metadata.id) {
+        _ = frame.send(Connection::Failure(BLOCKED_ERR)).await;
+        debug!("blocked peer asked to pair");
+        return Err(err::HandshakeError::Failure(BLOCKED_ERR));
+    }
+    let (Some(cert_id), Ok(auth)) = (cert_id, PairingAuthenticator::new(secret.clone())) else {
+        _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+        error!("pairing requests need TLS and a valid secret");
+        return Err(err::HandshakeError::Auth);
+    };
+    if cert_id != metadata.id || String::from_utf8(secret.clone()).is_err() {
+        _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+        error!("peer asked to pair with a certificate or secret that is not valid");
+        return Err(err::HandshakeError::Auth);
+    }
+
+    let accepted = match proof {
+        PairingProof::Pin { share } => {
+            // the pin is used up by any attempt so it can't be guessed
+            let Some(pin) = manager.take_pin(&metadata.id) else {
+                _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                error!("peer asked to pair with a pin while none is shown");
+                return Err(err::HandshakeError::Auth);
+            };
+            let rng = manager.rng();
+            let exchange = PinExchange::server(&pin, &secret, &metadata.id, &manager.id, &*rng)?;
+            let ours = exchange.share();
+            let Some(confirmations) = exchange.finish(&share) else {
+                _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                error!("peer asked to pair with a share which is not valid");
+                return Err(err::HandshakeError::Auth);
+            };
+            frame.send(Connection::PinShare(ours)).await?;
+            let Connection::PinConfirmation(confirmation) =
+                receive(frame, "PinConfirmation").await?
+            else {
+                return Err(err::HandshakeError::Msg);
+            };
+            if verify_slices_are_equal(&confirmations.theirs, &confirmation).is_err() {
+                _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                error!("peer asked to pair with a pin that is not valid");
+                return Err(err::HandshakeError::Auth);
+            }
+            frame
+                .send(Connection::PinConfirmation(confirmations.ours))
+                .await?;
+            true
+        }
+        PairingProof::Code { commitment } => {
+            let nonce = pairing::new_nonce(&*manager.rng())?;
+            frame.send(Connection::PairNonce(nonce.clone())).await?;
+            let Connection::PairNonce(client_nonce) = receive(frame, "PairNonce").await? else {
+                return Err(err::HandshakeError::Msg);
+            };
+            if !pairing::verify_commitment(&commitment, &client_nonce) {
+                _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                error!("peer revealed a nonce it did not commit to");
+                return Err(err::HandshakeError::Auth);
+            }
+            let code = pairing::confirmation_code(
+                &secret,
+                &metadata.id,
+                &manager.id,
+                &client_nonce,
+                &nonce,
+            );
+            let answer = manager.pairing_requested(metadata.clone(), code);
+            match timeout(PAIR_TIMEOUT, answer).await {
+                Ok(Ok(accepted)) => accepted,
+                _ => {
+                    manager.pairing_expired(&metadata.id);
+                    false
+                }
+            }
+        }
+    };
+    if !accepted {
+        _ = frame.send(Connection::Failure(PAIR_DENIED_ERR)).await;
+        debug!("pairing request was declined");
+        return Err(err::HandshakeError::Failure(PAIR_DENIED_ERR));
+    }
+
+    let mut candidate = PeerCandidate::new(&metadata, auth);
+    candidate.addrs.extend(metadata.addrs.iter().copied());
+    frame.send(Connection::CompleteResponse(Features::ALL)).await?;
+    manager.paired(candidate, secret);
+    debug!("Peer is paired!");
+    Ok(())
+}
+
+/// handshake as the host to accept an incoming tcp connection as a connected peer.
+/// None is returned when the connection was only used to pair, which `relayed` connections
+/// can't do.
 pub(crate) async fn accept(
     manager: &Arc<P2pManager>,
-    conn: TcpStream,
-) -> Result<Peer, err::HandshakeError> {
-    let mut frame = Framed::new(conn, ConnectionCodec);
+    conn: BoxedStream,
+    relayed: bool,
+) -> Result<Option<Peer>, err::HandshakeError> {
+    // the client certificate is checked against the id it connects as
+    let (conn, cert_id) = match manager.tls() {
+        Some(tls) => {
+            let (conn, id) = timeout(Duration::from_secs(1), tls.accept(conn))
+                .await
+                .map_err(|_| err::HandshakeError::Timeout)??;
+            (conn, Some(id))
+        }
+        None => (conn, None),
+    };
+
+    let tracer = manager.start_trace();
+    let mut frame = Framed::new(conn, TracedCodec::new(ConnectionCodec, tracer.clone()));
 
     // timeout in 1 sec to ensure no bad intent
     // wait for a connect request
@@ -123,59 +443,53 @@ pub(crate) async fn accept(
         }
         Some(req) => {
             match req? {
-                Connection::Request { id, tag } => {
-                    let Some(peer) = manager.get_peer_candidate(&id) else {
-                        _ = frame.send(crate::proto::Connection::Failure(NOT_FOUND_ERR)).await;
-                        error!("peer is not known nor discovered");
-                        return Err(err::HandshakeError::NotFound);
-                    };
-                    debug!("validating peer's totp code");
-                    let code = peer.auth.generate().unwrap();
-                    let key = code.as_bytes();
-                    if let Err(e) = hmac::verify(key, peer.id.as_bytes(), &tag) {
-                        error!("Error verifying totp hmac: {:?}", e);
-                        _ = frame
-                            .send(crate::proto::Connection::Failure(AUTH_ERR))
-                            .await;
+                Connection::Request { id, tag, previous } => {
+                    manager.keep_trace(&id, tracer.as_ref());
+                    if cert_id.as_ref().is_some_and(|cert| *cert != id) {
+                        _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+                        error!("peer connected with a certificate that is not its own");
                         return Err(err::HandshakeError::Auth);
                     }
-                    let tag = hmac::sign(key, manager.id.as_bytes());
-                    // send a connect response & wait for a complete request
-                    frame
-                        .send(crate::proto::Connection::Response(tag.as_ref().to_vec()))
-                        .await?;
-                    let Ok(complete) = timeout(Duration::from_secs(1), frame.next()).await else {
-                        error!("peer timed out waiting for ConnectionCompleteRequest");
-                        _ = frame.send(crate::proto::Connection::Failure(TIMEOUT_ERR)).await;
-                        return Err(err::HandshakeError::Timeout);
-                    };
-                    match complete {
-                        Some(res) => {
-                            match res? {
-                                Connection::CompleteRequest => {
-                                    // send a complete response
-                                    frame.send(Connection::CompleteResponse).await?;
-                                    let connected = Peer::new(
-                                        manager,
-                                        crate::peer::ConnectionType::Server,
-                                        frame.into_inner(),
-                                        peer.metadata,
-                                    )
-                                    .unwrap();
-                                    debug!("Peer is connected!");
-                                    Ok(connected)
-                                }
-                                _ => {
-                                    error!("peer recieved the wrong message instead of ConnectionCompleteRequest");
-                                    Err(err::HandshakeError::Msg)
-                                }
-                            }
-                        }
-                        None => {
-                            error!("peer closed the connection");
-                            Err(err::HandshakeError::Disconnect)
-                        }
+                    // everyone relayed comes from the server's ip, so they are told apart by the
+                    // certificate, a claimed id is all there is without tls
+                    let key = cert_id.clone().unwrap_or_else(|| id.clone());
+                    if relayed && !manager.admit_relayed(&key) {
+                        _ = frame.send(Connection::Failure(CONNECTION_DENIED_ERR)).await;
+                        debug!("dropping the relayed connection from {}", key);
+                        return Err(err::HandshakeError::Failure(CONNECTION_DENIED_ERR));
+                    }
+                    let tags = [Some(tag), previous];
+                    let accepted = accept_request(manager, frame, cert_id, id, tags).await;
+                    if relayed {
+                        manager.relayed_handshake_done(&key, &accepted);
                     }
+                    accepted
+                }
+                // pairing needs the peer to be discovered nearby, never through a relay
+                Connection::PairRequest { .. } | Connection::PinPairRequest { .. } if relayed => {
+                    _ = frame.send(Connection::Failure(PAIR_DENIED_ERR)).await;
+                    error!("peer tried to pair through a relay");
+                    Err(err::HandshakeError::Failure(PAIR_DENIED_ERR))
+                }
+                Connection::PairRequest {
+                    metadata,
+                    secret,
+                    commitment,
+                } => {
+                    manager.keep_trace(&metadata.id, tracer.as_ref());
+                    let proof = PairingProof::Code { commitment };
+                    accept_pairing(manager, &mut frame, cert_id, metadata, secret, proof).await?;
+                    Ok(None)
+                }
+                Connection::PinPairRequest {
+                    metadata,
+                    secret,
+                    share,
+                } => {
+                    manager.keep_trace(&metadata.id, tracer.as_ref());
+                    let proof = PairingProof::Pin { share };
+                    accept_pairing(manager, &mut frame, cert_id, metadata, secret, proof).await?;
+                    Ok(None)
                 }
                 Connection::Failure(code) => {
                     error!("received error {} instead of ConnectionRequest", code);
@@ -189,3 +503,135 @@ pub(crate) async fn accept(
         }
     }
 }
+
+// store the secret a connected peer hands over, `used` is the one it connected with and stays
+// accepted in case the acknowledgement is lost
+async fn accept_rotation(
+    manager: &Arc<P2pManager>,
+    frame: &mut Framed<BoxedStream, TracedCodec<ConnectionCodec>>,
+    id: &PeerId,
+    used: PairingAuthenticator,
+) -> Result<(), err::HandshakeError> {
+    let Connection::Rotate(secret) = receive(frame, "Rotate").await? else {
+        return Err(err::HandshakeError::Msg);
+    };
+    let auth = match PairingAuthenticator::new(secret.clone()) {
+        Ok(auth) if String::from_utf8(secret).is_ok() => auth,
+        _ => {
+            _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+            error!("peer sent a secret that is not valid");
+            return Err(err::HandshakeError::Auth);
+        }
+    };
+    manager.rekey(id, auth, Some(used));
+    frame.send(Connection::RotateAck).await?;
+    Ok(())
+}
+
+// answer a connection request from a paired peer
+async fn accept_request(
+    manager: &Arc<P2pManager>,
+    mut frame: Framed<BoxedStream, TracedCodec<ConnectionCodec>>,
+    cert_id: Option<PeerId>,
+    id: PeerId,
+    tags: [Option<Vec<u8>>; 2],
+) -> Result<Option<Peer>, err::HandshakeError> {
+    if id == manager.id {
+        _ = frame.send(Connection::Failure(AUTH_ERR)).await;
+        error!("peer connected with this node's own id");
+        return Err(err::HandshakeError::Auth);
+    }
+    if manager.is_blocked(&id) {
+        _ = frame.send(Connection::Failure(BLOCKED_ERR)).await;
+        debug!("blocked peer tried to connect");
+        return Err(err::HandshakeError::Failure(BLOCKED_ERR));
+    }
+    let Some(peer) = manager.get_peer_candidate(&id) else {
+        _ = frame.send(crate::proto::Connection::Failure(NOT_FOUND_ERR)).await;
+        error!("peer is not known nor discovered");
+        return Err(err::HandshakeError::NotFound);
+    };
+    debug!("validating peer's totp code");
+    // the peer's code is looked for around now in case the clocks drifted apart, with every
+    // secret either side may still use
+    let signed = |code: &str| {
+        tags.iter()
+            .flatten()
+            .any(|tag| hmac::verify(code.as_bytes(), peer.id.as_bytes(), tag).is_ok())
+    };
+    let found = peer.keys().enumerate().find_map(|(index, auth)| {
+        let found = auth.find_skew(CLOCK_SKEW_SEARCH, signed).unwrap()?;
+        Some((index == 0, auth.clone(), found))
+    });
+    let Some((current, used, (skew, code))) = found else {
+        error!("Error verifying totp hmac");
+        _ = frame
+            .send(crate::proto::Connection::Failure(AUTH_ERR))
+            .await;
+        return Err(err::HandshakeError::Auth);
+    };
+    if skew.unsigned_abs() > CLOCK_SKEW_TOLERANCE.as_secs() {
+        error!("peer's clock is {}s off, beyond the tolerance", skew);
+        manager.report_clock_skew(&peer.id, skew);
+        _ = frame
+            .send(crate::proto::Connection::Failure(AUTH_ERR))
+            .await;
+        return Err(err::HandshakeError::Auth);
+    }
+    // answer with the peer's code so it verifies the response with its own clock
+    let key = code.as_bytes();
+    if !manager.has_room_for_peer() {
+        _ = frame.send(Connection::Failure(TOO_MANY_PEERS_ERR)).await;
+        debug!("too many peers are connected to accept another");
+        return Err(err::HandshakeError::Full);
+    }
+    if !manager.allows_connection(&peer.metadata) {
+        _ = frame.send(Connection::Failure(CONNECTION_DENIED_ERR)).await;
+        debug!("peer is not allowed to connect");
+        return Err(err::HandshakeError::Failure(CONNECTION_DENIED_ERR));
+    }
+    let tag = hmac::sign(key, manager.id.as_bytes());
+    // send a connect response & wait for a complete request
+    frame
+        .send(crate::proto::Connection::Response(tag.as_ref().to_vec()))
+        .await?;
+    let Ok(complete) = timeout(Duration::from_secs(1), frame.next()).await else {
+        error!("peer timed out waiting for ConnectionCompleteRequest");
+        _ = frame.send(crate::proto::Connection::Failure(TIMEOUT_ERR)).await;
+        return Err(err::HandshakeError::Timeout);
+    };
+    match complete {
+        Some(res) => {
+            match res? {
+                Connection::CompleteRequest(features) => {
+                    // send a complete response
+                    frame.send(Connection::CompleteResponse(Features::ALL)).await?;
+                    let features = Features::ALL.common(features);
+                    if features.contains(Features::ROTATE) && cert_id.is_some() {
+                        accept_rotation(manager, &mut frame, &peer.id, used).await?;
+                    } else if current && peer.previous.is_some() {
+                        manager.rekey(&peer.id, peer.auth.clone(), None);
+                    }
+                    let connected = Peer::new(
+                        manager,
+                        crate::peer::ConnectionType::Server,
+                        frame.into_inner(),
+                        peer.metadata,
+                        features,
+                    )
+                    .unwrap();
+                    debug!("Peer is connected!");
+                    Ok(Some(connected))
+                }
+                _ => {
+                    error!("peer recieved the wrong message instead of ConnectionCompleteRequest");
+                    Err(err::HandshakeError::Msg)
+                }
+            }
+        }
+        None => {
+            error!("peer closed the connection");
+            Err(err::HandshakeError::Disconnect)
+        }
+    }
+}
@@ -1,11 +1,117 @@
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use bytes::BufMut;
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
+use qrcodegen::{QrCode, QrCodeEcc};
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
 use totp_rs::{Secret, TOTP};
 
-use crate::err;
+use crate::{
+    discovery::PRESENCE_TAG_LEN,
+    err,
+    peer::{PeerId, PeerMetadata},
+    rng::Rng,
+};
+
+/// how long the user has to accept a pairing request or type a pin
+pub const PAIR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// how far apart the clocks of two peers can be and still connect
+pub const CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// how far apart clocks are searched when a connection fails, to tell the user their clock is off
+pub const CLOCK_SKEW_SEARCH: Duration = Duration::from_secs(600);
+
+/// The number of random bytes in a secret made for a pairing request
+const PAIRING_SECRET_LEN: usize = 20;
+
+/// Keeps presence tags apart from the codes made with the same secret
+const PRESENCE_CONTEXT: &[u8] = b"flydrop presence";
+
+/// The number of random bytes each side adds to the confirmation code
+pub(crate) const PAIRING_NONCE_LEN: usize = 32;
+
+/// Keeps the commitment to a nonce apart from other hashes of it
+const COMMITMENT_CONTEXT: &[u8] = b"flydrop pairing commitment";
+
+/// Keeps the points, scalars and keys of the pin exchange apart from other hashes
+const PIN_CONTEXT: &[u8] = b"flydrop pin";
 
 pub struct Png(String);
 
+/// A qr code as a square grid of modules, for front ends which can't decode images
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QrMatrix {
+    /// the number of modules along each side
+    pub size: usize,
+    /// the modules row by row, true when dark
+    pub modules: Vec<bool>,
+}
+
+impl QrMatrix {
+    /// encode `text` as a qr code
+    pub fn encode(text: &str) -> Result<Self, err::PairingError> {
+        let qr = QrCode::encode_text(text, QrCodeEcc::Medium)?;
+        let size = usize::try_from(qr.size()).unwrap();
+        let modules = (0..qr.size())
+            .flat_map(|y| (0..qr.size()).map(move |x| (x, y)))
+            .map(|(x, y)| qr.get_module(x, y))
+            .collect();
+        Ok(Self { size, modules })
+    }
+
+    /// the rows of the code, so small displays can draw it a line at a time
+    pub fn rows(&self) -> impl Iterator<Item = &[bool]> {
+        self.modules.chunks(self.size)
+    }
+}
+
+/// The pin a device offers to pair with, as a link another device scans from a qr code and
+/// pairs with through [crate::manager::P2pManager::pair_with_pin]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinInvitation {
+    /// the device offering the pin
+    pub id: PeerId,
+    /// the pin, valid for a single attempt
+    pub pin: String,
+}
+
+impl PinInvitation {
+    const PREFIX: &'static str = "flydrop://pair?";
+
+    pub fn to_url(&self) -> String {
+        format!("{}id={}&pin={}", Self::PREFIX, self.id, self.pin)
+    }
+
+    /// read an invitation back from the link in a scanned qr code
+    pub fn from_url(url: &str) -> Option<Self> {
+        let (mut id, mut pin) = (None, None);
+        for param in url.strip_prefix(Self::PREFIX)?.split('&') {
+            match param.split_once('=')? {
+                ("id", value) => id = PeerId::from_string(value.to_string()).ok(),
+                ("pin", value) if value.len() == 6 && value.bytes().all(|b| b.is_ascii_digit()) => {
+                    pin = Some(value.to_string())
+                }
+                _ => {}
+            }
+        }
+        Some(Self { id: id?, pin: pin? })
+    }
+
+    pub fn to_qr_matrix(&self) -> Result<QrMatrix, err::PairingError> {
+        QrMatrix::encode(&self.to_url())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PairingAuthenticator {
     totp: TOTP,
@@ -32,6 +138,11 @@ impl PairingAuthenticator {
         })
     }
 
+    /// the secret as the application stores it, secrets are printable
+    pub(crate) fn secret(&self) -> String {
+        String::from_utf8_lossy(&self.totp.secret).into_owned()
+    }
+
     pub fn to_qr_code(&self) -> Result<Png, err::PairingError> {
         let png = self.totp.get_qr().map_err(err::PairingError::QrCode)?;
         Ok(Png(png))
@@ -44,6 +155,66 @@ impl PairingAuthenticator {
     pub fn generate(&self) -> Result<String, err::PairingError> {
         Ok(self.totp.generate_current()?)
     }
+
+    /// find the code the peer used by trying the codes of the time steps around now, nearest
+    /// first, up to `limit` away. Returns how many seconds the peer's clock is ahead of this
+    /// one, negative when it is behind, with the code that `matches`.
+    pub(crate) fn find_skew(
+        &self,
+        limit: Duration,
+        matches: impl Fn(&str) -> bool,
+    ) -> Result<Option<(i64, String)>, err::PairingError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let step = self.totp.step;
+        let steps = (limit.as_secs() / step) as i64;
+        let offsets = (0..=steps).flat_map(|n| if n == 0 { vec![0] } else { vec![n, -n] });
+        for offset in offsets {
+            let skew = offset * step as i64;
+            let code = self.totp.generate(now.saturating_add_signed(skew));
+            if matches(&code) {
+                return Ok(Some((skew, code)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// a tag proving `metadata` was announced by a peer holding this secret, in the current
+    /// time step. There is none while the clock is before the unix epoch.
+    pub(crate) fn sign_presence(&self, metadata: &PeerMetadata) -> Option<[u8; PRESENCE_TAG_LEN]> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(self.sign_presence_at(metadata, now.as_secs()))
+    }
+
+    pub(crate) fn sign_presence_at(
+        &self,
+        metadata: &PeerMetadata,
+        time: u64,
+    ) -> [u8; PRESENCE_TAG_LEN] {
+        let data = presence_data(metadata, time / self.totp.step);
+        let tag = crate::hmac::sign(&self.totp.secret, &data);
+        tag.as_ref().try_into().unwrap()
+    }
+
+    /// whether `tag` was made for `metadata` by a peer holding this secret in a time step
+    /// within [CLOCK_SKEW_TOLERANCE] of now, so a captured presence soon goes stale
+    pub(crate) fn verify_presence(&self, metadata: &PeerMetadata, tag: &[u8]) -> bool {
+        let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+            return false;
+        };
+        let step = now.as_secs() / self.totp.step;
+        let steps = CLOCK_SKEW_TOLERANCE.as_secs() / self.totp.step;
+        (step.saturating_sub(steps)..=step + steps).any(|step| {
+            crate::hmac::verify(&self.totp.secret, &presence_data(metadata, step), tag).is_ok()
+        })
+    }
+}
+
+fn presence_data(metadata: &PeerMetadata, step: u64) -> Vec<u8> {
+    let mut data = bytes::BytesMut::from(PRESENCE_CONTEXT);
+    data.put_u64(step);
+    // metadata too long to encode can't be announced either, so its tag is never checked
+    _ = crate::proto::encode_metadata(metadata, &mut data);
+    data.to_vec()
 }
 
 impl ToString for PairingAuthenticator {
@@ -52,6 +223,178 @@ impl ToString for PairingAuthenticator {
     }
 }
 
+/// a new secret for a pairing request, hex encoded so it can be stored like any other password
+pub(crate) fn new_secret(rng: &dyn Rng) -> Result<Vec<u8>, ring::error::Unspecified> {
+    let mut secret = [0u8; PAIRING_SECRET_LEN];
+    rng.fill(&mut secret)?;
+    Ok(secret
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>()
+        .into_bytes())
+}
+
+/// a new pin for the user to type on the peer which pairs, it is never sent over the network
+pub(crate) fn new_pin(rng: &dyn Rng) -> Result<String, ring::error::Unspecified> {
+    let mut bytes = [0u8; 4];
+    rng.fill(&mut bytes)?;
+    Ok(format!("{:06}", u32::from_be_bytes(bytes) % 1_000_000))
+}
+
+/// One side of a SPAKE2 exchange over ristretto255 which proves both users know the pin without
+/// sending anything it can be recovered from. An exchange tests a single guess of the pin, a
+/// recorded one can't be searched offline.
+pub(crate) struct PinExchange {
+    client: bool,
+    scalar: Scalar,
+    password: Scalar,
+    share: RistrettoPoint,
+    context: Vec<u8>,
+}
+
+/// The key confirmations of a finished [PinExchange], each side proves it derived the same key
+pub(crate) struct PinConfirmations {
+    /// sent to the other side
+    pub(crate) ours: Vec<u8>,
+    /// expected from the other side
+    pub(crate) theirs: Vec<u8>,
+}
+
+impl PinExchange {
+    /// the exchange of the peer which asks to pair, its user typed the pin
+    pub(crate) fn client(
+        pin: &str,
+        secret: &[u8],
+        client: &PeerId,
+        server: &PeerId,
+        rng: &dyn Rng,
+    ) -> Result<Self, ring::error::Unspecified> {
+        Self::new(true, pin, secret, client, server, rng)
+    }
+
+    /// the exchange of the peer which shows the pin
+    pub(crate) fn server(
+        pin: &str,
+        secret: &[u8],
+        client: &PeerId,
+        server: &PeerId,
+        rng: &dyn Rng,
+    ) -> Result<Self, ring::error::Unspecified> {
+        Self::new(false, pin, secret, client, server, rng)
+    }
+
+    fn new(
+        client_side: bool,
+        pin: &str,
+        secret: &[u8],
+        client: &PeerId,
+        server: &PeerId,
+        rng: &dyn Rng,
+    ) -> Result<Self, ring::error::Unspecified> {
+        let mut random = [0u8; 64];
+        rng.fill(&mut random)?;
+        let scalar = Scalar::from_bytes_mod_order_wide(&random);
+        let password = Scalar::hash_from_bytes::<Sha512>(&[PIN_CONTEXT, pin.as_bytes()].concat());
+        let share = RISTRETTO_BASEPOINT_POINT * scalar + pin_blind(client_side) * password;
+
+        // the secret is bound to the key so it can't be swapped
+        let mut context = PIN_CONTEXT.to_vec();
+        context.extend_from_slice(client.as_bytes());
+        context.extend_from_slice(server.as_bytes());
+        context.extend_from_slice(&(secret.len() as u64).to_be_bytes());
+        context.extend_from_slice(secret);
+        Ok(Self {
+            client: client_side,
+            scalar,
+            password,
+            share,
+            context,
+        })
+    }
+
+    /// the share sent to the other side
+    pub(crate) fn share(&self) -> Vec<u8> {
+        self.share.compress().as_bytes().to_vec()
+    }
+
+    /// derive the key from the other side's share, None when the share is not a valid point
+    pub(crate) fn finish(self, theirs: &[u8]) -> Option<PinConfirmations> {
+        let theirs = CompressedRistretto::from_slice(theirs).ok()?.decompress()?;
+        let key = (theirs - pin_blind(!self.client) * self.password) * self.scalar;
+        let (client_share, server_share) = match self.client {
+            true => (self.share, theirs),
+            false => (theirs, self.share),
+        };
+
+        let mut context = Context::new(&SHA256);
+        context.update(&self.context);
+        context.update(client_share.compress().as_bytes());
+        context.update(server_share.compress().as_bytes());
+        context.update(key.compress().as_bytes());
+        context.update(self.password.as_bytes());
+        let key = context.finish();
+
+        let client = crate::hmac::sign(key.as_ref(), b"client").as_ref().to_vec();
+        let server = crate::hmac::sign(key.as_ref(), b"server").as_ref().to_vec();
+        Some(match self.client {
+            true => PinConfirmations {
+                ours: client,
+                theirs: server,
+            },
+            false => PinConfirmations {
+                ours: server,
+                theirs: client,
+            },
+        })
+    }
+}
+
+/// the point hiding the password in a share, M for the client and N for the server. Both are
+/// hashed to the group so no one knows their discrete logarithm.
+fn pin_blind(client: bool) -> RistrettoPoint {
+    let name: &[u8] = if client { b" M" } else { b" N" };
+    RistrettoPoint::hash_from_bytes::<Sha512>(&[PIN_CONTEXT, name].concat())
+}
+
+/// a new nonce for the confirmation code of a pairing request
+pub(crate) fn new_nonce(rng: &dyn Rng) -> Result<Vec<u8>, ring::error::Unspecified> {
+    let mut nonce = vec![0u8; PAIRING_NONCE_LEN];
+    rng.fill(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// the client commits to its nonce in the pairing request and reveals it only once it has the
+/// server's, so neither side can pick its nonce to choose the code
+pub(crate) fn commitment(nonce: &[u8]) -> Vec<u8> {
+    let mut context = Context::new(&SHA256);
+    context.update(COMMITMENT_CONTEXT);
+    context.update(nonce);
+    context.finish().as_ref().to_vec()
+}
+
+pub(crate) fn verify_commitment(commitment: &[u8], nonce: &[u8]) -> bool {
+    ring::constant_time::verify_slices_are_equal(&self::commitment(nonce), commitment).is_ok()
+}
+
+/// the code both devices show for a pairing request, the user accepts it only if they match
+pub fn confirmation_code(
+    secret: &[u8],
+    client: &PeerId,
+    server: &PeerId,
+    client_nonce: &[u8],
+    server_nonce: &[u8],
+) -> String {
+    let mut context = Context::new(&SHA256);
+    context.update(secret);
+    context.update(client.as_bytes());
+    context.update(server.as_bytes());
+    context.update(client_nonce);
+    context.update(server_nonce);
+    let digest = context.finish();
+    let code = u32::from_be_bytes(digest.as_ref()[..4].try_into().unwrap()) % 1_000_000;
+    format!("{code:06}")
+}
+
 impl FromStr for PairingAuthenticator {
     type Err = err::PairingError;
 
@@ -64,3 +407,102 @@ impl FromStr for PairingAuthenticator {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use ring::rand::SystemRandom;
+
+    use super::{
+        commitment, confirmation_code, new_nonce, verify_commitment, PairingAuthenticator,
+        PinExchange, PinInvitation, QrMatrix,
+    };
+    use crate::peer::PeerId;
+
+    #[test]
+    fn skew_is_found_within_the_limit() {
+        let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec()).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let ahead = auth.totp.generate(now + 120);
+
+        let (skew, code) = auth
+            .find_skew(Duration::from_secs(600), |code| code == ahead)
+            .unwrap()
+            .unwrap();
+        // a step may have passed since the code was made
+        assert!((105..=135).contains(&skew), "skew was {}", skew);
+        assert_eq!(ahead, code);
+
+        let found = auth.find_skew(Duration::from_secs(60), |code| code == ahead);
+        assert_eq!(None, found.unwrap());
+    }
+
+    #[test]
+    fn code_depends_on_both_nonces() {
+        let client = PeerId::from_string("a".repeat(40)).unwrap();
+        let server = PeerId::from_string("b".repeat(40)).unwrap();
+        let rng = SystemRandom::new();
+        let (ours, theirs) = (new_nonce(&rng).unwrap(), new_nonce(&rng).unwrap());
+
+        let commitment = commitment(&ours);
+        assert!(verify_commitment(&commitment, &ours));
+        assert!(!verify_commitment(&commitment, &theirs));
+
+        let code = confirmation_code(b"secret", &client, &server, &ours, &theirs);
+        assert_eq!(6, code.len());
+        assert_eq!(code, confirmation_code(b"secret", &client, &server, &ours, &theirs));
+        assert_ne!(code, confirmation_code(b"secret", &client, &server, &ours, &ours));
+    }
+
+    #[test]
+    fn pin_exchange_agrees_on_the_same_pin_only() {
+        let client = PeerId::from_string("a".repeat(40)).unwrap();
+        let server = PeerId::from_string("b".repeat(40)).unwrap();
+        let rng = SystemRandom::new();
+        let exchange = |pin_a: &str, pin_b: &str| {
+            let a = PinExchange::client(pin_a, b"secret", &client, &server, &rng).unwrap();
+            let b = PinExchange::server(pin_b, b"secret", &client, &server, &rng).unwrap();
+            let (share_a, share_b) = (a.share(), b.share());
+            (a.finish(&share_b).unwrap(), b.finish(&share_a).unwrap())
+        };
+
+        let (a, b) = exchange("123456", "123456");
+        assert_eq!(a.ours, b.theirs);
+        assert_eq!(b.ours, a.theirs);
+        assert_ne!(a.ours, a.theirs);
+
+        let (a, b) = exchange("123456", "123457");
+        assert_ne!(a.ours, b.theirs);
+        assert_ne!(b.ours, a.theirs);
+
+        let a = PinExchange::client("123456", b"secret", &client, &server, &rng).unwrap();
+        assert!(a.finish(&[0xff; 32]).is_none());
+    }
+
+    #[test]
+    fn pin_invitation_round_trips_through_its_qr_code() {
+        let invitation = PinInvitation {
+            id: PeerId::from_string("QWERTYUIOPQWERTYUIOPQWERTYUIOPQWERTYUIOP".into()).unwrap(),
+            pin: "042917".into(),
+        };
+        let url = invitation.to_url();
+        assert_eq!(Some(invitation.clone()), PinInvitation::from_url(&url));
+
+        let matrix = invitation.to_qr_matrix().unwrap();
+        assert_eq!(QrMatrix::encode(&url).unwrap(), matrix);
+        assert_eq!(matrix.size * matrix.size, matrix.modules.len());
+        assert_eq!(matrix.size, matrix.rows().count());
+        // every corner but the bottom right holds a finder pattern framed in dark modules
+        let dark = |x: usize, y: usize| matrix.modules[y * matrix.size + x];
+        let end = matrix.size - 1;
+        for (x, y) in [(0, 0), (end - 6, 0), (0, end - 6)] {
+            assert!((0..7).all(|i| dark(x + i, y) && dark(x + i, y + 6)));
+            assert!((0..7).all(|i| dark(x, y + i) && dark(x + 6, y + i)));
+            assert!(!dark(x + 1, y + 1) && dark(x + 3, y + 3));
+        }
+
+        assert_eq!(None, PinInvitation::from_url(&url.replace("042917", "42917")));
+        assert_eq!(None, PinInvitation::from_url(&url.replace("flydrop", "http")));
+    }
+}
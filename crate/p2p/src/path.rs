@@ -0,0 +1,155 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The kind of network path an address is reached over, from most to least preferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum PathKind {
+    /// a private or link-local IPv4 address on the local network
+    Lan,
+    /// a unique local or link-local IPv6 address on the local network
+    LanV6,
+    /// any other address, like a VPN overlay or a public address
+    Other,
+}
+
+impl PathKind {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_private() || ip.is_link_local() || ip.is_loopback() => {
+                PathKind::Lan
+            }
+            IpAddr::V6(ip)
+                if ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local() =>
+            {
+                PathKind::LanV6
+            }
+            _ => PathKind::Other,
+        }
+    }
+}
+
+/// Order the addresses of a peer by preference: the manually preferred address first, then by
+/// kind of path, then by how fast each was last dialed. Addresses never dialed come after
+/// measured ones of the same kind.
+pub(crate) fn order<'a>(
+    addrs: impl IntoIterator<Item = &'a SocketAddr>,
+    preferred: Option<SocketAddr>,
+    latency: &HashMap<SocketAddr, Duration>,
+) -> Vec<SocketAddr> {
+    let mut ordered: Vec<SocketAddr> = addrs.into_iter().copied().collect();
+    ordered.sort_by_key(|addr| {
+        (
+            Some(*addr) != preferred,
+            PathKind::of(addr),
+            latency.get(addr).copied().unwrap_or(Duration::MAX),
+            *addr,
+        )
+    });
+    ordered
+}
+
+/// how many round trips are kept in the latency history of a peer
+pub const LATENCY_HISTORY: usize = 32;
+
+/// The latest round trips to a connected peer, measured by pinging it while the connection is idle
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LatencyHistory {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyHistory {
+    pub(crate) fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == LATENCY_HISTORY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// the round trips from oldest to newest
+    pub fn samples(&self) -> Vec<Duration> {
+        self.samples.iter().copied().collect()
+    }
+
+    pub fn latest(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    /// the mean difference between consecutive round trips
+    pub fn jitter(&self) -> Duration {
+        let changes = self.samples.len().saturating_sub(1);
+        if changes == 0 {
+            return Duration::ZERO;
+        }
+        let total: Duration = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .map(|(a, b)| a.abs_diff(*b))
+            .sum();
+        total / changes as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+    use super::{order, LatencyHistory, PathKind, LATENCY_HISTORY};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn paths_are_classified() {
+        assert_eq!(PathKind::Lan, PathKind::of(&addr("192.168.1.5:5001")));
+        assert_eq!(PathKind::Lan, PathKind::of(&addr("169.254.3.4:5001")));
+        assert_eq!(PathKind::LanV6, PathKind::of(&addr("[fd00::5]:5001")));
+        assert_eq!(PathKind::LanV6, PathKind::of(&addr("[fe80::5]:5001")));
+        // a vpn overlay in the shared address space
+        assert_eq!(PathKind::Other, PathKind::of(&addr("100.64.0.5:5001")));
+        assert_eq!(PathKind::Other, PathKind::of(&addr("[2001:db8::5]:5001")));
+    }
+
+    #[test]
+    fn lan_is_preferred_then_faster_paths() {
+        let vpn = addr("100.64.0.5:5001");
+        let v6 = addr("[fd00::5]:5001");
+        let slow = addr("192.168.1.5:5001");
+        let fast = addr("10.0.0.5:5001");
+        let latency = HashMap::from([
+            (vpn, Duration::from_millis(1)),
+            (slow, Duration::from_millis(50)),
+            (fast, Duration::from_millis(5)),
+        ]);
+        let addrs = [vpn, v6, slow, fast];
+        assert_eq!(vec![fast, slow, v6, vpn], order(&addrs, None, &latency));
+
+        // a manual override wins over everything
+        assert_eq!(vec![vpn, fast, slow, v6], order(&addrs, Some(vpn), &latency));
+    }
+
+    #[test]
+    fn latency_history_is_bounded_with_jitter() {
+        let mut history = LatencyHistory::default();
+        assert_eq!(Duration::ZERO, history.jitter());
+
+        for ms in [10, 20, 10, 30] {
+            history.record(Duration::from_millis(ms));
+        }
+        // (10 + 10 + 20) / 3
+        assert_eq!(Duration::from_nanos(13_333_333), history.jitter());
+        assert_eq!(Some(Duration::from_millis(30)), history.latest());
+
+        for _ in 0..LATENCY_HISTORY {
+            history.record(Duration::from_millis(5));
+        }
+        assert_eq!(vec![Duration::from_millis(5); LATENCY_HISTORY], history.samples());
+        assert_eq!(Duration::ZERO, history.jitter());
+    }
+}
@@ -1,6 +1,6 @@
 use std::{fmt, net::Ipv4Addr, ops::Deref};
 
-use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType, PKCS_ED25519};
 use ring::digest::digest;
 use serde::{Deserialize, Serialize};
 
@@ -24,7 +24,7 @@ impl PeerId {
     /// from_cert will derive a [PeerId] from a [rustls::Certificate].
     pub fn from_cert(cert: &rustls::Certificate) -> Self {
         // SHA-1 is used due to the limitation of the length of a DNS record used for mDNS local network discovery.
-        let peer_id = digest(&ring::digest::SHA256, &cert.0)
+        let peer_id = digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, &cert.0)
             .as_ref()
             .iter()
             .map(|b| format!("{b:02x}"))
@@ -67,6 +67,11 @@ impl Default for PeerId {
 /// The common name of the identity certificate generated by fd-cdp.
 const CERTIFICATE_COMMON_NAME: &str = "fd-p2p-identity";
 
+/// The PKCS#8 v1 header of an Ed25519 private key, followed by the 32 byte seed.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
 /// Is the identity which respresents the current peer. An Identity is made from a public key and a private key combo. [crate::PeerId]'s are derived from the public key portion of a peer's [Identity].
 /// The public key is safe to share while the private key must remain private to ensure the connections between peers are secure.
 #[derive(Clone, Serialize, Deserialize)]
@@ -78,13 +83,32 @@ pub struct Identity {
 impl Identity {
     /// Create a new Identity for the current peer.
     pub fn new() -> Self {
+        Self::from_params(Self::params())
+    }
+
+    /// Create the same Identity every time for a seed, so tests and simulations are reproducible.
+    /// The key is derived directly from the seed, never use this for a real peer.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+        pkcs8.extend_from_slice(&seed);
+
+        let mut parameters = Self::params();
+        parameters.alg = &PKCS_ED25519;
+        parameters.key_pair = Some(KeyPair::from_der(&pkcs8).unwrap());
+        Self::from_params(parameters)
+    }
+
+    fn params() -> CertificateParams {
         let mut parameters: CertificateParams = Default::default();
         parameters.distinguished_name = DistinguishedName::new();
         parameters
             .distinguished_name
             .push(DnType::CommonName, CERTIFICATE_COMMON_NAME);
         parameters.subject_alt_names = vec![SanType::IpAddress(Ipv4Addr::LOCALHOST.into())];
+        parameters
+    }
 
+    fn from_params(parameters: CertificateParams) -> Self {
         let cert = rcgen::Certificate::from_params(parameters).unwrap();
 
         Self {
@@ -114,3 +138,24 @@ impl Identity {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::{Identity, PeerId};
+
+    fn peer_id(identity: Identity) -> PeerId {
+        PeerId::from_cert(&identity.into_rustls().0)
+    }
+
+    #[test]
+    fn seeded_identity_is_reproducible() {
+        assert_eq!(
+            peer_id(Identity::from_seed([7; 32])),
+            peer_id(Identity::from_seed([7; 32]))
+        );
+        assert_ne!(
+            peer_id(Identity::from_seed([7; 32])),
+            peer_id(Identity::from_seed([8; 32]))
+        );
+    }
+}
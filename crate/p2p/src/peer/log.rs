@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use super::{PeerId, PeerMetadata};
+
+/// The latest change to a discovered peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum PeerChange {
+    /// the peer was discovered or what is known about it changed
+    Updated(PeerMetadata),
+    /// the peer is no longer discovered
+    Removed(PeerId),
+}
+
+/// The changes to the discovered peers since a sequence number
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PeerDelta {
+    /// the sequence to ask for changes since next time
+    pub sequence: u64,
+    pub changes: Vec<PeerChange>,
+}
+
+/// Records the latest change of every discovered peer so callers can poll for what changed
+#[derive(Debug, Default)]
+pub(crate) struct PeerLog {
+    sequence: u64,
+    changes: HashMap<PeerId, (u64, PeerChange)>,
+}
+
+impl PeerLog {
+    pub(crate) fn updated(&mut self, metadata: PeerMetadata) {
+        self.record(metadata.id.clone(), PeerChange::Updated(metadata));
+    }
+
+    pub(crate) fn removed(&mut self, id: PeerId) {
+        self.record(id.clone(), PeerChange::Removed(id));
+    }
+
+    fn record(&mut self, id: PeerId, change: PeerChange) {
+        self.sequence += 1;
+        self.changes.insert(id, (self.sequence, change));
+    }
+
+    /// the latest change of every peer which changed after `sequence`, oldest first
+    pub(crate) fn since(&self, sequence: u64) -> PeerDelta {
+        let mut changes: Vec<_> = self
+            .changes
+            .values()
+            .filter(|(seq, _)| *seq > sequence)
+            .collect();
+        changes.sort_by_key(|(seq, _)| *seq);
+        PeerDelta {
+            sequence: self.sequence,
+            changes: changes.into_iter().map(|(_, c)| c.clone()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::peer::{DeviceType, PeerChange, PeerId, PeerLog, PeerMetadata};
+
+    fn metadata(id: &str, name: &str) -> PeerMetadata {
+        PeerMetadata {
+            name: name.to_string(),
+            typ: DeviceType::LinuxDevice,
+            id: PeerId::from_string(id.repeat(40)).unwrap(),
+            addrs: vec![SocketAddr::from(([127, 0, 0, 1], 5001))],
+        }
+    }
+
+    #[test]
+    fn since_returns_latest_changes() {
+        let mut log = PeerLog::default();
+        log.updated(metadata("a", "first"));
+        log.updated(metadata("b", "second"));
+        let delta = log.since(0);
+        assert_eq!(2, delta.sequence);
+        assert_eq!(2, delta.changes.len());
+
+        // only the latest change of a peer is returned
+        log.updated(metadata("a", "renamed"));
+        log.removed(metadata("b", "second").id);
+        let delta = log.since(delta.sequence);
+        assert_eq!(4, delta.sequence);
+        assert_eq!(
+            vec![
+                PeerChange::Updated(metadata("a", "renamed")),
+                PeerChange::Removed(metadata("b", "second").id)
+            ],
+            delta.changes
+        );
+        assert!(log.since(4).changes.is_empty());
+    }
+}
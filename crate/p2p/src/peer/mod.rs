@@ -1,5 +1,7 @@
 mod id;
+mod log;
 mod peer;
 
 pub use id::*;
+pub use log::*;
 pub use peer::*;
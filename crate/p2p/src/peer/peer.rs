@@ -1,8 +1,25 @@
-use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, hash::Hash, net::SocketAddr, sync::Arc};
-use tokio::{io::DuplexStream, net::TcpStream};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::HashSet, hash::Hash, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::Notify,
+    time::{interval, timeout_at, Instant},
+};
+use tokio_util::codec::Framed;
 
-use crate::{manager::P2pManager, pairing::PairingAuthenticator};
+use crate::{
+    discovery::DiscoverySource,
+    err::ParseError,
+    manager::P2pManager,
+    pairing::PairingAuthenticator,
+    proto::{Control, ControlCodec, Features, CHUNK_LEN, MAX_DATA_LEN},
+    transport::BoxedStream,
+};
+
+/// how many pings an idle connection gets to answer before it is closed
+const KEEPALIVE_PINGS: u32 = 3;
 
 use super::PeerId;
 
@@ -16,8 +33,23 @@ pub struct PeerMetadata {
     pub name: String,
     pub typ: DeviceType,
     pub id: PeerId,
-    pub addr: std::net::SocketAddr, //pub ip: String,
-                                    //pub port: u16
+    /// every address the peer can be reached at, in order of preference
+    #[serde(alias = "addr", deserialize_with = "one_or_many")]
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// configs written before peers had many addresses stored a single `addr`
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
 }
 
 impl Hash for PeerMetadata {
@@ -42,6 +74,8 @@ impl Hash for PeerMetadata {
 #[repr(u16)]
 #[derive(Eq)]
 pub enum DeviceType {
+    /// the peer chose not to share its device type
+    Unknown = 0,
     // XboxOne = 1,
     AppleiPhone = 6,
     AppleiPad = 7,
@@ -73,7 +107,10 @@ pub struct PeerCandidate {
     pub id: PeerId,
     pub metadata: PeerMetadata,
     pub addrs: HashSet<SocketAddr>,
+    pub sources: HashSet<DiscoverySource>,
     pub auth: PairingAuthenticator,
+    /// the secret before the last rotation, accepted until the peer shows it has the current one
+    pub previous: Option<PairingAuthenticator>,
 }
 
 impl PeerCandidate {
@@ -81,17 +118,24 @@ impl PeerCandidate {
         Self {
             id: metadata.id.clone(),
             addrs: HashSet::new(),
+            sources: HashSet::new(),
             auth,
+            previous: None,
             metadata: metadata.clone(),
         }
     }
+
+    /// every secret the peer may use, the current one first
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &PairingAuthenticator> {
+        std::iter::once(&self.auth).chain(&self.previous)
+    }
 }
 
 /// This emum represents the type of the connection to the current peer.
 /// QUIC is a client/server protocol so when doing P2P communication one client will be the server and one will be the client from a QUIC perspective.
 /// The protocol is bi-directional so this doesn't matter a huge amount and the P2P library does it's best to hide this detail from the embedding application as thinking about this can be very confusing.
 /// The decision for who is the client and server should be treated as arbitrary and shouldn't affect how the protocol operates.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum ConnectionType {
     /// I am the QUIC (soon) server.
     Server,
@@ -99,6 +143,15 @@ pub enum ConnectionType {
     Client,
 }
 
+/// The live connection with a peer, see [P2pManager::connection]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    pub id: PeerId,
+    pub conn_type: ConnectionType,
+    /// the remote address the connection uses
+    pub addr: SocketAddr,
+}
+
 /// Represents a currently connected peer. This struct holds the connection as well as any information
 /// the network manager may required about the remote peer.
 /// It also stores a reference to the network manager for communication back to the [P2PManager].
@@ -123,18 +176,27 @@ pub struct Peer {
 
 impl Peer {
     /// create a new peer from a network connection.
-    /// Peers can only be created after mutual validation of pairing codes
+    /// Peers can only be created after mutual validation of pairing codes, `features` are the
+    /// ones both peers understand
     pub(crate) fn new(
         manager: &Arc<P2pManager>,
         conn_type: ConnectionType,
-        conn: TcpStream,
+        conn: BoxedStream,
         metadata: PeerMetadata,
+        features: Features,
     ) -> Result<Self, ()> {
-        let (transport, application) = tokio::io::duplex(64);
+        let (transport, application) = tokio::io::duplex(CHUNK_LEN);
 
         let id = metadata.id.clone();
         let m = manager.clone();
-        tokio::spawn(handler(conn, application, m, id.clone()));
+        let hangup = manager.hangup(&id);
+        let timeout = manager.keepalive_timeout();
+        if features.contains(Features::CONTROL) {
+            let chunks = features.contains(Features::CHUNK);
+            manager.spawn(handler(conn, application, m, id.clone(), hangup, timeout, chunks));
+        } else {
+            manager.spawn(raw_handler(conn, application, m, id.clone(), hangup));
+        }
 
         Ok(Self {
             id,
@@ -145,8 +207,140 @@ impl Peer {
     }
 }
 
-/// continuously running handler for transporting data between local peer & remote peer
-async fn handler(conn: TcpStream, app: DuplexStream, manager: Arc<P2pManager>, id: PeerId) {
+/// continuously running handler for transporting data between local peer & remote peer.
+/// An idle connection is pinged, measuring the round trip, and closed once the remote peer stops
+/// answering for `timeout`. Application data is sent in chunks when the remote peer understands
+/// them, in data frames otherwise.
+async fn handler(
+    conn: BoxedStream,
+    app: DuplexStream,
+    manager: Arc<P2pManager>,
+    id: PeerId,
+    hangup: Arc<Notify>,
+    timeout: Duration,
+    chunks: bool,
+) {
+    let mut transport = Framed::new(conn, ControlCodec);
+    let (mut app_reader, mut app_writer) = tokio::io::split(app);
+    // frames are split off this buffer and copied once, into the transport's write buffer. The
+    // encoded frame is dropped and the buffer takes its allocation back, so a transfer reuses the
+    // same memory throughout
+    let mut buffer = BytesMut::with_capacity(CHUNK_LEN);
+    let mut keepalive = interval(timeout / KEEPALIVE_PINGS);
+    let mut last_heard = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
+
+    // a peer which stopped reading would block a send, and the keepalive with it, forever
+    async fn send(
+        transport: &mut Framed<BoxedStream, ControlCodec>,
+        frame: Control,
+        deadline: Instant,
+    ) -> Result<(), ParseError> {
+        match timeout_at(deadline, transport.send(frame)).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        }
+    }
+
+    loop {
+        tokio::select! {
+            frame = transport.next() => {
+                last_heard = Instant::now();
+                match frame {
+                    Some(Ok(Control::Data(data))) => {
+                        if let Err(e) = app_writer.write_all(&data).await {
+                            tracing::error!("error occured writing data to application {:?}", e);
+                            break;
+                        }
+                    }
+                    Some(Ok(Control::Chunk(chunk))) => {
+                        if let Err(e) = app_writer.write_all(&chunk).await {
+                            tracing::error!("error occured writing data to application {:?}", e);
+                            break;
+                        }
+                    }
+                    Some(Ok(Control::Ping)) => {
+                        if let Err(e) = send(&mut transport, Control::Pong, last_heard + timeout).await {
+                            tracing::error!("error occured answering a ping {:?}", e);
+                            break;
+                        }
+                    }
+                    Some(Ok(Control::Pong)) => {
+                        if let Some(sent) = ping_sent.take() {
+                            manager.record_latency(&id, sent.elapsed());
+                        }
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!("error occured reading data from transport {:?}", e);
+                        break;
+                    }
+                    None => {
+                        tracing::debug!("transport buffer drained");
+                        break;
+                    }
+                }
+            },
+            result = app_reader.read_buf(&mut buffer) => {
+                match result {
+                    Ok(0) => {
+                        tracing::debug!("application buffer drained");
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!("error occured reading data from application {:?}", e);
+                        break;
+                    }
+                    Ok(_) => {
+                        let mut sent = Ok(());
+                        while !buffer.is_empty() && sent.is_ok() {
+                            let frame = if chunks {
+                                Control::Chunk(buffer.split().freeze())
+                            } else {
+                                let len = buffer.len().min(MAX_DATA_LEN);
+                                Control::Data(buffer.split_to(len).to_vec())
+                            };
+                            sent = send(&mut transport, frame, last_heard + timeout).await;
+                        }
+                        if let Err(e) = sent {
+                            tracing::error!("error occured writing data to transport {:?}", e);
+                            break;
+                        }
+                        buffer.reserve(CHUNK_LEN);
+                    }
+                }
+            }
+            _ = keepalive.tick() => {
+                let silent = last_heard.elapsed();
+                if silent >= timeout {
+                    tracing::warn!("peer {} stopped answering, closing the connection", id);
+                    break;
+                }
+                if silent >= timeout / KEEPALIVE_PINGS {
+                    ping_sent.get_or_insert_with(Instant::now);
+                    if let Err(e) = send(&mut transport, Control::Ping, last_heard + timeout).await {
+                        tracing::warn!("peer {} stopped reading, closing the connection {:?}", id, e);
+                        break;
+                    }
+                }
+            }
+            _ = hangup.notified() => {
+                tracing::debug!("connection closed by the application");
+                break;
+            }
+        }
+    }
+    manager.peer_disconnected(&id, &hangup);
+}
+
+/// the handler for peers which don't understand [Features::CONTROL], the bytes are copied as they
+/// are and the connection is never pinged
+async fn raw_handler(
+    conn: BoxedStream,
+    app: DuplexStream,
+    manager: Arc<P2pManager>,
+    id: PeerId,
+    hangup: Arc<Notify>,
+) {
     let (mut transport_reader, mut transport_writer) = tokio::io::split(conn);
     let (mut app_reader, mut app_writer) = tokio::io::split(app);
 
@@ -178,7 +372,11 @@ async fn handler(conn: TcpStream, app: DuplexStream, manager: Arc<P2pManager>, i
                     _ => {}
                 }
             }
+            _ = hangup.notified() => {
+                tracing::debug!("connection closed by the application");
+                break;
+            }
         }
     }
-    manager.peer_disconnected(&id);
+    manager.peer_disconnected(&id, &hangup);
 }
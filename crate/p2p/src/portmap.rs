@@ -0,0 +1,400 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use igd_next::{aio::tokio::Tokio, PortMappingProtocol, SearchOptions};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::err::PortMapError;
+
+/// The port NAT-PMP gateways listen on
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// The group UPnP gateways are searched on
+pub const SSDP_MULTICAST: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+
+/// A UPnP Internet Gateway Device
+pub type UpnpGateway = igd_next::aio::Gateway<Tokio>;
+
+/// How long the search for a UPnP gateway waits for one to answer
+const UPNP_SEARCH_WAIT: Duration = Duration::from_secs(3);
+
+/// What the mapping is listed as on the gateway
+const UPNP_DESCRIPTION: &str = "flydrop";
+
+/// How long a mapping is asked for, it is renewed halfway through
+pub const MAPPING_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// How long the first request waits for an answer, every retry waits twice as long
+const FIRST_WAIT: Duration = Duration::from_millis(250);
+
+/// Requests sent before the gateway is given up on
+const ATTEMPTS: u32 = 4;
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+const OP_RESPONSE: u8 = 128;
+
+/// A TCP port forwarded by the gateway to the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    /// the address peers outside the subnet reach the listener on
+    pub external: SocketAddr,
+    /// how long the gateway keeps the mapping without a renewal
+    pub lifetime: Duration,
+}
+
+/// The gateway of a lan most likely is the first host on its subnet. Routers handing out
+/// another address need it to be configured instead.
+pub fn guess_gateway(lan: &[IpAddr]) -> Option<SocketAddr> {
+    lan.iter().find_map(|ip| match ip {
+        IpAddr::V4(ip) if ip.is_private() => {
+            let [a, b, c, _] = ip.octets();
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(a, b, c, 1),
+                NAT_PMP_PORT,
+            )))
+        }
+        _ => None,
+    })
+}
+
+/// The ip of this host on the subnet of `gateway`, the one a mapping forwards to
+pub fn local_ip(lan: &[IpAddr], gateway: SocketAddr) -> Option<IpAddr> {
+    let IpAddr::V4(gateway) = gateway.ip() else {
+        return None;
+    };
+    lan.iter()
+        .find(|ip| match ip {
+            IpAddr::V4(ip) => ip.octets()[..3] == gateway.octets()[..3],
+            IpAddr::V6(_) => false,
+        })
+        .copied()
+}
+
+/// ask the NAT-PMP `gateway` to forward a tcp port to `port` on this host for `lifetime`
+pub async fn map_tcp(
+    gateway: SocketAddr,
+    port: u16,
+    lifetime: Duration,
+) -> Result<PortMapping, PortMapError> {
+    let socket = connect(gateway).await?;
+    let ip = decode_external_address(&request(&socket, &[VERSION, OP_EXTERNAL_ADDRESS]).await?)?;
+    let (external_port, lifetime) = decode_mapping(
+        &request(&socket, &encode_mapping(port, port, lifetime)).await?,
+        port,
+    )?;
+    Ok(PortMapping {
+        external: SocketAddr::V4(SocketAddrV4::new(ip, external_port)),
+        lifetime,
+    })
+}
+
+/// ask the NAT-PMP `gateway` to stop forwarding to `port` on this host
+pub async fn unmap_tcp(gateway: SocketAddr, port: u16) -> Result<(), PortMapError> {
+    let socket = connect(gateway).await?;
+    let response = request(&socket, &encode_mapping(port, 0, Duration::ZERO)).await?;
+    decode_mapping(&response, port).map(|_| ())
+}
+
+/// search for the UPnP gateway of the lan `local` is on, sending the search to `ssdp`
+pub async fn search_upnp(local: IpAddr, ssdp: SocketAddr) -> Result<UpnpGateway, PortMapError> {
+    let options = SearchOptions {
+        bind_addr: SocketAddr::new(local, 0),
+        broadcast_address: ssdp,
+        timeout: Some(UPNP_SEARCH_WAIT),
+        ..Default::default()
+    };
+    igd_next::aio::tokio::search_gateway(options)
+        .await
+        .map_err(upnp_error)
+}
+
+/// ask the UPnP `gateway` to forward the port of `local` to it for `lifetime`
+pub async fn map_tcp_upnp(
+    gateway: &UpnpGateway,
+    local: SocketAddr,
+    lifetime: Duration,
+) -> Result<PortMapping, PortMapError> {
+    let ip = gateway.get_external_ip().await.map_err(upnp_error)?;
+    let lease = lifetime.as_secs() as u32;
+    gateway
+        .add_port(PortMappingProtocol::TCP, local.port(), local, lease, UPNP_DESCRIPTION)
+        .await
+        .map_err(upnp_error)?;
+    Ok(PortMapping {
+        external: SocketAddr::new(ip, local.port()),
+        lifetime,
+    })
+}
+
+/// ask the UPnP `gateway` to stop forwarding `port`
+pub async fn unmap_tcp_upnp(gateway: &UpnpGateway, port: u16) -> Result<(), PortMapError> {
+    gateway
+        .remove_port(PortMappingProtocol::TCP, port)
+        .await
+        .map_err(upnp_error)
+}
+
+fn upnp_error(error: impl std::error::Error) -> PortMapError {
+    PortMapError::Upnp(error.to_string())
+}
+
+async fn connect(gateway: SocketAddr) -> Result<UdpSocket, PortMapError> {
+    let socket =
+        UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket.connect(gateway).await?;
+    Ok(socket)
+}
+
+// send a request until the gateway answers, waiting longer after every attempt
+async fn request(socket: &UdpSocket, request: &[u8]) -> Result<Vec<u8>, PortMapError> {
+    let mut buf = [0u8; 16];
+    let mut wait = FIRST_WAIT;
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await?;
+        if let Ok(len) = timeout(wait, socket.recv(&mut buf)).await {
+            return Ok(buf[..len?].to_vec());
+        }
+        wait *= 2;
+    }
+    Err(PortMapError::Timeout)
+}
+
+fn encode_mapping(internal: u16, external: u16, lifetime: Duration) -> Vec<u8> {
+    let mut request = vec![VERSION, OP_MAP_TCP, 0, 0];
+    request.extend_from_slice(&internal.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request
+}
+
+// check the header shared by every response, returning the rest of it
+fn decode_header(response: &[u8], op: u8) -> Result<&[u8], PortMapError> {
+    if response.len() < 8 || response[0] != VERSION || response[1] != OP_RESPONSE + op {
+        return Err(PortMapError::Malformed);
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(&response[8..]),
+        code => Err(PortMapError::Refused(code)),
+    }
+}
+
+fn decode_external_address(response: &[u8]) -> Result<Ipv4Addr, PortMapError> {
+    match decode_header(response, OP_EXTERNAL_ADDRESS)? {
+        &[a, b, c, d] => Ok(Ipv4Addr::new(a, b, c, d)),
+        _ => Err(PortMapError::Malformed),
+    }
+}
+
+fn decode_mapping(response: &[u8], port: u16) -> Result<(u16, Duration), PortMapError> {
+    let body = decode_header(response, OP_MAP_TCP)?;
+    if body.len() != 8 || u16::from_be_bytes([body[0], body[1]]) != port {
+        return Err(PortMapError::Malformed);
+    }
+    let external = u16::from_be_bytes([body[2], body[3]]);
+    let lifetime = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+    Ok((external, Duration::from_secs(lifetime.into())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    use crate::err::PortMapError;
+
+    use super::{
+        guess_gateway, local_ip, map_tcp, map_tcp_upnp, search_upnp, unmap_tcp, unmap_tcp_upnp,
+        NAT_PMP_PORT,
+    };
+
+    const UPNP_DEVICE: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0"><device><serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<SCPDURL>/scpd.xml</SCPDURL><controlURL>/control</controlURL>
+</service></serviceList></device></root>"#;
+
+    const UPNP_ACTIONS: &str = r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0"><actionList>
+<action><name>AddPortMapping</name><argumentList>
+<argument><name>NewExternalPort</name><direction>in</direction></argument>
+<argument><name>NewProtocol</name><direction>in</direction></argument>
+<argument><name>NewInternalPort</name><direction>in</direction></argument>
+<argument><name>NewInternalClient</name><direction>in</direction></argument>
+<argument><name>NewLeaseDuration</name><direction>in</direction></argument>
+</argumentList></action>
+<action><name>DeletePortMapping</name><argumentList>
+<argument><name>NewExternalPort</name><direction>in</direction></argument>
+<argument><name>NewProtocol</name><direction>in</direction></argument>
+</argumentList></action>
+</actionList></scpd>"#;
+
+    // answer a UPnP search like an Internet Gateway Device with the external ip 203.0.113.7,
+    // returns where to send the search and the body of every action it was asked for
+    async fn upnp_gateway() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/root.xml", http.local_addr().unwrap());
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let requested = actions.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = http.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // read the headers and as much of the body as they announce
+                let (head, body) = loop {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = head
+                        .to_lowercase()
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:")?.trim().parse().ok())
+                        .unwrap_or(0);
+                    if len == 0 || body.len() >= length {
+                        break (head.to_string(), body.to_string());
+                    }
+                };
+                let response = if head.starts_with("GET /root.xml") {
+                    UPNP_DEVICE.to_string()
+                } else if head.starts_with("GET /scpd.xml") {
+                    UPNP_ACTIONS.to_string()
+                } else {
+                    let action = ["GetExternalIPAddress", "AddPortMapping", "DeletePortMapping"]
+                        .into_iter()
+                        .find(|action| head.contains(&format!("#{}\"", action)))
+                        .unwrap();
+                    requested.lock().unwrap().push(body);
+                    let ip = if action == "GetExternalIPAddress" {
+                        "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>"
+                    } else {
+                        ""
+                    };
+                    format!(
+                        r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body>
+<u:{action}Response xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+{ip}</u:{action}Response>
+</s:Body></s:Envelope>"#
+                    )
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((_, from)) = ssdp.recv_from(&mut buf).await {
+                let response = format!("HTTP/1.1 200 OK\r\nLOCATION: {}\r\n\r\n", location);
+                ssdp.send_to(response.as_bytes(), from).await.unwrap();
+            }
+        });
+        (addr, actions)
+    }
+
+    // answer NAT-PMP requests like a gateway with the external ip 203.0.113.7 which maps every
+    // port to one 10000 higher, or refuses everything with `refuse`
+    async fn gateway(refuse: Option<u16>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let op = buf[1];
+                let mut response = vec![0, 128 + op];
+                response.extend_from_slice(&refuse.unwrap_or(0).to_be_bytes());
+                response.extend_from_slice(&42u32.to_be_bytes());
+                if op == 0 {
+                    response.extend_from_slice(&[203, 0, 113, 7]);
+                } else if len == 12 {
+                    let port = u16::from_be_bytes([buf[4], buf[5]]);
+                    response.extend_from_slice(&port.to_be_bytes());
+                    response.extend_from_slice(&(port + 10000).to_be_bytes());
+                    response.extend_from_slice(&buf[8..12]);
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn ports_are_mapped_and_unmapped() {
+        let mapping = map_tcp(gateway(None).await, 5000, Duration::from_secs(600))
+            .await
+            .unwrap();
+        assert_eq!(
+            "203.0.113.7:15000".parse::<SocketAddr>().unwrap(),
+            mapping.external
+        );
+        assert_eq!(Duration::from_secs(600), mapping.lifetime);
+        unmap_tcp(gateway(None).await, 5000).await.unwrap();
+
+        let refused = map_tcp(gateway(Some(2)).await, 5000, Duration::from_secs(600)).await;
+        assert!(matches!(refused, Err(PortMapError::Refused(2))));
+    }
+
+    #[tokio::test]
+    async fn ports_are_mapped_and_unmapped_over_upnp() {
+        let (ssdp, actions) = upnp_gateway().await;
+        let gateway = search_upnp(IpAddr::V4(Ipv4Addr::LOCALHOST), ssdp).await.unwrap();
+        let local = "127.0.0.1:5000".parse().unwrap();
+        let mapping = map_tcp_upnp(&gateway, local, Duration::from_secs(600)).await.unwrap();
+        assert_eq!(
+            "203.0.113.7:5000".parse::<SocketAddr>().unwrap(),
+            mapping.external
+        );
+        assert_eq!(Duration::from_secs(600), mapping.lifetime);
+        unmap_tcp_upnp(&gateway, 5000).await.unwrap();
+
+        let actions = actions.lock().unwrap();
+        assert_eq!(3, actions.len());
+        assert!(actions[1].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        assert!(actions[1].contains("<NewLeaseDuration>600</NewLeaseDuration>"));
+        assert!(actions[2].contains("u:DeletePortMapping "));
+        assert!(actions[2].contains("<NewExternalPort>5000</NewExternalPort>"));
+    }
+
+    #[test]
+    fn local_ip_is_on_the_gateway_subnet() {
+        let lan = [
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 4, 23)),
+        ];
+        let gateway = "192.168.4.1:5351".parse().unwrap();
+        assert_eq!(Some(lan[1]), local_ip(&lan, gateway));
+        assert_eq!(None, local_ip(&lan[..1], gateway));
+    }
+
+    #[test]
+    fn gateway_is_guessed_from_private_ips() {
+        let lan = [
+            IpAddr::V4(Ipv4Addr::new(100, 64, 0, 9)),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 4, 23)),
+        ];
+        let gateway = guess_gateway(&lan).unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::new(192, 168, 4, 1)), gateway.ip());
+        assert_eq!(NAT_PMP_PORT, gateway.port());
+        assert_eq!(None, guess_gateway(&lan[..1]));
+    }
+}
@@ -1,11 +1,12 @@
 use std::net::SocketAddr;
 
 use byteorder::{BigEndian, ReadBytesExt};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{
+    discovery::{Presence, PRESENCE_TAG_LEN},
     err, event,
     peer::{DeviceType, PeerId, PeerMetadata},
 };
@@ -34,35 +35,48 @@ impl Decoder for DiscoveryCodec {
             return Err(Self::Error::MsgType(header.message_type));
         }
 
-        match src.get_u8() {
+        let mut body = header.body(src)?;
+        match body.try_get_u8()? {
             0 => Ok(Some(event::DiscoveryEvent::PresenceRequest)),
             1 => {
-                let device_type_raw = src.get_u16();
-                let device_name_length = src.get_u16();
-                let device_name_bytes = src.split_to(device_name_length.into());
-                let device_name_raw = &device_name_bytes[..];
-                let device_name = String::from_utf8(device_name_raw.to_vec()).unwrap();
-                let device_id_raw = src.split_to(40);
-                let device_id = String::from_utf8(device_id_raw.to_vec()).unwrap();
-                let id = PeerId::from_string(device_id)?;
-                let device_addr_length = src.get_u16();
-                let device_addr_bytes = src.split_to(device_addr_length.into());
-                let device_addr_str = String::from_utf8(device_addr_bytes.to_vec()).unwrap();
-                let device_addr: SocketAddr = device_addr_str.parse()?;
-                let device_type = DeviceType::try_from_primitive(device_type_raw)?;
-
-                Ok(Some(event::DiscoveryEvent::PresenceResponse(
-                    PeerMetadata {
-                        typ: device_type,
-                        name: device_name,
-                        id,
-                        addr: device_addr,
-                    },
-                )))
+                let metadata = decode_metadata(&mut body)?;
+                // responses of older peers end with the metadata
+                let mut tags = Vec::new();
+                if body.has_remaining() {
+                    let count = usize::from(body.try_get_u8()?);
+                    if body.remaining() != count * PRESENCE_TAG_LEN {
+                        return Err(Self::Error::NotAPacket);
+                    }
+                    for _ in 0..count {
+                        let mut tag = [0u8; PRESENCE_TAG_LEN];
+                        body.copy_to_slice(&mut tag);
+                        tags.push(tag);
+                    }
+                }
+                Ok(Some(event::DiscoveryEvent::PresenceResponse(Presence {
+                    metadata,
+                    tags,
+                })))
             }
             x => Err(Self::Error::Enum(x.into())),
         }
     }
+
+    // a datagram ends where its frame does, drop whatever could not be read so the same bytes
+    // are not decoded again instead of the next datagram
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src) {
+            Ok(None) if !src.is_empty() => {
+                src.clear();
+                Err(Self::Error::Truncated)
+            }
+            Err(error) => {
+                src.clear();
+                Err(error)
+            }
+            decoded => decoded,
+        }
+    }
 }
 
 impl Encoder<event::DiscoveryEvent> for DiscoveryCodec {
@@ -73,44 +87,195 @@ impl Encoder<event::DiscoveryEvent> for DiscoveryCodec {
         item: event::DiscoveryEvent,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        HeaderCodec.encode(Header::new(MessageType::Discovery, &item), dst)?;
+        HeaderCodec.encode(Header::new(MessageType::Discovery, &item)?, dst)?;
         match item {
             event::DiscoveryEvent::PresenceRequest => {
                 dst.put_u8(0); // DiscoveryType
             }
-            event::DiscoveryEvent::PresenceResponse(metadata) => {
+            event::DiscoveryEvent::PresenceResponse(presence) => {
                 dst.put_u8(1); // DiscoveryType
-                dst.put_u16(metadata.typ.into()); // DeviceType
-                dst.put_u16(metadata.name.len().try_into().unwrap()); // DeviceNameLength
-                dst.put(metadata.name.as_bytes()); // DeviceName
-                dst.put(metadata.id.as_bytes()); // DeviceId
-                let addr = metadata.addr.to_string(); // DeviceAddressLength
-                dst.put_u16(u16::try_from(addr.len()).unwrap()); // DeviceAddress
-                dst.put(addr.as_bytes());
+                encode_metadata(&presence.metadata, dst)?;
+                dst.put_u8(short(presence.tags.len())?); // TagCount
+                for tag in &presence.tags {
+                    dst.put(&tag[..]); // Tag
+                }
             }
         }
         Ok(())
     }
 }
 
+/// the encoded length of a peer's metadata
+pub(crate) fn metadata_len(metadata: &PeerMetadata) -> usize {
+    2 + 2 + metadata.name.len() + 40 + 2 + encode_addrs(&metadata.addrs).len()
+}
+
+pub(crate) fn encode_metadata(
+    metadata: &PeerMetadata,
+    dst: &mut BytesMut,
+) -> Result<(), err::ParseError> {
+    dst.put_u16(metadata.typ.into()); // DeviceType
+    dst.put_u16(short(metadata.name.len())?); // DeviceNameLength
+    dst.put(metadata.name.as_bytes()); // DeviceName
+    dst.put(metadata.id.as_bytes()); // DeviceId
+    let addr = encode_addrs(&metadata.addrs); // DeviceAddressLength
+    dst.put_u16(short(addr.len())?); // DeviceAddress
+    dst.put(addr.as_bytes());
+    Ok(())
+}
+
+// a length which has to fit the field it is written to
+fn short<T: TryFrom<usize>>(len: usize) -> Result<T, err::ParseError> {
+    T::try_from(len).map_err(|_| err::ParseError::TooLong(len))
+}
+
+fn decode_metadata(src: &mut BytesMut) -> Result<PeerMetadata, err::ParseError> {
+    let device_type_raw = src.try_get_u16()?;
+    let device_name_length = src.try_get_u16()?;
+    let device_name_bytes = take(src, device_name_length.into())?;
+    let device_name = String::from_utf8(device_name_bytes.to_vec())?;
+    let device_id_raw = take(src, 40)?;
+    let device_id = String::from_utf8(device_id_raw.to_vec())?;
+    let id = PeerId::from_string(device_id)?;
+    let device_addr_length = src.try_get_u16()?;
+    let device_addr_bytes = take(src, device_addr_length.into())?;
+    let device_addr_str = String::from_utf8(device_addr_bytes.to_vec())?;
+    let device_addrs = decode_addrs(&device_addr_str)?;
+    let device_type = DeviceType::try_from_primitive(device_type_raw)?;
+
+    Ok(PeerMetadata {
+        typ: device_type,
+        name: device_name,
+        id,
+        addrs: device_addrs,
+    })
+}
+
+/// split the next `len` bytes off a frame, failing when the frame ends first
+fn take(src: &mut BytesMut, len: usize) -> Result<BytesMut, err::ParseError> {
+    if src.len() < len {
+        return Err(err::ParseError::Truncated);
+    }
+    Ok(src.split_to(len))
+}
+
+/// Addresses are sent as a comma separated list so a single address is encoded exactly as before
+pub(crate) fn encode_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub(crate) fn decode_addrs(addrs: &str) -> Result<Vec<SocketAddr>, err::ParseError> {
+    addrs
+        .split(',')
+        .filter(|a| !a.is_empty())
+        .map(|a| Ok(a.parse()?))
+        .collect()
+}
+
 pub struct ConnectionCodec;
 
+/// What a peer understands beyond the original protocol, sent with the completion of the
+/// connection handshake. Older peers send no features and ignore the ones they are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u8);
+
+impl Features {
+    /// connected peers exchange [Control] frames instead of raw bytes, idle links are pinged
+    pub const CONTROL: Features = Features(1);
+
+    /// application data is sent in [Control::Chunk] frames, otherwise in [Control::Data] ones
+    pub const CHUNK: Features = Features(2);
+
+    /// the connecting peer sends a fresh pairing secret once connected over TLS, see
+    /// [Connection::Rotate]
+    pub const ROTATE: Features = Features(4);
+
+    /// every feature this implementation understands
+    pub const ALL: Features = Features(7);
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// the features both peers understand
+    pub fn common(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Connection {
-    Request { id: PeerId, tag: Vec<u8> }, // sent by client
+    Request {
+        id: PeerId,
+        tag: Vec<u8>,
+        previous: Option<Vec<u8>>,
+    }, // sent by client, also signed with the secret before the last rotation while it is kept
     Response(Vec<u8>),                    // sent by host
-    CompleteRequest,                      // sent by client
-    CompleteResponse,                     // sent by host
+    CompleteRequest(Features),            // sent by client
+    CompleteResponse(Features),           // sent by host
     Failure(u32),                         // sent by either on error
+    PairRequest {
+        metadata: PeerMetadata,
+        secret: Vec<u8>,
+        commitment: Vec<u8>,
+    }, // sent by an unpaired client, answered with CompleteResponse once the user accepts
+    PinPairRequest {
+        metadata: PeerMetadata,
+        secret: Vec<u8>,
+        share: Vec<u8>,
+    }, // sent by an unpaired client whose user typed the server's pin, answered without asking
+    PairNonce(Vec<u8>), // sent by the host after a PairRequest, then by the client to reveal its own
+    PinShare(Vec<u8>),  // sent by the host after a PinPairRequest
+    PinConfirmation(Vec<u8>), // sent by the client after a PinShare, then by the host
+    Rotate(Vec<u8>), // sent by the client after CompleteResponse, the secret to use from now on
+    RotateAck,       // sent by the host once it stored the secret of a Rotate
+}
+
+impl Connection {
+    /// the name of the message, used when tracing connections
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Connection::Request { .. } => "Request",
+            Connection::Response(_) => "Response",
+            Connection::CompleteRequest(_) => "CompleteRequest",
+            Connection::CompleteResponse(_) => "CompleteResponse",
+            Connection::Failure(_) => "Failure",
+            Connection::PairRequest { .. } => "PairRequest",
+            Connection::PinPairRequest { .. } => "PinPairRequest",
+            Connection::PairNonce(_) => "PairNonce",
+            Connection::PinShare(_) => "PinShare",
+            Connection::PinConfirmation(_) => "PinConfirmation",
+            Connection::Rotate(_) => "Rotate",
+            Connection::RotateAck => "RotateAck",
+        }
+    }
 }
 
 impl Frame for Connection {
-    fn len(&self) -> u16 {
+    fn len(&self) -> usize {
         match self {
-            Connection::Request { .. } => 1 + 40 + 32,
+            Connection::Request { previous, .. } => {
+                1 + 40 + 32 + if previous.is_some() { 32 } else { 0 }
+            }
             Connection::Response(_) => 1 + 32,
-            Connection::CompleteRequest => 1,
-            Connection::CompleteResponse => 1,
+            Connection::CompleteRequest(_) => 1 + 1,
+            Connection::CompleteResponse(_) => 1 + 1,
             Connection::Failure(_) => 1 + 4,
+            Connection::PairRequest {
+                metadata, secret, ..
+            }
+            | Connection::PinPairRequest {
+                metadata, secret, ..
+            } => 1 + metadata_len(metadata) + 2 + secret.len() + 32,
+            Connection::PairNonce(_) | Connection::PinShare(_) | Connection::PinConfirmation(_) => {
+                1 + 32
+            }
+            Connection::Rotate(secret) => 1 + 2 + secret.len(),
+            Connection::RotateAck => 1,
         }
     }
 }
@@ -129,24 +294,66 @@ impl Decoder for ConnectionCodec {
             return Err(Self::Error::MsgType(header.message_type));
         }
 
-        match src.get_u8() {
+        let src = &mut header.body(src)?;
+        match src.try_get_u8()? {
             0 => {
-                let peer_id_raw = src.split_to(40);
-                let peer_id =
-                    PeerId::from_string(String::from_utf8(peer_id_raw.to_vec()).unwrap()).unwrap();
-                let hmac = src.split_to(32).to_vec();
+                let peer_id_raw = take(src, 40)?;
+                let peer_id = PeerId::from_string(String::from_utf8(peer_id_raw.to_vec())?)?;
+                let hmac = take(src, 32)?.to_vec();
+                // older peers only send the one tag
+                let previous = if src.is_empty() {
+                    None
+                } else {
+                    Some(take(src, 32)?.to_vec())
+                };
                 Ok(Some(Connection::Request {
                     id: peer_id,
                     tag: hmac,
+                    previous,
                 }))
             }
             1 => {
-                let hmac = src.split_to(32).to_vec();
+                let hmac = take(src, 32)?.to_vec();
                 Ok(Some(Connection::Response(hmac)))
             }
-            2 => Ok(Some(Connection::CompleteRequest)),
-            3 => Ok(Some(Connection::CompleteResponse)),
-            4 => Ok(Some(Connection::Failure(src.get_u32()))),
+            // older peers end the message before the features
+            2 => Ok(Some(Connection::CompleteRequest(Features(
+                src.try_get_u8().unwrap_or_default(),
+            )))),
+            3 => Ok(Some(Connection::CompleteResponse(Features(
+                src.try_get_u8().unwrap_or_default(),
+            )))),
+            4 => Ok(Some(Connection::Failure(src.try_get_u32()?))),
+            5 => {
+                let metadata = decode_metadata(src)?;
+                let secret_length = src.try_get_u16()?;
+                let secret = take(src, secret_length.into())?.to_vec();
+                let commitment = take(src, 32)?.to_vec();
+                Ok(Some(Connection::PairRequest {
+                    metadata,
+                    secret,
+                    commitment,
+                }))
+            }
+            6 => {
+                let metadata = decode_metadata(src)?;
+                let secret_length = src.try_get_u16()?;
+                let secret = take(src, secret_length.into())?.to_vec();
+                let share = take(src, 32)?.to_vec();
+                Ok(Some(Connection::PinPairRequest {
+                    metadata,
+                    secret,
+                    share,
+                }))
+            }
+            7 => Ok(Some(Connection::PairNonce(take(src, 32)?.to_vec()))),
+            8 => Ok(Some(Connection::PinShare(take(src, 32)?.to_vec()))),
+            9 => Ok(Some(Connection::PinConfirmation(take(src, 32)?.to_vec()))),
+            10 => {
+                let secret_length = src.try_get_u16()?;
+                Ok(Some(Connection::Rotate(take(src, secret_length.into())?.to_vec())))
+            }
+            11 => Ok(Some(Connection::RotateAck)),
             x => Err(Self::Error::Enum(x.into())),
         }
     }
@@ -156,27 +363,301 @@ impl Encoder<Connection> for ConnectionCodec {
     type Error = err::ParseError;
 
     fn encode(&mut self, item: Connection, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        HeaderCodec.encode(Header::new(MessageType::Connect, &item), dst)?;
+        HeaderCodec.encode(Header::new(MessageType::Connect, &item)?, dst)?;
         match item {
-            Connection::Request { id, tag } => {
+            Connection::Request { id, tag, previous } => {
                 dst.put_u8(0);
                 dst.put(id.as_bytes());
                 dst.put(tag.as_ref());
+                if let Some(previous) = previous {
+                    dst.put(previous.as_ref());
+                }
             }
             Connection::Response(tag) => {
                 dst.put_u8(1);
                 dst.put(tag.as_ref());
             }
-            Connection::CompleteRequest => {
+            Connection::CompleteRequest(features) => {
                 dst.put_u8(2);
+                dst.put_u8(features.0);
             }
-            Connection::CompleteResponse => {
+            Connection::CompleteResponse(features) => {
                 dst.put_u8(3);
+                dst.put_u8(features.0);
             }
             Connection::Failure(code) => {
                 dst.put_u8(4);
                 dst.put_u32(code);
             }
+            Connection::PairRequest {
+                metadata,
+                secret,
+                commitment,
+            } => {
+                dst.put_u8(5);
+                encode_metadata(&metadata, dst)?;
+                dst.put_u16(short(secret.len())?);
+                dst.put(secret.as_ref());
+                dst.put(commitment.as_ref());
+            }
+            Connection::PinPairRequest {
+                metadata,
+                secret,
+                share,
+            } => {
+                dst.put_u8(6);
+                encode_metadata(&metadata, dst)?;
+                dst.put_u16(short(secret.len())?);
+                dst.put(secret.as_ref());
+                dst.put(share.as_ref());
+            }
+            Connection::PairNonce(nonce) => {
+                dst.put_u8(7);
+                dst.put(nonce.as_ref());
+            }
+            Connection::PinShare(share) => {
+                dst.put_u8(8);
+                dst.put(share.as_ref());
+            }
+            Connection::PinConfirmation(confirmation) => {
+                dst.put_u8(9);
+                dst.put(confirmation.as_ref());
+            }
+            Connection::Rotate(secret) => {
+                dst.put_u8(10);
+                dst.put_u16(short(secret.len())?);
+                dst.put(secret.as_ref());
+            }
+            Connection::RotateAck => dst.put_u8(11),
+        }
+        Ok(())
+    }
+}
+
+pub struct ControlCodec;
+
+/// the largest chunk of application data a single frame carries
+pub(crate) const MAX_DATA_LEN: usize = 8192;
+
+/// how much application data the peer writer sends in one chunk
+pub(crate) const CHUNK_LEN: usize = 64 * 1024;
+
+/// the largest chunk accepted from a peer
+pub(crate) const MAX_CHUNK_LEN: usize = 1024 * 1024;
+
+/// the common header, the control type and the length of a chunk
+const CHUNK_HEADER_LEN: usize = 10;
+
+/// Frames exchanged by connected peers, application data is wrapped so keep-alives can share the link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    Data(Vec<u8>), // application bytes, at most MAX_DATA_LEN
+    Ping,          // sent on an idle connection, answered with Pong
+    Pong,
+    Chunk(Bytes), // application bytes after a length prefix, at most MAX_CHUNK_LEN
+}
+
+impl Frame for Control {
+    fn len(&self) -> usize {
+        match self {
+            Control::Data(data) => 1 + data.len(),
+            Control::Ping => 1,
+            Control::Pong => 1,
+            // the message length ends at the prefix, the chunk itself follows the frame
+            Control::Chunk(_) => 1 + 4,
+        }
+    }
+}
+
+// the length of the chunk at the start of `src`, if it starts with one
+fn peek_chunk(src: &BytesMut) -> Option<usize> {
+    let frame = src.get(..CHUNK_HEADER_LEN)?;
+    if frame[..2] != SIGNATURE || frame[4] != u8::from(MessageType::Control) || frame[5] != 3 {
+        return None;
+    }
+    Some(u32::from_be_bytes([frame[6], frame[7], frame[8], frame[9]]) as usize)
+}
+
+impl Decoder for ControlCodec {
+    type Item = Control;
+
+    type Error = err::ParseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // nothing of a chunk is consumed before all of it arrived
+        if let Some(len) = peek_chunk(src) {
+            if len > MAX_CHUNK_LEN {
+                return Err(Self::Error::TooLong(len));
+            }
+            if src.len() < CHUNK_HEADER_LEN + len {
+                src.reserve(CHUNK_HEADER_LEN + len - src.len());
+                return Ok(None);
+            }
+        }
+
+        let Some(header) = HeaderCodec.decode(src)? else {
+            return Ok(None);
+        };
+
+        if header.message_type != MessageType::Control {
+            return Err(Self::Error::MsgType(header.message_type));
+        }
+
+        let mut body = header.body(src)?;
+        match body.try_get_u8()? {
+            0 => Ok(Some(Control::Data(body.to_vec()))),
+            1 => Ok(Some(Control::Ping)),
+            2 => Ok(Some(Control::Pong)),
+            3 => {
+                let len = body.try_get_u32()? as usize;
+                Ok(Some(Control::Chunk(take(src, len)?.freeze())))
+            }
+            x => Err(Self::Error::Enum(x.into())),
+        }
+    }
+}
+
+impl Encoder<Control> for ControlCodec {
+    type Error = err::ParseError;
+
+    fn encode(&mut self, item: Control, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match &item {
+            Control::Data(data) if data.len() > MAX_DATA_LEN => {
+                return Err(Self::Error::TooLong(data.len()))
+            }
+            Control::Chunk(chunk) if chunk.len() > MAX_CHUNK_LEN => {
+                return Err(Self::Error::TooLong(chunk.len()))
+            }
+            _ => {}
+        }
+        HeaderCodec.encode(Header::new(MessageType::Control, &item)?, dst)?;
+        match item {
+            Control::Data(data) => {
+                dst.put_u8(0);
+                dst.put(data.as_ref());
+            }
+            Control::Ping => dst.put_u8(1),
+            Control::Pong => dst.put_u8(2),
+            Control::Chunk(chunk) => {
+                dst.reserve(4 + chunk.len());
+                dst.put_u8(3);
+                dst.put_u32(chunk.len() as u32);
+                dst.put(chunk);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct RendezvousCodec;
+
+/// Frames exchanged with a rendezvous server, which introduces paired peers on different networks
+/// and relays between them when they can't reach each other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rendezvous {
+    Register(PeerMetadata), // sent by a peer to be found, the connection stays open for Incoming
+    Lookup(PeerId),         // sent by a peer looking for another
+    Found(PeerMetadata),    // sent by the server, the addresses start with the public ones it saw
+    Missing,                // sent by the server when the peer is not registered
+    Relay(PeerId),          // sent by a peer to be relayed to another, answered with Relayed
+    Incoming(u64),          // sent by the server to a registered peer someone is relayed to
+    Attach(u64),            // sent by the registered peer on a new connection to join the session
+    Relayed,                // sent by the server once both ends are joined, raw bytes follow
+}
+
+impl Rendezvous {
+    /// the name of the message
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Rendezvous::Register(_) => "Register",
+            Rendezvous::Lookup(_) => "Lookup",
+            Rendezvous::Found(_) => "Found",
+            Rendezvous::Missing => "Missing",
+            Rendezvous::Relay(_) => "Relay",
+            Rendezvous::Incoming(_) => "Incoming",
+            Rendezvous::Attach(_) => "Attach",
+            Rendezvous::Relayed => "Relayed",
+        }
+    }
+}
+
+impl Frame for Rendezvous {
+    fn len(&self) -> usize {
+        match self {
+            Rendezvous::Register(metadata) | Rendezvous::Found(metadata) => {
+                1 + metadata_len(metadata)
+            }
+            Rendezvous::Lookup(_) | Rendezvous::Relay(_) => 1 + 40,
+            Rendezvous::Incoming(_) | Rendezvous::Attach(_) => 1 + 8,
+            Rendezvous::Missing | Rendezvous::Relayed => 1,
+        }
+    }
+}
+
+impl Decoder for RendezvousCodec {
+    type Item = Rendezvous;
+
+    type Error = err::ParseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(header) = HeaderCodec.decode(src)? else {
+            return Ok(None);
+        };
+
+        if header.message_type != MessageType::Rendezvous {
+            return Err(Self::Error::MsgType(header.message_type));
+        }
+
+        let decode_id = |src: &mut BytesMut| -> Result<PeerId, err::ParseError> {
+            Ok(PeerId::from_string(String::from_utf8_lossy(&take(src, 40)?).into_owned())?)
+        };
+        let src = &mut header.body(src)?;
+        match src.try_get_u8()? {
+            0 => Ok(Some(Rendezvous::Register(decode_metadata(src)?))),
+            1 => Ok(Some(Rendezvous::Lookup(decode_id(src)?))),
+            2 => Ok(Some(Rendezvous::Found(decode_metadata(src)?))),
+            3 => Ok(Some(Rendezvous::Missing)),
+            4 => Ok(Some(Rendezvous::Relay(decode_id(src)?))),
+            5 => Ok(Some(Rendezvous::Incoming(src.try_get_u64()?))),
+            6 => Ok(Some(Rendezvous::Attach(src.try_get_u64()?))),
+            7 => Ok(Some(Rendezvous::Relayed)),
+            x => Err(Self::Error::Enum(x.into())),
+        }
+    }
+}
+
+impl Encoder<Rendezvous> for RendezvousCodec {
+    type Error = err::ParseError;
+
+    fn encode(&mut self, item: Rendezvous, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        HeaderCodec.encode(Header::new(MessageType::Rendezvous, &item)?, dst)?;
+        match item {
+            Rendezvous::Register(metadata) => {
+                dst.put_u8(0);
+                encode_metadata(&metadata, dst)?;
+            }
+            Rendezvous::Lookup(id) => {
+                dst.put_u8(1);
+                dst.put(id.as_bytes());
+            }
+            Rendezvous::Found(metadata) => {
+                dst.put_u8(2);
+                encode_metadata(&metadata, dst)?;
+            }
+            Rendezvous::Missing => dst.put_u8(3),
+            Rendezvous::Relay(id) => {
+                dst.put_u8(4);
+                dst.put(id.as_bytes());
+            }
+            Rendezvous::Incoming(session) => {
+                dst.put_u8(5);
+                dst.put_u64(session);
+            }
+            Rendezvous::Attach(session) => {
+                dst.put_u8(6);
+                dst.put_u64(session);
+            }
+            Rendezvous::Relayed => dst.put_u8(7),
         }
         Ok(())
     }
@@ -221,6 +702,9 @@ impl Decoder for HeaderCodec {
         let Ok(message_length) = len_bytes.read_u16::<BigEndian>() else {
             return Ok(None);
         };
+        if message_length < Header::LEN {
+            return Err(Self::Error::Truncated);
+        }
         if src.len() < message_length.into() {
             return Ok(None);
         }
@@ -254,19 +738,27 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn new(typ: MessageType, item: &impl Frame) -> Header {
-        let mut header = Header {
+    /// the signature, the length and the message type
+    const LEN: u16 = 2 + 2 + 1;
+
+    /// the header of `item`, which fails when the frame is longer than its length field allows
+    pub fn new(typ: MessageType, item: &impl Frame) -> Result<Header, err::ParseError> {
+        let len = item.len() + usize::from(Header::LEN);
+        Ok(Header {
             message_type: typ,
-            length: item.len(),
-        };
-        header.length += header.len();
-        header
+            length: short(len)?,
+        })
+    }
+
+    /// split the rest of the frame off `src`, the header itself was already read
+    fn body(&self, src: &mut BytesMut) -> Result<BytesMut, err::ParseError> {
+        take(src, usize::from(self.length - Header::LEN))
     }
 }
 
 impl Frame for Header {
-    fn len(&self) -> u16 {
-        2 + 2 + 1 // dont forget signature ;)
+    fn len(&self) -> usize {
+        Header::LEN.into() // dont forget signature ;)
     }
 }
 
@@ -276,14 +768,15 @@ pub enum MessageType {
     // None = 0,
     Discovery = 1,
     Connect = 2,
-    // Control = 3,
+    Control = 3,
     // Session = 4,
     // Ack = 5
+    Rendezvous = 6,
 }
 
 /// Each frame needs to know it's length before sending
 pub trait Frame {
-    fn len(&self) -> u16;
+    fn len(&self) -> usize;
 }
 
 #[cfg(test)]
@@ -291,11 +784,13 @@ mod tests {
 
     use super::{DiscoveryCodec, SIGNATURE};
     use crate::{
+        discovery::Presence,
         event::DiscoveryEvent,
         peer::{PeerId, PeerMetadata},
-        proto::{Connection, ConnectionCodec},
+        proto::{Connection, ConnectionCodec, Control, ControlCodec, Features},
     };
-    use bytes::{BufMut, BytesMut};
+    use bytes::{BufMut, Bytes, BytesMut};
+    use hex_literal::hex;
     use std::{
         fmt::Debug,
         net::{Ipv4Addr, SocketAddr, SocketAddrV4},
@@ -356,9 +851,15 @@ mod tests {
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(DiscoveryEvent::PresenceResponse(meta))) = result.pop() else {
+        let Some(Some(DiscoveryEvent::PresenceResponse(Presence {
+            metadata: meta,
+            tags,
+        }))) = result.pop()
+        else {
             panic!("invalid frame");
         };
+        // frames of older peers carry no tags
+        assert!(tags.is_empty());
 
         assert_eq!(
             PeerMetadata {
@@ -366,12 +867,51 @@ mod tests {
                 typ: crate::peer::DeviceType::AppleiPhone,
                 id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
                     .unwrap(),
-                addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5001))
+                addrs: vec![SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(127, 0, 0, 1),
+                    5001
+                ))]
             },
             meta
         );
     }
 
+    #[test]
+    fn decode_discovery_presence_response_many_addrs() {
+        let mut decoder = DiscoveryCodec;
+        let mut src = BytesMut::new();
+
+        src.put(&SIGNATURE[..]);
+        src.put_u16(87); // length
+        src.put_u8(1); // type
+        src.put_u8(1); // discovery type
+        src.put_u16(6); // device type
+        src.put_u16(10); // device name length
+        src.put(&b"test phone"[..]); // device name
+        src.put(&b"0123456789012345678901234567890123456789"[..]); // device id
+        src.put_u16(25); // address length
+        src.put(&b"127.0.0.1:5001,[::1]:5002"[..]); // addresses
+        let mut result = consume(&mut decoder, &mut src);
+
+        assert_eq!(0, src.len());
+        let Some(Some(DiscoveryEvent::PresenceResponse(Presence {
+            metadata: meta,
+            tags,
+        }))) = result.pop()
+        else {
+            panic!("invalid frame");
+        };
+        // frames of older peers carry no tags
+        assert!(tags.is_empty());
+        assert_eq!(
+            vec![
+                "127.0.0.1:5001".parse::<SocketAddr>().unwrap(),
+                "[::1]:5002".parse::<SocketAddr>().unwrap()
+            ],
+            meta.addrs
+        );
+    }
+
     #[test]
     fn encode_discovery_presence_request() {
         let mut encoder = DiscoveryCodec;
@@ -395,13 +935,16 @@ mod tests {
         let mut encoder = DiscoveryCodec;
         let mut dst = BytesMut::new();
 
-        let item = DiscoveryEvent::PresenceResponse(PeerMetadata {
+        let item = DiscoveryEvent::PresenceResponse(Presence::from(PeerMetadata {
             name: "test phone".to_string(),
             typ: crate::peer::DeviceType::AppleiPhone,
             id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
                 .unwrap(),
-            addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5001)),
-        });
+            addrs: vec![SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(127, 0, 0, 1),
+                5001,
+            ))],
+        }));
 
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
@@ -409,9 +952,14 @@ mod tests {
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(DiscoveryEvent::PresenceResponse(meta))) = result.pop() else {
+        let Some(Some(DiscoveryEvent::PresenceResponse(Presence {
+            metadata: meta,
+            tags,
+        }))) = result.pop()
+        else {
             panic!("invalid frame");
         };
+        assert!(tags.is_empty());
 
         assert_eq!(
             PeerMetadata {
@@ -419,7 +967,10 @@ mod tests {
                 typ: crate::peer::DeviceType::AppleiPhone,
                 id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
                     .unwrap(),
-                addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5001))
+                addrs: vec![SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(127, 0, 0, 1),
+                    5001
+                ))]
             },
             meta
         );
@@ -440,7 +991,7 @@ mod tests {
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::Request { id, tag })) = result.pop() else {
+        let Some(Some(Connection::Request { id, tag, previous: None })) = result.pop() else {
             panic!("invalid frame");
         };
         assert_eq!("0123456789012345678901234567890123456789", id.to_string());
@@ -486,9 +1037,11 @@ mod tests {
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::CompleteRequest)) = result.pop() else {
+        // older peers send no features
+        let Some(Some(Connection::CompleteRequest(features))) = result.pop() else {
             panic!("invalid frame");
         };
+        assert_eq!(Features::default(), features);
     }
 
     #[test]
@@ -504,9 +1057,10 @@ mod tests {
 
         assert_eq!(0, src.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::CompleteResponse)) = result.pop() else {
+        let Some(Some(Connection::CompleteResponse(features))) = result.pop() else {
             panic!("invalid frame");
         };
+        assert_eq!(Features::default(), features);
     }
 
     #[test]
@@ -538,6 +1092,7 @@ mod tests {
             id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
                 .unwrap(),
             tag: Vec::from(&b"0TQEnaM5YHPJ8LJ2KD32bTGdnfK23ScT"[..]),
+            previous: None,
         };
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
@@ -545,7 +1100,7 @@ mod tests {
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::Request { id, tag })) = result.pop() else {
+        let Some(Some(Connection::Request { id, tag, previous: None })) = result.pop() else {
             panic!("invalid frame");
         };
         assert_eq!("0123456789012345678901234567890123456789", id.to_string());
@@ -581,14 +1136,14 @@ mod tests {
         let mut encoder = ConnectionCodec;
         let mut dst = BytesMut::new();
 
-        let item = Connection::CompleteRequest;
+        let item = Connection::CompleteRequest(Features::ALL);
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
 
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::CompleteRequest)) = result.pop() else {
+        let Some(Some(Connection::CompleteRequest(Features::ALL))) = result.pop() else {
             panic!("invalid frame");
         };
     }
@@ -598,14 +1153,14 @@ mod tests {
         let mut encoder = ConnectionCodec;
         let mut dst = BytesMut::new();
 
-        let item = Connection::CompleteResponse;
+        let item = Connection::CompleteResponse(Features::ALL);
         encoder.encode(item, &mut dst).expect("Error Encoding");
         // assert_eq!(dst, BytesMut::from(&hex!("")[..]))
 
         let mut result = consume(&mut encoder, &mut dst);
         assert_eq!(0, dst.len());
         assert_eq!(1, result.len());
-        let Some(Some(Connection::CompleteResponse)) = result.pop() else {
+        let Some(Some(Connection::CompleteResponse(Features::ALL))) = result.pop() else {
             panic!("invalid frame");
         };
     }
@@ -627,4 +1182,463 @@ mod tests {
         };
         assert_eq!(2001, code);
     }
+
+    /// the id used by every golden vector
+    const GOLDEN_ID: &str = "0123456789012345678901234567890123456789";
+
+    /// the tag used by every golden vector
+    const GOLDEN_TAG: [u8; 32] = hex!(
+        "000102030405060708090a0b0c0d0e0f"
+        "101112131415161718191a1b1c1d1e1f"
+    );
+
+    /// assert the item encodes to the exact bytes and decodes back into itself
+    fn assert_golden<C, T>(codec: &mut C, item: T, expected: &[u8])
+    where
+        C: Encoder<T, Error = crate::err::ParseError> + Decoder<Item = T>,
+        <C as Decoder>::Error: Debug,
+        T: Clone + Debug + PartialEq,
+    {
+        let mut dst = BytesMut::new();
+        codec
+            .encode(item.clone(), &mut dst)
+            .expect("Error Encoding");
+        assert_eq!(expected, &dst[..]);
+
+        let result = consume(codec, &mut dst);
+        assert_eq!(0, dst.len());
+        assert_eq!(vec![Some(item)], result);
+    }
+
+    #[test]
+    fn golden_discovery_presence_request() {
+        assert_golden(
+            &mut DiscoveryCodec,
+            DiscoveryEvent::PresenceRequest,
+            &hex!("4040 0006 01 00"),
+        );
+    }
+
+    #[test]
+    fn golden_discovery_presence_response() {
+        let item = DiscoveryEvent::PresenceResponse(Presence {
+            metadata: PeerMetadata {
+                name: "test phone".to_string(),
+                typ: crate::peer::DeviceType::AppleiPhone,
+                id: PeerId::from_string(GOLDEN_ID.to_string()).unwrap(),
+                addrs: vec![SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(127, 0, 0, 1),
+                    5001,
+                ))],
+            },
+            tags: vec![GOLDEN_TAG],
+        });
+        assert_golden(
+            &mut DiscoveryCodec,
+            item,
+            &hex!(
+                "4040 006d 01 01 0006"
+                "000a 746573742070686f6e65"
+                "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+                "000e 3132372e302e302e313a35303031"
+                "01"
+                "000102030405060708090a0b0c0d0e0f"
+                "101112131415161718191a1b1c1d1e1f"
+            ),
+        );
+    }
+
+    #[test]
+    fn golden_connect_request() {
+        let item = Connection::Request {
+            id: PeerId::from_string(GOLDEN_ID.to_string()).unwrap(),
+            tag: GOLDEN_TAG.to_vec(),
+            previous: None,
+        };
+        assert_golden(
+            &mut ConnectionCodec,
+            item,
+            &hex!(
+                "4040 004e 02 00"
+                "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+                "000102030405060708090a0b0c0d0e0f"
+                "101112131415161718191a1b1c1d1e1f"
+            ),
+        );
+    }
+
+    #[test]
+    fn golden_connect_request_with_previous_tag() {
+        let item = Connection::Request {
+            id: PeerId::from_string(GOLDEN_ID.to_string()).unwrap(),
+            tag: GOLDEN_TAG.to_vec(),
+            previous: Some(GOLDEN_TAG.to_vec()),
+        };
+        assert_golden(
+            &mut ConnectionCodec,
+            item,
+            &hex!(
+                "4040 006e 02 00"
+                "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+                "000102030405060708090a0b0c0d0e0f"
+                "101112131415161718191a1b1c1d1e1f"
+                "000102030405060708090a0b0c0d0e0f"
+                "101112131415161718191a1b1c1d1e1f"
+            ),
+        );
+    }
+
+    #[test]
+    fn golden_connect_rotate() {
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::Rotate(b"ABCD".to_vec()),
+            &hex!("4040 000c 02 0a 0004 41424344"),
+        );
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::RotateAck,
+            &hex!("4040 0006 02 0b"),
+        );
+    }
+
+    #[test]
+    fn golden_connect_response() {
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::Response(GOLDEN_TAG.to_vec()),
+            &hex!(
+                "4040 0026 02 01"
+                "000102030405060708090a0b0c0d0e0f"
+                "101112131415161718191a1b1c1d1e1f"
+            ),
+        );
+    }
+
+    #[test]
+    fn golden_connect_complete() {
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::CompleteRequest(Features::CONTROL),
+            &hex!("4040 0007 02 02 01"),
+        );
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::CompleteResponse(Features::CONTROL),
+            &hex!("4040 0007 02 03 01"),
+        );
+    }
+
+    #[test]
+    fn golden_connect_failure() {
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::Failure(2001),
+            &hex!("4040 000a 02 04 000007d1"),
+        );
+    }
+
+    #[test]
+    fn golden_connect_pair_request() {
+        let item = Connection::PairRequest {
+            metadata: PeerMetadata {
+                name: "test phone".to_string(),
+                typ: crate::peer::DeviceType::AppleiPhone,
+                id: PeerId::from_string(GOLDEN_ID.to_string()).unwrap(),
+                addrs: vec![SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(127, 0, 0, 1),
+                    5001,
+                ))],
+            },
+            secret: b"secret".to_vec(),
+            commitment: [0xab; 32].to_vec(),
+        };
+        assert_golden(
+            &mut ConnectionCodec,
+            item,
+            &hex!(
+                "4040 0074 02 05 0006"
+                "000a 746573742070686f6e65"
+                "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+                "000e 3132372e302e302e313a35303031"
+                "0006 736563726574"
+                "abababababababababababababababababababababababababababababababab"
+            ),
+        );
+    }
+
+    #[test]
+    fn golden_connect_pair_nonce() {
+        assert_golden(
+            &mut ConnectionCodec,
+            Connection::PairNonce([0xab; 32].to_vec()),
+            &hex!("4040 0026 02 07 abababababababababababababababababababababababababababababababab"),
+        );
+    }
+
+    #[test]
+    fn golden_control_data() {
+        assert_golden(
+            &mut ControlCodec,
+            Control::Data(b"PING".to_vec()),
+            &hex!("4040 000a 03 00 50494e47"),
+        );
+    }
+
+    #[test]
+    fn golden_control_ping() {
+        assert_golden(&mut ControlCodec, Control::Ping, &hex!("4040 0006 03 01"));
+        assert_golden(&mut ControlCodec, Control::Pong, &hex!("4040 0006 03 02"));
+    }
+
+    #[test]
+    fn golden_control_chunk() {
+        assert_golden(
+            &mut ControlCodec,
+            Control::Chunk(Bytes::from_static(b"PING")),
+            &hex!("4040 000a 03 03 00000004 50494e47"),
+        );
+    }
+
+    #[test]
+    fn chunk_waits_for_its_data() {
+        let mut src = BytesMut::from(&hex!("4040 000a 03 03 00000004 5049")[..]);
+        assert_eq!(None, ControlCodec.decode(&mut src).unwrap());
+        assert_eq!(12, src.len());
+        src.put(&b"NG"[..]);
+        assert_eq!(
+            Some(Control::Chunk(Bytes::from_static(b"PING"))),
+            ControlCodec.decode(&mut src).unwrap()
+        );
+
+        let mut src = BytesMut::from(&hex!("4040 000a 03 03 00100001")[..]);
+        assert!(matches!(
+            ControlCodec.decode(&mut src),
+            Err(crate::err::ParseError::TooLong(0x100001))
+        ));
+    }
+
+    #[test]
+    fn fields_past_the_frame_are_refused() {
+        // the name claims 255 bytes of a frame which ends after 10
+        let mut src = BytesMut::from(
+            &hex!(
+                "4040 0054 02 05 0006"
+                "00ff 746573742070686f6e65"
+                "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+                "000e 3132372e302e302e313a35303031"
+                "0006 736563726574"
+            )[..],
+        );
+        assert!(matches!(
+            ConnectionCodec.decode(&mut src),
+            Err(crate::err::ParseError::Truncated)
+        ));
+
+        // the next frame is not read as the end of this one
+        let mut src = BytesMut::from(&hex!("4040 0006 02 04 4040 0006 02 02")[..]);
+        assert!(matches!(
+            ConnectionCodec.decode(&mut src),
+            Err(crate::err::ParseError::Truncated)
+        ));
+        assert_eq!(
+            Some(Connection::CompleteRequest(Features::default())),
+            ConnectionCodec.decode(&mut src).unwrap()
+        );
+
+        let mut src = BytesMut::from(&hex!("4040 0004 01")[..]);
+        assert!(DiscoveryCodec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn names_must_be_utf8() {
+        let mut src = BytesMut::from(
+            &hex!(
+                "4040 0044 01 01 0006"
+                "0002 c328"
+                "30313233343536373839303132333435363738393031323334353637383930313233343536373839"
+                "000e 3132372e302e302e313a35303031"
+            )[..],
+        );
+        assert!(matches!(
+            DiscoveryCodec.decode(&mut src),
+            Err(crate::err::ParseError::Utf8(_))
+        ));
+    }
+
+    #[test]
+    fn frames_longer_than_their_length_field_are_refused() {
+        // the name alone fits its field, but not together with the rest of the frame
+        let metadata = PeerMetadata {
+            name: "a".repeat(usize::from(u16::MAX) - 10),
+            typ: crate::peer::DeviceType::LinuxDevice,
+            id: PeerId::from_string("0123456789012345678901234567890123456789".to_string())
+                .unwrap(),
+            addrs: Vec::new(),
+        };
+        let presence = DiscoveryEvent::PresenceResponse(Presence {
+            metadata,
+            tags: Vec::new(),
+        });
+        let mut dst = BytesMut::new();
+        assert!(matches!(
+            DiscoveryCodec.encode(presence, &mut dst),
+            Err(crate::err::ParseError::TooLong(_))
+        ));
+    }
+
+    mod roundtrip {
+        use std::net::{IpAddr, SocketAddr};
+
+        use bytes::BytesMut;
+        use proptest::prelude::*;
+        use tokio_util::codec::{Decoder, Encoder};
+
+        use crate::{
+            discovery::Presence,
+            event::DiscoveryEvent,
+            peer::{DeviceType, PeerId, PeerMetadata},
+            proto::{
+                Connection, ConnectionCodec, Control, ControlCodec, DiscoveryCodec, Features,
+                RendezvousCodec, CHUNK_LEN, MAX_DATA_LEN,
+            },
+        };
+
+        fn peer_id() -> impl Strategy<Value = PeerId> {
+            "[a-zA-Z0-9]{40}".prop_map(|id| PeerId::from_string(id).unwrap())
+        }
+
+        fn device_type() -> impl Strategy<Value = DeviceType> {
+            prop_oneof![
+                Just(DeviceType::Unknown),
+                Just(DeviceType::AppleiPhone),
+                Just(DeviceType::AppleiPad),
+                Just(DeviceType::AndroidDevice),
+                Just(DeviceType::Windows10Desktop),
+                Just(DeviceType::LinuxDevice),
+                Just(DeviceType::WindowsLaptop),
+            ]
+        }
+
+        fn addr() -> impl Strategy<Value = SocketAddr> {
+            // flow info and scope ids are not part of the textual address
+            (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| SocketAddr::new(ip, port))
+        }
+
+        fn metadata() -> impl Strategy<Value = PeerMetadata> {
+            let addrs = proptest::collection::vec(addr(), 0..4);
+            (".{0,64}", device_type(), peer_id(), addrs).prop_map(|(name, typ, id, addrs)| {
+                PeerMetadata {
+                    name,
+                    typ,
+                    id,
+                    addrs,
+                }
+            })
+        }
+
+        fn presence() -> impl Strategy<Value = Presence> {
+            let tags = proptest::collection::vec(any::<[u8; 32]>(), 0..4);
+            (metadata(), tags).prop_map(|(metadata, tags)| Presence { metadata, tags })
+        }
+
+        fn discovery_event() -> impl Strategy<Value = DiscoveryEvent> {
+            prop_oneof![
+                Just(DiscoveryEvent::PresenceRequest),
+                presence().prop_map(DiscoveryEvent::PresenceResponse),
+            ]
+        }
+
+        fn connection() -> impl Strategy<Value = Connection> {
+            let tag = proptest::collection::vec(any::<u8>(), 32);
+            prop_oneof![
+                (peer_id(), tag.clone(), proptest::option::of(tag.clone())).prop_map(
+                    |(id, tag, previous)| Connection::Request { id, tag, previous }
+                ),
+                tag.clone().prop_map(Connection::Response),
+                any::<u8>().prop_map(|f| Connection::CompleteRequest(Features(f))),
+                any::<u8>().prop_map(|f| Connection::CompleteResponse(Features(f))),
+                any::<u32>().prop_map(Connection::Failure),
+                (metadata(), proptest::collection::vec(any::<u8>(), 0..64), tag.clone()).prop_map(
+                    |(metadata, secret, commitment)| Connection::PairRequest {
+                        metadata,
+                        secret,
+                        commitment,
+                    }
+                ),
+                tag.clone().prop_map(Connection::PairNonce),
+                tag.clone().prop_map(Connection::PinShare),
+                tag.clone().prop_map(Connection::PinConfirmation),
+                (metadata(), proptest::collection::vec(any::<u8>(), 0..64), tag).prop_map(
+                    |(metadata, secret, share)| Connection::PinPairRequest {
+                        metadata,
+                        secret,
+                        share,
+                    }
+                ),
+                proptest::collection::vec(any::<u8>(), 0..64).prop_map(Connection::Rotate),
+                Just(Connection::RotateAck),
+            ]
+        }
+
+        fn control() -> impl Strategy<Value = Control> {
+            prop_oneof![
+                proptest::collection::vec(any::<u8>(), 0..=MAX_DATA_LEN).prop_map(Control::Data),
+                Just(Control::Ping),
+                Just(Control::Pong),
+                proptest::collection::vec(any::<u8>(), 0..=CHUNK_LEN)
+                    .prop_map(|chunk| Control::Chunk(chunk.into())),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn discovery_roundtrip(item in discovery_event()) {
+                let mut dst = BytesMut::new();
+                DiscoveryCodec.encode(item.clone(), &mut dst).unwrap();
+                let decoded = DiscoveryCodec.decode(&mut dst).unwrap();
+                prop_assert_eq!(Some(item), decoded);
+                prop_assert_eq!(0, dst.len());
+            }
+
+            #[test]
+            fn connection_roundtrip(item in connection()) {
+                let mut dst = BytesMut::new();
+                ConnectionCodec.encode(item.clone(), &mut dst).unwrap();
+                let decoded = ConnectionCodec.decode(&mut dst).unwrap();
+                prop_assert_eq!(Some(item), decoded);
+                prop_assert_eq!(0, dst.len());
+            }
+
+            #[test]
+            fn control_roundtrip(item in control()) {
+                let mut dst = BytesMut::new();
+                ControlCodec.encode(item.clone(), &mut dst).unwrap();
+                let decoded = ControlCodec.decode(&mut dst).unwrap();
+                prop_assert_eq!(Some(item), decoded);
+                prop_assert_eq!(0, dst.len());
+            }
+
+            #[test]
+            fn decoders_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+                let mut src = BytesMut::from(&bytes[..]);
+                _ = DiscoveryCodec.decode(&mut src.clone());
+                _ = ControlCodec.decode(&mut src.clone());
+                _ = RendezvousCodec.decode(&mut src.clone());
+                _ = ConnectionCodec.decode(&mut src);
+            }
+
+            #[test]
+            fn connection_roundtrip_split(item in connection(), at in 0usize..128) {
+                // a frame split across reads must only decode once it is complete
+                let mut encoded = BytesMut::new();
+                ConnectionCodec.encode(item.clone(), &mut encoded).unwrap();
+                let at = at.min(encoded.len() - 1);
+                let mut dst = encoded.split_to(at);
+                prop_assert!(ConnectionCodec.decode(&mut dst).unwrap().is_none());
+                dst.unsplit(encoded);
+                prop_assert_eq!(Some(item), ConnectionCodec.decode(&mut dst).unwrap());
+            }
+        }
+    }
 }
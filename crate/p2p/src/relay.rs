@@ -0,0 +1,306 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::{
+    err::{HandshakeError, ParseError},
+    peer::{PeerId, PeerMetadata},
+    proto::{Rendezvous, RendezvousCodec},
+    transport::{BoxedStream, Transport},
+};
+
+/// How long the rendezvous server has to answer a request
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long dialing an address the server saw a peer at may take before relaying instead
+pub(crate) const DIRECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait before registering again after the rendezvous server was lost
+pub const REGISTER_RETRY: Duration = Duration::from_secs(30);
+
+/// A connection with the rendezvous server
+pub(crate) type RelayFrame = Framed<BoxedStream, RendezvousCodec>;
+
+async fn open(transport: &dyn Transport, server: SocketAddr) -> Result<RelayFrame, HandshakeError> {
+    let stream = timeout(RELAY_TIMEOUT, transport.dial(server))
+        .await
+        .map_err(|_| HandshakeError::Timeout)?
+        .map_err(ParseError::from)?;
+    Ok(Framed::new(stream, RendezvousCodec))
+}
+
+async fn answer(frame: &mut RelayFrame) -> Result<Rendezvous, HandshakeError> {
+    match timeout(RELAY_TIMEOUT, frame.next()).await {
+        Err(_) => Err(HandshakeError::Timeout),
+        Ok(None) => Err(HandshakeError::Disconnect),
+        Ok(Some(answer)) => Ok(answer?),
+    }
+}
+
+/// register with the server to be found, the connection then carries the
+/// [Rendezvous::Incoming] sessions for as long as it stays open
+pub(crate) async fn register(
+    transport: &dyn Transport,
+    server: SocketAddr,
+    metadata: PeerMetadata,
+) -> Result<RelayFrame, HandshakeError> {
+    let mut frame = open(transport, server).await?;
+    frame.send(Rendezvous::Register(metadata)).await?;
+    Ok(frame)
+}
+
+/// where the server saw a registered peer, None if it is not registered
+pub(crate) async fn lookup(
+    transport: &dyn Transport,
+    server: SocketAddr,
+    id: &PeerId,
+) -> Result<Option<PeerMetadata>, HandshakeError> {
+    let mut frame = open(transport, server).await?;
+    frame.send(Rendezvous::Lookup(id.clone())).await?;
+    match answer(&mut frame).await? {
+        Rendezvous::Found(metadata) if &metadata.id == id => Ok(Some(metadata)),
+        Rendezvous::Missing => Ok(None),
+        _ => Err(HandshakeError::Msg),
+    }
+}
+
+/// a stream the server relays to the registered peer `id`. The server only passes bytes along,
+/// the handshake over it is secured and authenticated end to end like any other.
+pub(crate) async fn relay(
+    transport: &dyn Transport,
+    server: SocketAddr,
+    id: &PeerId,
+) -> Result<BoxedStream, HandshakeError> {
+    let mut frame = open(transport, server).await?;
+    frame.send(Rendezvous::Relay(id.clone())).await?;
+    match answer(&mut frame).await? {
+        Rendezvous::Relayed => Ok(frame.into_inner()),
+        Rendezvous::Missing => Err(HandshakeError::NotFound),
+        _ => Err(HandshakeError::Msg),
+    }
+}
+
+/// join a session the server announced with [Rendezvous::Incoming], the stream is relayed to
+/// the peer which asked for it
+pub(crate) async fn attach(
+    transport: &dyn Transport,
+    server: SocketAddr,
+    session: u64,
+) -> Result<BoxedStream, HandshakeError> {
+    let mut frame = open(transport, server).await?;
+    frame.send(Rendezvous::Attach(session)).await?;
+    Ok(frame.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, oneshot},
+        time::{sleep, timeout},
+    };
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        discovery::DISCOVERY_MULTICAST,
+        event::P2pEvent,
+        manager::{P2pConfig, P2pManager},
+        pairing::PairingAuthenticator,
+        err::HandshakeError,
+        guard::BAN_AFTER_FAILURES,
+        net::{AUTH_ERR, PAIR_DENIED_ERR},
+        peer::{ConnectionType, DeviceType, Identity, PeerCandidate, PeerId, PeerMetadata},
+        proto::{Connection, ConnectionCodec, Rendezvous, RendezvousCodec},
+        transport::TcpTransport,
+    };
+
+    #[derive(Default)]
+    struct Server {
+        registered: HashMap<PeerId, (PeerMetadata, mpsc::UnboundedSender<u64>)>,
+        sessions: HashMap<u64, oneshot::Sender<TcpStream>>,
+        next: u64,
+    }
+
+    // a rendezvous server which never reveals where peers are, so every connection is relayed
+    async fn rendezvous() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Mutex::new(Server::default()));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let mut frame = Framed::new(stream, RendezvousCodec);
+                    let Some(Ok(request)) = frame.next().await else {
+                        return;
+                    };
+                    match request {
+                        Rendezvous::Register(metadata) => {
+                            let (tx, mut incoming) = mpsc::unbounded_channel();
+                            server
+                                .lock()
+                                .unwrap()
+                                .registered
+                                .insert(metadata.id.clone(), (metadata, tx));
+                            while let Some(session) = incoming.recv().await {
+                                frame.send(Rendezvous::Incoming(session)).await.unwrap();
+                            }
+                        }
+                        Rendezvous::Lookup(id) => {
+                            let found = server
+                                .lock()
+                                .unwrap()
+                                .registered
+                                .get(&id)
+                                .map(|r| r.0.clone());
+                            let answer = match found {
+                                Some(metadata) => Rendezvous::Found(PeerMetadata {
+                                    addrs: Vec::new(),
+                                    ..metadata
+                                }),
+                                None => Rendezvous::Missing,
+                            };
+                            frame.send(answer).await.unwrap();
+                        }
+                        Rendezvous::Relay(id) => {
+                            let (tx, attached) = oneshot::channel();
+                            {
+                                let mut server = server.lock().unwrap();
+                                server.next += 1;
+                                let session = server.next;
+                                server.sessions.insert(session, tx);
+                                server.registered[&id].1.send(session).unwrap();
+                            }
+                            let mut attached = attached.await.unwrap();
+                            frame.send(Rendezvous::Relayed).await.unwrap();
+                            let mut stream = frame.into_inner();
+                            _ = tokio::io::copy_bidirectional(&mut stream, &mut attached).await;
+                        }
+                        Rendezvous::Attach(session) => {
+                            let tx = server.lock().unwrap().sessions.remove(&session).unwrap();
+                            _ = tx.send(frame.into_inner());
+                        }
+                        _ => {}
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    // a manager securing its connections with TLS, like a real device
+    async fn manager(
+        seed: u8,
+        device: DeviceType,
+    ) -> (Arc<P2pManager>, mpsc::UnboundedReceiver<P2pEvent>) {
+        let identity = Identity::from_seed([seed; 32]);
+        let config = P2pConfig {
+            id: PeerId::from_cert(&identity.clone().into_rustls().0),
+            device,
+            name: String::from("Tester"),
+            multicast: SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50692)),
+            multicast_v6: None,
+            p2p_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+            lan: Vec::new(),
+            identity: Some(identity),
+            limits: Default::default(),
+        };
+        P2pManager::new(config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn paired_peers_off_the_lan_are_relayed() {
+        let server = rendezvous().await;
+        let (host, mut events) = manager(1, DeviceType::AppleiPhone).await;
+        let (client, _events) = manager(2, DeviceType::LinuxDevice).await;
+        let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec()).unwrap();
+        host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+        client.add_known_peer(PeerCandidate::new(&host.get_metadata(), auth));
+
+        // the host is not discovered and unreachable until it registers
+        let id = host.get_metadata().id;
+        assert!(client.connect_to_peer(&id).await.is_err());
+        client.start_relay(server);
+        host.start_relay(server);
+        sleep(Duration::from_millis(100)).await;
+
+        let peer = timeout(Duration::from_secs(2), client.connect_to_peer(&id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(id, peer.id);
+        let connection = client.connection(&id).unwrap();
+        assert_eq!(ConnectionType::Client, connection.conn_type);
+        assert_eq!(server, connection.addr);
+        let Some(P2pEvent::KeyRotated { .. }) = events.recv().await else {
+            panic!("the host did not take the new secret");
+        };
+        let Some(P2pEvent::PeerConnected(peer)) = events.recv().await else {
+            panic!("the host did not accept the relayed connection");
+        };
+        assert_eq!(client.get_metadata().id, peer.id);
+    }
+
+    #[tokio::test]
+    async fn pairing_is_refused_through_the_relay() {
+        let server = rendezvous().await;
+        let (host, _events) = manager(1, DeviceType::AppleiPhone).await;
+        let (client, _events) = manager(2, DeviceType::LinuxDevice).await;
+        host.start_relay(server);
+        sleep(Duration::from_millis(100)).await;
+
+        let metadata = host.get_metadata();
+        let conn = super::relay(&TcpTransport, server, &metadata.id).await.unwrap();
+        let paired = crate::net::pair(&client, conn, &metadata, None).await;
+        assert!(matches!(paired, Err(HandshakeError::Failure(PAIR_DENIED_ERR))));
+    }
+
+    #[tokio::test]
+    async fn claiming_another_peers_id_does_not_get_it_banned() {
+        let server = rendezvous().await;
+        let (host, _events) = manager(1, DeviceType::AppleiPhone).await;
+        let (client, _events) = manager(2, DeviceType::LinuxDevice).await;
+        let (attacker, _events) = manager(3, DeviceType::LinuxDevice).await;
+        let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec()).unwrap();
+        host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+        client.add_known_peer(PeerCandidate::new(&host.get_metadata(), auth));
+        client.start_relay(server);
+        host.start_relay(server);
+        sleep(Duration::from_millis(100)).await;
+
+        // the attacker's own certificate gives it away every time
+        let id = host.get_metadata().id;
+        for _ in 0..=BAN_AFTER_FAILURES {
+            let conn = super::relay(&TcpTransport, server, &id).await.unwrap();
+            let tls = attacker.tls().unwrap();
+            let conn = tls.connect(conn, &id).await.unwrap();
+            let mut frame = Framed::new(conn, ConnectionCodec);
+            let request = Connection::Request {
+                id: client.get_metadata().id,
+                tag: vec![0; 32],
+                previous: None,
+            };
+            frame.send(request).await.unwrap();
+            let Some(Ok(Connection::Failure(code))) = frame.next().await else {
+                panic!("the host did not refuse the claimed id");
+            };
+            assert_eq!(AUTH_ERR, code);
+        }
+
+        let peer = timeout(Duration::from_secs(2), client.connect_to_peer(&id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(id, peer.id);
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ring::{
+    digest::{Context, SHA256},
+    error::Unspecified,
+    rand::{SecureRandom, SystemRandom},
+};
+
+/// Where pairing secrets, pins and nonces come from. The manager uses [SystemRandom] unless
+/// another is set with [crate::manager::P2pManager::set_rng].
+pub trait Rng: Send + Sync {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), Unspecified>;
+}
+
+impl Rng for SystemRandom {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), Unspecified> {
+        SecureRandom::fill(self, dest)
+    }
+}
+
+/// Bytes expanded from a seed, the same seed always gives the same bytes so simulations and
+/// tests can reproduce a run. Never use this for a real peer.
+pub struct SeededRng {
+    seed: [u8; 32],
+    block: AtomicU64,
+}
+
+impl SeededRng {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self {
+            seed,
+            block: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), Unspecified> {
+        for chunk in dest.chunks_mut(SHA256.output_len) {
+            let block = self.block.fetch_add(1, Ordering::SeqCst);
+            let mut context = Context::new(&SHA256);
+            context.update(&self.seed);
+            context.update(&block.to_be_bytes());
+            chunk.copy_from_slice(&context.finish().as_ref()[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rng, SeededRng};
+
+    fn bytes(rng: &SeededRng, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rng.fill(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_bytes() {
+        let (a, b) = (SeededRng::new([1; 32]), SeededRng::new([1; 32]));
+        assert_eq!(bytes(&a, 40), bytes(&b, 40));
+        assert_eq!(bytes(&a, 4), bytes(&b, 4));
+
+        // every fill continues the stream
+        let first = bytes(&a, 32);
+        assert_ne!(first, bytes(&a, 32));
+        assert_ne!(first, bytes(&SeededRng::new([2; 32]), 32));
+    }
+}
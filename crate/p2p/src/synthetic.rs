@@ -0,0 +1,118 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::debug;
+
+use crate::{
+    err,
+    event::P2pEvent,
+    manager::{P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::{DeviceType, Identity, PeerCandidate, PeerId, PeerMetadata},
+};
+
+/// A swarm of synthetic peers which answer discovery and handshakes.
+/// Every peer is a [P2pManager] with its own TLS identity, so the node under test goes through
+/// the same discovery and handshakes as with real devices. They share the same pairing secret so
+/// a single node can be paired with all of them, and echo any data sent to them once connected.
+/// This is only meant to load test a node.
+pub struct SyntheticPeers {
+    managers: Vec<Arc<P2pManager>>,
+    auth: PairingAuthenticator,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl SyntheticPeers {
+    /// spawn `count` synthetic peers answering discovery on the multicast address, which accept
+    /// connections from the `node` under test
+    pub async fn spawn(
+        count: usize,
+        node: &PeerMetadata,
+        multicast: SocketAddr,
+        secret: Vec<u8>,
+    ) -> Result<Self, err::InitError> {
+        let auth = PairingAuthenticator::new(secret)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{e:?}")))?;
+        let mut managers = Vec::with_capacity(count);
+        let mut tasks = Vec::with_capacity(count);
+
+        for i in 0..count {
+            let identity = synthetic_identity(i);
+            let config = P2pConfig {
+                id: PeerId::from_cert(&identity.clone().into_rustls().0),
+                device: DeviceType::LinuxDevice,
+                name: format!("Synthetic peer {i}"),
+                multicast,
+                multicast_v6: None,
+                p2p_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+                lan: Vec::new(),
+                identity: Some(identity),
+                limits: Default::default(),
+            };
+            let (manager, events) = P2pManager::new(config).await?;
+            manager.add_known_peer(PeerCandidate::new(node, auth.clone()));
+            tasks.push(tokio::spawn(echo(manager.id.clone(), events)));
+            managers.push(manager);
+        }
+
+        Ok(Self {
+            managers,
+            auth,
+            tasks,
+        })
+    }
+
+    /// the metadata of every synthetic peer
+    pub fn metadata(&self) -> Vec<PeerMetadata> {
+        self.managers.iter().map(|m| m.get_metadata()).collect()
+    }
+
+    /// known peer candidates for every synthetic peer, to be added to the node under test
+    pub fn candidates(&self) -> Vec<PeerCandidate> {
+        self.managers
+            .iter()
+            .map(|m| PeerCandidate::new(&m.get_metadata(), self.auth.clone()))
+            .collect()
+    }
+
+    /// stop every synthetic peer
+    pub async fn shutdown(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        for manager in &self.managers {
+            manager.shutdown().await;
+        }
+    }
+}
+
+impl Drop for SyntheticPeers {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// a stable identity for the nth synthetic peer
+fn synthetic_identity(n: usize) -> Identity {
+    let mut seed = [0x5e; 32];
+    seed[24..].copy_from_slice(&(n as u64).to_be_bytes());
+    Identity::from_seed(seed)
+}
+
+/// echo everything a connected node sends back to it
+async fn echo(id: PeerId, mut events: mpsc::UnboundedReceiver<P2pEvent>) {
+    while let Some(event) = events.recv().await {
+        if let P2pEvent::PeerConnected(peer) = event {
+            debug!("Synthetic peer {} accepted {}", id, peer.id);
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(peer.conn);
+                _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{io, sync::Arc, time::SystemTime};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, ClientConfig, DistinguishedNames, PrivateKey, ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::{
+    err,
+    peer::{Identity, PeerId},
+    transport::BoxedStream,
+};
+
+/// The name sent in the client hello, peers are identified by their certificate instead
+const SERVER_NAME: &str = "flydrop.local";
+
+/// Secures connections with TLS 1.3 where both sides present the self-signed certificate of their
+/// [Identity]. A certificate is trusted when it hashes to the [PeerId] expected on the other end.
+pub(crate) struct Tls {
+    cert: Certificate,
+    key: PrivateKey,
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    pub(crate) fn new(identity: Identity) -> Result<Self, err::InitError> {
+        let (cert, key) = identity.into_rustls();
+        let server = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(AnyPeer))
+            .with_single_cert(vec![cert.clone()], key.clone())?;
+        Ok(Self {
+            cert,
+            key,
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+        })
+    }
+
+    /// secure a connection as the client, the server must present the certificate of `id`
+    pub(crate) async fn connect(
+        &self,
+        conn: BoxedStream,
+        id: &PeerId,
+    ) -> Result<BoxedStream, err::HandshakeError> {
+        let client = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(invalid)?
+            .with_custom_certificate_verifier(Arc::new(Pinned(id.clone())))
+            .with_single_cert(vec![self.cert.clone()], self.key.clone())
+            .map_err(invalid)?;
+        let name = ServerName::try_from(SERVER_NAME).unwrap();
+        let stream = TlsConnector::from(Arc::new(client))
+            .connect(name, conn)
+            .await
+            .map_err(err::HandshakeError::Tls)?;
+        Ok(Box::new(stream))
+    }
+
+    /// secure a connection as the server, returning the id of the certificate the client presented
+    pub(crate) async fn accept(
+        &self,
+        conn: BoxedStream,
+    ) -> Result<(BoxedStream, PeerId), err::HandshakeError> {
+        let stream = self
+            .acceptor
+            .accept(conn)
+            .await
+            .map_err(err::HandshakeError::Tls)?;
+        let Some([cert, ..]) = stream.get_ref().1.peer_certificates() else {
+            return Err(err::HandshakeError::Auth);
+        };
+        let id = PeerId::from_cert(cert);
+        Ok((Box::new(stream), id))
+    }
+}
+
+fn invalid(e: rustls::Error) -> err::HandshakeError {
+    err::HandshakeError::Tls(io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Trusts only the server certificate of the expected peer
+struct Pinned(PeerId);
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if PeerId::from_cert(end_entity) != self.0 {
+            return Err(rustls::Error::InvalidCertificateData(String::from(
+                "the certificate does not belong to the expected peer",
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Requires a client certificate but accepts any, the handshake checks the peer it belongs to
+/// once the client says who it is
+struct AnyPeer;
+
+impl ClientCertVerifier for AnyPeer {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::Tls;
+    use crate::peer::{Identity, PeerId};
+
+    fn peer_id(identity: &Identity) -> PeerId {
+        PeerId::from_cert(&identity.clone().into_rustls().0)
+    }
+
+    #[tokio::test]
+    async fn pinned_peers_connect() {
+        let (client, server) = (Identity::from_seed([1; 32]), Identity::from_seed([2; 32]));
+        let (client_id, server_id) = (peer_id(&client), peer_id(&server));
+        let (client, server) = (Tls::new(client).unwrap(), Tls::new(server).unwrap());
+        let (a, b) = duplex(4096);
+
+        let (connected, accepted) = tokio::join!(
+            client.connect(Box::new(a), &server_id),
+            server.accept(Box::new(b))
+        );
+        let (mut connected, mut accepted) = (connected.unwrap(), accepted.unwrap());
+        assert_eq!(client_id, accepted.1);
+
+        connected.write_all(b"PING").await.unwrap();
+        connected.flush().await.unwrap();
+        let mut buffer = [0u8; 4];
+        accepted.0.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"PING", &buffer);
+    }
+
+    #[tokio::test]
+    async fn unexpected_server_is_rejected() {
+        let (client, server) = (Identity::from_seed([1; 32]), Identity::from_seed([2; 32]));
+        let expected = peer_id(&Identity::from_seed([3; 32]));
+        let (client, server) = (Tls::new(client).unwrap(), Tls::new(server).unwrap());
+        let (a, b) = duplex(4096);
+
+        let (connected, _) = tokio::join!(
+            client.connect(Box::new(a), &expected),
+            server.accept(Box::new(b))
+        );
+        assert!(connected.is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::proto::Connection;
+
+/// The number of frames kept per connection, older frames are dropped
+const TRACE_CAPACITY: usize = 64;
+
+/// Whether a traced frame was sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A frame seen on a connection. Only its shape is recorded, never the payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameRecord {
+    pub direction: Direction,
+    /// the message of the frame
+    pub kind: String,
+    /// the size of the frame in bytes, including the header
+    pub size: usize,
+    /// the time since the connection started
+    pub elapsed: Duration,
+}
+
+/// Records the most recent frames of a connection
+#[derive(Debug)]
+pub(crate) struct Tracer {
+    start: Instant,
+    frames: Mutex<VecDeque<FrameRecord>>,
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            frames: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn record(&self, direction: Direction, kind: &str, size: usize) {
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() == TRACE_CAPACITY {
+            frames.pop_front();
+        }
+        frames.push_back(FrameRecord {
+            direction,
+            kind: kind.to_string(),
+            size,
+            elapsed: self.start.elapsed(),
+        });
+    }
+
+    pub(crate) fn frames(&self) -> Vec<FrameRecord> {
+        self.frames.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Wraps a connection codec to record every frame it encodes or decodes when a tracer is set
+pub(crate) struct TracedCodec<C> {
+    inner: C,
+    tracer: Option<std::sync::Arc<Tracer>>,
+}
+
+impl<C> TracedCodec<C> {
+    pub(crate) fn new(inner: C, tracer: Option<std::sync::Arc<Tracer>>) -> Self {
+        Self { inner, tracer }
+    }
+}
+
+impl<C: Decoder<Item = Connection>> Decoder for TracedCodec<C> {
+    type Item = Connection;
+
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let before = src.len();
+        let item = self.inner.decode(src)?;
+        if let (Some(tracer), Some(item)) = (&self.tracer, &item) {
+            tracer.record(Direction::Received, item.kind(), before - src.len());
+        }
+        Ok(item)
+    }
+}
+
+impl<C: Encoder<Connection>> Encoder<Connection> for TracedCodec<C> {
+    type Error = C::Error;
+
+    fn encode(&mut self, item: Connection, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let kind = item.kind();
+        let before = dst.len();
+        self.inner.encode(item, dst)?;
+        if let Some(tracer) = &self.tracer {
+            tracer.record(Direction::Sent, kind, dst.len() - before);
+        }
+        Ok(())
+    }
+}
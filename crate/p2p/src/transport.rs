@@ -0,0 +1,108 @@
+use std::{io, net::SocketAddr};
+
+use futures::{future::BoxFuture, FutureExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+/// A bi-directional byte stream to a remote peer
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+/// A type erased [Stream] so transports can be swapped without changing the [crate::peer::Peer]
+pub type BoxedStream = Box<dyn Stream>;
+
+/// A way of establishing connections with remote peers. TCP is used by default.
+pub trait Transport: Send + Sync + 'static {
+    /// open a connection to the remote address
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>>;
+
+    /// start listening for incoming connections on the local address
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>>;
+}
+
+/// Accepts incoming connections for a [Transport]
+pub trait Listener: Send + 'static {
+    /// wait for the next incoming connection, this must be cancel safe
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedStream, SocketAddr)>>;
+
+    /// the address connections are being accepted on
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// The default transport using plain TCP
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>> {
+        async move {
+            let stream = TcpStream::connect(addr).await?;
+            Ok(Box::new(stream) as BoxedStream)
+        }
+        .boxed()
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        async move {
+            let listener = dual_stack(addr)?;
+            Ok(Box::new(listener) as Box<dyn Listener>)
+        }
+        .boxed()
+    }
+}
+
+/// bind a tcp listener, an unspecified ipv6 address also accepts ipv4 connections
+fn dual_stack(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedStream, SocketAddr)>> {
+        async move {
+            let (stream, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as BoxedStream, addr))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::{TcpTransport, Transport};
+
+    #[tokio::test]
+    async fn unspecified_ipv6_listener_is_dual_stack() {
+        let mut listener = TcpTransport
+            .listen(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for ip in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()] {
+            let dialed = TcpTransport.dial(SocketAddr::new(ip, port));
+            let (dialed, accepted) = tokio::join!(dialed, listener.accept());
+            assert!(dialed.is_ok());
+            assert!(accepted.is_ok());
+        }
+    }
+}
@@ -1,6 +1,17 @@
+#![allow(dead_code)]
+
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
-use p2p::{discovery::DISCOVERY_MULTICAST, peer::PeerId};
+use futures::{future::BoxFuture, FutureExt};
+use p2p::{
+    discovery::{Discovery, DiscoverySource, Presence, DISCOVERY_MULTICAST},
+    event::DiscoveryEvent,
+    manager::P2pConfig,
+    peer::{DeviceType, Identity, PeerId},
+};
+use tokio::sync::mpsc;
+
+pub mod sim;
 
 pub fn create_p2p_addr() -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
@@ -17,3 +28,86 @@ pub fn create_peer_id_one() -> PeerId {
 pub fn create_peer_id_two() -> PeerId {
     PeerId::from_string("QWERTYUIOPQWERTYUIOPQWERTYUIOPQWERTYUIOP".to_string()).unwrap()
 }
+
+/// a plaintext manager config on the test multicast group and a random localhost port
+pub fn create_config(id: PeerId, device: DeviceType, name: &str) -> P2pConfig {
+    P2pConfig {
+        id,
+        device,
+        name: name.into(),
+        multicast: create_multicast_addr(),
+        multicast_v6: None,
+        p2p_addr: create_p2p_addr(),
+        lan: Vec::new(),
+        identity: None,
+        limits: Default::default(),
+    }
+}
+
+/// a reproducible identity and the id derived from it, for connections secured with TLS
+pub fn create_identity(seed: u8) -> (PeerId, Identity) {
+    let identity = Identity::from_seed([seed; 32]);
+    (
+        PeerId::from_cert(&identity.clone().into_rustls().0),
+        identity,
+    )
+}
+
+/// a discovery mechanism the test feeds presence responses into
+pub struct Injected {
+    source: &'static str,
+    events: Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>>,
+    announced: Option<mpsc::UnboundedSender<Presence>>,
+}
+
+impl Injected {
+    pub fn new(events: mpsc::Receiver<(DiscoveryEvent, SocketAddr)>) -> Self {
+        Self::named("injected", events)
+    }
+
+    /// a mechanism discovering peers as its own source, for registering several at once
+    pub fn named(
+        source: &'static str,
+        events: mpsc::Receiver<(DiscoveryEvent, SocketAddr)>,
+    ) -> Self {
+        Self {
+            source,
+            events: Some(events),
+            announced: None,
+        }
+    }
+
+    /// the mechanism and every presence announced through it
+    pub fn recording(
+        events: mpsc::Receiver<(DiscoveryEvent, SocketAddr)>,
+    ) -> (Self, mpsc::UnboundedReceiver<Presence>) {
+        let (announced, announcements) = mpsc::unbounded_channel();
+        let injected = Self {
+            source: "injected",
+            events: Some(events),
+            announced: Some(announced),
+        };
+        (injected, announcements)
+    }
+}
+
+impl Discovery for Injected {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Custom(self.source)
+    }
+
+    fn announce(&self, presence: Presence) -> BoxFuture<'_, ()> {
+        if let Some(announced) = &self.announced {
+            let _ = announced.send(presence);
+        }
+        async {}.boxed()
+    }
+
+    fn request(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn events(&mut self) -> Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>> {
+        self.events.take()
+    }
+}
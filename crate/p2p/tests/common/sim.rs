@@ -0,0 +1,210 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{sleep, Sleep},
+};
+
+/// The network conditions to simulate on a link
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    /// delay applied before every read and write
+    pub latency: Duration,
+
+    /// the max number of bytes returned by a single read
+    pub max_read: Option<usize>,
+
+    /// the number of bytes after which the stream is cut
+    pub disconnect_after: Option<usize>,
+
+    /// the number of bytes after which the stream stops moving without closing, like a peer
+    /// which lost power
+    pub stall_after: Option<usize>,
+
+    /// drop every nth datagram, only used by the udp relay
+    pub drop_every: Option<usize>,
+}
+
+/// Wraps a stream and injects latency, slow reads and disconnects into it
+pub struct SimStream<S> {
+    inner: S,
+    conditions: Conditions,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+    /// bytes read from the inner stream which wait out the latency
+    arrived: Vec<u8>,
+    transferred: usize,
+}
+
+impl<S> SimStream<S> {
+    pub fn new(inner: S, conditions: Conditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            read_delay: None,
+            write_delay: None,
+            arrived: Vec::new(),
+            transferred: 0,
+        }
+    }
+
+    /// whether the link stopped moving
+    fn stalled(&self) -> bool {
+        self.conditions
+            .stall_after
+            .is_some_and(|max| self.transferred >= max)
+    }
+
+    /// the bytes left before the simulated disconnect
+    fn remaining(&self) -> usize {
+        self.conditions
+            .disconnect_after
+            .map(|max| max.saturating_sub(self.transferred))
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// wait for the latency of a link to pass, the delay stays elapsed until the caller clears it
+/// once the frame moved so polling again doesn't delay the same frame twice
+fn poll_latency(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    latency: Duration,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    if latency.is_zero() {
+        return Poll::Ready(());
+    }
+    let sleep = delay.get_or_insert_with(|| Box::pin(sleep(latency)));
+    ready!(sleep.as_mut().poll(cx));
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SimStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.arrived.is_empty() {
+            let remaining = this.remaining();
+            if remaining == 0 {
+                // simulate the remote closing the connection
+                return Poll::Ready(Ok(()));
+            }
+            if this.stalled() {
+                return Poll::Pending;
+            }
+            let limit = this
+                .conditions
+                .max_read
+                .unwrap_or(usize::MAX)
+                .min(remaining)
+                .min(buf.remaining());
+            let mut tmp = vec![0u8; limit];
+            let mut limited = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            if limited.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.transferred += limited.filled().len();
+            this.arrived = limited.filled().to_vec();
+        }
+        // the latency starts once the frame arrived
+        ready!(poll_latency(
+            &mut this.read_delay,
+            this.conditions.latency,
+            cx
+        ));
+        this.read_delay = None;
+        let len = this.arrived.len().min(buf.remaining());
+        buf.put_slice(&this.arrived[..len]);
+        this.arrived.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SimStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let remaining = this.remaining();
+        if remaining == 0 {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if this.stalled() {
+            return Poll::Pending;
+        }
+        ready!(poll_latency(
+            &mut this.write_delay,
+            this.conditions.latency,
+            cx
+        ));
+        let len = buf.len().min(remaining);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.write_delay = None;
+        this.transferred += written;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Start a tcp proxy in front of `target` which applies the conditions to the upstream link.
+/// Returns the address to connect to.
+pub async fn tcp_proxy(target: SocketAddr, conditions: Conditions) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let conditions = conditions.clone();
+            tokio::spawn(async move {
+                let Ok(upstream) = TcpStream::connect(target).await else {
+                    return;
+                };
+                let mut upstream = SimStream::new(upstream, conditions);
+                _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Start a udp relay which forwards datagrams to `target`, dropping and delaying them
+/// according to the conditions. Returns the address to send to.
+pub async fn udp_relay(target: SocketAddr, conditions: Conditions) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; u16::MAX.into()];
+        let mut count = 0;
+        while let Ok((len, _)) = socket.recv_from(&mut buf).await {
+            count += 1;
+            if conditions.drop_every.is_some_and(|n| count % n == 0) {
+                continue;
+            }
+            if !conditions.latency.is_zero() {
+                sleep(conditions.latency).await;
+            }
+            _ = socket.send_to(&buf[..len], target).await;
+        }
+    });
+    Ok(addr)
+}
@@ -0,0 +1,585 @@
+use std::{error::Error, net::SocketAddr, pin::pin, sync::Arc, time::Duration};
+
+use p2p::{
+    discovery,
+    event::{DiscoveryEvent, P2pEvent},
+    manager::{ConnectionLimits, P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::{ConnectionType, DeviceType, PeerCandidate, PeerId, PeerMetadata},
+    trace::Direction,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::mpsc::UnboundedReceiver,
+    select,
+    time::{interval, sleep, timeout, Instant},
+};
+
+use crate::common::{sim::*, *};
+
+mod common;
+
+/// a raw connection request frame from a peer the host does not know
+fn unknown_connection_request() -> Vec<u8> {
+    let mut frame = vec![0x40, 0x40, 0, 78, 2, 0];
+    frame.extend_from_slice(b"ABCDEFGHIJABCDEFGHIJABCDEFGHIJABCDEFGHIJ");
+    frame.extend_from_slice(&[0; 32]);
+    frame
+}
+
+/// the raw connection failure frame carrying the result code
+fn connection_failure(code: u32) -> Vec<u8> {
+    let mut frame = vec![0x40, 0x40, 0, 10, 2, 4];
+    frame.extend_from_slice(&code.to_be_bytes());
+    frame
+}
+
+async fn host() -> Result<SocketAddr, Box<dyn Error>> {
+    Ok(host_manager().await?.get_metadata().addrs[0])
+}
+
+async fn host_manager() -> Result<Arc<P2pManager>, Box<dyn Error>> {
+    Ok(host_manager_with_events().await?.0)
+}
+
+async fn host_manager_with_events(
+) -> Result<(Arc<P2pManager>, UnboundedReceiver<P2pEvent>), Box<dyn Error>> {
+    let config =
+        create_config(create_peer_id_two(), DeviceType::AppleiPhone, "Tester's phone");
+    Ok(P2pManager::new(config).await?)
+}
+
+#[tokio::test]
+async fn handshake_times_out_over_slow_link() -> Result<(), Box<dyn Error>> {
+    let conditions = Conditions {
+        latency: Duration::from_millis(1500),
+        ..Default::default()
+    };
+    let proxy = tcp_proxy(host().await?, conditions).await?;
+
+    let mut conn = TcpStream::connect(proxy).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+
+    let mut buffer = [0u8; 10];
+    timeout(Duration::from_secs(5), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2001), buffer);
+    Ok(())
+}
+
+#[tokio::test]
+async fn latency_delays_a_frame_once() -> Result<(), Box<dyn Error>> {
+    let latency = Duration::from_millis(200);
+    let (local, mut remote) = tokio::io::duplex(64);
+    let conditions = Conditions {
+        latency,
+        ..Default::default()
+    };
+    let mut link = SimStream::new(local, conditions);
+
+    // the read is polled again and again while it waits, like in the event loop
+    let read = tokio::spawn(async move {
+        let mut buffer = [0u8; 4];
+        let mut read = pin!(link.read_exact(&mut buffer));
+        let mut tick = interval(Duration::from_millis(50));
+        loop {
+            select! {
+                read = &mut read => break read.map(|_| Instant::now()),
+                _ = tick.tick() => {}
+            }
+        }
+    });
+    sleep(latency * 2).await;
+    let sent = Instant::now();
+    remote.write_all(b"PING").await?;
+    let received = timeout(Duration::from_secs(1), read).await???;
+    let delay = received - sent;
+    assert!(delay >= latency && delay < latency * 2, "delayed by {:?}", delay);
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_survives_slow_reads() -> Result<(), Box<dyn Error>> {
+    let conditions = Conditions {
+        max_read: Some(1),
+        ..Default::default()
+    };
+    let proxy = tcp_proxy(host().await?, conditions).await?;
+
+    let mut conn = TcpStream::connect(proxy).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+
+    let mut buffer = [0u8; 10];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2002), buffer);
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_disconnect_mid_stream() -> Result<(), Box<dyn Error>> {
+    let conditions = Conditions {
+        disconnect_after: Some(3),
+        ..Default::default()
+    };
+    let proxy = tcp_proxy(host().await?, conditions).await?;
+
+    let mut conn = TcpStream::connect(proxy).await?;
+    _ = conn.write_all(&unknown_connection_request()).await;
+
+    let mut buffer = [0u8; 10];
+    let read = timeout(Duration::from_secs(2), conn.read(&mut buffer)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_frames_are_traced() -> Result<(), Box<dyn Error>> {
+    let manager = host_manager().await?;
+    manager.set_connection_trace(true);
+
+    let mut conn = TcpStream::connect(manager.get_metadata().addrs[0]).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+    let mut buffer = [0u8; 10];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+
+    let id = PeerId::from_string(String::from("ABCDEFGHIJABCDEFGHIJABCDEFGHIJABCDEFGHIJ"))?;
+    let trace = manager.connection_trace(&id).expect("trace was not kept");
+    let frames: Vec<_> = trace
+        .iter()
+        .map(|f| (f.direction, f.kind.as_str(), f.size))
+        .collect();
+    assert_eq!(
+        vec![
+            (Direction::Received, "Request", 78),
+            (Direction::Sent, "Failure", 10)
+        ],
+        frames
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn older_peer_is_not_pinged() -> Result<(), Box<dyn Error>> {
+    let (manager, _events) = host_manager_with_events().await?;
+    manager.set_keepalive_timeout(Duration::from_millis(300));
+    let id = create_peer_id_one();
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    let mut metadata = manager.get_metadata();
+    metadata.id = id.clone();
+    manager.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+
+    // a peer from before features were negotiated completes the handshake without any
+    let mut conn = TcpStream::connect(manager.get_metadata().addrs[0]).await?;
+    let code = auth.generate()?;
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, code.as_bytes());
+    let mut request = vec![0x40, 0x40, 0, 78, 2, 0];
+    request.extend_from_slice(id.as_bytes());
+    request.extend_from_slice(ring::hmac::sign(&key, id.as_bytes()).as_ref());
+    conn.write_all(&request).await?;
+    let mut response = [0u8; 38];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut response)).await??;
+    assert_eq!([0x40, 0x40, 0, 38, 2, 1], response[..6]);
+    conn.write_all(&[0x40, 0x40, 0, 6, 2, 2]).await?;
+    let mut complete = [0u8; 7];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut complete)).await??;
+    assert_eq!([0x40, 0x40, 0, 7, 2, 3, 7], complete);
+
+    // it would not answer pings, so none are sent and the quiet link stays open
+    let mut buffer = [0u8; 1];
+    assert!(timeout(Duration::from_secs(1), conn.read(&mut buffer)).await.is_err());
+    assert!(manager.is_connected(&id));
+    Ok(())
+}
+
+/// connect to `manager` as the known peer `id` from before features were negotiated
+async fn legacy_connect(
+    manager: &P2pManager,
+    id: &PeerId,
+    auth: &PairingAuthenticator,
+) -> Result<TcpStream, Box<dyn Error>> {
+    let mut conn = TcpStream::connect(manager.get_metadata().addrs[0]).await?;
+    let code = auth.generate()?;
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, code.as_bytes());
+    let mut request = vec![0x40, 0x40, 0, 78, 2, 0];
+    request.extend_from_slice(id.as_bytes());
+    request.extend_from_slice(ring::hmac::sign(&key, id.as_bytes()).as_ref());
+    conn.write_all(&request).await?;
+    let mut response = [0u8; 38];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut response)).await??;
+    conn.write_all(&[0x40, 0x40, 0, 6, 2, 2]).await?;
+    let mut complete = [0u8; 7];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut complete)).await??;
+    Ok(conn)
+}
+
+#[tokio::test]
+async fn replaced_connection_closing_keeps_the_peer() -> Result<(), Box<dyn Error>> {
+    let (manager, mut events) = host_manager_with_events().await?;
+    let id = create_peer_id_one();
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    let mut metadata = manager.get_metadata();
+    metadata.id = id.clone();
+    manager.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+
+    // the peer reconnects before its first connection notices it is gone
+    let first = legacy_connect(&manager, &id, &auth).await?;
+    let second = legacy_connect(&manager, &id, &auth).await?;
+    drop(first);
+    sleep(Duration::from_millis(200)).await;
+    assert!(manager.is_connected(&id));
+    assert!(manager.connection(&id).is_some());
+
+    drop(second);
+    sleep(Duration::from_millis(200)).await;
+    assert!(!manager.is_connected(&id));
+    let mut disconnects = 0;
+    while let Ok(event) = events.try_recv() {
+        if let P2pEvent::PeerDisconnected(disconnected) = event {
+            assert_eq!(id, disconnected);
+            disconnects += 1;
+        }
+    }
+    assert_eq!(1, disconnects);
+    Ok(())
+}
+
+#[tokio::test]
+async fn peer_without_chunks_is_sent_data_frames() -> Result<(), Box<dyn Error>> {
+    let (manager, mut events) = host_manager_with_events().await?;
+    let id = create_peer_id_one();
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    let mut metadata = manager.get_metadata();
+    metadata.id = id.clone();
+    manager.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+
+    // a peer which understands control frames but not chunks
+    let mut conn = TcpStream::connect(manager.get_metadata().addrs[0]).await?;
+    let code = auth.generate()?;
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, code.as_bytes());
+    let mut request = vec![0x40, 0x40, 0, 78, 2, 0];
+    request.extend_from_slice(id.as_bytes());
+    request.extend_from_slice(ring::hmac::sign(&key, id.as_bytes()).as_ref());
+    conn.write_all(&request).await?;
+    let mut response = [0u8; 38];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut response)).await??;
+    conn.write_all(&[0x40, 0x40, 0, 7, 2, 2, 1]).await?;
+    let mut complete = [0u8; 7];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut complete)).await??;
+    let Some(P2pEvent::PeerConnected(mut peer)) = events.recv().await else {
+        panic!("the peer did not connect");
+    };
+
+    let data = vec![7u8; 20_000];
+    peer.conn.write_all(&data).await?;
+    let mut received = Vec::new();
+    while received.len() < data.len() {
+        let mut header = [0u8; 6];
+        timeout(Duration::from_secs(1), conn.read_exact(&mut header)).await??;
+        assert_eq!([0x40, 0x40], header[..2]);
+        assert_eq!([3, 0], header[4..], "only data frames are sent");
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]])) - 6;
+        assert!(len <= 8192);
+        let mut payload = vec![0u8; len];
+        timeout(Duration::from_secs(1), conn.read_exact(&mut payload)).await??;
+        received.extend(payload);
+    }
+    assert_eq!(data, received);
+    Ok(())
+}
+
+#[tokio::test]
+async fn unpaired_peer_is_unknown() -> Result<(), Box<dyn Error>> {
+    let manager = host_manager().await?;
+    let id = PeerId::from_string(String::from("ABCDEFGHIJABCDEFGHIJABCDEFGHIJABCDEFGHIJ"))?;
+    let metadata = PeerMetadata {
+        name: String::from("Tester's laptop"),
+        typ: DeviceType::Windows10Desktop,
+        id: id.clone(),
+        addrs: Vec::new(),
+    };
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    manager.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+
+    // a known peer gets as far as checking its code
+    let mut conn = TcpStream::connect(manager.get_metadata().addrs[0]).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+    let mut buffer = [0u8; 10];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2003), buffer);
+
+    manager.remove_known_peer(&id);
+    let mut conn = TcpStream::connect(manager.get_metadata().addrs[0]).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2002), buffer);
+    Ok(())
+}
+
+#[tokio::test]
+async fn discovery_packet_loss() -> Result<(), Box<dyn Error>> {
+    let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+    let receiver_addr = receiver.local_addr()?;
+    let (_receiver_tx, mut receiver_rx) = discovery::start(receiver, receiver_addr);
+
+    let conditions = Conditions {
+        drop_every: Some(2),
+        ..Default::default()
+    };
+    let relay = udp_relay(receiver_addr, conditions).await?;
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    let (sender_tx, _sender_rx) = discovery::start(sender, relay);
+
+    for _ in 0..10 {
+        sender_tx.send(DiscoveryEvent::PresenceRequest).await?;
+    }
+    sleep(Duration::from_millis(200)).await;
+
+    let mut received = 0;
+    while let Ok((DiscoveryEvent::PresenceRequest, _)) = receiver_rx.try_recv() {
+        received += 1;
+    }
+    assert_eq!(5, received);
+    Ok(())
+}
+
+#[tokio::test]
+async fn silent_peer_is_disconnected() -> Result<(), Box<dyn Error>> {
+    let host = host_manager().await?;
+    let config =
+        create_config(create_peer_id_one(), DeviceType::Windows10Desktop, "Tester's laptop");
+    let (client, mut events) = P2pManager::new(config).await?;
+    client.set_keepalive_timeout(Duration::from_millis(300));
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+
+    // the host stops answering right after the handshake, like it lost power
+    let conditions = Conditions {
+        stall_after: Some(128),
+        ..Default::default()
+    };
+    let mut metadata = host.get_metadata();
+    metadata.addrs = vec![tcp_proxy(metadata.addrs[0], conditions).await?];
+    client.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    client.add_discovery(Injected::new(rx));
+    tx.send((
+        DiscoveryEvent::PresenceResponse(discovery::Presence::new(metadata.clone(), [&auth])),
+        create_p2p_addr(),
+    ))
+    .await?;
+    sleep(Duration::from_millis(100)).await;
+
+    let _peer = timeout(Duration::from_secs(1), client.connect_to_peer(&metadata.id)).await??;
+    assert!(client.is_connected(&metadata.id));
+    loop {
+        match timeout(Duration::from_secs(2), events.recv()).await? {
+            Some(P2pEvent::PeerDisconnected(id)) => {
+                assert_eq!(metadata.id, id);
+                break;
+            }
+            Some(_) => continue,
+            None => panic!("the client stopped"),
+        }
+    }
+    assert!(!client.is_connected(&metadata.id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn peer_which_stops_reading_is_disconnected() -> Result<(), Box<dyn Error>> {
+    let host = host_manager().await?;
+    let config =
+        create_config(create_peer_id_one(), DeviceType::Windows10Desktop, "Tester's laptop");
+    let (client, mut events) = P2pManager::new(config).await?;
+    client.set_keepalive_timeout(Duration::from_millis(300));
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+
+    let conditions = Conditions {
+        stall_after: Some(128),
+        ..Default::default()
+    };
+    let mut metadata = host.get_metadata();
+    metadata.addrs = vec![tcp_proxy(metadata.addrs[0], conditions).await?];
+    client.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    client.add_discovery(Injected::new(rx));
+    tx.send((
+        DiscoveryEvent::PresenceResponse(discovery::Presence::new(metadata.clone(), [&auth])),
+        create_p2p_addr(),
+    ))
+    .await?;
+    sleep(Duration::from_millis(100)).await;
+
+    // the writes fill the socket buffers and block the connection's sends
+    let mut peer = timeout(Duration::from_secs(1), client.connect_to_peer(&metadata.id)).await??;
+    tokio::spawn(async move {
+        let data = vec![7u8; 1024 * 1024];
+        while peer.conn.write_all(&data).await.is_ok() {}
+    });
+    loop {
+        match timeout(Duration::from_secs(2), events.recv()).await? {
+            Some(P2pEvent::PeerDisconnected(id)) => {
+                assert_eq!(metadata.id, id);
+                break;
+            }
+            Some(_) => continue,
+            None => panic!("the client stopped"),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn filtered_peer_is_denied() -> Result<(), Box<dyn Error>> {
+    let host = host_manager().await?;
+    let config =
+        create_config(create_peer_id_one(), DeviceType::Windows10Desktop, "Tester's laptop");
+    let (client, _events) = P2pManager::new(config).await?;
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+    let metadata = host.get_metadata();
+    client.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    client.add_discovery(Injected::new(rx));
+    tx.send((
+        DiscoveryEvent::PresenceResponse(discovery::Presence::new(metadata.clone(), [&auth])),
+        create_p2p_addr(),
+    ))
+    .await?;
+    sleep(Duration::from_millis(100)).await;
+
+    // the host refuses the laptop even though it is paired
+    host.set_connection_filter(Some(Arc::new(|peer| {
+        peer.typ != DeviceType::Windows10Desktop
+    })));
+    let result = timeout(Duration::from_secs(1), client.connect_to_peer(&metadata.id)).await?;
+    assert!(matches!(
+        result,
+        Err(p2p::err::HandshakeError::Failure(2005))
+    ));
+    assert!(!host.is_connected(&client.get_metadata().id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_state_and_latency() -> Result<(), Box<dyn Error>> {
+    // the host keeps its events so its end of the connection stays open
+    let (host, _host_events) = host_manager_with_events().await?;
+    let config =
+        create_config(create_peer_id_one(), DeviceType::Windows10Desktop, "Tester's laptop");
+    let (client, _events) = P2pManager::new(config).await?;
+    client.set_keepalive_timeout(Duration::from_millis(300));
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+
+    let conditions = Conditions {
+        latency: Duration::from_millis(25),
+        ..Default::default()
+    };
+    let mut metadata = host.get_metadata();
+    metadata.addrs = vec![tcp_proxy(metadata.addrs[0], conditions).await?];
+    client.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    client.add_discovery(Injected::new(rx));
+    tx.send((
+        DiscoveryEvent::PresenceResponse(discovery::Presence::new(metadata.clone(), [&auth])),
+        create_p2p_addr(),
+    ))
+    .await?;
+    sleep(Duration::from_millis(100)).await;
+
+    let _peer = timeout(Duration::from_secs(1), client.connect_to_peer(&metadata.id)).await??;
+    assert_eq!(None, client.latency(&metadata.id));
+    let connection = client.connection(&metadata.id).expect("the client is connected");
+    assert_eq!(ConnectionType::Client, connection.conn_type);
+    assert_eq!(metadata.addrs[0], connection.addr);
+    sleep(Duration::from_millis(100)).await;
+    let connections = host.connections();
+    assert_eq!(1, connections.len());
+    assert_eq!(client.get_metadata().id, connections[0].id);
+    assert_eq!(ConnectionType::Server, connections[0].conn_type);
+
+    // nothing is sent, so the client pings the host
+    sleep(Duration::from_millis(600)).await;
+    assert!(client.is_connected(&metadata.id));
+    let history = client.latency(&metadata.id).expect("the host answered a ping");
+    assert!(history.latest().unwrap() >= Duration::from_millis(50));
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshakes_beyond_the_limit_are_dropped() -> Result<(), Box<dyn Error>> {
+    let config = P2pConfig {
+        limits: ConnectionLimits {
+            max_handshakes: 1,
+            ..Default::default()
+        },
+        ..create_config(create_peer_id_two(), DeviceType::AppleiPhone, "Tester's phone")
+    };
+    let (host, _events) = P2pManager::new(config).await?;
+    let addr = host.get_metadata().addrs[0];
+
+    // a silent connection holds the only handshake slot until it times out
+    let mut silent = TcpStream::connect(addr).await?;
+    sleep(Duration::from_millis(100)).await;
+    let mut dropped = TcpStream::connect(addr).await?;
+    _ = dropped.write_all(&unknown_connection_request()).await;
+    let mut buffer = [0u8; 10];
+    let read = timeout(Duration::from_millis(500), dropped.read(&mut buffer)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    timeout(Duration::from_secs(2), silent.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2001), buffer);
+
+    // the slot is free again
+    let mut conn = TcpStream::connect(addr).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2002), buffer);
+    Ok(())
+}
+
+#[tokio::test]
+async fn blocked_peer_is_ignored_and_turned_away() -> Result<(), Box<dyn Error>> {
+    let host = host_manager().await?;
+    let blocked = PeerId::from_string(String::from("ABCDEFGHIJABCDEFGHIJABCDEFGHIJABCDEFGHIJ"))?;
+    let metadata = PeerMetadata {
+        name: String::from("Spammer"),
+        typ: DeviceType::LinuxDevice,
+        id: blocked.clone(),
+        addrs: vec![create_p2p_addr()],
+    };
+    let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+    host.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    host.add_discovery(Injected::new(rx));
+    let presence = (
+        DiscoveryEvent::PresenceResponse(discovery::Presence::new(metadata.clone(), [&auth])),
+        create_p2p_addr(),
+    );
+    tx.send(presence.clone()).await?;
+    sleep(Duration::from_millis(100)).await;
+    assert!(host.is_discovered(&blocked));
+
+    host.block_peer(&blocked);
+    assert!(!host.is_discovered(&blocked));
+    tx.send(presence).await?;
+    sleep(Duration::from_millis(100)).await;
+    assert!(!host.is_discovered(&blocked));
+
+    let mut conn = TcpStream::connect(host.get_metadata().addrs[0]).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+    let mut buffer = [0u8; 10];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2007), buffer);
+
+    // once unblocked it is only unknown
+    host.unblock_peer(&blocked);
+    host.remove_known_peer(&blocked);
+    let mut conn = TcpStream::connect(host.get_metadata().addrs[0]).await?;
+    conn.write_all(&unknown_connection_request()).await?;
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buffer)).await??;
+    assert_eq!(connection_failure(2002), buffer);
+    Ok(())
+}
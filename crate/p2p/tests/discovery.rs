@@ -0,0 +1,114 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use p2p::{
+    discovery::{DiscoverySource, Presence},
+    event::{DiscoveryEvent, P2pEvent},
+    manager::P2pManager,
+    pairing::PairingAuthenticator,
+    peer::{DeviceType, PeerCandidate, PeerChange, PeerMetadata},
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::common::*;
+
+mod common;
+
+fn phone(port: u16) -> PeerMetadata {
+    PeerMetadata {
+        name: "Tester's phone".into(),
+        typ: DeviceType::AppleiPhone,
+        id: create_peer_id_two(),
+        addrs: vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))],
+    }
+}
+
+#[tokio::test]
+async fn peer_found_by_several_mechanisms_is_merged() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, mut rx) = P2pManager::new(config).await?;
+    let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+    manager.add_known_peer(PeerCandidate::new(&phone(1000), auth.clone()));
+
+    let (lan, events) = mpsc::channel(1);
+    manager.add_discovery(Injected::named("lan", events));
+    let (bluetooth, events) = mpsc::channel(1);
+    manager.add_discovery(Injected::named("bluetooth", events));
+
+    // each mechanism sees the peer at another address
+    let presence = Presence::new(phone(1000), [&auth]);
+    lan.send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+        .await?;
+    let Some(P2pEvent::PeerDiscovered(_)) = timeout(Duration::from_secs(1), rx.recv()).await? else {
+        panic!("the peer was not discovered");
+    };
+    let presence = Presence::new(phone(2000), [&auth]);
+    bluetooth
+        .send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+        .await?;
+    sleep(Duration::from_millis(100)).await;
+
+    // the peer is discovered once, with what both found
+    assert!(rx.try_recv().is_err());
+    let id = create_peer_id_two();
+    let sources = HashSet::from([
+        DiscoverySource::Custom("lan"),
+        DiscoverySource::Custom("bluetooth"),
+    ]);
+    assert_eq!(sources, manager.discovered_by(&id));
+    let mut paths = manager.paths(&id);
+    paths.sort();
+    assert_eq!([phone(1000).addrs, phone(2000).addrs].concat(), paths);
+    let Some(PeerChange::Updated(metadata)) = manager.discovered_since(0).changes.pop() else {
+        panic!("the merged peer was not logged");
+    };
+    assert_eq!(2, metadata.addrs.len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn paused_discovery_ignores_peers_and_resuming_forgets_them() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, mut rx) = P2pManager::new(config).await?;
+    let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+    manager.add_known_peer(PeerCandidate::new(&phone(1000), auth.clone()));
+    let (tx, events) = mpsc::channel(1);
+    manager.add_discovery(Injected::new(events));
+    let presence = DiscoveryEvent::PresenceResponse(Presence::new(phone(1000), [&auth]));
+    let id = create_peer_id_two();
+
+    // asleep, answers are ignored
+    manager.pause_discovery();
+    assert!(manager.is_paused());
+    tx.send((presence.clone(), create_p2p_addr())).await?;
+    sleep(Duration::from_millis(100)).await;
+    assert!(rx.try_recv().is_err());
+    assert!(!manager.is_discovered(&id));
+
+    // awake, the peer is found again
+    manager.resume_discovery();
+    tx.send((presence.clone(), create_p2p_addr())).await?;
+    let Some(P2pEvent::PeerDiscovered(_)) = timeout(Duration::from_secs(1), rx.recv()).await? else {
+        panic!("the peer was not discovered after resuming");
+    };
+    assert!(manager.is_discovered(&id));
+
+    // its address may have changed during the next sleep, so it has to answer again
+    manager.pause_discovery();
+    manager.resume_discovery();
+    assert!(!manager.is_discovered(&id));
+    let changes = manager.discovered_since(0).changes;
+    assert_eq!(Some(&PeerChange::Removed(id.clone())), changes.last());
+    tx.send((presence, create_p2p_addr())).await?;
+    let Some(P2pEvent::PeerDiscovered(_)) = timeout(Duration::from_secs(1), rx.recv()).await? else {
+        panic!("the peer was not rediscovered");
+    };
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::{error::Error, net::SocketAddr, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt};
+use p2p::{
+    discovery::{Discovery, DiscoverySource, Presence, MAX_PRESENCE_TAGS},
+    event::{DiscoveryEvent, P2pEvent},
+    manager::{P2pManager, MAX_STRANGERS},
+    pairing::PairingAuthenticator,
+    peer::{DeviceType, PeerCandidate, PeerChange, PeerId, PeerMetadata},
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::common::*;
+
+mod common;
+
+/// a discovery where a peer answers every presence request
+struct Answering {
+    peer: Presence,
+    tx: mpsc::Sender<(DiscoveryEvent, SocketAddr)>,
+    rx: Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>>,
+}
+
+impl Discovery for Answering {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Custom("answering")
+    }
+
+    fn announce(&self, _presence: Presence) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn request(&self) -> BoxFuture<'_, ()> {
+        let answer = DiscoveryEvent::PresenceResponse(self.peer.clone());
+        async move {
+            _ = self.tx.send((answer, create_p2p_addr())).await;
+        }
+        .boxed()
+    }
+
+    fn events(&mut self) -> Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>> {
+        self.rx.take()
+    }
+}
+
+#[tokio::test]
+async fn silent_peer_is_lost() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, mut rx) = P2pManager::new(config).await?;
+    manager.set_peer_ttl(Duration::from_millis(200));
+
+    let peer = PeerMetadata {
+        name: "Tester's phone".into(),
+        typ: DeviceType::AppleiPhone,
+        id: create_peer_id_two(),
+        addrs: vec![create_p2p_addr()],
+    };
+    let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+    manager.add_known_peer(PeerCandidate::new(&peer, auth.clone()));
+
+    let (tx, events) = mpsc::channel(1);
+    manager.add_discovery(Injected::new(events));
+    let presence = Presence::new(peer.clone(), [&auth]);
+    tx.send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+        .await?;
+    let Some(P2pEvent::PeerDiscovered(_)) = timeout(Duration::from_secs(1), rx.recv()).await? else {
+        panic!("the peer was not discovered");
+    };
+    assert!(manager.is_discovered(&peer.id));
+
+    // the peer never answers again
+    let Some(P2pEvent::PeerLost(id)) = timeout(Duration::from_secs(3), rx.recv()).await? else {
+        panic!("the peer was not lost");
+    };
+    assert_eq!(peer.id, id);
+    assert!(!manager.is_discovered(&peer.id));
+    let delta = manager.discovered_since(0);
+    assert_eq!(vec![PeerChange::Removed(peer.id)], delta.changes);
+    Ok(())
+}
+
+#[tokio::test]
+async fn forged_presence_is_ignored() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, mut rx) = P2pManager::new(config).await?;
+
+    let peer = PeerMetadata {
+        name: "Tester's phone".into(),
+        typ: DeviceType::AppleiPhone,
+        id: create_peer_id_two(),
+        addrs: vec![create_p2p_addr()],
+    };
+    let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+    manager.add_known_peer(PeerCandidate::new(&peer, auth.clone()));
+
+    // someone else on the lan announces the peer with their own address
+    let (tx, events) = mpsc::channel(2);
+    manager.add_discovery(Injected::new(events));
+    let forged = PeerMetadata {
+        addrs: vec!["192.168.1.66:4000".parse()?],
+        ..peer.clone()
+    };
+    let attacker = PairingAuthenticator::new(b"ASDFGHJKLZASDFGHJKLZ".to_vec())?;
+    for presence in [forged.clone().into(), Presence::new(forged, [&attacker])] {
+        tx.send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+            .await?;
+    }
+    assert!(timeout(Duration::from_millis(200), rx.recv()).await.is_err());
+    assert!(!manager.is_discovered(&peer.id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn presence_signs_for_every_known_peer_in_turn() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, _rx) = P2pManager::new(config).await?;
+    let mut auths = Vec::new();
+    for i in 0..MAX_PRESENCE_TAGS + 4 {
+        let peer = PeerMetadata {
+            name: "Tester's phone".into(),
+            typ: DeviceType::AppleiPhone,
+            id: PeerId::from_string(format!("{i:0>40}"))?,
+            addrs: vec![create_p2p_addr()],
+        };
+        let auth = PairingAuthenticator::new(format!("QWERTYUIOPQWERTY{i:0>4}").into_bytes())?;
+        manager.add_known_peer(PeerCandidate::new(&peer, auth.clone()));
+        auths.push(auth);
+    }
+
+    // a presence only has room for some of the tags, the next one signs for the rest
+    let presences = [manager.presence(), manager.presence()];
+    assert!(presences.iter().all(|p| p.tags.len() == MAX_PRESENCE_TAGS));
+    for auth in &auths {
+        assert!(presences.iter().any(|p| p.is_signed_by(auth)));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn refresh_returns_after_peers_answer() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, _rx) = P2pManager::new(config).await?;
+
+    let peer = PeerMetadata {
+        name: "Tester's phone".into(),
+        typ: DeviceType::AppleiPhone,
+        id: create_peer_id_two(),
+        addrs: vec![create_p2p_addr()],
+    };
+    let auth = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+    manager.add_known_peer(PeerCandidate::new(&peer, auth.clone()));
+
+    let (tx, rx) = mpsc::channel(1);
+    manager.add_discovery(Answering {
+        peer: Presence::new(peer.clone(), [&auth]),
+        tx,
+        rx: Some(rx),
+    });
+    assert!(!manager.is_discovered(&peer.id));
+
+    manager.refresh_discovery().await;
+    assert!(manager.is_discovered(&peer.id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn copied_identity_is_reported_once() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_two(), DeviceType::LinuxDevice, "Tester");
+    let (manager, mut rx) = P2pManager::new(config).await?;
+    let (tx, events) = mpsc::channel(4);
+    manager.add_discovery(Injected::new(events));
+
+    // the node's own response coming back is ignored
+    let own = manager.get_metadata();
+    tx.send((DiscoveryEvent::PresenceResponse(own.clone().into()), create_p2p_addr()))
+        .await?;
+
+    let clone = PeerMetadata {
+        name: "Tester's clone".into(),
+        addrs: vec!["192.168.1.20:4000".parse()?],
+        ..own
+    };
+    for _ in 0..2 {
+        tx.send((DiscoveryEvent::PresenceResponse(clone.clone().into()), create_p2p_addr()))
+            .await?;
+    }
+    let Some(P2pEvent::IdentityConflict { addrs }) =
+        timeout(Duration::from_secs(1), rx.recv()).await?
+    else {
+        panic!("the conflict was not reported");
+    };
+    assert_eq!(clone.addrs, addrs);
+    assert!(timeout(Duration::from_millis(200), rx.recv()).await.is_err());
+    assert!(!manager.is_discovered(&clone.id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn strangers_are_capped_and_expire() -> Result<(), Box<dyn Error>> {
+    let config = create_config(create_peer_id_one(), DeviceType::LinuxDevice, "Tester");
+    let (manager, _rx) = P2pManager::new(config).await?;
+    manager.set_peer_ttl(Duration::from_millis(200));
+
+    // a flood of presences, each with a fresh id
+    let (tx, events) = mpsc::channel(16);
+    manager.add_discovery(Injected::new(events));
+    for n in 0..MAX_STRANGERS + 10 {
+        let stranger = PeerMetadata {
+            name: "Stranger".into(),
+            typ: DeviceType::LinuxDevice,
+            id: PeerId::from_string(format!("{:040}", n))?,
+            addrs: vec![create_p2p_addr()],
+        };
+        tx.send((DiscoveryEvent::PresenceResponse(stranger.into()), create_p2p_addr()))
+            .await?;
+    }
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(MAX_STRANGERS, manager.stranger_count());
+
+    // none of them answers again
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(0, manager.stranger_count());
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use std::{
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use p2p::{
+    discovery::{
+        Discovery, DiscoverySource, Presence, DISCOVERY_MULTICAST, DISCOVERY_MULTICAST_V6,
+    },
+    event::DiscoveryEvent,
+    manager::{P2pConfig, P2pManager},
+    peer::PeerMetadata,
+};
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::common::*;
+
+mod common;
+
+/// a discovery mechanism which records announcements
+struct Recorded(mpsc::UnboundedSender<PeerMetadata>);
+
+impl Discovery for Recorded {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Custom("recorded")
+    }
+
+    fn announce(&self, presence: Presence) -> BoxFuture<'_, ()> {
+        let _ = self.0.send(presence.metadata);
+        async {}.boxed()
+    }
+
+    fn request(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn events(&mut self) -> Option<mpsc::Receiver<(DiscoveryEvent, SocketAddr)>> {
+        None
+    }
+}
+
+#[tokio::test]
+async fn lan_change_is_advertised() -> Result<(), Box<dyn Error>> {
+    let config = P2pConfig {
+        lan: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+        ..create_config(create_peer_id_one(), p2p::peer::DeviceType::LinuxDevice, "Tester")
+    };
+    let (manager, _rx) = P2pManager::new(config).await?;
+    let port = manager.get_metadata().addrs[0].port();
+    let (announced, mut announcements) = mpsc::unbounded_channel();
+    manager.add_discovery(Recorded(announced));
+
+    // the listener is now reachable on another ip, only v4 since it is not dual-stack
+    let lan = [
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+        IpAddr::V6("fd00::2".parse()?),
+    ];
+    manager.set_lan(&lan).await;
+    let expected = vec![SocketAddr::new(lan[0], port)];
+    assert_eq!(expected, manager.get_metadata().addrs);
+
+    // peers hear about it without asking
+    let metadata = timeout(Duration::from_secs(1), announcements.recv()).await?;
+    assert_eq!(Some(expected), metadata.map(|m| m.addrs));
+    Ok(())
+}
+
+#[tokio::test]
+async fn discovery_follows_the_interfaces() -> Result<(), Box<dyn Error>> {
+    // the ip of the interface routing off the host, there is none on a host without network
+    let Ok(ip) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect((Ipv4Addr::new(198, 51, 100, 1), 9)).map(|_| socket))
+        .and_then(|socket| socket.local_addr())
+        .map(|addr| addr.ip())
+    else {
+        return Ok(());
+    };
+    let config = P2pConfig {
+        multicast: SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50699)),
+        multicast_v6: Some(SocketAddr::V6(SocketAddrV6::new(
+            DISCOVERY_MULTICAST_V6,
+            50699,
+            0,
+            0,
+        ))),
+        lan: vec![ip],
+        ..create_config(create_peer_id_one(), p2p::peer::DeviceType::LinuxDevice, "Tester")
+    };
+    let (manager, _rx) = P2pManager::new(config).await?;
+    assert_eq!(vec![ip], manager.multicast_interfaces());
+
+    // an ipv6 address comes up after starting
+    manager.set_lan(&[ip, IpAddr::V6("fd00::2".parse()?)]).await;
+    let v6 = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+    assert_eq!(vec![ip, v6], manager.multicast_interfaces());
+
+    // the interface goes down and the group is left on it
+    manager.set_lan(&[IpAddr::V6("fd00::2".parse()?)]).await;
+    assert_eq!(vec![v6], manager.multicast_interfaces());
+    manager.set_lan(&[]).await;
+    assert!(manager.multicast_interfaces().is_empty());
+    Ok(())
+}
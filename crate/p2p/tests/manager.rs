@@ -1,12 +1,15 @@
 use std::{error::Error, time::Duration};
 
 use p2p::{
-    event::P2pEvent,
+    discovery::Presence,
+    event::{DiscoveryEvent, P2pEvent},
     manager::{P2pConfig, P2pManager},
     pairing::PairingAuthenticator,
     peer::{ConnectionType, PeerCandidate},
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
 use tokio::time::{sleep, timeout};
 use tracing::Level;
 
@@ -26,30 +29,40 @@ async fn peers_discover_connect_send_data() -> Result<(), Box<dyn Error>> {
     let auth_b = PairingAuthenticator::new(shared_secret.to_vec())?;
 
     // node A setup
+    let (id_a, identity_a) = create_identity(1);
     let config = P2pConfig {
-        id: create_peer_id_one(),
+        id: id_a,
         device: p2p::peer::DeviceType::Windows10Desktop,
         name: String::from("Tester's laptop"),
         multicast: create_multicast_addr(),
+        multicast_v6: None,
         p2p_addr: create_p2p_addr(),
+        lan: Vec::new(),
+        identity: Some(identity_a),
+        limits: Default::default(),
     };
     let (manager_a, mut rx_a) = P2pManager::new(config).await?;
 
     // node B setup
+    let (id_b, identity_b) = create_identity(2);
     let config = P2pConfig {
-        id: create_peer_id_two(),
+        id: id_b,
         device: p2p::peer::DeviceType::AppleiPhone,
         name: String::from("Tester's phone"),
         multicast: create_multicast_addr(),
+        multicast_v6: None,
         p2p_addr: create_p2p_addr(),
+        lan: Vec::new(),
+        identity: Some(identity_b),
+        limits: Default::default(),
     };
     let (manager_b, mut rx_b) = P2pManager::new(config).await?;
 
     // subscribe to node B
     let a = manager_a.get_metadata();
     let b = manager_b.get_metadata();
-    manager_a.add_known_peer(PeerCandidate::new(b, auth_b));
-    manager_b.add_known_peer(PeerCandidate::new(a, auth_a));
+    manager_a.add_known_peer(PeerCandidate::new(&b, auth_b));
+    manager_b.add_known_peer(PeerCandidate::new(&a, auth_a));
 
     // node A sends presence request
     sleep(Duration::from_millis(100)).await;
@@ -119,3 +132,42 @@ async fn peers_discover_connect_send_data() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn shutdown_stops_every_task() -> Result<(), Box<dyn Error>> {
+    let config = create_config(
+        create_peer_id_two(),
+        p2p::peer::DeviceType::AppleiPhone,
+        "Tester's phone",
+    );
+    let (host, _host_events) = P2pManager::new(config).await?;
+    let config = create_config(
+        create_peer_id_one(),
+        p2p::peer::DeviceType::Windows10Desktop,
+        "Tester's laptop",
+    );
+    let (client, _client_events) = P2pManager::new(config).await?;
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    let metadata = host.get_metadata();
+    host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+    client.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+
+    // connected, discovering, mapping a port and looking for a rendezvous server
+    let (tx, rx) = mpsc::channel(1);
+    client.add_discovery(Injected::new(rx));
+    let presence = Presence::new(metadata.clone(), [&auth]);
+    tx.send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+        .await?;
+    sleep(Duration::from_millis(100)).await;
+    let _peer = timeout(Duration::from_secs(1), client.connect_to_peer(&metadata.id)).await??;
+    client.start_port_mapping("127.0.0.1:9".parse()?).await;
+    client.start_relay("127.0.0.1:9".parse()?);
+    assert!(client.task_count() > 0);
+
+    for manager in [&client, &host] {
+        manager.shutdown().await;
+        assert_eq!(0, manager.task_count());
+    }
+    assert_eq!(0, Handle::current().metrics().num_alive_tasks());
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use p2p::{
+    discovery::{DiscoverySource, DISCOVERY_MULTICAST},
+    event::{DiscoveryEvent, Observation, P2pEvent},
+    manager::{P2pConfig, P2pManager},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::common::*;
+
+mod common;
+
+#[tokio::test]
+async fn observer_reports_frames_without_announcing() -> Result<(), Box<dyn Error>> {
+    let config = create_config(
+        create_peer_id_one(),
+        p2p::peer::DeviceType::LinuxDevice,
+        "Observer",
+    );
+    let (manager, mut rx) = P2pManager::new(config).await?;
+    let (tx, events) = mpsc::channel(4);
+    let (injected, mut announcements) = Injected::recording(events);
+    manager.add_discovery(injected);
+    manager.set_observer(true);
+
+    let from = create_p2p_addr();
+    tx.send((DiscoveryEvent::PresenceRequest, from)).await?;
+    let Some(P2pEvent::Observed { from: seen, observation, .. }) = timeout(Duration::from_secs(1), rx.recv()).await? else {
+        panic!("the presence request was not observed");
+    };
+    assert_eq!(Some(from), seen);
+    assert_eq!(Observation::PresenceRequest, observation);
+
+    // the request is never answered while observing
+    sleep(Duration::from_millis(100)).await;
+    assert!(announcements.try_recv().is_err());
+
+    // nothing is reported once observing stops and requests are answered again
+    manager.set_observer(false);
+    tx.send((DiscoveryEvent::PresenceRequest, from)).await?;
+    timeout(Duration::from_secs(1), announcements.recv()).await?;
+    assert!(rx.try_recv().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn observer_reports_truncated_frames() -> Result<(), Box<dyn Error>> {
+    let group = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50700));
+    let config = P2pConfig {
+        multicast: group,
+        ..create_config(create_peer_id_one(), p2p::peer::DeviceType::LinuxDevice, "Observer")
+    };
+    let (manager, mut rx) = P2pManager::new(config).await?;
+    manager.set_observer(true);
+
+    // a presence response header announcing more than the datagram carries
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    socket.send_to(&[0x40, 0x40, 0, 40, 1, 1, 0], (Ipv4Addr::LOCALHOST, group.port())).await?;
+
+    let event = timeout(Duration::from_secs(1), rx.recv()).await?;
+    let Some(P2pEvent::Observed {
+        source,
+        from,
+        observation,
+    }) = event
+    else {
+        panic!("the truncated frame was not observed");
+    };
+    assert_eq!(DiscoverySource::Multicast, source);
+    assert_eq!(None, from);
+    assert!(matches!(observation, Observation::Malformed(_)));
+    Ok(())
+}
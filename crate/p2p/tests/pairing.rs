@@ -0,0 +1,265 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use p2p::{
+    event::{DiscoveryEvent, P2pEvent},
+    manager::{P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::PeerCandidate,
+    rng::SeededRng,
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::common::*;
+
+mod common;
+
+async fn manager(
+    seed: u8,
+) -> Result<(Arc<P2pManager>, mpsc::UnboundedReceiver<P2pEvent>), Box<dyn Error>> {
+    let (id, identity) = create_identity(seed);
+    let config = P2pConfig {
+        identity: Some(identity),
+        ..create_config(id, p2p::peer::DeviceType::LinuxDevice, &format!("Tester {seed}"))
+    };
+    Ok(P2pManager::new(config).await?)
+}
+
+/// make `to` visible to `from` as an unpaired peer
+async fn discover(from: &P2pManager, to: &P2pManager) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel(1);
+    from.add_discovery(Injected::new(rx));
+    tx.send((DiscoveryEvent::PresenceResponse(to.presence()), create_p2p_addr()))
+        .await?;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(1, from.stranger_count());
+    Ok(())
+}
+
+/// make the paired peer `to` visible to `from` through a presence it signed
+async fn find(from: &P2pManager, to: &P2pManager) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel(1);
+    from.add_discovery(Injected::new(rx));
+    tx.send((DiscoveryEvent::PresenceResponse(to.presence()), create_p2p_addr()))
+        .await?;
+    sleep(Duration::from_millis(100)).await;
+    assert!(from.is_discovered(&to.get_metadata().id));
+    Ok(())
+}
+
+/// the secret and previous one of the next KeyRotated event on `rx`
+async fn rotated(
+    rx: &mut mpsc::UnboundedReceiver<P2pEvent>,
+) -> Result<(String, Option<String>), Box<dyn Error>> {
+    loop {
+        match timeout(Duration::from_secs(1), rx.recv()).await? {
+            Some(P2pEvent::KeyRotated { secret, previous, .. }) => return Ok((secret, previous)),
+            Some(_) => continue,
+            None => panic!("the manager stopped"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unpaired_peers_pair_after_confirming_the_code() -> Result<(), Box<dyn Error>> {
+    let (manager_a, mut rx_a) = manager(11).await?;
+    let (manager_b, mut rx_b) = manager(12).await?;
+    discover(&manager_a, &manager_b).await?;
+
+    let id_b = manager_b.get_metadata().id.clone();
+    let pairing = tokio::spawn({
+        let manager_a = manager_a.clone();
+        async move { manager_a.pair_with(&id_b).await }
+    });
+
+    let Some(P2pEvent::PairCode { code: shown, .. }) = timeout(Duration::from_secs(1), rx_a.recv()).await? else {
+        panic!("node a did not show a pairing code");
+    };
+    let Some(P2pEvent::PairRequest { metadata, code }) = timeout(Duration::from_secs(1), rx_b.recv()).await? else {
+        panic!("node b did not receive the pairing request");
+    };
+    assert_eq!(manager_a.get_metadata().id, metadata.id);
+    assert_eq!(shown, code);
+    assert!(manager_b.answer_pairing(&metadata.id, true));
+
+    let candidate = timeout(Duration::from_secs(1), pairing).await???;
+    assert_eq!(manager_b.get_metadata().id, candidate.id);
+    assert_eq!(0, manager_a.stranger_count());
+
+    // both sides hand the same secret to the application to store
+    let Some(P2pEvent::Paired { secret: secret_a, .. }) = timeout(Duration::from_secs(1), rx_a.recv()).await? else {
+        panic!("node a did not pair");
+    };
+    let Some(P2pEvent::Paired { secret: secret_b, .. }) = timeout(Duration::from_secs(1), rx_b.recv()).await? else {
+        panic!("node b did not pair");
+    };
+    assert_eq!(secret_a, secret_b);
+    Ok(())
+}
+
+#[tokio::test]
+async fn declined_pairing_fails() -> Result<(), Box<dyn Error>> {
+    let (manager_a, _rx_a) = manager(13).await?;
+    let (manager_b, mut rx_b) = manager(14).await?;
+    discover(&manager_a, &manager_b).await?;
+
+    let id_b = manager_b.get_metadata().id.clone();
+    let pairing = tokio::spawn({
+        let manager_a = manager_a.clone();
+        async move { manager_a.pair_with(&id_b).await }
+    });
+
+    let Some(P2pEvent::PairRequest { metadata, .. }) = timeout(Duration::from_secs(1), rx_b.recv()).await? else {
+        panic!("node b did not receive the pairing request");
+    };
+    assert!(manager_b.answer_pairing(&metadata.id, false));
+
+    let result = timeout(Duration::from_secs(1), pairing).await?;
+    assert!(matches!(result?, Err(p2p::err::HandshakeError::Failure(2004))));
+    assert!(!manager_b.answer_pairing(&metadata.id, true));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pin_pairing_needs_no_answer() -> Result<(), Box<dyn Error>> {
+    let (manager_a, mut rx_a) = manager(15).await?;
+    let (manager_b, mut rx_b) = manager(16).await?;
+    discover(&manager_a, &manager_b).await?;
+
+    let pin = manager_b.start_pin_pairing()?;
+    let id_b = manager_b.get_metadata().id.clone();
+    let candidate = timeout(Duration::from_secs(1), manager_a.pair_with_pin(&id_b, &pin)).await??;
+    assert_eq!(id_b, candidate.id);
+
+    // neither user is asked to compare a code
+    let Some(P2pEvent::Paired { secret: secret_a, .. }) = timeout(Duration::from_secs(1), rx_a.recv()).await? else {
+        panic!("node a did not pair");
+    };
+    let Some(P2pEvent::PinUsed(by)) = timeout(Duration::from_secs(1), rx_b.recv()).await? else {
+        panic!("node b did not report who used the pin");
+    };
+    assert_eq!(manager_a.get_metadata().id, by);
+    let Some(P2pEvent::Paired { secret: secret_b, .. }) = timeout(Duration::from_secs(1), rx_b.recv()).await? else {
+        panic!("node b did not pair");
+    };
+    assert_eq!(secret_a, secret_b);
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoked_pin_does_not_pair() -> Result<(), Box<dyn Error>> {
+    let (manager_a, _rx_a) = manager(29).await?;
+    let (manager_b, _rx_b) = manager(30).await?;
+    discover(&manager_a, &manager_b).await?;
+
+    let pin = manager_b.start_pin_pairing()?;
+    assert!(manager_b.revoke_pin());
+    assert!(!manager_b.revoke_pin());
+    assert!(manager_b.pin_invitation().is_none());
+    let id_b = manager_b.get_metadata().id.clone();
+    let result = timeout(Duration::from_secs(1), manager_a.pair_with_pin(&id_b, &pin)).await?;
+    assert!(matches!(result, Err(p2p::err::HandshakeError::Failure(2003))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pin_is_used_up_by_a_wrong_guess() -> Result<(), Box<dyn Error>> {
+    let (manager_a, _rx_a) = manager(17).await?;
+    let (manager_b, _rx_b) = manager(18).await?;
+    discover(&manager_a, &manager_b).await?;
+
+    let pin = manager_b.start_pin_pairing()?;
+    let wrong = if pin == "000000" { "000001" } else { "000000" };
+    let id_b = manager_b.get_metadata().id.clone();
+    let result = timeout(Duration::from_secs(1), manager_a.pair_with_pin(&id_b, wrong)).await?;
+    assert!(matches!(result, Err(p2p::err::HandshakeError::Failure(2003))));
+
+    let result = timeout(Duration::from_secs(1), manager_a.pair_with_pin(&id_b, &pin)).await?;
+    assert!(matches!(result, Err(p2p::err::HandshakeError::Failure(2003))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn secret_rotates_on_every_connection() -> Result<(), Box<dyn Error>> {
+    let (manager_a, mut rx_a) = manager(19).await?;
+    let (manager_b, mut rx_b) = manager(20).await?;
+    let paired = "QWERTYUIOPQWERTYUIOP";
+    let auth = PairingAuthenticator::new(paired.as_bytes().to_vec())?;
+    manager_a.add_known_peer(PeerCandidate::new(&manager_b.get_metadata(), auth.clone()));
+    manager_b.add_known_peer(PeerCandidate::new(&manager_a.get_metadata(), auth));
+    find(&manager_a, &manager_b).await?;
+
+    // the client hands over a new secret, the host keeps the paired one until it is used
+    let id_b = manager_b.get_metadata().id;
+    let peer = timeout(Duration::from_secs(1), manager_a.connect_to_peer(&id_b)).await??;
+    let (secret_a, previous_a) = rotated(&mut rx_a).await?;
+    let (secret_b, previous_b) = rotated(&mut rx_b).await?;
+    assert_eq!(secret_a, secret_b);
+    assert_ne!(paired, secret_a);
+    assert_eq!(None, previous_a);
+    assert_eq!(Some(paired.to_string()), previous_b);
+
+    // the next connection is made with the new secret and rotates it again
+    drop(peer);
+    sleep(Duration::from_millis(100)).await;
+    let _peer = timeout(Duration::from_secs(1), manager_a.connect_to_peer(&id_b)).await??;
+    let (next, _) = rotated(&mut rx_a).await?;
+    let (_, previous) = rotated(&mut rx_b).await?;
+    assert_ne!(secret_a, next);
+    assert_eq!(Some(secret_a), previous);
+    Ok(())
+}
+
+#[tokio::test]
+async fn missed_rotation_falls_back_to_the_previous_secret() -> Result<(), Box<dyn Error>> {
+    // either side connects first, the other one only has the old secret
+    for (seed, a_connects) in [(21, true), (23, false)] {
+        let (manager_a, _rx_a) = manager(seed).await?;
+        let (manager_b, _rx_b) = manager(seed + 1).await?;
+        let old = PairingAuthenticator::new(b"QWERTYUIOPQWERTYUIOP".to_vec())?;
+        let new = PairingAuthenticator::new(b"ASDFGHJKLZASDFGHJKLZ".to_vec())?;
+
+        // b took the new secret but a never saw it acknowledged
+        let mut rotated = PeerCandidate::new(&manager_a.get_metadata(), new);
+        rotated.previous = Some(old.clone());
+        manager_b.add_known_peer(rotated);
+        manager_a.add_known_peer(PeerCandidate::new(&manager_b.get_metadata(), old));
+
+        find(&manager_a, &manager_b).await?;
+        find(&manager_b, &manager_a).await?;
+        let (client, host) = if a_connects {
+            (&manager_a, &manager_b)
+        } else {
+            (&manager_b, &manager_a)
+        };
+        let id = host.get_metadata().id;
+        timeout(Duration::from_secs(1), client.connect_to_peer(&id)).await??;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn seeded_pairing_is_reproducible() -> Result<(), Box<dyn Error>> {
+    // the pin shown and the secret agreed on by a pin pairing where both use the same seed
+    async fn pair(seeds: (u8, u8)) -> Result<(String, String), Box<dyn Error>> {
+        let (manager_a, mut rx_a) = manager(seeds.0).await?;
+        let (manager_b, _rx_b) = manager(seeds.1).await?;
+        for manager in [&manager_a, &manager_b] {
+            manager.set_rng(Arc::new(SeededRng::new([7; 32])));
+        }
+        discover(&manager_a, &manager_b).await?;
+
+        let pin = manager_b.start_pin_pairing()?;
+        let id_b = manager_b.get_metadata().id.clone();
+        timeout(Duration::from_secs(1), manager_a.pair_with_pin(&id_b, &pin)).await??;
+        let Some(P2pEvent::Paired { secret, .. }) = timeout(Duration::from_secs(1), rx_a.recv()).await? else {
+            panic!("node a did not pair");
+        };
+        Ok((pin, secret))
+    }
+
+    assert_eq!(pair((25, 26)).await?, pair((27, 28)).await?);
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::{
+    error::Error,
+    net::{SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use p2p::{
+    discovery::{Presence, DISCOVERY_MULTICAST},
+    event::DiscoveryEvent,
+    manager::{P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::DeviceType,
+    synthetic::SyntheticPeers,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::common::*;
+
+mod common;
+
+#[tokio::test]
+async fn synthetic_peers_handshake_over_tls_and_echo() -> Result<(), Box<dyn Error>> {
+    let secret = b"123ABCThisIsSuperSecretShhhh!".to_vec();
+    let multicast = SocketAddr::V4(SocketAddrV4::new(DISCOVERY_MULTICAST, 50701));
+    let (id, identity) = create_identity(1);
+    let config = P2pConfig {
+        multicast,
+        identity: Some(identity),
+        ..create_config(id, DeviceType::Windows10Desktop, "Tester's laptop")
+    };
+    let (node, _events) = P2pManager::new(config).await?;
+    let swarm = SyntheticPeers::spawn(3, &node.get_metadata(), multicast, secret.clone()).await?;
+    for candidate in swarm.candidates() {
+        node.add_known_peer(candidate);
+    }
+
+    // the swarm's presences are handed over directly, multicast may not loop back
+    let auth = PairingAuthenticator::new(secret)?;
+    let (tx, rx) = mpsc::channel(8);
+    node.add_discovery(Injected::new(rx));
+    for metadata in swarm.metadata() {
+        let presence = Presence::new(metadata, [&auth]);
+        tx.send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+            .await?;
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    for metadata in swarm.metadata() {
+        let mut peer = timeout(Duration::from_secs(2), node.connect_to_peer(&metadata.id)).await??;
+        assert_eq!(metadata.id, peer.id);
+        let mut buffer = [0u8; 4];
+        peer.conn.write_all(b"PING").await?;
+        timeout(Duration::from_secs(1), peer.conn.read_exact(&mut buffer)).await??;
+        assert_eq!(b"PING", &buffer);
+    }
+    swarm.shutdown().await;
+    Ok(())
+}
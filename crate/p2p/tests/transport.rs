@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use p2p::{
+    discovery::Presence,
+    event::{DiscoveryEvent, P2pEvent},
+    manager::{P2pConfig, P2pManager},
+    pairing::PairingAuthenticator,
+    peer::{DeviceType, PeerCandidate},
+    transport::{BoxedStream, Listener, Transport},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::common::*;
+
+mod common;
+
+type Incoming = mpsc::UnboundedSender<(BoxedStream, SocketAddr)>;
+
+/// A transport which never leaves the process, connections are in-memory pipes between managers
+/// sharing it
+#[derive(Clone, Default)]
+struct Memory {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Incoming>>>,
+    ports: Arc<AtomicU16>,
+}
+
+impl Memory {
+    fn next_addr(&self) -> SocketAddr {
+        let port = self.ports.fetch_add(1, Ordering::SeqCst) + 1;
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port))
+    }
+}
+
+struct MemoryListener {
+    addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<(BoxedStream, SocketAddr)>,
+}
+
+impl Transport for Memory {
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>> {
+        async move {
+            let listener = self.listeners.lock().unwrap().get(&addr).cloned();
+            let Some(listener) = listener else {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            };
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            listener
+                .send((Box::new(server), self.next_addr()))
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            Ok(Box::new(client) as BoxedStream)
+        }
+        .boxed()
+    }
+
+    fn listen(&self, _addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        async move {
+            let addr = self.next_addr();
+            let (tx, incoming) = mpsc::unbounded_channel();
+            self.listeners.lock().unwrap().insert(addr, tx);
+            Ok(Box::new(MemoryListener { addr, incoming }) as Box<dyn Listener>)
+        }
+        .boxed()
+    }
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedStream, SocketAddr)>> {
+        async move {
+            self.incoming
+                .recv()
+                .await
+                .ok_or_else(|| io::ErrorKind::BrokenPipe.into())
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[tokio::test]
+async fn peers_connect_over_a_custom_transport() -> Result<(), Box<dyn Error>> {
+    let memory = Memory::default();
+    let (id, identity) = create_identity(1);
+    let config = P2pConfig {
+        identity: Some(identity),
+        ..create_config(id, DeviceType::AppleiPhone, "Tester's phone")
+    };
+    let (host, mut host_events) = P2pManager::with_transport(config, memory.clone()).await?;
+    let (id, identity) = create_identity(2);
+    let config = P2pConfig {
+        identity: Some(identity),
+        ..create_config(id, DeviceType::Windows10Desktop, "Tester's laptop")
+    };
+    let (client, _events) = P2pManager::with_transport(config, memory).await?;
+
+    // the host listens on the in-memory address, not on a socket
+    let metadata = host.get_metadata();
+    assert_eq!(Ipv4Addr::new(10, 0, 0, 1), metadata.addrs[0].ip());
+    let auth = PairingAuthenticator::new(b"123ABCThisIsSuperSecretShhhh!".to_vec())?;
+    host.add_known_peer(PeerCandidate::new(&client.get_metadata(), auth.clone()));
+    client.add_known_peer(PeerCandidate::new(&metadata, auth.clone()));
+    let (tx, rx) = mpsc::channel(1);
+    client.add_discovery(Injected::new(rx));
+    let presence = Presence::new(metadata.clone(), [&auth]);
+    tx.send((DiscoveryEvent::PresenceResponse(presence), create_p2p_addr()))
+        .await?;
+    sleep(Duration::from_millis(100)).await;
+
+    let mut peer = timeout(Duration::from_secs(2), client.connect_to_peer(&metadata.id)).await??;
+    assert_eq!(metadata.addrs[0], client.connection(&metadata.id).unwrap().addr);
+    peer.conn.write_all(b"hello").await?;
+    let mut connected = loop {
+        match timeout(Duration::from_secs(1), host_events.recv()).await? {
+            Some(P2pEvent::PeerConnected(peer)) => break peer,
+            Some(_) => continue,
+            None => panic!("the host stopped"),
+        }
+    };
+    let mut buffer = [0u8; 5];
+    timeout(Duration::from_secs(1), connected.conn.read_exact(&mut buffer)).await??;
+    assert_eq!(b"hello", &buffer);
+    Ok(())
+}